@@ -0,0 +1,309 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    fs, io, mem,
+    os::fd::{AsFd, AsRawFd, BorrowedFd},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use aya::{maps::loaded_maps, programs::loaded_programs};
+use serde::Serialize;
+
+const STATS_ENABLED_SYSCTL: &str = "/proc/sys/kernel/bpf_stats_enabled";
+
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+
+/// Where a tc program is attached.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AttachPoint {
+    pub iface: String,
+    pub ifindex: u32,
+    pub direction: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProgramInfo {
+    pub id: u32,
+    pub name: String,
+    pub program_type: String,
+    pub attached_to: Vec<AttachPoint>,
+    /// Only counted while `kernel.bpf_stats_enabled` is set.
+    pub run_count: Option<u64>,
+    pub run_time_ns: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MapInfo {
+    pub id: u32,
+    pub name: String,
+    pub map_type: String,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    /// `None` for maps that cannot be iterated, e.g. ring buffers.
+    pub entries: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BpfInfo {
+    pub stats_enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    pub programs: Vec<ProgramInfo>,
+    pub maps: Vec<MapInfo>,
+}
+
+/// A program the loader loaded: the kernel's id for it and its name in the
+/// object, which the kernel truncates to 15 bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedProgram {
+    pub id: u32,
+    pub name: String,
+}
+
+/// Reports the eBPF programs the agent loaded and the maps they use, as the
+/// kernel sees them. Everything is looked up by id on each read, so run
+/// counts and entry counts are current.
+#[derive(Clone)]
+pub struct BpfInfoReader {
+    programs: Arc<Vec<LoadedProgram>>,
+    map_ids: Arc<Vec<u32>>,
+    attachments: Arc<Vec<(String, AttachPoint)>>,
+}
+
+impl BpfInfoReader {
+    /// `attachments` pairs a program name with one place it is attached.
+    pub fn new(
+        programs: Vec<LoadedProgram>,
+        map_ids: Vec<u32>,
+        attachments: Vec<(String, AttachPoint)>,
+    ) -> Self {
+        Self {
+            programs: Arc::new(programs),
+            map_ids: Arc::new(map_ids),
+            attachments: Arc::new(attachments),
+        }
+    }
+
+    /// Makes a bpf syscall per program and map, and walks the keys of hash
+    /// maps, so it blocks.
+    pub fn read(&self) -> Result<BpfInfo> {
+        let stats_enabled =
+            fs::read_to_string(STATS_ENABLED_SYSCTL).is_ok_and(|enabled| enabled.trim() == "1");
+
+        // Objects can go away while they are listed; those are skipped.
+        let mut programs = BTreeMap::new();
+        for info in loaded_programs().flatten() {
+            let Some(loaded) = self.programs.iter().find(|loaded| loaded.id == info.id()) else {
+                continue;
+            };
+            let (run_count, run_time_ns) = match stats_enabled {
+                true => run_counters(&read_fdinfo(info.fd()?.as_fd())?),
+                false => (None, None),
+            };
+            let attached_to = self
+                .attachments
+                .iter()
+                .filter(|(program, _)| *program == loaded.name)
+                .map(|(_, attach_point)| attach_point.clone())
+                .collect();
+
+            programs.insert(
+                info.id(),
+                ProgramInfo {
+                    id: info.id(),
+                    name: loaded.name.clone(),
+                    program_type: program_type_name(info.program_type()),
+                    attached_to,
+                    run_count,
+                    run_time_ns,
+                },
+            );
+        }
+
+        let mut maps = BTreeMap::new();
+        for info in loaded_maps().flatten() {
+            if !self.map_ids.contains(&info.id()) {
+                continue;
+            }
+            let entries = match has_entry_count(info.map_type()) {
+                true => count_entries(info.fd()?.as_fd(), info.key_size(), info.max_entries()),
+                false => None,
+            };
+
+            maps.insert(
+                info.id(),
+                MapInfo {
+                    id: info.id(),
+                    name: info.name_as_str().unwrap_or_default().to_string(),
+                    map_type: map_type_name(info.map_type()),
+                    key_size: info.key_size(),
+                    value_size: info.value_size(),
+                    max_entries: info.max_entries(),
+                    entries,
+                },
+            );
+        }
+
+        Ok(BpfInfo {
+            stats_enabled,
+            hint: (!stats_enabled)
+                .then(|| "run counts need `sysctl -w kernel.bpf_stats_enabled=1`".to_string()),
+            programs: programs.into_values().collect(),
+            maps: maps.into_values().collect(),
+        })
+    }
+}
+
+/// The interface index of `iface`, or 0 if it is gone.
+pub fn ifindex(iface: &str) -> u32 {
+    CString::new(iface)
+        .map(|iface| unsafe { libc::if_nametoindex(iface.as_ptr()) })
+        .unwrap_or(0)
+}
+
+fn read_fdinfo(fd: BorrowedFd<'_>) -> Result<String> {
+    Ok(fs::read_to_string(format!(
+        "/proc/self/fdinfo/{}",
+        fd.as_raw_fd()
+    ))?)
+}
+
+/// Reads a program's run counters from the `key:\tvalue` lines of its
+/// fdinfo. Kernels before 5.1 have none.
+fn run_counters(fdinfo: &str) -> (Option<u64>, Option<u64>) {
+    let fields = fdinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect::<HashMap<_, _>>();
+    let counter = |key| fields.get(key).and_then(|value| value.parse().ok());
+
+    (counter("run_cnt"), counter("run_time_ns"))
+}
+
+/// Whether counting a map's keys says anything: an array always holds
+/// `max_entries` of them, and a ring buffer has none.
+fn has_entry_count(map_type: u32) -> bool {
+    // hash, percpu_hash, lru_hash, lru_percpu_hash, lpm_trie, sockhash
+    matches!(map_type, 1 | 5 | 9 | 10 | 11 | 18)
+}
+
+fn program_type_name(program_type: u32) -> String {
+    match program_type {
+        1 => "socket_filter",
+        3 => "sched_cls",
+        6 => "xdp",
+        8 => "cgroup_skb",
+        9 => "cgroup_sock",
+        13 => "sock_ops",
+        14 => "sk_skb",
+        16 => "sk_msg",
+        other => return other.to_string(),
+    }
+    .to_string()
+}
+
+fn map_type_name(map_type: u32) -> String {
+    match map_type {
+        1 => "hash",
+        2 => "array",
+        3 => "prog_array",
+        4 => "perf_event_array",
+        5 => "percpu_hash",
+        6 => "percpu_array",
+        9 => "lru_hash",
+        10 => "lru_percpu_hash",
+        11 => "lpm_trie",
+        15 => "sockmap",
+        18 => "sockhash",
+        27 => "ringbuf",
+        other => return other.to_string(),
+    }
+    .to_string()
+}
+
+#[repr(C)]
+struct MapGetNextKeyAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    next_key: u64,
+    flags: u64,
+}
+
+/// Counts a map's entries by walking its keys. Entries can come and go
+/// meanwhile, so the walk never goes past the map's capacity.
+fn count_entries(fd: BorrowedFd<'_>, key_size: u32, max_entries: u32) -> Option<u64> {
+    let mut key = vec![0u8; key_size as usize];
+    let mut next_key = vec![0u8; key_size as usize];
+    let mut count = 0;
+
+    while count < max_entries as u64 {
+        let mut attr = MapGetNextKeyAttr {
+            map_fd: fd.as_raw_fd() as u32,
+            _pad: 0,
+            // No key asks for the first one.
+            key: if count == 0 { 0 } else { key.as_ptr() as u64 },
+            next_key: next_key.as_mut_ptr() as u64,
+            flags: 0,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_MAP_GET_NEXT_KEY,
+                &mut attr as *mut MapGetNextKeyAttr,
+                mem::size_of::<MapGetNextKeyAttr>(),
+            )
+        };
+        if ret < 0 {
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENOENT) => Some(count),
+                _ => None,
+            };
+        }
+
+        count += 1;
+        mem::swap(&mut key, &mut next_key);
+    }
+
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROG_FDINFO: &str = "pos:\t0\nflags:\t02000002\nmnt_id:\t15\nino:\t1057\nprog_type:\t3\nprog_jited:\t1\nprog_tag:\tc5b5d2c6ef4ad2ac\nmemlock:\t4096\nprog_id:\t42\nrun_time_ns:\t183204\nrun_cnt:\t917\nrecursion_misses:\t0\nverified_insns:\t311\n";
+
+    #[test]
+    fn test_run_counters() {
+        assert_eq!(run_counters(PROG_FDINFO), (Some(917), Some(183204)));
+
+        // Kernels before 5.1 have no run counters at all.
+        assert_eq!(run_counters("prog_type:\t13\nprog_id:\t7\n"), (None, None));
+    }
+
+    #[test]
+    fn test_type_names() {
+        assert_eq!(program_type_name(3), "sched_cls");
+        assert_eq!(program_type_name(13), "sock_ops");
+        assert_eq!(map_type_name(1), "hash");
+        assert_eq!(map_type_name(32), "32");
+    }
+
+    #[test]
+    fn test_entry_count_only_for_hash_maps() {
+        // NODE_MAP, SNAT_IPV4_MAP and SOCK_OPS_MAP are counted.
+        assert!(has_entry_count(1));
+        assert!(has_entry_count(9));
+        assert!(has_entry_count(18));
+
+        // STATS_MAP, MARK_CONFIG_MAP, AYA_LOGS and ring buffers are not.
+        assert!(!has_entry_count(2));
+        assert!(!has_entry_count(6));
+        assert!(!has_entry_count(4));
+        assert!(!has_entry_count(27));
+    }
+}
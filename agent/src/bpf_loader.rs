@@ -1,82 +1,204 @@
 use std::net::Ipv4Addr;
 
 use anyhow::Result;
-use aya::maps::HashMap;
+use aya::maps::{Array, HashMap, PerCpuArray};
+use aya::programs::tc::SchedClassifierLinkId;
 use aya::programs::{tc, SchedClassifier, TcAttachType};
-use aya::{include_bytes_aligned, Bpf};
-use common::{NetworkInfo, CLUSTER_CIDR_KEY, HOST_IP_KEY};
+use aya::{include_bytes_aligned, Bpf, BpfLoader as EbpfLoader};
+use aya_log::BpfLogger;
+use common::{
+    MarkConfig, NetworkInfo, SockOpsConfig, CLUSTER_CIDR_KEY, HOST_IP_KEY, LOCAL_POD_CIDR_KEY,
+    SERVICE_CIDR_KEY,
+};
+use ipnet::Ipv4Net;
+use tracing::{info, warn};
+
+use crate::{
+    bpf_info::{self, AttachPoint, BpfInfoReader, LoadedProgram},
+    conntrack::ConntrackReader,
+    netlink::BRIDGE_NAME,
+    setup_report::{SetupReport, SetupStep},
+    stats::StatsReader,
+};
 
 pub struct BpfLoader {
     pub bpf: Bpf,
-    iface: String,
+    ifaces: Vec<String>,
+    snat: bool,
+    sock_ops_max_entries: u32,
+    service_cidr: Option<Ipv4Net>,
+    attachments: Vec<TcAttachment>,
     #[allow(dead_code)]
     cgroup_path: String,
 }
 
+struct TcAttachment {
+    iface: String,
+    program: &'static str,
+    attach_type: TcAttachType,
+    link: SchedClassifierLinkId,
+}
+
+const SNAT_PROGRAMS: &[(&str, TcAttachType)] = &[
+    ("tc_ingress", TcAttachType::Ingress),
+    ("tc_egress", TcAttachType::Egress),
+];
+
+const ROUTE_MARK_PROGRAM: &str = "tc_route_mark";
+
 impl BpfLoader {
-    pub fn load(iface: &str, cgroup_path: &str) -> Result<Self> {
+    pub fn load(
+        ifaces: &[String],
+        cgroup_path: &str,
+        snat: bool,
+        sock_ops_max_entries: u32,
+    ) -> Result<Self> {
+        let mut loader = EbpfLoader::new();
+        loader
+            .set_max_entries("SOCK_OPS_MAP", sock_ops_max_entries)
+            .set_max_entries("SOCK_OPS_MAP_V6", sock_ops_max_entries);
+
         #[cfg(debug_assertions)]
-        let bpf = Bpf::load(include_bytes_aligned!(
+        let bpf = loader.load(include_bytes_aligned!(
             "../../target/bpfel-unknown-none/debug/ebpf"
         ))?;
         #[cfg(not(debug_assertions))]
-        let bpf = Bpf::load(include_bytes_aligned!(
+        let bpf = loader.load(include_bytes_aligned!(
             "../../target/bpfel-unknown-none/release/ebpf"
         ))?;
 
         Ok(Self {
             bpf,
-            iface: iface.to_string(),
+            ifaces: dedup_ifaces(ifaces),
+            snat,
+            sock_ops_max_entries,
+            service_cidr: None,
+            attachments: vec![],
             cgroup_path: cgroup_path.to_string(),
         })
     }
 
+    /// Keeps traffic to ClusterIPs in `service_cidr` out of SNAT.
+    pub fn with_service_cidr(mut self, service_cidr: Option<Ipv4Net>) -> Self {
+        self.service_cidr = service_cidr;
+        self
+    }
+
     pub async fn attach(
         &mut self,
         host_ip: &str,
         cluster_cidr: &str,
+        pod_cidr: &str,
         node_ips: &[String],
+        marks: MarkConfig,
+        sock_ops: SockOpsConfig,
+        report: &SetupReport,
     ) -> Result<()> {
-        let _ = tc::qdisc_add_clsact(&self.iface);
+        let mut sock_ops_config_map: Array<_, SockOpsConfig> =
+            Array::try_from(self.bpf.take_map("SOCK_OPS_CONFIG_MAP").unwrap())?;
+        sock_ops_config_map.set(0, sock_ops, 0)?;
 
-        let tc_ingress: &mut SchedClassifier =
-            self.bpf.program_mut("tc_ingress").unwrap().try_into()?;
-        tc_ingress.load()?;
-        tc_ingress.attach(&self.iface, TcAttachType::Ingress)?;
-
-        let tc_egress: &mut SchedClassifier =
-            self.bpf.program_mut("tc_egress").unwrap().try_into()?;
-        tc_egress.load()?;
-        tc_egress.attach(&self.iface, TcAttachType::Egress)?;
-
-        let mut net_config_map: HashMap<_, u8, NetworkInfo> =
-            HashMap::try_from(self.bpf.take_map("NET_CONFIG_MAP").unwrap())?;
-
-        let mut node_map: HashMap<_, u32, u8> =
-            HashMap::try_from(self.bpf.take_map("NODE_MAP").unwrap())?;
+        // The tc programs only understand IPv4 and let anything else through,
+        // so on an IPv6 node there is nothing for them to do.
+        let Ok(host_ip) = host_ip.parse::<Ipv4Addr>() else {
+            info!(
+                "{} is not an IPv4 address, not attaching the tc programs",
+                host_ip
+            );
+            return Ok(());
+        };
 
         let host_ip_info = NetworkInfo {
-            ip: host_ip.parse::<Ipv4Addr>()?.into(),
+            ip: host_ip.into(),
             subnet_mask: 0,
         };
 
         let parts: Vec<&str> = cluster_cidr.split('/').collect();
         let cidr_bits = parts[1].parse::<u32>()?;
 
-        let cluster_cidr_info = NetworkInfo {
-            ip: parts[0].parse::<Ipv4Addr>()?.into(),
-            subnet_mask: u32::MAX << (32 - cidr_bits),
-        };
+        let cluster_cidr_info = NetworkInfo::new(parts[0].parse::<Ipv4Addr>()?.into(), cidr_bits);
 
-        net_config_map.insert(HOST_IP_KEY, host_ip_info, 0)?;
-        net_config_map.insert(CLUSTER_CIDR_KEY, cluster_cidr_info, 0)?;
+        let mut net_config = vec![
+            (HOST_IP_KEY, host_ip_info),
+            (CLUSTER_CIDR_KEY, cluster_cidr_info),
+        ];
 
-        node_ips.iter().for_each(|ip| {
-            let ip_addr: u32 = ip.parse::<Ipv4Addr>().unwrap().into();
-            node_map
-                .insert(ip_addr, 0, 0)
-                .expect("failed to insert node ip");
-        });
+        // Keeps same-node pod traffic out of NAT whatever the cluster CIDR
+        // covers. An IPv6 pod CIDR has nothing to do with the IPv4 programs.
+        if let Ok(pod_cidr) = pod_cidr.parse::<Ipv4Net>() {
+            net_config.push((
+                LOCAL_POD_CIDR_KEY,
+                NetworkInfo::new(pod_cidr.network().into(), pod_cidr.prefix_len().into()),
+            ));
+        }
+
+        if let Some(services) = self.service_cidr {
+            net_config.push((
+                SERVICE_CIDR_KEY,
+                NetworkInfo::new(services.network().into(), services.prefix_len().into()),
+            ));
+        }
+
+        let keys = net_config_keys(self.snat, marks.route != 0);
+        if !keys.is_empty() {
+            let mut net_config_map: HashMap<_, u8, NetworkInfo> =
+                HashMap::try_from(self.bpf.take_map("NET_CONFIG_MAP").unwrap())?;
+            for (key, info) in net_config.into_iter().filter(|(key, _)| keys.contains(key)) {
+                net_config_map.insert(key, info, 0)?;
+            }
+        }
+
+        let mut mark_config_map: Array<_, MarkConfig> =
+            Array::try_from(self.bpf.take_map("MARK_CONFIG_MAP").unwrap())?;
+        mark_config_map.set(0, marks, 0)?;
+
+        // Only SNAT tells node addresses apart from other destinations.
+        if self.snat {
+            let mut node_map: HashMap<_, u32, u8> =
+                HashMap::try_from(self.bpf.take_map("NODE_MAP").unwrap())?;
+            node_ips.iter().for_each(|ip| {
+                let ip_addr: u32 = ip.parse::<Ipv4Addr>().unwrap().into();
+                node_map
+                    .insert(ip_addr, 0, 0)
+                    .expect("failed to insert node ip");
+            });
+        }
+
+        if marks.route != 0 {
+            report.run(SetupStep::RouteMark, || self.attach_route_mark());
+        }
+
+        let programs = tc_programs(self.snat);
+
+        if programs.is_empty() {
+            info!("snat is disabled, not attaching the tc programs");
+            return report.check();
+        }
+
+        for iface in &self.ifaces {
+            let _ = tc::qdisc_add_clsact(iface);
+        }
+
+        for &(name, attach_type) in programs {
+            let program: &mut SchedClassifier = self.bpf.program_mut(name).unwrap().try_into()?;
+            program.load()?;
+
+            // Every interface is tried, so the report names all that failed.
+            for iface in &self.ifaces {
+                let step = SetupStep::TcAttach {
+                    program: name.to_string(),
+                    iface: iface.clone(),
+                };
+                if let Some(link) = report.run(step, || Ok(program.attach(iface, attach_type)?)) {
+                    self.attachments.push(TcAttachment {
+                        iface: iface.clone(),
+                        program: name,
+                        attach_type,
+                        link,
+                    });
+                }
+            }
+        }
 
         // let tcp_accelerate: &mut SockOps =
         //     self.bpf.program_mut("tcp_accelerate").unwrap().try_into()?;
@@ -92,6 +214,191 @@ impl BpfLoader {
         // tcp_bypass.load()?;
         // tcp_bypass.attach(&map_fd)?;
 
+        report.check()
+    }
+
+    /// Attaches `tc_route_mark` to the bridge's ingress, where pod traffic
+    /// enters the host before it is routed.
+    fn attach_route_mark(&mut self) -> Result<()> {
+        let _ = tc::qdisc_add_clsact(BRIDGE_NAME);
+
+        let program: &mut SchedClassifier = self
+            .bpf
+            .program_mut(ROUTE_MARK_PROGRAM)
+            .unwrap()
+            .try_into()?;
+        program.load()?;
+
+        let link = program.attach(BRIDGE_NAME, TcAttachType::Ingress)?;
+        self.attachments.push(TcAttachment {
+            iface: BRIDGE_NAME.to_string(),
+            program: ROUTE_MARK_PROGRAM,
+            attach_type: TcAttachType::Ingress,
+            link,
+        });
+        info!("attached {} to {}", ROUTE_MARK_PROGRAM, BRIDGE_NAME);
+
         Ok(())
     }
+
+    /// Forwards the eBPF programs' log messages to the agent's log. The
+    /// dataplane works without it, so a failure, e.g. an object built without
+    /// the log map, is only a warning. Returns whether the logger is running.
+    pub fn init_logger(&mut self) -> bool {
+        init_logger(|| BpfLogger::init(&mut self.bpf).map(|_| ()))
+    }
+
+    pub fn stats(&mut self) -> Result<StatsReader> {
+        let map = PerCpuArray::try_from(self.bpf.take_map("STATS_MAP").unwrap())?;
+        Ok(StatsReader::new(map, self.sock_ops_max_entries))
+    }
+
+    pub fn conntrack(&mut self) -> Result<ConntrackReader> {
+        let map = HashMap::try_from(self.bpf.take_map("SNAT_IPV4_MAP").unwrap())?;
+        Ok(ConntrackReader::new(map))
+    }
+
+    /// Reports the loaded programs, where they are attached, and the maps
+    /// they use. Call it after `attach`, once the programs are loaded.
+    pub fn bpf_info(&self) -> Result<BpfInfoReader> {
+        let mut programs = vec![];
+        let mut map_ids = vec![];
+        for (name, program) in self.bpf.programs() {
+            // Programs left unloaded, e.g. the SNAT ones with --no-snat, have
+            // no info.
+            let Ok(info) = program.info() else {
+                continue;
+            };
+            map_ids.extend(info.map_ids()?);
+            programs.push(LoadedProgram {
+                id: info.id(),
+                name: name.to_string(),
+            });
+        }
+        map_ids.sort_unstable();
+        map_ids.dedup();
+
+        let attachments = self
+            .attachments
+            .iter()
+            .map(|attachment| {
+                let direction = match attachment.attach_type {
+                    TcAttachType::Ingress => "ingress",
+                    TcAttachType::Egress => "egress",
+                    _ => "custom",
+                };
+                let attach_point = AttachPoint {
+                    iface: attachment.iface.clone(),
+                    ifindex: bpf_info::ifindex(&attachment.iface),
+                    direction: direction.to_string(),
+                };
+
+                (attachment.program.to_string(), attach_point)
+            })
+            .collect();
+
+        Ok(BpfInfoReader::new(programs, map_ids, attachments))
+    }
+
+    /// Detaches the tc programs from every interface they were attached to.
+    pub fn detach(&mut self) -> Result<()> {
+        for attachment in std::mem::take(&mut self.attachments) {
+            let program: &mut SchedClassifier = self
+                .bpf
+                .program_mut(attachment.program)
+                .unwrap()
+                .try_into()?;
+            program.detach(attachment.link)?;
+
+            info!("detached {} from {}", attachment.program, attachment.iface);
+        }
+
+        Ok(())
+    }
+}
+
+fn init_logger<E: std::fmt::Display>(init: impl FnOnce() -> Result<(), E>) -> bool {
+    match init() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "failed to initialize the eBPF logger, eBPF logs are disabled: {}",
+                e
+            );
+            false
+        }
+    }
+}
+
+/// The tc classifiers to attach; they implement SNAT, so routing-only mode
+/// attaches none of them.
+fn tc_programs(snat: bool) -> &'static [(&'static str, TcAttachType)] {
+    if snat {
+        SNAT_PROGRAMS
+    } else {
+        &[]
+    }
+}
+
+/// The `NET_CONFIG_MAP` entries the attached programs read: the SNAT
+/// programs use all of them, `tc_route_mark` only the cluster CIDR.
+fn net_config_keys(snat: bool, route_mark: bool) -> &'static [u8] {
+    match (snat, route_mark) {
+        (true, _) => &[
+            HOST_IP_KEY,
+            CLUSTER_CIDR_KEY,
+            LOCAL_POD_CIDR_KEY,
+            SERVICE_CIDR_KEY,
+        ],
+        (false, true) => &[CLUSTER_CIDR_KEY],
+        (false, false) => &[],
+    }
+}
+
+fn dedup_ifaces(ifaces: &[String]) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+
+    for iface in ifaces {
+        if !result.contains(iface) {
+            result.push(iface.clone());
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_ifaces() {
+        let ifaces = vec!["eth0".to_string(), "eth1".to_string(), "eth0".to_string()];
+
+        assert_eq!(dedup_ifaces(&ifaces), vec!["eth0", "eth1"]);
+    }
+
+    #[test]
+    fn test_tc_programs() {
+        let names = tc_programs(true)
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["tc_ingress", "tc_egress"]);
+
+        assert!(tc_programs(false).is_empty());
+
+        assert_eq!(net_config_keys(true, false).len(), 4);
+        assert_eq!(net_config_keys(true, true), net_config_keys(true, false));
+        // Routing-only mode with a route mark fills just what tc_route_mark
+        // reads, and without one nothing at all.
+        assert_eq!(net_config_keys(false, true), &[CLUSTER_CIDR_KEY]);
+        assert!(net_config_keys(false, false).is_empty());
+    }
+
+    #[test]
+    fn test_logger_failure_is_not_fatal() {
+        assert!(init_logger(|| Ok::<_, String>(())));
+        assert!(!init_logger(|| Err("map AYA_LOGS not found".to_string())));
+    }
 }
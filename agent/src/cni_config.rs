@@ -0,0 +1,234 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sinabro_config::write_atomic;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The CNI config the agent last wrote and whether the file on disk still
+/// matches it. Timestamps are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CniConfigState {
+    pub path: String,
+    pub sha256: String,
+    pub written_at: u64,
+    /// The file differs from what was written and was left that way.
+    pub drifted: bool,
+    /// How many times a changed file was found, one per distinct edit.
+    pub drift_count: u64,
+    pub last_checked_at: Option<u64>,
+}
+
+/// Shared view of the CNI config for `/status`.
+#[derive(Clone, Default)]
+pub struct CniConfigStatus(Arc<Mutex<Option<CniConfigState>>>);
+
+impl CniConfigStatus {
+    pub fn state(&self) -> Option<CniConfigState> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut CniConfigState)) {
+        if let Some(state) = self.0.lock().unwrap().as_mut() {
+            f(state);
+        }
+    }
+}
+
+/// What a check found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Drift {
+    None,
+    /// The file was edited or removed and has been written again.
+    Rewritten,
+    /// The file was edited and `--respect-manual-cni-config` keeps the edit.
+    Kept,
+}
+
+/// Writes the CNI config and keeps checking that nobody changed it behind
+/// the agent's back. A hand-edited file is written again unless manual edits
+/// are respected, in which case it is only reported.
+pub struct CniConfigWatcher {
+    path: String,
+    contents: String,
+    sha256: String,
+    respect_manual: bool,
+    /// The last edited contents seen, so one edit is counted once.
+    seen_sha256: Option<String>,
+    status: CniConfigStatus,
+}
+
+impl CniConfigWatcher {
+    pub fn write(
+        path: &str,
+        contents: String,
+        respect_manual: bool,
+        status: CniConfigStatus,
+    ) -> Result<Self> {
+        write_atomic(path, &contents)?;
+
+        let sha256 = sha256_hex(contents.as_bytes());
+        *status.0.lock().unwrap() = Some(CniConfigState {
+            path: path.to_owned(),
+            sha256: sha256.clone(),
+            written_at: now(),
+            drifted: false,
+            drift_count: 0,
+            last_checked_at: None,
+        });
+
+        Ok(Self {
+            path: path.to_owned(),
+            contents,
+            sha256,
+            respect_manual,
+            seen_sha256: None,
+            status,
+        })
+    }
+
+    pub fn check(&mut self) -> Result<Drift> {
+        let actual = match fs::read(&self.path) {
+            Ok(bytes) => Some(sha256_hex(&bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        self.status
+            .update(|state| state.last_checked_at = Some(now()));
+
+        if actual.as_deref() == Some(self.sha256.as_str()) {
+            self.seen_sha256 = None;
+            self.status.update(|state| state.drifted = false);
+            return Ok(Drift::None);
+        }
+
+        // A removed file is never respected: without it the node has no
+        // network for new pods.
+        if self.respect_manual && actual.is_some() {
+            if self.seen_sha256 != actual {
+                warn!(
+                    "{} was changed by hand, keeping it as --respect-manual-cni-config is set",
+                    self.path
+                );
+                self.seen_sha256 = actual;
+                self.status.update(|state| {
+                    state.drifted = true;
+                    state.drift_count += 1;
+                });
+            }
+            return Ok(Drift::Kept);
+        }
+
+        warn!(
+            "{} differs from the config the agent wrote, writing it again",
+            self.path
+        );
+        write_atomic(&self.path, &self.contents)?;
+        self.seen_sha256 = None;
+        self.status.update(|state| {
+            state.drifted = false;
+            state.drift_count += 1;
+            state.written_at = now();
+        });
+
+        Ok(Drift::Rewritten)
+    }
+
+    pub async fn run(mut self, shutdown: CancellationToken) {
+        info!("checking {} every {:?}", self.path, CHECK_INTERVAL);
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = interval.tick() => {
+                    if let Err(e) = self.check() {
+                        warn!("failed to check {}: {}", self.path, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watcher(dir: &tempfile::TempDir, respect_manual: bool) -> (CniConfigWatcher, String) {
+        let path = dir.path().join("10-sinabro.conf").display().to_string();
+        let watcher = CniConfigWatcher::write(
+            &path,
+            r#"{"cniVersion":"0.3.1","name":"sinabro"}"#.to_string(),
+            respect_manual,
+            CniConfigStatus::default(),
+        )
+        .unwrap();
+
+        (watcher, path)
+    }
+
+    #[test]
+    fn test_rewrites_a_hand_edited_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut watcher, path) = watcher(&dir, false);
+        assert_eq!(watcher.check().unwrap(), Drift::None);
+
+        fs::write(&path, r#"{"cniVersion":"0.3.1","name":"edited"}"#).unwrap();
+        assert_eq!(watcher.check().unwrap(), Drift::Rewritten);
+        assert_eq!(fs::read_to_string(&path).unwrap(), watcher.contents);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(watcher.check().unwrap(), Drift::Rewritten);
+        assert_eq!(watcher.check().unwrap(), Drift::None);
+
+        let state = watcher.status.state().unwrap();
+        assert!(!state.drifted);
+        assert_eq!(state.drift_count, 2);
+    }
+
+    #[test]
+    fn test_respects_a_manual_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut watcher, path) = watcher(&dir, true);
+
+        let edited = r#"{"cniVersion":"0.3.1","name":"edited"}"#;
+        fs::write(&path, edited).unwrap();
+        assert_eq!(watcher.check().unwrap(), Drift::Kept);
+        // The same edit is counted once.
+        assert_eq!(watcher.check().unwrap(), Drift::Kept);
+        assert_eq!(fs::read_to_string(&path).unwrap(), edited);
+
+        let state = watcher.status.state().unwrap();
+        assert!(state.drifted);
+        assert_eq!(state.drift_count, 1);
+
+        // Removing the file is not an edit to keep.
+        fs::remove_file(&path).unwrap();
+        assert_eq!(watcher.check().unwrap(), Drift::Rewritten);
+        assert!(!watcher.status.state().unwrap().drifted);
+    }
+}
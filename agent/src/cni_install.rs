@@ -0,0 +1,133 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+pub const CNI_BIN_NAME: &str = "sinabro-cni";
+
+/// Installs the bundled CNI plugin binary into `bin_dir`.
+///
+/// The binary is written to a temporary file in the destination directory,
+/// verified and then renamed over the target, so the kubelet never observes a
+/// partially written plugin. Returns `false` when the installed binary already
+/// matches the bundled one.
+pub fn install_binary(source: &Path, bin_dir: &Path) -> Result<bool> {
+    let expected = sha256_of(source)?;
+    let dest = bin_dir.join(CNI_BIN_NAME);
+
+    if dest.exists() && sha256_of(&dest)? == expected {
+        info!("{} is up to date", dest.display());
+        return Ok(false);
+    }
+
+    fs::create_dir_all(bin_dir)?;
+    let tmp = bin_dir.join(format!(".{}.tmp-{}", CNI_BIN_NAME, std::process::id()));
+
+    let result = write_verified(source, &tmp, &expected)
+        .and_then(|_| fs::rename(&tmp, &dest).map_err(Into::into));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+
+    info!("installed {}", dest.display());
+    Ok(true)
+}
+
+fn write_verified(source: &Path, tmp: &Path, expected: &str) -> Result<()> {
+    let mut reader = File::open(source)?;
+    let mut writer = File::create(tmp)?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    writer.sync_all()?;
+    fs::set_permissions(tmp, fs::Permissions::from_mode(0o755))?;
+
+    let actual = sha256_of(tmp)?;
+    if actual != expected {
+        bail!(
+            "checksum mismatch for {}: expected {}, got {}",
+            tmp.display(),
+            expected,
+            actual
+        );
+    }
+
+    Ok(())
+}
+
+fn sha256_of(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).map_err(|e| anyhow!("failed to open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, std::path::PathBuf, std::path::PathBuf) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source = tmp_dir.path().join("bundled");
+        let bin_dir = tmp_dir.path().join("opt/cni/bin");
+        fs::write(&source, b"plugin v1").unwrap();
+
+        (tmp_dir, source, bin_dir)
+    }
+
+    #[test]
+    fn test_install_binary() {
+        let (_tmp_dir, source, bin_dir) = setup();
+
+        assert!(install_binary(&source, &bin_dir).unwrap());
+
+        let dest = bin_dir.join(CNI_BIN_NAME);
+        assert_eq!(fs::read(&dest).unwrap(), b"plugin v1");
+        assert_eq!(
+            fs::metadata(&dest).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(fs::read_dir(&bin_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_install_binary_skips_when_up_to_date() {
+        let (_tmp_dir, source, bin_dir) = setup();
+
+        assert!(install_binary(&source, &bin_dir).unwrap());
+        assert!(!install_binary(&source, &bin_dir).unwrap());
+    }
+
+    #[test]
+    fn test_install_binary_replaces_on_hash_change() {
+        let (_tmp_dir, source, bin_dir) = setup();
+
+        install_binary(&source, &bin_dir).unwrap();
+        fs::write(&source, b"plugin v2").unwrap();
+
+        assert!(install_binary(&source, &bin_dir).unwrap());
+        assert_eq!(fs::read(bin_dir.join(CNI_BIN_NAME)).unwrap(), b"plugin v2");
+        assert_eq!(fs::read_dir(&bin_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_install_binary_missing_source() {
+        let (tmp_dir, _source, bin_dir) = setup();
+
+        let result = install_binary(&tmp_dir.path().join("missing"), &bin_dir);
+        assert!(result.is_err());
+        assert!(!bin_dir.join(CNI_BIN_NAME).exists());
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use aya::maps::{HashMap, MapData};
+use common::{NatKey, OriginValue};
+use serde::Serialize;
+
+/// Reads the SNAT entries the tc egress program keeps in `SNAT_IPV4_MAP`.
+#[derive(Clone)]
+pub struct ConntrackReader {
+    map: Arc<HashMap<MapData, NatKey, OriginValue>>,
+}
+
+impl ConntrackReader {
+    pub fn new(map: HashMap<MapData, NatKey, OriginValue>) -> Self {
+        Self { map: Arc::new(map) }
+    }
+
+    pub fn read(&self) -> Result<Vec<Flow>> {
+        let mut flows = self
+            .map
+            .iter()
+            .map(|entry| entry.map(|(key, value)| Flow::new(&key, &value)))
+            .collect::<Result<Vec<_>, _>>()?;
+        flows.sort_by_key(|flow| (flow.original.src, flow.original.dst));
+
+        Ok(flows)
+    }
+}
+
+/// A masqueraded connection. The map does not track state or age yet, so
+/// only the tuples before and after translation are reported.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Flow {
+    pub original: Tuple,
+    pub nat: Tuple,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Tuple {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+}
+
+impl Flow {
+    fn new(key: &NatKey, value: &OriginValue) -> Self {
+        let dst = SocketAddrV4::new(Ipv4Addr::from(key.dst_ip), key.dst_port);
+
+        Self {
+            original: Tuple {
+                src: SocketAddrV4::new(Ipv4Addr::from(value.ip), value.port),
+                dst,
+            },
+            nat: Tuple {
+                src: SocketAddrV4::new(Ipv4Addr::from(key.src_ip), key.src_port),
+                dst,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_from_entry() {
+        let key = NatKey {
+            src_ip: Ipv4Addr::new(172, 18, 0, 2).into(),
+            dst_ip: Ipv4Addr::new(1, 1, 1, 1).into(),
+            src_port: 30001,
+            dst_port: 443,
+        };
+        let value = OriginValue {
+            ip: Ipv4Addr::new(10, 244, 0, 5).into(),
+            dummy: 0,
+            port: 41000,
+        };
+
+        let flow = Flow::new(&key, &value);
+
+        assert_eq!(flow.original.src.to_string(), "10.244.0.5:41000");
+        assert_eq!(flow.original.dst.to_string(), "1.1.1.1:443");
+        assert_eq!(flow.nat.src.to_string(), "172.18.0.2:30001");
+        assert_eq!(flow.nat.dst.to_string(), "1.1.1.1:443");
+
+        let json = serde_json::to_value(&flow).unwrap();
+        assert_eq!(json["original"]["src"], "10.244.0.5:41000");
+        assert_eq!(json["nat"]["src"], "172.18.0.2:30001");
+    }
+}
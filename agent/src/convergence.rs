@@ -0,0 +1,223 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::metrics::{self, Histogram};
+
+const BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// What started an overlay programming round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// `initialize_overlay` programming every remote node on startup.
+    Startup,
+    /// The node watcher programming a node that joined or moved.
+    Node,
+}
+
+impl Trigger {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Startup => "startup",
+            Trigger::Node => "node",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Recorded {
+    startup: Option<Duration>,
+    startup_rounds: Histogram,
+    node_rounds: Histogram,
+    failures: u64,
+}
+
+impl Default for Recorded {
+    fn default() -> Self {
+        Self {
+            startup: None,
+            startup_rounds: Histogram::new(BUCKETS),
+            node_rounds: Histogram::new(BUCKETS),
+            failures: 0,
+        }
+    }
+}
+
+/// How long the overlay takes to converge: from the start of a programming
+/// round until every per-node task of it has finished successfully.
+#[derive(Clone, Default)]
+pub struct ConvergenceMetrics(Arc<Mutex<Recorded>>);
+
+impl ConvergenceMetrics {
+    /// Waits for every task of the round started at `started`. The round is
+    /// timed only if all of them succeed; otherwise it counts as a failure,
+    /// since the overlay never converged.
+    pub async fn track(
+        &self,
+        trigger: Trigger,
+        started: Instant,
+        tasks: Vec<JoinHandle<Result<()>>>,
+    ) {
+        let mut converged = true;
+
+        for task in tasks {
+            match task.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!("overlay setup failed: {}", e);
+                    converged = false;
+                }
+                Err(e) => {
+                    warn!("overlay setup task did not finish: {}", e);
+                    converged = false;
+                }
+            }
+        }
+
+        if converged {
+            self.record(trigger, started.elapsed());
+        } else {
+            self.0.lock().unwrap().failures += 1;
+        }
+    }
+
+    fn record(&self, trigger: Trigger, elapsed: Duration) {
+        info!(
+            "overlay converged after {:?} ({})",
+            elapsed,
+            trigger.as_str()
+        );
+
+        let mut recorded = self.0.lock().unwrap();
+        match trigger {
+            Trigger::Startup => {
+                recorded.startup = Some(elapsed);
+                recorded.startup_rounds.observe(elapsed.as_secs_f64());
+            }
+            Trigger::Node => recorded.node_rounds.observe(elapsed.as_secs_f64()),
+        }
+    }
+
+    pub fn write_metrics(&self, out: &mut String) {
+        let recorded = self.0.lock().unwrap();
+
+        metrics::write_gauge(
+            out,
+            "sinabro_overlay_startup_convergence_seconds",
+            "Time from the start of initialize_overlay until every remote node was programmed.",
+            recorded
+                .startup
+                .map(|elapsed| (String::new(), elapsed.as_secs_f64())),
+        );
+
+        let name = "sinabro_overlay_convergence_duration_seconds";
+        metrics::write_header(
+            out,
+            name,
+            "Time for an overlay programming round to finish on every node it covers.",
+            "histogram",
+        );
+        for (trigger, histogram) in [
+            (Trigger::Startup, &recorded.startup_rounds),
+            (Trigger::Node, &recorded.node_rounds),
+        ] {
+            histogram.write(out, name, &[("trigger", trigger.as_str())]);
+        }
+
+        metrics::write_header(
+            out,
+            "sinabro_overlay_convergence_failures_total",
+            "Overlay programming rounds in which at least one node failed.",
+            "counter",
+        );
+        out.push_str(&format!(
+            "sinabro_overlay_convergence_failures_total {}\n",
+            recorded.failures
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// Stands in for one per-node setup task finishing after `delay`.
+    fn node_task(delay: Duration, ok: bool) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if ok {
+                Ok(())
+            } else {
+                Err(anyhow!("neighbor lookup failed"))
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_round_waits_for_slowest_node() {
+        let metrics = ConvergenceMetrics::default();
+        let started = Instant::now();
+
+        metrics
+            .track(
+                Trigger::Startup,
+                started,
+                vec![
+                    node_task(Duration::from_millis(5), true),
+                    node_task(Duration::from_millis(50), true),
+                ],
+            )
+            .await;
+
+        let recorded = metrics.0.lock().unwrap();
+        let startup = recorded.startup.unwrap();
+        assert!(startup >= Duration::from_millis(50));
+        assert!(startup <= started.elapsed());
+        assert_eq!(recorded.failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_node_is_not_converged() {
+        let metrics = ConvergenceMetrics::default();
+
+        metrics
+            .track(
+                Trigger::Startup,
+                Instant::now(),
+                vec![
+                    node_task(Duration::ZERO, true),
+                    node_task(Duration::ZERO, false),
+                ],
+            )
+            .await;
+
+        let recorded = metrics.0.lock().unwrap();
+        assert_eq!(recorded.startup, None);
+        assert_eq!(recorded.failures, 1);
+    }
+
+    #[test]
+    fn test_write_metrics() {
+        let metrics = ConvergenceMetrics::default();
+        metrics.record(Trigger::Startup, Duration::from_millis(1500));
+        metrics.record(Trigger::Node, Duration::from_millis(200));
+
+        let mut out = String::new();
+        metrics.write_metrics(&mut out);
+
+        assert!(out.contains("sinabro_overlay_startup_convergence_seconds 1.5\n"));
+        assert!(out.contains(
+            "sinabro_overlay_convergence_duration_seconds_bucket{trigger=\"startup\",le=\"2.5\"} 1\n"
+        ));
+        assert!(out
+            .contains("sinabro_overlay_convergence_duration_seconds_count{trigger=\"node\"} 1\n"));
+        assert!(out.contains("sinabro_overlay_convergence_failures_total 0\n"));
+    }
+}
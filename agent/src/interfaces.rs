@@ -0,0 +1,202 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use rsln::types::{addr::AddrFamily, link::LinkAttrs};
+use serde::Serialize;
+
+use crate::netlink::Netlink;
+
+const CACHE_TTL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub oper_state: String,
+    pub mtu: u32,
+    pub mac: String,
+    pub addresses: Vec<String>,
+    pub stats: InterfaceStats,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct InterfaceStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+/// Looks up the current state of a single interface.
+pub trait InterfaceSource: Send + Sync {
+    fn interface(&self, name: &str) -> Result<InterfaceInfo>;
+}
+
+/// Reads link attributes and addresses over netlink and the oper state and
+/// counters from sysfs.
+pub struct NetlinkInterfaceSource;
+
+impl InterfaceSource for NetlinkInterfaceSource {
+    fn interface(&self, name: &str) -> Result<InterfaceInfo> {
+        let mut netlink = Netlink::new();
+        let link = netlink
+            .link_get(&LinkAttrs::new(name))
+            .map_err(|e| anyhow!("failed to get {}: {}", name, e))?;
+
+        let mut addresses = vec![];
+        for family in [AddrFamily::V4, AddrFamily::V6] {
+            addresses.extend(
+                netlink
+                    .addr_list(&link, family)?
+                    .iter()
+                    .map(|addr| addr.ip.to_string()),
+            );
+        }
+
+        let sysfs = Path::new("/sys/class/net").join(name);
+        let counter = |name: &str| read_counter(&sysfs.join("statistics").join(name));
+
+        Ok(InterfaceInfo {
+            name: name.to_owned(),
+            oper_state: fs::read_to_string(sysfs.join("operstate"))
+                .map(|state| state.trim().to_owned())
+                .unwrap_or_else(|_| "unknown".to_owned()),
+            mtu: link.attrs().mtu,
+            mac: format_mac(&link.attrs().hw_addr),
+            addresses,
+            stats: InterfaceStats {
+                rx_bytes: counter("rx_bytes"),
+                rx_packets: counter("rx_packets"),
+                rx_errors: counter("rx_errors"),
+                rx_dropped: counter("rx_dropped"),
+                tx_bytes: counter("tx_bytes"),
+                tx_packets: counter("tx_packets"),
+                tx_errors: counter("tx_errors"),
+                tx_dropped: counter("tx_dropped"),
+            },
+        })
+    }
+}
+
+/// Reports the interfaces sinabro manages, caching the result briefly so a
+/// polling dashboard does not cost a netlink dump per request.
+#[derive(Clone)]
+pub struct InterfaceReader {
+    source: Arc<dyn InterfaceSource>,
+    names: Vec<String>,
+    cache: Arc<Mutex<Option<(Instant, Vec<InterfaceInfo>)>>>,
+}
+
+impl InterfaceReader {
+    pub fn new(source: Arc<dyn InterfaceSource>, names: &[&str]) -> Self {
+        Self {
+            source,
+            names: names.iter().map(|name| name.to_string()).collect(),
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn read(&self) -> Result<Vec<InterfaceInfo>> {
+        let mut cache = self.cache.lock().unwrap();
+
+        if let Some((read_at, interfaces)) = cache.as_ref() {
+            if read_at.elapsed() < CACHE_TTL {
+                return Ok(interfaces.clone());
+            }
+        }
+
+        let interfaces = self
+            .names
+            .iter()
+            .map(|name| self.source.interface(name))
+            .collect::<Result<Vec<_>>>()?;
+        *cache = Some((Instant::now(), interfaces.clone()));
+
+        Ok(interfaces)
+    }
+}
+
+fn read_counter(path: &Path) -> u64 {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or_default()
+}
+
+pub fn format_mac(hw_addr: &[u8]) -> String {
+    hw_addr
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct StubSource {
+        pub calls: AtomicUsize,
+    }
+
+    impl InterfaceSource for StubSource {
+        fn interface(&self, name: &str) -> Result<InterfaceInfo> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if name == "missing" {
+                return Err(anyhow!("failed to get {}", name));
+            }
+
+            Ok(InterfaceInfo {
+                name: name.to_owned(),
+                oper_state: "up".to_owned(),
+                mtu: 1450,
+                mac: "aa:bb:cc:dd:00:01".to_owned(),
+                addresses: vec!["10.244.0.0/32".to_owned()],
+                stats: InterfaceStats {
+                    rx_packets: 7,
+                    ..Default::default()
+                },
+            })
+        }
+    }
+
+    #[test]
+    fn test_read_is_cached() {
+        let source = Arc::new(StubSource::default());
+        let reader = InterfaceReader::new(source.clone(), &["cni0", "sinabro_vxlan"]);
+
+        let first = reader.read().unwrap();
+        let second = reader.read().unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(first[1].name, "sinabro_vxlan");
+        assert_eq!(source.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_read_fails_on_missing_interface() {
+        let reader = InterfaceReader::new(Arc::new(StubSource::default()), &["cni0", "missing"]);
+
+        assert!(reader.read().is_err());
+    }
+
+    #[test]
+    fn test_format_mac() {
+        assert_eq!(
+            format_mac(&[0xaa, 0xbb, 0xcc, 0xdd, 0x00, 0x01]),
+            "aa:bb:cc:dd:00:01"
+        );
+    }
+}
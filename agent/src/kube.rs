@@ -1,52 +1,252 @@
-use std::fmt::Debug;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::{anyhow, bail, Result};
 use futures::{StreamExt, TryStreamExt};
-use k8s_openapi::api::core::v1::{ConfigMap, Node, Pod, Service};
+use ipnet::Ipv4Net;
+use k8s_openapi::{
+    api::core::v1::{
+        ConfigMap, Event as KubeEvent, EventSource, Node, ObjectReference, Pod, Service,
+    },
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time},
+    chrono::Utc,
+};
 use kube::{
-    api::{AttachParams, AttachedProcess, ListParams, WatchEvent, WatchParams},
-    runtime::{watcher, WatchStreamExt},
+    api::{
+        AttachParams, AttachedProcess, ListParams, Patch, PatchParams, PostParams, WatchEvent,
+        WatchParams,
+    },
+    runtime::{watcher, watcher::Event, WatchStreamExt},
     Api, ResourceExt,
 };
+use serde::Serialize;
 use sinabro_config::parse_mac;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{info, warn};
+
+use crate::{interfaces::format_mac, node_route::NodeRoute, rate_limit::TokenBucket};
+
+/// Node annotations a peer's VTEP details are read from when present.
+pub const VXLAN_MAC_ANNOTATION: &str = "sinabro.io/vxlan-mac";
+pub const WG_PUBLIC_KEY_ANNOTATION: &str = "sinabro.io/wireguard-public-key";
+/// The pod CIDR a node's agent settled on, which may not be the Node's
+/// `spec.podCIDR`.
+pub const POD_CIDR_ANNOTATION: &str = "sinabro.io/pod-cidr";
+
+/// What the overlay needs to know about a node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeInfo {
+    pub internal_ip: String,
+    pub pod_cidrs: Vec<String>,
+    pub vxlan_mac: Option<String>,
+    pub wg_public_key: Option<String>,
+}
+
+impl From<&Node> for NodeInfo {
+    fn from(node: &Node) -> Self {
+        let addresses = node
+            .status
+            .as_ref()
+            .and_then(|status| status.addresses.as_deref())
+            .unwrap_or_default();
+        let internal_ip = addresses
+            .iter()
+            .find(|address| address.type_ == "InternalIP")
+            .or_else(|| addresses.first())
+            .map(|address| address.address.clone())
+            .unwrap_or_default();
+
+        let pod_cidrs = node.spec.as_ref().and_then(|spec| {
+            spec.pod_cidrs
+                .clone()
+                .or_else(|| spec.pod_cidr.clone().map(|cidr| vec![cidr]))
+        });
+
+        let annotations = node.annotations();
+        let pod_cidrs = pod_cidrs
+            .or_else(|| {
+                annotations
+                    .get(POD_CIDR_ANNOTATION)
+                    .map(|cidr| vec![cidr.clone()])
+            })
+            .unwrap_or_default();
+        Self {
+            internal_ip,
+            pod_cidrs,
+            vxlan_mac: annotations.get(VXLAN_MAC_ANNOTATION).cloned(),
+            wg_public_key: annotations.get(WG_PUBLIC_KEY_ANNOTATION).cloned(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NodeInfos {
+    nodes: BTreeMap<String, NodeInfo>,
+    /// Nodes listed since the watcher last (re)started.
+    seen: HashSet<String>,
+}
+
+/// Node details by node name, kept current by the node watcher so the
+/// overlay does not have to ask the API server per node. An entry is
+/// replaced whenever its Node changes, which also drops a VXLAN MAC that
+/// was looked up rather than annotated.
+#[derive(Debug, Clone, Default)]
+pub struct NodeInfoCache(Arc<RwLock<NodeInfos>>);
+
+impl NodeInfoCache {
+    /// Replaces the cache with the result of a full Node list.
+    pub fn fill<'a>(&self, nodes: impl IntoIterator<Item = &'a Node>) {
+        let mut infos = self.0.write().unwrap();
+        infos.nodes = nodes
+            .into_iter()
+            .map(|node| (node.name_any(), NodeInfo::from(node)))
+            .collect();
+    }
+
+    pub fn observe(&self, event: &Event<Node>) {
+        let mut infos = self.0.write().unwrap();
+        match event {
+            Event::Apply(node) => {
+                infos.nodes.insert(node.name_any(), NodeInfo::from(node));
+            }
+            Event::Delete(node) => {
+                infos.nodes.remove(&node.name_any());
+            }
+            Event::Init => infos.seen.clear(),
+            Event::InitApply(node) => {
+                infos.seen.insert(node.name_any());
+                infos.nodes.insert(node.name_any(), NodeInfo::from(node));
+            }
+            Event::InitDone => {
+                let NodeInfos { nodes, seen } = &mut *infos;
+                nodes.retain(|name, _| seen.contains(name));
+            }
+        }
+    }
 
-use crate::node_route::NodeRoute;
+    pub fn get(&self, name: &str) -> Option<NodeInfo> {
+        self.0.read().unwrap().nodes.get(name).cloned()
+    }
+
+    /// Remembers a MAC found by asking the node's agent, until the Node
+    /// next changes.
+    pub fn set_vxlan_mac(&self, name: &str, mac: &[u8]) {
+        if let Some(info) = self.0.write().unwrap().nodes.get_mut(name) {
+            info.vxlan_mac = Some(format_mac(mac));
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, NodeInfo> {
+        self.0.read().unwrap().nodes.clone()
+    }
+}
 
 pub struct Context {
     client: kube::Client,
     token: CancellationToken,
+    /// Paces the per-node lookups the overlay setup makes, so a large
+    /// cluster does not flood the API server on startup.
+    lookups: TokenBucket,
+    nodes: NodeInfoCache,
 }
 
 impl Context {
-    pub async fn new(token: CancellationToken) -> Result<Self> {
+    /// `lookups_per_sec` caps `get_vxlan_mac_address` calls; 0 disables the
+    /// limit.
+    pub async fn new(token: CancellationToken, lookups_per_sec: u32) -> Result<Self> {
         let client = kube::Client::try_default().await?;
-        Ok(Self { client, token })
+        Ok(Self {
+            client,
+            token,
+            lookups: TokenBucket::new(lookups_per_sec, lookups_per_sec),
+            nodes: NodeInfoCache::default(),
+        })
+    }
+
+    /// Shares `nodes` with whoever else reads it, e.g. the status server.
+    pub fn with_nodes(mut self, nodes: NodeInfoCache) -> Self {
+        self.nodes = nodes;
+        self
+    }
+
+    pub fn nodes(&self) -> &NodeInfoCache {
+        &self.nodes
     }
 
     pub async fn get_cluster_cidr(&self) -> Result<String> {
-        Api::<ConfigMap>::namespaced(self.client.clone(), "kube-system")
-            .get("kube-proxy")
-            .await?
-            .data
-            .and_then(|data| data.get("config.conf").cloned())
-            .and_then(|conf| serde_yaml::from_str::<serde_yaml::Value>(&conf).ok())
-            .and_then(|yaml| yaml["clusterCIDR"].as_str().map(ToOwned::to_owned))
-            .ok_or_else(|| anyhow!("failed to get cluster cidr"))
+        let config_map = Api::<ConfigMap>::namespaced(self.client.clone(), "kube-system")
+            .get_opt("kube-proxy")
+            .await?;
+
+        cluster_cidr_from(config_map)
     }
 
+    pub async fn get_service_cidr(&self) -> Result<Ipv4Net> {
+        let config_map = Api::<ConfigMap>::namespaced(self.client.clone(), "kube-system")
+            .get_opt("kubeadm-config")
+            .await?;
+
+        service_cidr_from(config_map)
+    }
+
+    /// Lists every node's route, filling the node cache on the way.
     pub async fn get_node_routes(&self) -> Result<Vec<NodeRoute>> {
-        Ok(Api::<Node>::all(self.client.clone())
+        let nodes = Api::<Node>::all(self.client.clone())
             .list(&Default::default())
             .await?
-            .items
-            .into_iter()
-            .map(NodeRoute::from)
-            .collect())
+            .items;
+        self.nodes.fill(&nodes);
+
+        Ok(nodes.into_iter().map(NodeRoute::from).collect())
+    }
+
+    /// Reads the route of a single node, for when the agent has to find its
+    /// own Node by name rather than by address.
+    pub async fn get_node_route(&self, name: &str) -> Result<NodeRoute> {
+        let node = Api::<Node>::all(self.client.clone()).get(name).await?;
+        Ok(NodeRoute::from(node))
+    }
+
+    /// The MAC of `node_route`'s VXLAN device: from the node cache when it
+    /// is known, otherwise asked of the node's agent and cached.
+    pub async fn vxlan_mac_of(&self, node_route: &NodeRoute) -> Result<Vec<u8>> {
+        if let Some(mac) = self
+            .nodes
+            .get(&node_route.name)
+            .and_then(|info| info.vxlan_mac)
+        {
+            return parse_mac(&mac);
+        }
+
+        let mac = self.get_vxlan_mac_address(&node_route.ip).await?;
+        self.nodes.set_vxlan_mac(&node_route.name, &mac);
+        Ok(mac)
+    }
+
+    /// Publishes the pod CIDR this node ended up with, so other components
+    /// need not repeat the agent's fallbacks to find it.
+    pub async fn annotate_pod_cidr(&self, node_name: &str, pod_cidr: &str) -> Result<()> {
+        let patch = serde_json::json!({
+            "metadata": {
+                "annotations": {
+                    POD_CIDR_ANNOTATION: pod_cidr
+                }
+            }
+        });
+
+        Api::<Node>::all(self.client.clone())
+            .patch(node_name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await?;
+
+        Ok(())
     }
 
     pub async fn get_vxlan_mac_address(&self, node_ip: &str) -> Result<Vec<u8>> {
+        self.lookups.acquire().await;
+
         let pods: Api<Pod> = Api::namespaced(self.client.clone(), "kube-system");
         let lp = ListParams::default().labels("name=agent");
 
@@ -94,6 +294,68 @@ impl Context {
         Ok(())
     }
 
+    /// Feeds Node watch events to `handle` until the agent shuts down. Watch
+    /// errors are logged and the watcher backs off and resumes on its own.
+    pub async fn watch_node_resource(&self, mut handle: impl FnMut(Event<Node>)) -> Result<()> {
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let mut events = watcher(nodes, watcher::Config::default())
+            .default_backoff()
+            .boxed();
+
+        loop {
+            tokio::select! {
+                event = events.try_next() => match event {
+                    Ok(Some(event)) => handle(event),
+                    Ok(None) => break,
+                    Err(e) => warn!("node watch failed: {}", e),
+                },
+                _ = self.token.cancelled() => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records a Warning Event against the Node `node_name`, where
+    /// `kubectl describe node` shows it.
+    pub async fn record_node_event(
+        &self,
+        node_name: &str,
+        reason: &str,
+        message: &str,
+    ) -> Result<()> {
+        let now = Time(Utc::now());
+        let event = KubeEvent {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{}.", node_name)),
+                ..Default::default()
+            },
+            involved_object: ObjectReference {
+                api_version: Some("v1".to_owned()),
+                kind: Some("Node".to_owned()),
+                name: Some(node_name.to_owned()),
+                ..Default::default()
+            },
+            reason: Some(reason.to_owned()),
+            message: Some(message.to_owned()),
+            type_: Some("Warning".to_owned()),
+            source: Some(EventSource {
+                component: Some("sinabro-agent".to_owned()),
+                host: Some(node_name.to_owned()),
+            }),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            count: Some(1),
+            ..Default::default()
+        };
+
+        Api::<KubeEvent>::namespaced(self.client.clone(), "default")
+            .create(&PostParams::default(), &event)
+            .await?;
+
+        Ok(())
+    }
+
     async fn watch_pod_until_running(pods: &Api<Pod>, name: &str) -> Result<()> {
         let wp = WatchParams::default()
             .fields(&format!("metadata.name={}", name))
@@ -146,6 +408,65 @@ impl Context {
     }
 }
 
+/// Reads `clusterCIDR` from the kube-proxy ConfigMap, naming the step that
+/// failed so operators can tell why detection did not work.
+fn cluster_cidr_from(config_map: Option<ConfigMap>) -> Result<String> {
+    let config_map = config_map.ok_or_else(|| {
+        anyhow!("failed to get cluster cidr: configmap kube-system/kube-proxy not found")
+    })?;
+
+    let conf = config_map
+        .data
+        .as_ref()
+        .and_then(|data| data.get("config.conf"))
+        .ok_or_else(|| {
+            anyhow!("failed to get cluster cidr: kube-proxy configmap has no config.conf")
+        })?;
+
+    let yaml = serde_yaml::from_str::<serde_yaml::Value>(conf).map_err(|e| {
+        anyhow!(
+            "failed to get cluster cidr: config.conf is not valid YAML: {}",
+            e
+        )
+    })?;
+
+    yaml["clusterCIDR"]
+        .as_str()
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| anyhow!("failed to get cluster cidr: clusterCIDR is not set in config.conf"))
+}
+
+/// Reads the IPv4 entry of `networking.serviceSubnet` from the kubeadm
+/// ClusterConfiguration. kube-proxy's own config does not carry the service
+/// CIDR; kubeadm's does, comma-separated when the cluster is dual-stack.
+fn service_cidr_from(config_map: Option<ConfigMap>) -> Result<Ipv4Net> {
+    let config_map = config_map.ok_or_else(|| {
+        anyhow!("failed to get service cidr: configmap kube-system/kubeadm-config not found")
+    })?;
+
+    let conf = config_map
+        .data
+        .as_ref()
+        .and_then(|data| data.get("ClusterConfiguration"))
+        .ok_or_else(|| {
+            anyhow!("failed to get service cidr: kubeadm-config has no ClusterConfiguration")
+        })?;
+
+    let yaml = serde_yaml::from_str::<serde_yaml::Value>(conf).map_err(|e| {
+        anyhow!(
+            "failed to get service cidr: ClusterConfiguration is not valid YAML: {}",
+            e
+        )
+    })?;
+
+    yaml["networking"]["serviceSubnet"]
+        .as_str()
+        .ok_or_else(|| anyhow!("failed to get service cidr: serviceSubnet is not set"))?
+        .split(',')
+        .find_map(|cidr| cidr.trim().parse::<Ipv4Net>().ok())
+        .ok_or_else(|| anyhow!("failed to get service cidr: serviceSubnet has no IPv4 CIDR"))
+}
+
 #[cfg(test)]
 mod tests {
     use futures::pin_mut;
@@ -194,13 +515,192 @@ mod tests {
 
         let client = kube::Client::new(mock_service, "test-namespace");
         let token = CancellationToken::new();
-        let context = Context { client, token };
+        let context = Context {
+            client,
+            token,
+            lookups: TokenBucket::new(0, 0),
+            nodes: NodeInfoCache::default(),
+        };
         let cluster_cidr = context.get_cluster_cidr().await.unwrap();
         assert_eq!(cluster_cidr, "10.244.0.0/16");
 
         spawned.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_get_cluster_cidr_configmap_missing() {
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            let (_, send) = handle.next_request().await.expect("service not called");
+
+            let status = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Status",
+                "status": "Failure",
+                "message": "configmaps \"kube-proxy\" not found",
+                "reason": "NotFound",
+                "code": 404
+            });
+
+            send.send_response(
+                Response::builder()
+                    .status(404)
+                    .body(Body::from(serde_json::to_vec(&status).unwrap()))
+                    .unwrap(),
+            );
+        });
+
+        let client = kube::Client::new(mock_service, "test-namespace");
+        let token = CancellationToken::new();
+        let context = Context {
+            client,
+            token,
+            lookups: TokenBucket::new(0, 0),
+            nodes: NodeInfoCache::default(),
+        };
+        let err = context.get_cluster_cidr().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to get cluster cidr: configmap kube-system/kube-proxy not found"
+        );
+
+        spawned.await.unwrap();
+    }
+
+    #[test]
+    fn test_service_cidr_from_kubeadm_config() {
+        let config_map = |conf: &str| ConfigMap {
+            data: Some([("ClusterConfiguration".to_string(), conf.to_string())].into()),
+            ..Default::default()
+        };
+
+        let conf = "apiServer:\n  certSANs:\n  - localhost\napiVersion: kubeadm.k8s.io/v1beta3\nclusterName: kind\ncontrolPlaneEndpoint: kind-control-plane:6443\nkind: ClusterConfiguration\nkubernetesVersion: v1.29.2\nnetworking:\n  dnsDomain: cluster.local\n  podSubnet: 10.244.0.0/16\n  serviceSubnet: 10.96.0.0/16\n";
+        assert_eq!(
+            service_cidr_from(Some(config_map(conf))).unwrap(),
+            "10.96.0.0/16".parse::<Ipv4Net>().unwrap()
+        );
+
+        // Dual-stack lists both families; the eBPF NAT only needs the IPv4 one.
+        let conf = "networking:\n  serviceSubnet: fd00:10:96::/112,10.96.0.0/16\n";
+        assert_eq!(
+            service_cidr_from(Some(config_map(conf))).unwrap(),
+            "10.96.0.0/16".parse::<Ipv4Net>().unwrap()
+        );
+
+        let conf = "networking:\n  podSubnet: 10.244.0.0/16\n";
+        assert_eq!(
+            service_cidr_from(Some(config_map(conf)))
+                .unwrap_err()
+                .to_string(),
+            "failed to get service cidr: serviceSubnet is not set"
+        );
+        assert!(service_cidr_from(None).is_err());
+    }
+
+    fn kube_proxy_config_map(data: serde_json::Value) -> ConfigMap {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {
+                "name": "kube-proxy",
+                "namespace": "kube-system",
+            },
+            "data": data
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cluster_cidr_from_missing_config_conf() {
+        let config_map = kube_proxy_config_map(serde_json::json!({}));
+
+        let err = cluster_cidr_from(Some(config_map)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to get cluster cidr: kube-proxy configmap has no config.conf"
+        );
+    }
+
+    #[test]
+    fn test_cluster_cidr_from_invalid_yaml() {
+        let config_map = kube_proxy_config_map(serde_json::json!({
+            "config.conf": "clusterCIDR: [10.244.0.0/16"
+        }));
+
+        let err = cluster_cidr_from(Some(config_map)).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("failed to get cluster cidr: config.conf is not valid YAML: "));
+    }
+
+    #[test]
+    fn test_cluster_cidr_from_missing_key() {
+        let config_map = kube_proxy_config_map(serde_json::json!({
+            "config.conf": "kind: KubeProxyConfiguration\nmode: iptables"
+        }));
+
+        let err = cluster_cidr_from(Some(config_map)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to get cluster cidr: clusterCIDR is not set in config.conf"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_node_route_by_name() {
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), &http::Method::GET);
+            assert_eq!(request.uri().path(), "/api/v1/nodes/kind-worker");
+
+            let node: Node = serde_json::from_value(serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Node",
+                "metadata": {
+                    "name": "kind-worker",
+                },
+                "spec": {
+                    "podCIDRs": [
+                        "10.244.1.0/24"
+                    ]
+                },
+                "status": {
+                    "addresses": [
+                        {
+                            "address": "172.18.0.2",
+                            "type": "InternalIP"
+                        }
+                    ]
+                }
+            }))
+            .unwrap();
+
+            send.send_response(
+                Response::builder()
+                    .body(Body::from(serde_json::to_vec(&node).unwrap()))
+                    .unwrap(),
+            );
+        });
+
+        let client = kube::Client::new(mock_service, "test-namespace");
+        let token = CancellationToken::new();
+        let context = Context {
+            client,
+            token,
+            lookups: TokenBucket::new(0, 0),
+            nodes: NodeInfoCache::default(),
+        };
+        let node_route = context.get_node_route("kind-worker").await.unwrap();
+        assert_eq!(node_route.name, "kind-worker");
+        assert_eq!(node_route.ip, "172.18.0.2");
+        assert_eq!(node_route.pod_cidr, "10.244.1.0/24");
+
+        spawned.await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_get_node_routes() {
         let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
@@ -286,14 +786,153 @@ mod tests {
 
         let client = kube::Client::new(mock_service, "test-namespace");
         let token = CancellationToken::new();
-        let context = Context { client, token };
+        let context = Context {
+            client,
+            token,
+            lookups: TokenBucket::new(0, 0),
+            nodes: NodeInfoCache::default(),
+        };
         let node_routes = context.get_node_routes().await.unwrap();
         assert_eq!(node_routes.len(), 2);
         assert_eq!(node_routes[0].ip, "172.18.0.3");
         assert_eq!(node_routes[0].pod_cidr, "10.244.0.0/24");
         assert_eq!(node_routes[1].ip, "172.18.0.2");
         assert_eq!(node_routes[1].pod_cidr, "10.244.1.0/24");
+        assert_eq!(
+            context.nodes().get("kind-worker").unwrap().pod_cidrs,
+            vec!["10.244.1.0/24"]
+        );
+
+        spawned.await.unwrap();
+    }
+
+    fn node(name: &str, ip: &str, vxlan_mac: Option<&str>) -> Node {
+        let annotations = vxlan_mac
+            .map(|mac| serde_json::json!({ VXLAN_MAC_ANNOTATION: mac }))
+            .unwrap_or_else(|| serde_json::json!({}));
+
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Node",
+            "metadata": {
+                "name": name,
+                "annotations": annotations,
+            },
+            "spec": {
+                "podCIDRs": ["10.244.1.0/24", "fd00:10:244:1::/64"]
+            },
+            "status": {
+                "addresses": [
+                    { "address": name, "type": "Hostname" },
+                    { "address": ip, "type": "InternalIP" }
+                ]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_node_info_cache_events() {
+        let cache = NodeInfoCache::default();
+
+        cache.observe(&Event::Apply(node(
+            "worker",
+            "172.18.0.2",
+            Some("aa:bb:cc:dd:ee:01"),
+        )));
+        let info = cache.get("worker").unwrap();
+        assert_eq!(info.internal_ip, "172.18.0.2");
+        assert_eq!(info.pod_cidrs, vec!["10.244.1.0/24", "fd00:10:244:1::/64"]);
+        assert_eq!(info.vxlan_mac.as_deref(), Some("aa:bb:cc:dd:ee:01"));
+        assert_eq!(info.wg_public_key, None);
+
+        // A looked-up MAC only lasts until the node changes.
+        cache.observe(&Event::Apply(node("worker", "172.18.0.5", None)));
+        cache.set_vxlan_mac("worker", &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x02]);
+        assert_eq!(
+            cache.get("worker").unwrap().vxlan_mac.as_deref(),
+            Some("aa:bb:cc:dd:ee:02")
+        );
+        cache.observe(&Event::Apply(node("worker", "172.18.0.5", None)));
+        assert_eq!(cache.get("worker").unwrap().vxlan_mac, None);
+
+        cache.observe(&Event::Delete(node("worker", "172.18.0.5", None)));
+        assert_eq!(cache.get("worker"), None);
+
+        // Nothing is cached for a node the cache has not seen.
+        cache.set_vxlan_mac("worker", &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x02]);
+        assert!(cache.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_annotate_pod_cidr() {
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), &http::Method::PATCH);
+            assert_eq!(request.uri().path(), "/api/v1/nodes/kind-worker");
+            assert_eq!(
+                request.headers()["content-type"],
+                "application/merge-patch+json"
+            );
+
+            let body = request.into_body().collect_bytes().await.unwrap();
+            let patch = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            assert_eq!(
+                patch["metadata"]["annotations"][POD_CIDR_ANNOTATION],
+                "10.244.1.0/24"
+            );
+
+            send.send_response(
+                Response::builder()
+                    .body(Body::from(
+                        serde_json::to_vec(&node("kind-worker", "172.18.0.2", None)).unwrap(),
+                    ))
+                    .unwrap(),
+            );
+        });
+
+        let client = kube::Client::new(mock_service, "test-namespace");
+        let context = Context {
+            client,
+            token: CancellationToken::new(),
+            lookups: TokenBucket::new(0, 0),
+            nodes: NodeInfoCache::default(),
+        };
+        context
+            .annotate_pod_cidr("kind-worker", "10.244.1.0/24")
+            .await
+            .unwrap();
 
         spawned.await.unwrap();
     }
+
+    #[test]
+    fn test_node_info_pod_cidr_annotation() {
+        let node: Node = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "worker",
+                "annotations": { POD_CIDR_ANNOTATION: "10.244.7.0/24" }
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(NodeInfo::from(&node).pod_cidrs, vec!["10.244.7.0/24"]);
+    }
+
+    #[test]
+    fn test_node_info_cache_relist() {
+        let cache = NodeInfoCache::default();
+        cache.fill(&[
+            node("worker", "172.18.0.2", None),
+            node("worker2", "172.18.0.3", None),
+        ]);
+
+        cache.observe(&Event::Init);
+        cache.observe(&Event::InitApply(node("worker2", "172.18.0.3", None)));
+        cache.observe(&Event::InitDone);
+
+        assert_eq!(cache.snapshot().keys().collect::<Vec<_>>(), vec!["worker2"]);
+    }
 }
@@ -1,32 +1,302 @@
+mod bpf_info;
 mod bpf_loader;
+mod cni_config;
+mod cni_install;
+mod conntrack;
+mod convergence;
+mod interfaces;
 mod kube;
+mod metrics;
 mod netlink;
 mod node_route;
+mod node_watcher;
+mod orphans;
+mod plan;
+mod prober;
+mod rate_limit;
+mod selftest;
 mod server;
+mod setup_report;
+mod stats;
+mod status;
+mod sysctl;
 
-use std::env;
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use aya_log::BpfLogger;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
 use bpf_loader::BpfLoader;
 use clap::Parser;
-use ipnet::IpNet;
+use common::{MarkConfig, SockOpsConfig, SOCK_OPS_DEBUG, SOCK_OPS_SKIP_IPV4, SOCK_OPS_SKIP_IPV6};
+use ipnet::{IpNet, Ipv4Net};
 use node_route::NodeRoute;
-use server::api_server;
-use sinabro_config::{setup_tracing_to_stdout, Config};
+use node_watcher::NodeWatcher;
+use server::{api_server, ipam::Ipam, state::AppState, status_server};
+use sinabro_config::{setup_tracing_to_stdout, validate_ifname, ConfList, Config, Datapath};
+use tokio::signal;
 use tokio_util::sync::CancellationToken;
-use tracing::Level;
+use tracing::{error, info, warn, Level};
 
-use crate::kube::Context;
-use crate::netlink::Netlink;
+use crate::bpf_info::BpfInfoReader;
+use crate::cni_config::{CniConfigStatus, CniConfigWatcher};
+use crate::conntrack::ConntrackReader;
+use crate::convergence::{ConvergenceMetrics, Trigger};
+use crate::interfaces::{InterfaceReader, NetlinkInterfaceSource};
+use crate::kube::{Context, NodeInfoCache};
+use crate::netlink::{Netlink, OverlayRound};
+use crate::plan::Plan;
+use crate::prober::{PeerTable, ProbeTargets, Prober, UdpTransport};
+use crate::setup_report::{SetupReport, SetupStep};
+use crate::stats::StatsReader;
+use crate::status::{AgentStatus, OverlayProgress, Startup, StatusReport};
+use crate::sysctl::HostSysctl;
+
+const CNI_CONFIG_PATH: &str = "/etc/cni/net.d/10-sinabro.conf";
+const CNI_CONFLIST_PATH: &str = "/etc/cni/net.d/10-sinabro.conflist";
+
+/// How long the startup summary waits for the overlay round; nodes still
+/// being retried after that are left out of it.
+const OVERLAY_SUMMARY_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "eth0")]
-    iface: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Interfaces to attach the tc programs to; repeat or comma-separate
+    #[clap(
+        short,
+        long,
+        default_value = "eth0",
+        value_delimiter = ',',
+        value_parser = parse_iface
+    )]
+    iface: Vec<String>,
 
     #[clap(short, long, default_value = "/sys/fs/cgroup")]
     cgroup_path: String,
+
+    /// Path of the file backing the IPAM store
+    #[clap(long, default_value = "/var/lib/sinabro/ip_store")]
+    ipam_store: String,
+
+    /// Install the bundled CNI plugin binary before writing the CNI config
+    #[clap(long)]
+    install_cni: bool,
+
+    #[clap(long, default_value = "/sinabro-cni")]
+    cni_bin_source: String,
+
+    #[clap(long, default_value = "/opt/cni/bin")]
+    cni_bin_dir: String,
+
+    /// Address serving /status and /readyz while the agent starts up
+    #[clap(long, default_value = "0.0.0.0:3001")]
+    status_addr: String,
+
+    /// Log every connection picked up by the socket acceleration programs
+    #[clap(long)]
+    sock_ops_debug: bool,
+
+    /// Maximum number of sockets in each socket acceleration map (IPv4 and
+    /// IPv6); connections beyond it are not accelerated
+    #[clap(long, default_value = "65535")]
+    sock_ops_map_size: u32,
+
+    /// Address families to accelerate between local sockets
+    #[clap(
+        long,
+        default_value = "ipv4,ipv6",
+        value_delimiter = ',',
+        value_parser = ["ipv4", "ipv6"]
+    )]
+    sock_ops_family: Vec<String>,
+
+    /// How pods are attached on the node: bridge or ptp (IPv4 only)
+    #[clap(long, default_value = "bridge")]
+    datapath: Datapath,
+
+    /// Address family of the cluster: v4 or v6 (dual-stack is not supported
+    /// yet). The eBPF NAT is IPv4 only and stays out of the way on v6
+    #[clap(long, default_value = "v4")]
+    ip_family: IpFamily,
+
+    /// Routing-only mode: skip the SNAT tc programs and their config maps
+    #[clap(long)]
+    no_snat: bool,
+
+    /// IPv4 Service CIDR; traffic to ClusterIPs in it is never masqueraded.
+    /// Read from the kubeadm-config ConfigMap when not set
+    #[clap(long)]
+    service_cidr: Option<Ipv4Net>,
+
+    /// Keep hand edits to the CNI config instead of writing it again; either
+    /// way they are logged and counted on /status
+    #[clap(long)]
+    respect_manual_cni_config: bool,
+
+    /// Host sysctl to apply on startup as key=value; overrides the default
+    /// with the same key. Repeatable
+    #[clap(long = "sysctl", value_parser = parse_sysctl)]
+    sysctls: Vec<(String, String)>,
+
+    /// Run discovery, IPAM, the API server and the watchers without loading
+    /// eBPF or changing the host's network, CNI config or sysctls
+    #[clap(long)]
+    no_dataplane: bool,
+
+    /// Remove the VXLAN device, overlay routes and an empty bridge and restore
+    /// the host sysctls on shutdown
+    #[clap(long)]
+    cleanup_on_exit: bool,
+
+    /// skb->mark bits set on packets sinabro masquerades (0 disables)
+    #[clap(long, default_value = "0", value_parser = parse_mark)]
+    masquerade_mark: u32,
+
+    /// skb->mark bits set on packets originating from pods (0 disables)
+    #[clap(long, default_value = "0", value_parser = parse_mark)]
+    pod_mark: u32,
+
+    /// Packets carrying these skb->mark bits bypass sinabro's NAT (0 disables)
+    #[clap(long, default_value = "0", value_parser = parse_mark)]
+    nat_exclude_mark: u32,
+
+    /// skb->mark bits set on pod traffic before routing, for fwmark policy
+    /// rules (0 disables; bridge datapath only)
+    #[clap(long, default_value = "0", value_parser = parse_mark)]
+    route_mark: u32,
+
+    /// Seconds between rounds of UDP probes to every remote node over the
+    /// overlay; results are served on /network/peers (0 disables)
+    #[clap(long, default_value = "0")]
+    probe_interval: u64,
+
+    /// Remote nodes programmed at once when the overlay is set up on startup
+    #[clap(long, default_value = "16")]
+    overlay_concurrency: usize,
+
+    /// Per-node API lookups (VTEP MAC) allowed per second while programming
+    /// the overlay (0 disables the limit)
+    #[clap(long, default_value = "10")]
+    overlay_lookup_rate: u32,
+
+    /// MTU of the VXLAN device, the bridge and the pod veths; defaults to the
+    /// underlay MTU minus the VXLAN overhead and is clamped to it
+    #[clap(long)]
+    overlay_mtu: Option<u32>,
+
+    /// CNI plugin to chain after sinabro, as its JSON config, e.g.
+    /// '{"type":"portmap","capabilities":{"portMappings":true}}'. Repeatable;
+    /// any of them makes the agent write a conflist
+    #[clap(long = "cni-chain", value_parser = parse_cni_plugin)]
+    cni_chain: Vec<serde_json::Value>,
+
+    /// Run discovery and log every change startup would make to the host,
+    /// then print them as JSON and exit without making any of them
+    #[clap(long)]
+    dry_run: bool,
+}
+
+impl Opt {
+    fn sock_ops_config(&self) -> SockOpsConfig {
+        let mut flags = 0;
+
+        if self.sock_ops_debug {
+            flags |= SOCK_OPS_DEBUG;
+        }
+        if !self.sock_ops_family.iter().any(|family| family == "ipv4") {
+            flags |= SOCK_OPS_SKIP_IPV4;
+        }
+        if !self.sock_ops_family.iter().any(|family| family == "ipv6") {
+            flags |= SOCK_OPS_SKIP_IPV6;
+        }
+
+        SockOpsConfig { flags }
+    }
+
+    fn mark_config(&self) -> MarkConfig {
+        MarkConfig {
+            masquerade: self.masquerade_mark,
+            pod: self.pod_mark,
+            exclude: self.nat_exclude_mark,
+            route: match self.datapath {
+                Datapath::Bridge => self.route_mark,
+                Datapath::Ptp => 0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IpFamily {
+    V4,
+    V6,
+    Dual,
+}
+
+impl FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v4" => Ok(IpFamily::V4),
+            "v6" => Ok(IpFamily::V6),
+            "dual" => Ok(IpFamily::Dual),
+            _ => Err(format!(
+                "unknown ip family {:?}, expected v4, v6 or dual",
+                s
+            )),
+        }
+    }
+}
+
+impl IpFamily {
+    fn as_str(&self) -> &'static str {
+        match self {
+            IpFamily::V4 => "v4",
+            IpFamily::V6 => "v6",
+            IpFamily::Dual => "dual",
+        }
+    }
+
+    /// Checks the node's pod CIDR and the chosen datapath against the
+    /// configured family, so a mismatch fails discovery instead of surfacing
+    /// later as a half-programmed node.
+    fn check(&self, pod_cidr: &IpNet, datapath: Datapath) -> Result<()> {
+        match (self, pod_cidr) {
+            (IpFamily::Dual, _) => bail!("dual-stack clusters are not supported yet"),
+            (IpFamily::V4, IpNet::V6(_)) | (IpFamily::V6, IpNet::V4(_)) => {
+                bail!(
+                    "pod CIDR {} does not match --ip-family {}",
+                    pod_cidr,
+                    self.as_str()
+                )
+            }
+            (IpFamily::V6, _) if datapath == Datapath::Ptp => {
+                bail!("the ptp datapath supports IPv4 only")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+enum Command {
+    /// Validate netlink capabilities in an isolated network namespace
+    Selftest,
+    /// Print the startup progress of the agent running on this node
+    Status {
+        #[clap(long, default_value = "http://127.0.0.1:3001")]
+        endpoint: String,
+    },
 }
 
 #[tokio::main]
@@ -34,55 +304,663 @@ async fn main() -> Result<()> {
     setup_tracing_to_stdout(Level::DEBUG);
 
     let opt = Opt::parse();
+
+    match &opt.command {
+        Some(Command::Selftest) => return run_selftest(),
+        Some(Command::Status { endpoint }) => return print_status(endpoint).await,
+        None => {}
+    }
+
+    let plan = opt.dry_run.then(Plan::default);
+
+    match (&plan, opt.install_cni) {
+        (_, false) => {}
+        (Some(plan), true) => plan.record("install_cni", &opt.cni_bin_dir, &opt.cni_bin_source),
+        (None, true) => {
+            cni_install::install_binary(
+                Path::new(&opt.cni_bin_source),
+                Path::new(&opt.cni_bin_dir),
+            )?;
+        }
+    }
+
     let token = CancellationToken::new();
-    let context = Context::new(token.clone()).await?;
+    handle_shutdown_signals(token.clone());
+
+    let status = AgentStatus::new();
+    start_status_server(&opt.status_addr, status.clone(), token.clone());
+
+    let mut startup = AgentStartup::new(&opt, token.clone(), &status, plan.clone());
+    // Only fatal steps fail startup; the report is logged once the overlay
+    // round is over too, so it includes the nodes that could not be reached.
+    if let Err(e) = status.run(&mut startup).await {
+        status.setup().log_summary();
+        return Err(e);
+    }
+    let overlay_report = status.clone();
+    let overlay_finished = tokio::spawn(async move {
+        let overlay = overlay_report.overlay();
+        if tokio::time::timeout(OVERLAY_SUMMARY_TIMEOUT, overlay.finished())
+            .await
+            .is_err()
+        {
+            let counts = overlay.counts();
+            warn!(
+                "overlay round still running after {:?} ({}/{} nodes done, {} failed), logging the setup summary so far",
+                OVERLAY_SUMMARY_TIMEOUT, counts.done, counts.total, counts.failed
+            );
+        }
+        overlay_report.setup().log_summary();
+    });
+
+    if let Some(plan) = plan {
+        overlay_finished.await?;
+
+        println!("{}", serde_json::to_string_pretty(&plan.changes())?);
+        return Ok(());
+    }
 
-    let node_routes = context.get_node_routes().await?;
-    let cluster_cidr = context.get_cluster_cidr().await?;
-    let host_ip = get_host_ip()?;
+    let vtep_ip = startup.vtep_ip()?;
+    let AgentStartup {
+        context,
+        host_ip,
+        node_routes,
+        bpf_loader,
+        mut host_sysctl,
+        node_watcher,
+        probe_targets,
+        convergence,
+        ..
+    } = startup;
+    let Some(context) = context else {
+        bail!("startup finished without a kube context");
+    };
+    let mut bpf_loader = match (bpf_loader, opt.no_dataplane) {
+        (Some(bpf_loader), _) => Some(bpf_loader),
+        (None, true) => None,
+        (None, false) => bail!("startup finished without a dataplane"),
+    };
     let host_route = find_host_route(&node_routes, &host_ip)?;
 
-    setup_cni_config(&cluster_cidr, &host_route.pod_cidr)?;
-    setup_network(&host_ip, host_route, &node_routes)?;
+    let peers = match (opt.probe_interval, opt.no_dataplane) {
+        (0, _) | (_, true) => None,
+        (interval, false) => Some(start_prober(
+            host_route,
+            probe_targets,
+            Duration::from_secs(interval),
+            context.clone(),
+            token.clone(),
+        )?),
+    };
+
+    watch_service_resource(context);
+    if let Some(node_watcher) = node_watcher {
+        tokio::spawn(node_watcher.run());
+    }
+
+    let dataplane = match bpf_loader.as_mut() {
+        Some(bpf_loader) => {
+            // Only a debugging aid, so the agent runs on without it.
+            let bpf_info = bpf_loader
+                .bpf_info()
+                .inspect_err(|e| warn!("/bpf/info is not available: {}", e))
+                .ok();
+            Some((bpf_loader.stats()?, bpf_loader.conntrack()?, bpf_info))
+        }
+        None => None,
+    };
+    let ipam = Ipam::new(&host_route.pod_cidr, &opt.ipam_store)?;
+    if !opt.no_dataplane {
+        sweep_orphaned_veths(&ipam);
+    }
+
+    start_api_server(ipam, dataplane, peers, convergence, token).await?;
+
+    if let Some(bpf_loader) = bpf_loader.as_mut() {
+        bpf_loader.detach()?;
+    }
+
+    if opt.cleanup_on_exit && !opt.no_dataplane {
+        teardown_network(&vtep_ip, host_route, &node_routes)?;
+        host_sysctl.restore()?;
+    }
+
+    Ok(())
+}
+
+/// The agent's real startup steps; each one fills in what the later ones
+/// need.
+struct AgentStartup<'a> {
+    opt: &'a Opt,
+    token: CancellationToken,
+    context: Option<Arc<Context>>,
+    host_ip: String,
+    cluster_cidr: String,
+    service_cidr: Option<Ipv4Net>,
+    node_routes: Vec<NodeRoute>,
+    host_sysctl: HostSysctl,
+    node_watcher: Option<NodeWatcher>,
+    probe_targets: ProbeTargets,
+    convergence: ConvergenceMetrics,
+    overlay_progress: OverlayProgress,
+    nodes: NodeInfoCache,
+    report: SetupReport,
+    cni_config: CniConfigStatus,
+    /// Set on a dry run; changes to the host are recorded here instead.
+    plan: Option<Plan>,
+    /// Overlay MTU, settled before the CNI config is written.
+    mtu: Option<u32>,
+    bpf_loader: Option<BpfLoader>,
+}
+
+impl<'a> AgentStartup<'a> {
+    fn new(
+        opt: &'a Opt,
+        token: CancellationToken,
+        status: &AgentStatus,
+        plan: Option<Plan>,
+    ) -> Self {
+        Self {
+            opt,
+            token,
+            context: None,
+            host_ip: String::new(),
+            cluster_cidr: String::new(),
+            service_cidr: None,
+            node_routes: vec![],
+            host_sysctl: HostSysctl::default(),
+            node_watcher: None,
+            probe_targets: ProbeTargets::default(),
+            convergence: ConvergenceMetrics::default(),
+            overlay_progress: status.overlay(),
+            nodes: status.nodes(),
+            report: status.setup(),
+            cni_config: status.cni_config(),
+            plan,
+            mtu: None,
+            bpf_loader: None,
+        }
+    }
+
+    fn host_route(&self) -> Result<&NodeRoute> {
+        find_host_route(&self.node_routes, &self.host_ip)
+    }
+
+    /// The local VXLAN endpoint: `HOST_IP`, unless it is not of the
+    /// `--ip-family` family and the Node lists an address that is. Nodes
+    /// with both a v4 and a v6 address run the overlay over IPv6 that way.
+    fn vtep_ip(&self) -> Result<String> {
+        let host_ip = self.host_ip.parse::<IpAddr>()?;
+        let v6 = self.opt.ip_family == IpFamily::V6;
+        if host_ip.is_ipv6() == v6 {
+            return Ok(self.host_ip.clone());
+        }
+
+        let vtep_ip = self.host_route()?.underlay_addr(v6).unwrap_or(host_ip);
+        Ok(vtep_ip.to_string())
+    }
+}
+
+#[async_trait]
+impl Startup for AgentStartup<'_> {
+    async fn discover(&mut self) -> Result<()> {
+        let context = Context::new(self.token.clone(), self.opt.overlay_lookup_rate)
+            .await?
+            .with_nodes(self.nodes.clone());
 
-    let mut bpf_loader = BpfLoader::load(&opt.iface, &opt.cgroup_path)?;
-    BpfLogger::init(&mut bpf_loader.bpf)?;
+        self.node_routes = context.get_node_routes().await?;
+        self.cluster_cidr = context.get_cluster_cidr().await?;
+        self.service_cidr = match self.opt.service_cidr {
+            Some(service_cidr) => Some(service_cidr),
+            // Without it ClusterIP traffic is masqueraded like before, so a
+            // cluster not set up by kubeadm still comes up.
+            None => context
+                .get_service_cidr()
+                .await
+                .inspect_err(|e| warn!("{:#}, set --service-cidr", e))
+                .ok(),
+        };
+        self.host_ip = get_host_ip()?;
 
-    bpf_loader
-        .attach(&host_ip, &cluster_cidr, &get_node_ips(&node_routes))
+        if self.host_route().is_err() {
+            let node_name = get_node_name()?;
+            warn!(
+                "no node has address {}, looking up node {} by name",
+                self.host_ip, node_name
+            );
+
+            let host_route = context.get_node_route(&node_name).await?;
+            upsert_host_route(&mut self.node_routes, host_route, &self.host_ip);
+            self.host_route()?;
+        }
+
+        let host_route = self.host_route()?;
+        let pod_cidr = host_route.pod_net()?;
+        self.opt.ip_family.check(&pod_cidr, self.opt.datapath)?;
+
+        match &self.plan {
+            Some(plan) => plan.record("annotate_node", &host_route.name, &host_route.pod_cidr),
+            None => {
+                if let Err(e) = context
+                    .annotate_pod_cidr(&host_route.name, &host_route.pod_cidr)
+                    .await
+                {
+                    warn!(
+                        "failed to annotate node {} with its pod CIDR: {}",
+                        host_route.name, e
+                    );
+                }
+            }
+        }
+        self.context = Some(Arc::new(context));
+
+        Ok(())
+    }
+
+    async fn write_cni_config(&mut self) -> Result<()> {
+        if self.opt.no_dataplane {
+            info!("no-dataplane mode, not writing the CNI config");
+            return Ok(());
+        }
+
+        let pod_cidr = self.host_route()?.pod_net()?;
+        let mut netlink = Netlink::init(&self.vtep_ip()?, &pod_cidr, &[]);
+        let mtu = netlink.overlay_mtu_for(self.opt.overlay_mtu)?;
+        self.mtu = Some(mtu);
+
+        if let Some(plan) = &self.plan {
+            let path = if self.opt.cni_chain.is_empty() {
+                CNI_CONFIG_PATH
+            } else {
+                CNI_CONFLIST_PATH
+            };
+            plan.record("write", path, format!("{} mtu {}", pod_cidr, mtu));
+            return Ok(());
+        }
+
+        let watcher = setup_cni_config(
+            &self.cluster_cidr,
+            &self.host_route()?.pod_cidr,
+            self.opt.datapath,
+            mtu,
+            &self.opt.cni_chain,
+            self.opt.respect_manual_cni_config,
+            self.cni_config.clone(),
+        )?;
+        tokio::spawn(watcher.run(self.token.clone()));
+
+        Ok(())
+    }
+
+    async fn setup_network(&mut self) -> Result<()> {
+        if self.opt.no_dataplane {
+            info!("no-dataplane mode, leaving the host network untouched");
+            return Ok(());
+        }
+
+        let sysctls = sysctl::with_defaults(&self.opt.sysctls, self.opt.ip_family != IpFamily::V4);
+        match &self.plan {
+            Some(plan) => {
+                for (key, value) in &sysctls {
+                    plan.record("sysctl", key, value);
+                }
+            }
+            None => {
+                self.report
+                    .run(SetupStep::Sysctl, || self.host_sysctl.apply(&sysctls));
+            }
+        }
+        let context = self
+            .context
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("kube context is not initialized"))?;
+        let pod_cidr = self.host_route()?.pod_net()?;
+        let vtep_ip = self.vtep_ip()?;
+        if vtep_ip != self.host_ip {
+            info!("running the VXLAN overlay from {}", vtep_ip);
+        }
+        let mut netlink = Netlink::init(&vtep_ip, &pod_cidr, &self.node_routes);
+        if let Some(plan) = &self.plan {
+            netlink = netlink.with_plan(plan.clone());
+        }
+        if let Some(mtu) = self.mtu {
+            netlink = netlink.with_mtu(mtu);
+        }
+        let node_watcher = setup_network(
+            netlink,
+            context,
+            self.probe_targets.clone(),
+            self.convergence.clone(),
+            OverlayRound {
+                concurrency: self.opt.overlay_concurrency,
+                progress: self.overlay_progress.clone(),
+                report: self.report.clone(),
+            },
+        )
         .await?;
+        self.node_watcher = Some(node_watcher);
 
-    watch_service_resource(context);
+        Ok(())
+    }
+
+    async fn attach_dataplane(&mut self) -> Result<()> {
+        if self.opt.no_dataplane {
+            info!("no-dataplane mode, not loading the eBPF programs");
+            return Ok(());
+        }
+        if let Some(plan) = &self.plan {
+            plan.record("attach_bpf", &self.opt.iface.join(","), "skipped");
+            return Ok(());
+        }
+
+        let mut bpf_loader = BpfLoader::load(
+            &self.opt.iface,
+            &self.opt.cgroup_path,
+            !self.opt.no_snat,
+            self.opt.sock_ops_map_size,
+        )?
+        .with_service_cidr(self.service_cidr);
+        bpf_loader.init_logger();
+
+        if self.opt.route_mark != 0 && self.opt.datapath == Datapath::Ptp {
+            warn!("--route-mark needs the bridge datapath, pod traffic will not be marked");
+        }
+
+        bpf_loader
+            .attach(
+                &self.host_ip,
+                &self.cluster_cidr,
+                &self.host_route()?.pod_cidr,
+                &get_node_ips(&self.node_routes),
+                self.opt.mark_config(),
+                self.opt.sock_ops_config(),
+                &self.report,
+            )
+            .await?;
+        self.bpf_loader = Some(bpf_loader);
+
+        Ok(())
+    }
+}
+
+fn handle_shutdown_signals(token: CancellationToken) {
+    tokio::spawn(async move {
+        let ctrl_c = async {
+            signal::ctrl_c()
+                .await
+                .expect("failed to install Ctrl+C handler");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            signal::unix::signal(signal::unix::SignalKind::terminate())
+                .expect("failed to install signal handler")
+                .recv()
+                .await;
+        };
+
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate => {}
+            _ = token.cancelled() => return,
+        }
+
+        info!("shutdown signal received");
+        token.cancel();
+    });
+}
+
+fn run_selftest() -> Result<()> {
+    let results = selftest::run()?;
+
+    for result in &results {
+        match &result.error {
+            None => println!("[PASS] {}", result.name),
+            Some(e) => println!("[FAIL] {}: {}", result.name, e),
+        }
+    }
+
+    if results.iter().any(|result| !result.passed()) {
+        bail!("selftest failed");
+    }
+
+    Ok(())
+}
+
+fn parse_iface(value: &str) -> Result<String> {
+    validate_ifname(value)?;
+    Ok(value.to_owned())
+}
+
+fn parse_cni_plugin(value: &str) -> Result<serde_json::Value> {
+    let plugin = serde_json::from_str::<serde_json::Value>(value)?;
+    if !plugin["type"].is_string() {
+        bail!("a chained plugin needs a \"type\", got {}", value);
+    }
+
+    Ok(plugin)
+}
 
-    start_api_server(&host_route.pod_cidr, token).await?;
+fn parse_mark(value: &str) -> Result<u32> {
+    let mark = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => value.parse()?,
+    };
+
+    Ok(mark)
+}
+
+async fn print_status(endpoint: &str) -> Result<()> {
+    let report = reqwest::get(format!("{}/status", endpoint))
+        .await?
+        .error_for_status()?
+        .json::<StatusReport>()
+        .await?;
+
+    print!("{}", format_status(&report));
 
     Ok(())
 }
 
+fn format_status(report: &StatusReport) -> String {
+    let mut output = format!("ready: {}\n", report.ready);
+
+    for phase in &report.phases {
+        let line = format!(
+            "{:<12} {:<8} {}",
+            phase.phase,
+            phase.state,
+            phase.error.as_deref().unwrap_or_default()
+        );
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+
+    output.push_str(&format!(
+        "overlay: {}/{} nodes, {} failed\n",
+        report.overlay.done, report.overlay.total, report.overlay.failed
+    ));
+
+    if let Some(cni_config) = &report.cni_config {
+        output.push_str(&format!(
+            "cni config: {}{}, drifted {} times\n",
+            cni_config.path,
+            if cni_config.drifted {
+                " (hand-edited)"
+            } else {
+                ""
+            },
+            cni_config.drift_count
+        ));
+    }
+
+    output
+}
+
+fn start_status_server(addr: &str, status: AgentStatus, shutdown: CancellationToken) {
+    let addr = addr.to_owned();
+
+    tokio::spawn(async move {
+        if let Err(e) = status_server::start(&addr, status, shutdown).await {
+            error!("status server failed: {}", e);
+        }
+    });
+}
+
+fn parse_sysctl(value: &str) -> Result<(String, String)> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected key=value, got {:?}", value))?;
+
+    Ok((key.trim().to_owned(), value.trim().to_owned()))
+}
+
 fn get_host_ip() -> Result<String> {
     env::var("HOST_IP").map_err(|_| anyhow::anyhow!("HOST_IP is not set"))
 }
 
+/// Names the Node the agent runs on: `NODE_NAME` if set, the hostname
+/// otherwise.
+fn get_node_name() -> Result<String> {
+    if let Ok(name) = env::var("NODE_NAME") {
+        return Ok(name);
+    }
+
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").map_err(|e| {
+        anyhow::anyhow!("NODE_NAME is not set and the hostname is unreadable: {}", e)
+    })?;
+    Ok(hostname.trim().to_owned())
+}
+
+/// Records the host's own route found by node name. Its address is replaced
+/// with `host_ip`, the one the agent actually uses (the Node may list a
+/// different, e.g. pre-NAT, address), so the rest of startup finds it.
+fn upsert_host_route(node_routes: &mut Vec<NodeRoute>, host_route: NodeRoute, host_ip: &str) {
+    let host_route = host_route.with_ip(host_ip);
+    node_routes.retain(|node_route| node_route.name != host_route.name);
+    node_routes.push(host_route);
+}
+
 fn find_host_route<'a>(node_routes: &'a [NodeRoute], host_ip: &str) -> Result<&'a NodeRoute> {
+    let host_ip = host_ip.parse::<IpAddr>()?;
+
     node_routes
         .iter()
-        .find(|node_route| node_route.ip == host_ip)
+        .find(|node_route| node_route.is_at(host_ip))
         .ok_or_else(|| anyhow::anyhow!("failed to find node route"))
 }
 
-fn setup_cni_config(cluster_cidr: &str, pod_cidr: &str) -> Result<()> {
-    Config::new(cluster_cidr, pod_cidr).write("/etc/cni/net.d/10-sinabro.conf")?;
-    Ok(())
+/// Writes sinabro's plugin config, as a conflist when plugins are chained
+/// after it, and returns the watcher that keeps it that way. The other file
+/// is removed: the runtime loads the first config in name order, and
+/// `.conf` sorts before `.conflist`.
+fn setup_cni_config(
+    cluster_cidr: &str,
+    pod_cidr: &str,
+    datapath: Datapath,
+    mtu: u32,
+    chained: &[serde_json::Value],
+    respect_manual: bool,
+    status: CniConfigStatus,
+) -> Result<CniConfigWatcher> {
+    let config = Config::new(cluster_cidr, pod_cidr)
+        .with_datapath(datapath)
+        .with_mtu(mtu);
+
+    let (path, contents, stale) = if chained.is_empty() {
+        (CNI_CONFIG_PATH, config.to_json()?, CNI_CONFLIST_PATH)
+    } else {
+        let conflist = ConfList::new(&config, chained.iter().cloned())?;
+        (CNI_CONFLIST_PATH, conflist.to_json()?, CNI_CONFIG_PATH)
+    };
+    let watcher = CniConfigWatcher::write(path, contents, respect_manual, status)?;
+
+    match std::fs::remove_file(stale) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(watcher),
+    }
 }
 
-fn setup_network(host_ip: &str, host_route: &NodeRoute, node_routes: &[NodeRoute]) -> Result<()> {
-    let pod_cidr = host_route.pod_cidr.parse::<IpNet>()?;
-    let mut netlink = Netlink::init(host_ip, &pod_cidr, node_routes);
-    let _ = netlink.setup_bridge()?;
-    let vxlan_index = netlink.setup_vxlan()?;
-    netlink.initialize_overlay(vxlan_index)?;
+async fn setup_network(
+    mut netlink: Netlink,
+    context: Arc<Context>,
+    probe_targets: ProbeTargets,
+    convergence: ConvergenceMetrics,
+    round: OverlayRound,
+) -> Result<NodeWatcher> {
+    // The bridge and the VXLAN device do not depend on each other, so both
+    // are tried before any failure is reported. Their netlink calls and the
+    // backoff between retries block, so they run off the async workers.
+    let report = round.report.clone();
+    let (mut netlink, vxlan_index) = tokio::task::spawn_blocking(move || {
+        report.run(SetupStep::Bridge, || netlink.setup_bridge());
+        let vxlan_index = report.run(SetupStep::Vxlan, || netlink.setup_vxlan());
+        (netlink, vxlan_index)
+    })
+    .await?;
+    round.report.check()?;
+    let vxlan_index = vxlan_index.ok_or_else(|| anyhow::anyhow!("vxlan is not set up"))?;
 
-    Ok(())
+    let started = Instant::now();
+    let task = netlink.initialize_overlay(vxlan_index, context.clone(), round)?;
+    let startup_convergence = convergence.clone();
+    tokio::spawn(async move {
+        startup_convergence
+            .track(Trigger::Startup, started, vec![task])
+            .await
+    });
+
+    NodeWatcher::new(context, netlink, vxlan_index, probe_targets, convergence)
+}
+
+/// Starts answering probes from other nodes and probing them in turn from
+/// the local VXLAN address.
+fn start_prober(
+    host_route: &NodeRoute,
+    probe_targets: ProbeTargets,
+    interval: Duration,
+    context: Arc<Context>,
+    token: CancellationToken,
+) -> Result<PeerTable> {
+    let vxlan_ip = host_route.pod_net()?.addr();
+    let any = match vxlan_ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let responder_token = token.clone();
+    tokio::spawn(async move {
+        let addr = SocketAddr::new(any, prober::PROBE_PORT);
+        if let Err(e) = prober::serve_probes(addr, responder_token).await {
+            error!("probe responder failed: {}", e);
+        }
+    });
+
+    let table = PeerTable::default();
+    let prober = Prober::new(
+        UdpTransport::new(vxlan_ip, prober::PROBE_PORT),
+        probe_targets,
+        table.clone(),
+        interval,
+    );
+    tokio::spawn(prober.run(context, host_route.name.clone(), token));
+
+    Ok(table)
+}
+
+fn teardown_network(
+    host_ip: &str,
+    host_route: &NodeRoute,
+    node_routes: &[NodeRoute],
+) -> Result<()> {
+    let pod_cidr = host_route.pod_net()?;
+    Netlink::init(host_ip, &pod_cidr, node_routes).teardown()
 }
 
 fn get_node_ips(node_routes: &[NodeRoute]) -> Vec<String> {
@@ -92,16 +970,306 @@ fn get_node_ips(node_routes: &[NodeRoute]) -> Vec<String> {
         .collect()
 }
 
-fn watch_service_resource(context: Context) {
+fn watch_service_resource(context: Arc<Context>) {
     tokio::spawn(async move { context.watch_service_resource().await });
 }
 
-async fn start_api_server(pod_cidr: &str, shutdown: CancellationToken) -> Result<()> {
-    let store_path = "/var/lib/sinabro/ip_store"; // TODO: make this configurable
+/// Removes host veths whose attachment is gone; failing to do so only
+/// costs bridge ports, so it never stops the agent.
+fn sweep_orphaned_veths(ipam: &Ipam) {
+    let lease_owners = ipam.lease_owners();
 
-    api_server::start(pod_cidr, store_path, shutdown)
-        .await
+    match orphans::cleanup_orphaned_veths(
+        &mut Netlink::new(),
+        netlink::BRIDGE_NAME,
+        Path::new(orphans::RESULTS_DIR),
+        Path::new(orphans::HOST_LOCAL_DIR),
+        lease_owners.iter().map(String::as_str),
+    ) {
+        Ok(0) => {}
+        Ok(removed) => info!("removed {} orphaned veths", removed),
+        Err(e) => warn!("skipping the orphaned veth sweep: {}", e),
+    }
+}
+
+async fn start_api_server(
+    ipam: Ipam,
+    dataplane: Option<(StatsReader, ConntrackReader, Option<BpfInfoReader>)>,
+    peers: Option<PeerTable>,
+    convergence: ConvergenceMetrics,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let interfaces = InterfaceReader::new(
+        Arc::new(NetlinkInterfaceSource),
+        &[
+            netlink::BRIDGE_NAME,
+            netlink::VXLAN_NAME,
+            netlink::UNDERLAY_NAME,
+        ],
+    );
+    let mut state = AppState::new(ipam)
+        .with_interfaces(interfaces)
+        .with_convergence(convergence);
+    if let Some((stats, conntrack, bpf_info)) = dataplane {
+        state = state.with_stats(stats).with_conntrack(conntrack);
+        if let Some(bpf_info) = bpf_info {
+            state = state.with_bpf_info(bpf_info);
+        }
+    }
+    if let Some(peers) = peers {
+        state = state.with_peers(peers);
+    }
+
+    api_server::start(state, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ifaces() {
+        let opt = Opt::try_parse_from(["agent"]).unwrap();
+        assert_eq!(opt.iface, vec!["eth0"]);
+
+        let opt = Opt::try_parse_from(["agent", "--iface", "eth0", "-i", "eth1,eth2"]).unwrap();
+        assert_eq!(opt.iface, vec!["eth0", "eth1", "eth2"]);
+
+        assert!(Opt::try_parse_from(["agent", "-i", "eth0,enp0s31f6-uplink0"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_ipam_store() {
+        let opt = Opt::try_parse_from(["agent"]).unwrap();
+        assert_eq!(opt.ipam_store, "/var/lib/sinabro/ip_store");
+
+        let opt = Opt::try_parse_from(["agent", "--ipam-store", "/tmp/sinabro/ip_store"]).unwrap();
+        assert_eq!(opt.ipam_store, "/tmp/sinabro/ip_store");
+    }
+
+    #[test]
+    fn test_parse_sysctls() {
+        let opt = Opt::try_parse_from([
+            "agent",
+            "--sysctl",
+            "net.ipv4.ip_forward=1",
+            "--sysctl",
+            "net.ipv4.conf.all.rp_filter = 2",
+        ])
         .unwrap();
+        assert_eq!(
+            opt.sysctls,
+            vec![
+                ("net.ipv4.ip_forward".to_string(), "1".to_string()),
+                ("net.ipv4.conf.all.rp_filter".to_string(), "2".to_string()),
+            ]
+        );
 
-    Ok(())
+        assert!(Opt::try_parse_from(["agent", "--sysctl", "net.ipv4.ip_forward"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_cni_chain() {
+        let opt = Opt::try_parse_from([
+            "agent",
+            "--cni-chain",
+            r#"{"type":"portmap","capabilities":{"portMappings":true}}"#,
+            "--cni-chain",
+            r#"{"type":"bandwidth"}"#,
+        ])
+        .unwrap();
+        assert_eq!(opt.cni_chain.len(), 2);
+        assert_eq!(opt.cni_chain[1]["type"], "bandwidth");
+
+        assert!(Opt::try_parse_from(["agent", "--cni-chain", "portmap"]).is_err());
+        assert!(Opt::try_parse_from(["agent", "--cni-chain", r#"{"name":"pm"}"#]).is_err());
+    }
+
+    #[test]
+    fn test_parse_status_command() {
+        let opt = Opt::try_parse_from(["agent", "status"]).unwrap();
+        assert!(
+            matches!(opt.command, Some(Command::Status { endpoint }) if endpoint == "http://127.0.0.1:3001")
+        );
+    }
+
+    #[test]
+    fn test_format_status() {
+        let mut report = AgentStatus::new().report();
+        report.phases[0].state = status::PhaseState::Failed;
+        report.phases[0].error = Some("HOST_IP is not set".to_string());
+
+        let output = format_status(&report);
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "ready: false");
+        assert_eq!(lines[1], "discovery    failed   HOST_IP is not set");
+        assert_eq!(lines[2], "cni-config   pending");
+        assert_eq!(lines[5], "overlay: 0/0 nodes, 0 failed");
+        assert_eq!(lines.len(), 6);
+
+        report.cni_config = Some(cni_config::CniConfigState {
+            path: CNI_CONFIG_PATH.to_string(),
+            sha256: String::new(),
+            written_at: 0,
+            drifted: true,
+            drift_count: 1,
+            last_checked_at: None,
+        });
+        assert_eq!(
+            format_status(&report).lines().last(),
+            Some("cni config: /etc/cni/net.d/10-sinabro.conf (hand-edited), drifted 1 times")
+        );
+    }
+
+    #[test]
+    fn test_parse_sock_ops_config() {
+        let opt = Opt::try_parse_from(["agent"]).unwrap();
+        assert_eq!(opt.sock_ops_config().flags, 0);
+
+        let opt = Opt::try_parse_from(["agent", "--sock-ops-debug", "--sock-ops-family", "ipv4"])
+            .unwrap();
+        assert_eq!(
+            opt.sock_ops_config().flags,
+            SOCK_OPS_DEBUG | SOCK_OPS_SKIP_IPV6
+        );
+
+        assert!(Opt::try_parse_from(["agent", "--sock-ops-family", "ipx"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_marks() {
+        let opt = Opt::try_parse_from(["agent"]).unwrap();
+        assert_eq!(opt.mark_config().masquerade, 0);
+        assert!(!opt.mark_config().excludes(u32::MAX));
+
+        let opt = Opt::try_parse_from([
+            "agent",
+            "--masquerade-mark",
+            "0x4000",
+            "--pod-mark",
+            "256",
+            "--nat-exclude-mark",
+            "0X8000",
+        ])
+        .unwrap();
+        let marks = opt.mark_config();
+        assert_eq!(marks.masquerade, 0x4000);
+        assert_eq!(marks.pod, 0x100);
+        assert!(marks.excludes(0x8000));
+
+        assert!(Opt::try_parse_from(["agent", "--pod-mark", "0xzz"]).is_err());
+    }
+
+    #[test]
+    fn test_route_mark_needs_bridge() {
+        let opt = Opt::try_parse_from(["agent", "--route-mark", "0x200"]).unwrap();
+        assert_eq!(opt.mark_config().route, 0x200);
+
+        let opt =
+            Opt::try_parse_from(["agent", "--route-mark", "0x200", "--datapath", "ptp"]).unwrap();
+        assert_eq!(opt.mark_config().route, 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_dataplane_leaves_host_alone() {
+        let opt = Opt::try_parse_from(["agent", "--no-dataplane"]).unwrap();
+        let mut startup =
+            AgentStartup::new(&opt, CancellationToken::new(), &AgentStatus::new(), None);
+        startup.host_ip = "172.18.0.3".to_string();
+        startup.cluster_cidr = "10.244.0.0/16".to_string();
+        startup.node_routes = vec![NodeRoute::new(
+            "control-plane",
+            "172.18.0.3",
+            "10.244.0.0/24",
+        )];
+
+        // Every one of these needs root unless the mode skips it.
+        startup.write_cni_config().await.unwrap();
+        startup.setup_network().await.unwrap();
+        startup.attach_dataplane().await.unwrap();
+
+        assert!(startup.node_watcher.is_none());
+        assert!(startup.bpf_loader.is_none());
+    }
+
+    #[test]
+    fn test_vtep_ip_follows_ip_family() {
+        let route = NodeRoute::new("control-plane", "172.18.0.3", "fd00:10:244::/64")
+            .with_addrs(["fc00::3"]);
+
+        for (family, vtep_ip) in [("v4", "172.18.0.3"), ("v6", "fc00::3")] {
+            let opt = Opt::try_parse_from(["agent", "--ip-family", family]).unwrap();
+            let mut startup =
+                AgentStartup::new(&opt, CancellationToken::new(), &AgentStatus::new(), None);
+            startup.host_ip = "172.18.0.3".to_string();
+            startup.node_routes = vec![route.clone()];
+
+            assert_eq!(startup.vtep_ip().unwrap(), vtep_ip);
+        }
+
+        // A node without an address of the family keeps HOST_IP.
+        let opt = Opt::try_parse_from(["agent", "--ip-family", "v6"]).unwrap();
+        let mut startup =
+            AgentStartup::new(&opt, CancellationToken::new(), &AgentStatus::new(), None);
+        startup.host_ip = "172.18.0.3".to_string();
+        startup.node_routes = vec![NodeRoute::new("control-plane", "172.18.0.3", "fd00::/64")];
+        assert_eq!(startup.vtep_ip().unwrap(), "172.18.0.3");
+    }
+
+    #[test]
+    fn test_ip_family_check() {
+        let opt = Opt::try_parse_from(["agent"]).unwrap();
+        assert_eq!(opt.ip_family, IpFamily::V4);
+
+        let v4 = "10.244.0.0/24".parse::<IpNet>().unwrap();
+        let v6 = "fd00:10:244::/64".parse::<IpNet>().unwrap();
+
+        assert!(IpFamily::V4.check(&v4, Datapath::Ptp).is_ok());
+        assert!(IpFamily::V4.check(&v6, Datapath::Bridge).is_err());
+        assert!(IpFamily::V6.check(&v6, Datapath::Bridge).is_ok());
+        assert!(IpFamily::V6.check(&v6, Datapath::Ptp).is_err());
+        assert!(IpFamily::Dual.check(&v4, Datapath::Bridge).is_err());
+
+        let opt = Opt::try_parse_from(["agent", "--ip-family", "v6"]).unwrap();
+        assert_eq!(opt.ip_family, IpFamily::V6);
+        assert!(Opt::try_parse_from(["agent", "--ip-family", "v5"]).is_err());
+    }
+
+    #[test]
+    fn test_upsert_host_route() {
+        let route = NodeRoute::new;
+        let mut node_routes = vec![
+            route("control-plane", "192.168.0.3", "10.244.0.0/24"),
+            route("worker", "172.18.0.2", "10.244.1.0/24"),
+        ];
+        assert!(find_host_route(&node_routes, "203.0.113.3").is_err());
+
+        upsert_host_route(
+            &mut node_routes,
+            route("control-plane", "192.168.0.3", "10.244.0.0/24"),
+            "203.0.113.3",
+        );
+
+        assert_eq!(node_routes.len(), 2);
+        let host_route = find_host_route(&node_routes, "203.0.113.3").unwrap();
+        assert_eq!(host_route.name, "control-plane");
+        assert_eq!(host_route.pod_cidr, "10.244.0.0/24");
+    }
+
+    #[test]
+    fn test_find_host_route_compares_addresses() {
+        let node_routes = [
+            NodeRoute::new("control-plane", "fd00:0:0::3", "fd00:10:244::/64"),
+            NodeRoute::new("worker", "fd00::2", "fd00:10:244:1::/64"),
+        ];
+
+        let host_route = find_host_route(&node_routes, "fd00::3").unwrap();
+        assert_eq!(host_route.name, "control-plane");
+        // Kept as listed, for display.
+        assert_eq!(host_route.ip, "fd00:0:0::3");
+
+        assert!(find_host_route(&node_routes, "fd00::4").is_err());
+        assert!(find_host_route(&node_routes, "not-an-ip").is_err());
+    }
 }
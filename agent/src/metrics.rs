@@ -0,0 +1,136 @@
+use std::fmt::Write;
+
+/// Renders a label set such as `{peer="worker"}`, escaping values as the
+/// Prometheus text format requires.
+pub fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', "\\\"")
+                .replace('\n', r"\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Appends the `# HELP` and `# TYPE` lines that open a metric family.
+pub fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Appends one gauge family in the Prometheus text format. Each sample is a
+/// rendered label set and its value.
+pub fn write_gauge(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    write_header(out, name, help, "gauge");
+
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// A histogram with fixed bucket bounds; counts are kept cumulative, the
+/// way the text format reports them.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Appends the bucket, sum and count samples; the family header is the
+    /// caller's, so several label sets can share it.
+    pub fn write(&self, out: &mut String, name: &str, pairs: &[(&str, &str)]) {
+        let buckets = self
+            .bounds
+            .iter()
+            .map(|bound| bound.to_string())
+            .zip(self.counts.iter().copied())
+            .chain([("+Inf".to_string(), self.count)]);
+
+        for (le, count) in buckets {
+            let mut bucket_pairs = pairs.to_vec();
+            bucket_pairs.push(("le", &le));
+            let _ = writeln!(out, "{}_bucket{} {}", name, labels(&bucket_pairs), count);
+        }
+
+        let _ = writeln!(out, "{}_sum{} {}", name, labels(pairs), self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels(pairs), self.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_gauge() {
+        let mut out = String::new();
+        write_gauge(
+            &mut out,
+            "sinabro_peer_loss_ratio",
+            "Lost probes.",
+            [(labels(&[("peer", "work\"er")]), 0.5)],
+        );
+
+        assert_eq!(
+            out,
+            "# HELP sinabro_peer_loss_ratio Lost probes.\n\
+             # TYPE sinabro_peer_loss_ratio gauge\n\
+             sinabro_peer_loss_ratio{peer=\"work\\\"er\"} 0.5\n"
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[0.5, 1.0]);
+        histogram.observe(0.25);
+        histogram.observe(0.75);
+        histogram.observe(3.0);
+
+        let mut out = String::new();
+        histogram.write(&mut out, "sinabro_test_seconds", &[("trigger", "startup")]);
+
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "sinabro_test_seconds_bucket{trigger=\"startup\",le=\"0.5\"} 1",
+                "sinabro_test_seconds_bucket{trigger=\"startup\",le=\"1\"} 2",
+                "sinabro_test_seconds_bucket{trigger=\"startup\",le=\"+Inf\"} 3",
+                "sinabro_test_seconds_sum{trigger=\"startup\"} 4",
+                "sinabro_test_seconds_count{trigger=\"startup\"} 3",
+            ]
+        );
+    }
+}
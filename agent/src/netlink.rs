@@ -1,34 +1,128 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    collections::HashMap,
+    fs,
+    net::IpAddr,
     ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use futures::{stream, Future, StreamExt};
 use ipnet::IpNet;
 use rsln::types::{
-    addr::AddressBuilder,
+    addr::{Address, AddressBuilder},
     link::{Kind, Link, LinkAttrs, VxlanAttrs},
-    neigh::NeighborBuilder,
-    routing::{RoutingBuilder, Via},
+    neigh::{Neighbor, NeighborBuilder},
+    routing::{Routing, RoutingBuilder, Via},
 };
-use sinabro_config::generate_mac;
-use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use sinabro_config::{
+    derive_mac, gateway_for,
+    link_index::LinkIndexCache,
+    retry::{retry_netlink, retry_netlink_async, NetlinkOp, RetryPolicy},
+};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
 
-use crate::{kube::Context, node_route::NodeRoute};
+use crate::{
+    kube::Context,
+    node_route::NodeRoute,
+    plan::{Plan, Recorder},
+    setup_report::{SetupReport, SetupStep},
+    status::{OverlayCounts, OverlayProgress},
+};
 
 const RTNH_F_ONLINK: u32 = 0x4;
-const BRIDGE_NAME: &str = "cni0";
+pub const BRIDGE_NAME: &str = "cni0";
+pub const VXLAN_NAME: &str = "sinabro_vxlan";
+pub const UNDERLAY_NAME: &str = "eth0";
+/// How often a running overlay round logs its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Encapsulation used between nodes; decides how much of the underlay MTU
+/// the tunnel headers take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlay {
+    Vxlan,
+    #[allow(dead_code)]
+    Wireguard,
+}
+
+impl Overlay {
+    fn overhead(&self, ipv6_underlay: bool) -> u32 {
+        match (self, ipv6_underlay) {
+            (Overlay::Vxlan, false) => 50,
+            (Overlay::Vxlan, true) => 70,
+            (Overlay::Wireguard, false) => 60,
+            (Overlay::Wireguard, true) => 80,
+        }
+    }
+}
+
+/// Derives the MTU of the overlay device from the MTU of the underlay
+/// interface it encapsulates into.
+pub fn overlay_mtu(underlay_mtu: u32, overlay: Overlay, ipv6_underlay: bool) -> u32 {
+    underlay_mtu.saturating_sub(overlay.overhead(ipv6_underlay))
+}
+
+/// The kernel-facing calls the network setup makes. Setup goes through
+/// this rather than rsln directly, so `--dry-run` can substitute a
+/// `Recorder` and tests can run it without root.
+pub trait NetlinkOps: Send {
+    fn link_get(&mut self, attrs: &LinkAttrs) -> Result<Box<dyn Link>>;
+    fn ensure_link(&mut self, kind: &Kind) -> Result<Box<dyn Link>>;
+    fn link_up(&mut self, link: &dyn Link) -> Result<()>;
+    fn addr_add(&mut self, link: &dyn Link, address: &Address) -> Result<()>;
+    fn route_add(&mut self, route: &Routing) -> Result<()>;
+    fn neigh_set(&mut self, neigh: &Neighbor) -> Result<()>;
+}
+
+impl NetlinkOps for rsln::netlink::Netlink {
+    fn link_get(&mut self, attrs: &LinkAttrs) -> Result<Box<dyn Link>> {
+        rsln::netlink::Netlink::link_get(self, attrs)
+    }
+
+    fn ensure_link(&mut self, kind: &Kind) -> Result<Box<dyn Link>> {
+        rsln::netlink::Netlink::ensure_link(self, kind)
+    }
+
+    fn link_up(&mut self, link: &dyn Link) -> Result<()> {
+        rsln::netlink::Netlink::link_up(self, link)
+    }
+
+    fn addr_add(&mut self, link: &dyn Link, address: &Address) -> Result<()> {
+        rsln::netlink::Netlink::addr_add(self, link, address)
+    }
+
+    fn route_add(&mut self, route: &Routing) -> Result<()> {
+        rsln::netlink::Netlink::route_add(self, route)
+    }
+
+    fn neigh_set(&mut self, neigh: &Neighbor) -> Result<()> {
+        rsln::netlink::Netlink::neigh_set(self, neigh)
+    }
+}
+
+/// VXLAN MAC of each remote node's VTEP, keyed by node IP. Removing a node's
+/// FDB entry needs its MAC, which can no longer be looked up once the node is
+/// gone.
+pub type VtepMacs = Arc<Mutex<HashMap<String, Vec<u8>>>>;
 
 #[derive(Default)]
-pub struct Netlink<'a> {
+pub struct Netlink {
     pub netlink: rsln::netlink::Netlink,
-    pub host_ip: Option<&'a str>,
-    pub pod_cidr: Option<&'a IpNet>,
-    pub node_routes: Option<&'a [NodeRoute]>,
+    pub host_ip: Option<String>,
+    pub pod_cidr: Option<IpNet>,
+    pub node_routes: Vec<NodeRoute>,
+    pub vtep_macs: VtepMacs,
+    index_cache: LinkIndexCache,
+    recorder: Option<Recorder>,
+    /// Overlay MTU applied to the bridge and the VXLAN device; derived from
+    /// the underlay when unset.
+    mtu: Option<u32>,
 }
 
-impl<'a> Deref for Netlink<'a> {
+impl Deref for Netlink {
     type Target = rsln::netlink::Netlink;
 
     fn deref(&self) -> &Self::Target {
@@ -36,35 +130,140 @@ impl<'a> Deref for Netlink<'a> {
     }
 }
 
-impl<'a> DerefMut for Netlink<'a> {
+impl DerefMut for Netlink {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.netlink
     }
 }
 
-impl<'a> Netlink<'a> {
+impl Netlink {
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn init(host_ip: &'a str, pod_cidr: &'a IpNet, node_routes: &'a [NodeRoute]) -> Self {
+    pub fn init(host_ip: &str, pod_cidr: &IpNet, node_routes: &[NodeRoute]) -> Self {
         Self {
             netlink: rsln::netlink::Netlink::new(),
-            host_ip: Some(host_ip),
-            pod_cidr: Some(pod_cidr),
-            node_routes: Some(node_routes),
+            host_ip: Some(host_ip.to_owned()),
+            pod_cidr: Some(*pod_cidr),
+            node_routes: node_routes.to_vec(),
+            vtep_macs: VtepMacs::default(),
+            index_cache: LinkIndexCache::new(),
+            recorder: None,
+            mtu: None,
+        }
+    }
+
+    pub fn with_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// The MAC `device` gets when it is created, derived from the host IP so
+    /// it survives agent restarts. A device that already exists keeps its
+    /// MAC; peers pick up a changed one through the node watcher.
+    fn device_mac(&self, device: &str) -> Result<Vec<u8>> {
+        let host_ip = self
+            .host_ip
+            .as_deref()
+            .ok_or(anyhow!("host_ip is not set"))?;
+        Ok(derive_mac(host_ip.parse()?, device))
+    }
+
+    /// Whether the VXLAN underlay runs over IPv6, i.e. the local VTEP
+    /// address is an IPv6 one.
+    fn underlay_v6(&self) -> bool {
+        self.host_ip
+            .as_deref()
+            .and_then(|host_ip| host_ip.parse::<IpAddr>().ok())
+            .is_some_and(|host_ip| host_ip.is_ipv6())
+    }
+
+    /// The MTU the overlay can run at over the underlay interface. A
+    /// `requested` MTU is used if it fits and clamped with a warning if not,
+    /// since a larger one would fragment or drop encapsulated packets.
+    pub fn overlay_mtu_for(&mut self, requested: Option<u32>) -> Result<u32> {
+        let host_ip = self.host_ip.clone().ok_or(anyhow!("host_ip is not set"))?;
+        let underlay = self.ops().link_get(&LinkAttrs::new(UNDERLAY_NAME))?;
+        let max = overlay_mtu(
+            underlay.attrs().mtu,
+            Overlay::Vxlan,
+            host_ip.parse::<IpAddr>()?.is_ipv6(),
+        );
+
+        Ok(match requested {
+            Some(requested) if requested > max => {
+                warn!(
+                    "overlay MTU {} does not fit the {} MTU of {}, using {}",
+                    requested,
+                    underlay.attrs().mtu,
+                    UNDERLAY_NAME,
+                    max
+                );
+                max
+            }
+            Some(requested) => requested,
+            None => max,
+        })
+    }
+
+    /// A handle that records the setup's writes in `plan` instead of making
+    /// them; reads still go to the kernel.
+    pub fn recording(plan: Plan) -> Self {
+        Self::new().with_plan(plan)
+    }
+
+    pub fn with_plan(mut self, plan: Plan) -> Self {
+        let reader = Box::new(rsln::netlink::Netlink::new());
+        self.recorder = Some(Recorder::new(reader, plan));
+        self
+    }
+
+    fn plan(&self) -> Option<Plan> {
+        self.recorder
+            .as_ref()
+            .map(|recorder| recorder.plan().clone())
+    }
+
+    /// Where setup sends its netlink calls: the recorder on a dry run, the
+    /// kernel otherwise.
+    fn ops(&mut self) -> &mut dyn NetlinkOps {
+        match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => &mut self.netlink,
         }
     }
 
+    /// Resolves an interface index by name, caching the result so repeated
+    /// lookups do not cost a netlink round-trip each. Link events drop
+    /// entries for links deleted or renamed behind the agent's back.
+    pub fn index_of(&mut self, name: &str) -> Result<i32> {
+        self.index_cache.index_of(name, |name| {
+            Ok(self.netlink.link_get(&LinkAttrs::new(name))?.attrs().index)
+        })
+    }
+
+    /// Drops a cached index right away, for links the agent itself deletes.
+    pub fn forget_index(&mut self, name: &str) {
+        self.index_cache.remove(name);
+    }
+
     pub fn setup_bridge(&mut self) -> Result<i32> {
         let pod_cidr = self.pod_cidr.ok_or(anyhow!("pod_cidr is not set"))?;
-        let ip_addr = Self::get_ip_addr(pod_cidr);
-        let bridge = self.ensure_link(&Kind::new_bridge(BRIDGE_NAME))?;
-        let address = AddressBuilder::default()
-            .ip(IpNet::new(ip_addr, pod_cidr.prefix_len())?)
-            .build()?;
+        // ensure_link reuses an existing link and EEXIST counts as done below.
+        let policy = RetryPolicy::default().with_non_idempotent();
+        let mut bridge = Kind::new_bridge(BRIDGE_NAME);
+        if let Kind::Bridge { attrs, .. } = &mut bridge {
+            attrs.hw_addr = self.device_mac(BRIDGE_NAME)?;
+        }
+        let bridge = retry_netlink(NetlinkOp::LinkAdd, &policy, || {
+            self.ops().ensure_link(&bridge)
+        })?;
+        let address = Self::bridge_address(&pod_cidr)?;
 
-        if let Err(e) = self.addr_add(&bridge, &address) {
+        if let Err(e) = retry_netlink(NetlinkOp::AddrAdd, &policy, || {
+            self.ops().addr_add(bridge.as_ref(), &address)
+        }) {
             if e.to_string().contains("File exists") {
                 info!("cni0 interface already has an ip address");
             } else {
@@ -72,45 +271,92 @@ impl<'a> Netlink<'a> {
             }
         }
 
+        // The bridge is the pods' router; it must not configure itself from
+        // router advertisements it happens to see.
+        if pod_cidr.addr().is_ipv6() {
+            match self.plan() {
+                Some(plan) => plan.record("sysctl", BRIDGE_NAME, "accept_ra=0"),
+                None => disable_accept_ra(BRIDGE_NAME)?,
+            }
+        }
+
+        // Pods reach remote nodes through the VXLAN device, so the bridge
+        // must not take frames the overlay cannot carry.
+        if let Some(mtu) = self.mtu {
+            match self.plan() {
+                Some(plan) => plan.record("set_mtu", BRIDGE_NAME, mtu.to_string()),
+                None => set_mtu(BRIDGE_NAME, mtu)?,
+            }
+        }
+
+        self.index_cache.insert(BRIDGE_NAME, bridge.attrs().index);
+
         Ok(bridge.attrs().index)
     }
 
     pub fn setup_vxlan(&mut self) -> Result<i32> {
-        let host_ip = self.host_ip.ok_or(anyhow!("host_ip is not set"))?;
+        let host_ip = self.host_ip.clone().ok_or(anyhow!("host_ip is not set"))?;
         let pod_cidr = self.pod_cidr.ok_or(anyhow!("pod_cidr is not set"))?;
 
-        let eth0_attrs = LinkAttrs::new("eth0");
-        let eth0 = self.link_get(&eth0_attrs)?;
+        let eth0_attrs = LinkAttrs::new(UNDERLAY_NAME);
+        let eth0 = self.ops().link_get(&eth0_attrs)?;
         let vtep_index = eth0.attrs().index as u32;
-        self.link_up(&eth0)?;
+        self.index_cache
+            .insert(&eth0_attrs.name, eth0.attrs().index);
+        self.ops().link_up(eth0.as_ref())?;
 
-        let vxlan_mac = generate_mac()?;
-        let host_ip_bytes = match host_ip.parse::<IpAddr>()? {
+        let vxlan_mac = self.device_mac(VXLAN_NAME)?;
+        let host_ip = host_ip.parse::<IpAddr>()?;
+        let host_ip_bytes = match host_ip {
             IpAddr::V4(ip) => ip.octets().to_vec(),
             IpAddr::V6(ip) => ip.octets().to_vec(),
         };
+        let mtu = self
+            .mtu
+            .unwrap_or_else(|| overlay_mtu(eth0.attrs().mtu, Overlay::Vxlan, host_ip.is_ipv6()));
 
         let vxlan = Kind::Vxlan {
             attrs: LinkAttrs {
-                name: "sinabro_vxlan".into(),
-                mtu: 1450,
+                name: VXLAN_NAME.into(),
+                mtu,
                 hw_addr: vxlan_mac,
                 ..Default::default()
             },
             vxlan_attrs: VxlanAttrs {
                 id: 1,
                 vtep_index: Some(vtep_index),
+                // Sent as IFLA_VXLAN_LOCAL6 when it is 16 bytes long.
                 src_addr: Some(host_ip_bytes),
                 port: Some(8472),
+                // Checksums stay on for what we send, but peers whose NIC or
+                // kernel leaves the UDP checksum of IPv6 packets at zero are
+                // still accepted.
+                udp6_zero_csum_tx: host_ip.is_ipv6().then_some(false),
+                udp6_zero_csum_rx: host_ip.is_ipv6().then_some(true),
                 ..Default::default()
             },
         };
 
-        let vxlan = self.ensure_link(&vxlan)?;
-        let vxlan_addr = IpNet::new(pod_cidr.addr(), 32)?;
-        let vxlan_addr = AddressBuilder::default().ip(vxlan_addr).build()?;
+        let policy = RetryPolicy::default().with_non_idempotent();
+        let vxlan = retry_netlink(NetlinkOp::LinkAdd, &policy, || {
+            self.ops().ensure_link(&vxlan)
+        })?;
+        // A device left by an earlier run keeps its MTU through ensure_link.
+        if vxlan.attrs().mtu != mtu {
+            match self.plan() {
+                Some(plan) => plan.record("set_mtu", VXLAN_NAME, mtu.to_string()),
+                None => set_mtu(VXLAN_NAME, mtu)?,
+            }
+        }
+        let vxlan_addr = IpNet::new(pod_cidr.addr(), pod_cidr.max_prefix_len())?;
+        let vxlan_addr = AddressBuilder::default()
+            .ip(vxlan_addr)
+            .scope(libc::RT_SCOPE_LINK)
+            .build()?;
 
-        if let Err(e) = self.addr_add(&vxlan, &vxlan_addr) {
+        if let Err(e) = retry_netlink(NetlinkOp::AddrAdd, &policy, || {
+            self.ops().addr_add(vxlan.as_ref(), &vxlan_addr)
+        }) {
             if e.to_string().contains("File exists") {
                 info!("vxlan interface already has an ip address");
             } else {
@@ -118,52 +364,275 @@ impl<'a> Netlink<'a> {
             }
         }
 
+        self.index_cache.insert(VXLAN_NAME, vxlan.attrs().index);
+
         Ok(vxlan.attrs().index)
     }
 
-    pub fn initialize_overlay(&mut self, vxlan_index: i32) -> Result<()> {
-        let host_ip = self.host_ip.ok_or(anyhow!("host_ip is not set"))?;
+    /// Programs the overlay towards every remote node in one background
+    /// round, with at most `round.concurrency` nodes in flight. The nodes
+    /// share the caller's kube client and each gets its own netlink socket;
+    /// the returned task fails if any node did.
+    pub fn initialize_overlay(
+        &mut self,
+        vxlan_index: i32,
+        context: Arc<Context>,
+        round: OverlayRound,
+    ) -> Result<JoinHandle<Result<()>>> {
+        let host_ip = self
+            .host_ip
+            .as_deref()
+            .ok_or(anyhow!("host_ip is not set"))?
+            .parse::<IpAddr>()?;
+
+        let peers = self
+            .node_routes
+            .iter()
+            .filter(|node_route| !node_route.is_at(host_ip))
+            .map(|node_route| self.overlay_peer(vxlan_index, context.clone(), node_route.clone()))
+            .collect::<Vec<_>>();
+        round.progress.start(peers.len());
 
-        if let Some(node_routes) = self.node_routes {
-            node_routes
-                .iter()
-                .filter(|node_route| node_route.ip != host_ip)
-                .for_each(|node_route| {
-                    let node_route_pod_cidr = node_route.pod_cidr.clone();
-                    let node_route_ip = node_route.ip.clone();
-
-                    tokio::spawn(async move {
-                        Self::setup_route_and_neighbors(
-                            &node_route_ip,
-                            &node_route_pod_cidr,
-                            vxlan_index,
-                        )
-                        .await
-                    });
-                });
+        let setups = peers.into_iter().map(|peer| {
+            let report = round.report.clone();
+            async move {
+                let step = SetupStep::OverlayNode(peer.node_route.name.clone());
+                let started = Instant::now();
+                let result = match tokio::spawn(peer.setup()).await {
+                    Ok(result) => result,
+                    Err(e) => Err(e.into()),
+                };
+                report.record(&step, started.elapsed(), result.as_ref().err());
+                result
+            }
+        });
+
+        Ok(tokio::spawn(setup_bounded(
+            setups,
+            round.concurrency,
+            round.progress,
+        )))
+    }
+
+    pub fn spawn_overlay_peer(
+        &self,
+        vxlan_index: i32,
+        context: Arc<Context>,
+        node_route: NodeRoute,
+    ) -> JoinHandle<Result<()>> {
+        tokio::spawn(self.overlay_peer(vxlan_index, context, node_route).setup())
+    }
+
+    fn overlay_peer(
+        &self,
+        vxlan_index: i32,
+        context: Arc<Context>,
+        node_route: NodeRoute,
+    ) -> OverlayPeer {
+        OverlayPeer {
+            context,
+            vxlan_index,
+            underlay_v6: self.underlay_v6(),
+            node_route,
+            vtep_macs: self.vtep_macs.clone(),
+            plan: self.plan(),
+        }
+    }
+
+    /// Removes the overlay route, neighbor and FDB entry towards one remote
+    /// node. Entries that are already gone are not an error, and the host's
+    /// own entry is never touched.
+    pub fn teardown_node_route(&mut self, node_route: &NodeRoute) -> Result<()> {
+        let host_ip = self.host_ip.clone().ok_or(anyhow!("host_ip is not set"))?;
+        if node_route.is_at(host_ip.parse()?) {
+            return Ok(());
+        }
+
+        let vxlan_index = self.index_of(VXLAN_NAME)?;
+        let pod_cidr = node_route.pod_net()?;
+
+        let route = Self::overlay_route(vxlan_index, &pod_cidr)?;
+        ignore_missing(self.route_del(&route))?;
+
+        let neigh = NeighborBuilder::default()
+            .link_index(vxlan_index as u32)
+            .ip_addr(Some(pod_cidr.network()))
+            .build()?;
+        ignore_missing(self.neigh_del(&neigh))?;
+
+        let vtep_mac = self.vtep_macs.lock().unwrap().remove(&node_route.ip);
+        if let Some(vtep_mac) = vtep_mac {
+            let fdb = Self::fdb_entry(
+                vxlan_index,
+                node_route.underlay_addr(self.underlay_v6())?,
+                vtep_mac,
+            )?;
+            ignore_missing(self.neigh_del(&fdb))?;
         }
 
+        info!("removed the overlay towards {}", node_route.ip);
         Ok(())
     }
 
-    async fn setup_route_and_neighbors(
-        node_ip: &str,
-        pod_cidr: &str,
-        vxlan_index: i32,
-    ) -> Result<()> {
-        let mut netlink = Netlink::new();
-        let token = CancellationToken::new();
-        let context = Context::new(token).await?;
-        let pod_cidr_ip_net = pod_cidr.parse::<IpNet>()?;
+    /// Removes the overlay routes and neighbors of every remote node, the
+    /// VXLAN device and, if no ports are left on it, the bridge.
+    pub fn teardown(&mut self) -> Result<()> {
+        let host_ip = self
+            .host_ip
+            .as_deref()
+            .ok_or(anyhow!("host_ip is not set"))?
+            .parse::<IpAddr>()?;
+        let node_routes = self.node_routes.clone();
+
+        if self.index_of(VXLAN_NAME).is_ok() {
+            for node_route in node_routes
+                .iter()
+                .filter(|node_route| !node_route.is_at(host_ip))
+            {
+                self.teardown_node_route(node_route)?;
+            }
+
+            let vxlan = self.link_get(&LinkAttrs::new(VXLAN_NAME))?;
+            self.link_del(&vxlan)?;
+            self.forget_index(VXLAN_NAME);
+            info!("deleted {}", VXLAN_NAME);
+        }
 
-        let route = RoutingBuilder::default()
+        if bridge_has_ports(BRIDGE_NAME) {
+            info!(
+                "{} still has ports attached, leaving it in place",
+                BRIDGE_NAME
+            );
+        } else if let Ok(bridge) = self.link_get(&LinkAttrs::new(BRIDGE_NAME)) {
+            self.link_del(&bridge)?;
+            self.forget_index(BRIDGE_NAME);
+            info!("deleted {}", BRIDGE_NAME);
+        }
+
+        Ok(())
+    }
+
+    fn overlay_route(vxlan_index: i32, pod_cidr: &IpNet) -> Result<Routing> {
+        Ok(RoutingBuilder::default()
             .oif_index(vxlan_index)
-            .dst(Some(pod_cidr_ip_net))
-            .via(Some(Via::new(&pod_cidr_ip_net.addr().to_string())?))
+            .dst(Some(*pod_cidr))
+            .via(Some(Via::new(&pod_cidr.addr().to_string())?))
             .flags(RTNH_F_ONLINK)
-            .build()?;
+            .build()?)
+    }
 
-        if let Err(e) = netlink.route_add(&route) {
+    fn fdb_entry(vxlan_index: i32, node_ip: IpAddr, vtep_mac: Vec<u8>) -> Result<Neighbor> {
+        Ok(NeighborBuilder::default()
+            .link_index(vxlan_index as u32)
+            .state(libc::NUD_PERMANENT)
+            .family(Some(libc::AF_BRIDGE as u8))
+            .flags(libc::NTF_SELF)
+            .ip_addr(Some(node_ip))
+            .mac_addr(Some(vtep_mac))
+            .build()?)
+    }
+
+    /// Builds the gateway address assigned to the bridge. IPv6 addresses skip
+    /// duplicate address detection so pods can use the gateway right away.
+    fn bridge_address(pod_cidr: &IpNet) -> Result<Address> {
+        let ip_addr = gateway_for(pod_cidr);
+        let mut builder = AddressBuilder::default();
+        builder.ip(IpNet::new(ip_addr, pod_cidr.prefix_len())?);
+
+        if let IpNet::V6(_) = pod_cidr {
+            builder.flags(libc::IFA_F_NODAD);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// How the startup overlay round runs: how many remote nodes are set up at
+/// once, and where its progress and per-node outcomes are reported.
+#[derive(Clone)]
+pub struct OverlayRound {
+    pub concurrency: usize,
+    pub progress: OverlayProgress,
+    pub report: SetupReport,
+}
+
+/// Runs the per-node `setups` with at most `concurrency` of them in flight,
+/// logging progress every `PROGRESS_INTERVAL`. Fails once all are done if
+/// any of them failed.
+async fn setup_bounded<F>(
+    setups: impl IntoIterator<Item = F>,
+    concurrency: usize,
+    progress: OverlayProgress,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let mut results = stream::iter(setups).buffer_unordered(concurrency.max(1));
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            result = results.next() => match result {
+                Some(Ok(())) => progress.succeeded(),
+                Some(Err(e)) => {
+                    warn!("overlay setup failed: {}", e);
+                    progress.failed();
+                }
+                None => break,
+            },
+            _ = ticker.tick() => log_progress(&progress.counts()),
+        }
+    }
+
+    let counts = progress.counts();
+    log_progress(&counts);
+    if counts.failed > 0 {
+        bail!("{} of {} nodes failed", counts.failed, counts.total);
+    }
+
+    Ok(())
+}
+
+fn log_progress(counts: &OverlayCounts) {
+    info!(
+        "overlay setup: {}/{} nodes done, {} failed",
+        counts.done, counts.total, counts.failed
+    );
+}
+
+/// The overlay state towards one remote node: the route to its pod CIDR,
+/// the neighbor entry for its VTEP and the matching FDB entry.
+struct OverlayPeer {
+    context: Arc<Context>,
+    vxlan_index: i32,
+    /// Picks which of the node's addresses its FDB entry points at.
+    underlay_v6: bool,
+    node_route: NodeRoute,
+    vtep_macs: VtepMacs,
+    plan: Option<Plan>,
+}
+
+impl OverlayPeer {
+    /// Opens its own netlink socket only once it runs, so a round waiting
+    /// on the concurrency limit does not hold one per queued node.
+    async fn setup(self) -> Result<()> {
+        let mut netlink = match self.plan.clone() {
+            Some(plan) => Netlink::recording(plan),
+            None => Netlink::new(),
+        };
+        let node_ip = self.node_route.underlay_addr(self.underlay_v6)?;
+        let pod_cidr_ip_net = self.node_route.pod_net()?;
+
+        let route = Netlink::overlay_route(self.vxlan_index, &pod_cidr_ip_net)?;
+        // A route or neighbor that already exists counts as done below.
+        let policy = RetryPolicy::default().with_non_idempotent();
+
+        if let Err(e) = retry_netlink_async(NetlinkOp::RouteAdd, &policy, || {
+            netlink.ops().route_add(&route)
+        })
+        .await
+        {
             if e.to_string().contains("File exists") {
                 info!("route already exists");
             } else {
@@ -171,17 +640,21 @@ impl<'a> Netlink<'a> {
             }
         }
 
-        let vxlan_mac = context.get_vxlan_mac_address(node_ip).await?;
+        let vxlan_mac = self.context.vxlan_mac_of(&self.node_route).await?;
 
         let neigh = NeighborBuilder::default()
-            .link_index(vxlan_index as u32)
+            .link_index(self.vxlan_index as u32)
             .state(libc::NUD_PERMANENT)
             .neigh_type(libc::RTN_UNICAST)
             .ip_addr(Some(pod_cidr_ip_net.network()))
             .mac_addr(Some(vxlan_mac.clone()))
             .build()?;
 
-        if let Err(e) = netlink.neigh_set(&neigh) {
+        if let Err(e) = retry_netlink_async(NetlinkOp::NeighSet, &policy, || {
+            netlink.ops().neigh_set(&neigh)
+        })
+        .await
+        {
             if e.to_string().contains("File exists") {
                 info!("neighbor already exists");
             } else {
@@ -190,16 +663,13 @@ impl<'a> Netlink<'a> {
             }
         }
 
-        let fdb = NeighborBuilder::default()
-            .link_index(vxlan_index as u32)
-            .state(libc::NUD_PERMANENT)
-            .family(Some(libc::AF_BRIDGE as u8))
-            .flags(libc::NTF_SELF)
-            .ip_addr(Some(node_ip.parse::<IpAddr>()?))
-            .mac_addr(Some(vxlan_mac))
-            .build()?;
+        let fdb = Netlink::fdb_entry(self.vxlan_index, node_ip, vxlan_mac.clone())?;
 
-        if let Err(e) = netlink.neigh_set(&fdb) {
+        if let Err(e) = retry_netlink_async(NetlinkOp::NeighSet, &policy, || {
+            netlink.ops().neigh_set(&fdb)
+        })
+        .await
+        {
             if e.to_string().contains("File exists") {
                 info!("fdb already exists");
             } else {
@@ -208,20 +678,399 @@ impl<'a> Netlink<'a> {
             }
         }
 
+        self.vtep_macs
+            .lock()
+            .unwrap()
+            .insert(self.node_route.ip.clone(), vxlan_mac);
+
         info!("completed setting up routes and neighbors for {}", node_ip);
         Ok(())
     }
+}
+
+fn set_mtu(name: &str, mtu: u32) -> Result<()> {
+    fs::write(format!("/sys/class/net/{}/mtu", name), mtu.to_string())?;
+    Ok(())
+}
+
+fn disable_accept_ra(name: &str) -> Result<()> {
+    let path = format!("/proc/sys/net/ipv6/conf/{}/accept_ra", name);
+    fs::write(&path, "0").map_err(|e| anyhow!("failed to write {}: {}", path, e))
+}
+
+fn bridge_has_ports(name: &str) -> bool {
+    fs::read_dir(format!("/sys/class/net/{}/brif", name))
+        .map(|mut ports| ports.next().is_some())
+        .unwrap_or(false)
+}
+
+fn ignore_missing(result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.to_string().contains("No such") => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rsln::types::addr::AddrFamily;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_setup_bounded_respects_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let progress = OverlayProgress::default();
+        progress.start(20);
 
-    fn get_ip_addr(ip_net: &IpNet) -> IpAddr {
-        match ip_net {
-            IpNet::V4(v4) => {
-                let net = u32::from(v4.network()) + 1;
-                IpAddr::V4(Ipv4Addr::from(net))
+        let setups = (0..20).map(|node| {
+            let in_flight = in_flight.clone();
+            let peak = peak.clone();
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                match node {
+                    7 => Err(anyhow!("no agent pod on node {}", node)),
+                    _ => Ok(()),
+                }
             }
-            IpNet::V6(v6) => {
-                let net = u128::from(v6.network()) + 1;
-                IpAddr::V6(Ipv6Addr::from(net))
+        });
+
+        let result = setup_bounded(setups, 4, progress.clone()).await;
+
+        assert_eq!(result.unwrap_err().to_string(), "1 of 20 nodes failed");
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            progress.counts(),
+            OverlayCounts {
+                total: 20,
+                done: 19,
+                failed: 1
             }
+        );
+    }
+
+    /// Answers reads for a node whose only link is the underlay.
+    struct Underlay {
+        mtu: u32,
+    }
+
+    impl NetlinkOps for Underlay {
+        fn link_get(&mut self, attrs: &LinkAttrs) -> Result<Box<dyn Link>> {
+            if attrs.name != UNDERLAY_NAME {
+                bail!("Link not found");
+            }
+
+            let mut attrs = LinkAttrs::new(UNDERLAY_NAME);
+            attrs.index = 2;
+            attrs.mtu = self.mtu;
+            Ok(Box::new(Kind::Veth {
+                attrs,
+                peer_name: String::new(),
+                peer_hw_addr: None,
+                peer_ns: None,
+            }))
+        }
+
+        fn ensure_link(&mut self, _: &Kind) -> Result<Box<dyn Link>> {
+            bail!("read only")
+        }
+
+        fn link_up(&mut self, _: &dyn Link) -> Result<()> {
+            bail!("read only")
         }
+
+        fn addr_add(&mut self, _: &dyn Link, _: &Address) -> Result<()> {
+            bail!("read only")
+        }
+
+        fn route_add(&mut self, _: &Routing) -> Result<()> {
+            bail!("read only")
+        }
+
+        fn neigh_set(&mut self, _: &Neighbor) -> Result<()> {
+            bail!("read only")
+        }
+    }
+
+    #[test]
+    fn test_setup_records_plan() {
+        let plan = Plan::default();
+        let pod_cidr = "10.244.1.0/24".parse::<IpNet>().unwrap();
+        let mut netlink = Netlink::init("172.18.0.3", &pod_cidr, &[]);
+        netlink.recorder = Some(Recorder::new(
+            Box::new(Underlay { mtu: 1500 }),
+            plan.clone(),
+        ));
+
+        netlink.setup_bridge().unwrap();
+        netlink.setup_vxlan().unwrap();
+
+        let changes = plan
+            .changes()
+            .into_iter()
+            .map(|change| (change.op, change.target, change.detail))
+            .collect::<Vec<_>>();
+        let expected = [
+            ("ensure_link", BRIDGE_NAME, "create"),
+            ("addr_add", BRIDGE_NAME, "10.244.1.1/24"),
+            ("link_up", UNDERLAY_NAME, ""),
+            ("ensure_link", VXLAN_NAME, "create mtu 1450"),
+            ("addr_add", VXLAN_NAME, "10.244.1.0/32"),
+        ]
+        .map(|(op, target, detail)| (op.to_string(), target.to_string(), detail.to_string()));
+
+        assert_eq!(changes, expected);
+        assert_eq!(netlink.index_of(UNDERLAY_NAME).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_overlay_mtu_follows_underlay() {
+        let plan = Plan::default();
+        let pod_cidr = "10.244.1.0/24".parse::<IpNet>().unwrap();
+        let mut netlink = Netlink::init("172.18.0.3", &pod_cidr, &[]);
+        netlink.recorder = Some(Recorder::new(
+            Box::new(Underlay { mtu: 1400 }),
+            plan.clone(),
+        ));
+
+        assert_eq!(netlink.overlay_mtu_for(None).unwrap(), 1350);
+        assert_eq!(netlink.overlay_mtu_for(Some(1400)).unwrap(), 1350);
+        assert_eq!(netlink.overlay_mtu_for(Some(1300)).unwrap(), 1300);
+
+        let mtu = netlink.overlay_mtu_for(None).unwrap();
+        let mut netlink = netlink.with_mtu(mtu);
+        netlink.setup_bridge().unwrap();
+        netlink.setup_vxlan().unwrap();
+
+        let changes = plan.changes();
+        assert!(changes.iter().any(|change| change.op == "set_mtu"
+            && change.target == BRIDGE_NAME
+            && change.detail == "1350"));
+        assert!(changes.iter().any(|change| change.op == "ensure_link"
+            && change.target == VXLAN_NAME
+            && change.detail == "create mtu 1350"));
+    }
+
+    #[test]
+    fn test_index_of_uses_cache() {
+        let mut netlink = Netlink::new();
+        netlink.index_cache.insert(BRIDGE_NAME, 7);
+
+        assert_eq!(netlink.index_of(BRIDGE_NAME).unwrap(), 7);
+
+        netlink.forget_index(BRIDGE_NAME);
+        assert_eq!(netlink.index_cache.cached(BRIDGE_NAME), None);
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
+    fn test_index_of_follows_link_changes() {
+        std::thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+
+            let mut netlink = Netlink::new();
+            let fresh_index = |netlink: &mut Netlink, name: &str| {
+                netlink
+                    .link_get(&LinkAttrs::new(name))
+                    .unwrap()
+                    .attrs()
+                    .index
+            };
+            let add_link = |netlink: &mut Netlink, name: &str| {
+                netlink.link_add(&Kind::new_bridge(name)).unwrap();
+            };
+
+            add_link(&mut netlink, "br0");
+            let index = netlink.index_of("br0").unwrap();
+            assert_eq!(index, fresh_index(&mut netlink, "br0"));
+
+            // Deleted and created again behind the cache's back.
+            let link = netlink.link_get(&LinkAttrs::new("br0")).unwrap();
+            netlink.link_del(&link).unwrap();
+            add_link(&mut netlink, "br0");
+            assert_ne!(fresh_index(&mut netlink, "br0"), index);
+            assert_eq!(
+                netlink.index_of("br0").unwrap(),
+                fresh_index(&mut netlink, "br0")
+            );
+
+            // Renamed, with another link taking over the old name.
+            let link = netlink.link_get(&LinkAttrs::new("br0")).unwrap();
+            netlink.link_set_name(&link, "br1").unwrap();
+            add_link(&mut netlink, "br0");
+            assert_eq!(
+                netlink.index_of("br0").unwrap(),
+                fresh_index(&mut netlink, "br0")
+            );
+            assert_eq!(
+                netlink.index_of("br1").unwrap(),
+                fresh_index(&mut netlink, "br1")
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_overlay_mtu() {
+        assert_eq!(overlay_mtu(1500, Overlay::Vxlan, false), 1450);
+        assert_eq!(overlay_mtu(1500, Overlay::Vxlan, true), 1430);
+        assert_eq!(overlay_mtu(9001, Overlay::Vxlan, false), 8951);
+        assert_eq!(overlay_mtu(1450, Overlay::Vxlan, false), 1400);
+        assert_eq!(overlay_mtu(1500, Overlay::Wireguard, false), 1440);
+        assert_eq!(overlay_mtu(1500, Overlay::Wireguard, true), 1420);
+        assert_eq!(overlay_mtu(40, Overlay::Vxlan, false), 0);
+    }
+
+    #[test]
+    fn test_bridge_address() {
+        let v4 = "10.244.0.0/24".parse::<IpNet>().unwrap();
+        let address = Netlink::bridge_address(&v4).unwrap();
+        assert_eq!(address.ip, "10.244.0.1/24".parse::<IpNet>().unwrap());
+        assert_eq!(address.flags & libc::IFA_F_NODAD, 0);
+
+        let v6 = "fd00:10:244::/64".parse::<IpNet>().unwrap();
+        let address = Netlink::bridge_address(&v6).unwrap();
+        assert_eq!(address.ip, "fd00:10:244::1/64".parse::<IpNet>().unwrap());
+        assert_ne!(address.flags & libc::IFA_F_NODAD, 0);
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
+    fn test_setup_ipv6() {
+        std::thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+
+            let mut netlink = Netlink::new();
+            netlink
+                .link_add(&Kind::Veth {
+                    attrs: LinkAttrs::new(UNDERLAY_NAME),
+                    peer_name: "underlay-peer".to_string(),
+                    peer_hw_addr: None,
+                    peer_ns: None,
+                })
+                .unwrap();
+
+            let pod_cidr = "fd00:10:244::/64".parse::<IpNet>().unwrap();
+            let mut netlink = Netlink::init("fd00:172:18::3", &pod_cidr, &[]);
+            let bridge_index = netlink.setup_bridge().unwrap();
+            let vxlan_index = netlink.setup_vxlan().unwrap();
+
+            let bridge = netlink.link_get(&LinkAttrs::new(BRIDGE_NAME)).unwrap();
+            let addresses = netlink.addr_list(&bridge, AddrFamily::V6).unwrap();
+            assert!(addresses
+                .iter()
+                .any(|address| address.ip == "fd00:10:244::1/64".parse::<IpNet>().unwrap()));
+            assert_eq!(bridge.attrs().index, bridge_index);
+            assert_eq!(
+                fs::read_to_string("/proc/sys/net/ipv6/conf/cni0/accept_ra")
+                    .unwrap()
+                    .trim(),
+                "0"
+            );
+
+            let vxlan = netlink.link_get(&LinkAttrs::new(VXLAN_NAME)).unwrap();
+            netlink.link_up(&vxlan).unwrap();
+            let addresses = netlink.addr_list(&vxlan, AddrFamily::V6).unwrap();
+            assert!(addresses
+                .iter()
+                .any(|address| address.ip == "fd00:10:244::/128".parse::<IpNet>().unwrap()));
+
+            let remote = "fd00:10:244:1::/64".parse::<IpNet>().unwrap();
+            let route = Netlink::overlay_route(vxlan_index, &remote).unwrap();
+            netlink.route_add(&route).unwrap();
+            assert!(netlink
+                .route_add(&route)
+                .unwrap_err()
+                .to_string()
+                .contains("File exists"));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
+    fn test_teardown_node_route() {
+        std::thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+
+            let mut netlink = Netlink::new();
+            let vxlan = netlink
+                .ensure_link(&Kind::Vxlan {
+                    attrs: LinkAttrs::new(VXLAN_NAME),
+                    vxlan_attrs: VxlanAttrs {
+                        id: 1,
+                        port: Some(8472),
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+            netlink.link_up(&vxlan).unwrap();
+            let vxlan_index = vxlan.attrs().index;
+
+            let node_route = NodeRoute::new("kind-worker", "172.18.0.2", "10.244.1.0/24");
+            let pod_cidr = node_route.pod_net().unwrap();
+            let vtep_mac = vec![0x0a, 0x58, 0x0a, 0xf4, 0x01, 0x01];
+
+            let route = Netlink::overlay_route(vxlan_index, &pod_cidr).unwrap();
+            netlink.route_add(&route).unwrap();
+            let fdb = Netlink::fdb_entry(vxlan_index, node_route.addr().unwrap(), vtep_mac.clone())
+                .unwrap();
+            netlink.neigh_set(&fdb).unwrap();
+            netlink
+                .vtep_macs
+                .lock()
+                .unwrap()
+                .insert(node_route.ip.clone(), vtep_mac);
+
+            netlink.host_ip = Some("172.18.0.3".to_string());
+            netlink.teardown_node_route(&node_route).unwrap();
+            assert!(netlink.vtep_macs.lock().unwrap().is_empty());
+
+            // Adding the route again only works once it is gone, and a second
+            // teardown finds nothing left to remove.
+            netlink.route_add(&route).unwrap();
+            netlink.route_del(&route).unwrap();
+            netlink.teardown_node_route(&node_route).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
+    fn test_teardown() {
+        std::thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+
+            let mut netlink = Netlink::new();
+            netlink.ensure_link(&Kind::new_bridge(BRIDGE_NAME)).unwrap();
+            netlink
+                .ensure_link(&Kind::Vxlan {
+                    attrs: LinkAttrs::new(VXLAN_NAME),
+                    vxlan_attrs: VxlanAttrs {
+                        id: 1,
+                        port: Some(8472),
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+
+            netlink.host_ip = Some("10.0.0.1".to_string());
+            netlink.teardown().unwrap();
+
+            assert!(netlink.link_get(&LinkAttrs::new(VXLAN_NAME)).is_err());
+            assert!(netlink.link_get(&LinkAttrs::new(BRIDGE_NAME)).is_err());
+        })
+        .join()
+        .unwrap();
     }
 }
@@ -1,34 +1,134 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
 use k8s_openapi::api::core::v1::Node;
 
-#[derive(Debug)]
+/// A node's addresses and pod CIDR. `ip` (the primary address) and
+/// `pod_cidr` are kept as the Node lists them, for display; comparisons go
+/// through the parsed values, so `fd00::1` and `fd00:0::1` are the same node.
+#[derive(Debug, Clone)]
 pub struct NodeRoute {
+    pub name: String,
     pub ip: String,
     pub pod_cidr: String,
+    addr: Option<IpAddr>,
+    /// Every address of the node, the primary one first, so the overlay can
+    /// pick the one of the underlay's family.
+    addrs: Vec<IpAddr>,
+    pod_net: Option<IpNet>,
+}
+
+impl NodeRoute {
+    pub fn new(name: &str, ip: &str, pod_cidr: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ip: ip.to_owned(),
+            pod_cidr: pod_cidr.to_owned(),
+            addr: ip.parse().ok(),
+            addrs: ip.parse().into_iter().collect(),
+            pod_net: pod_cidr.parse().ok(),
+        }
+    }
+
+    /// Adds the node's other addresses; unparsable ones are skipped.
+    pub fn with_addrs<'a>(mut self, addrs: impl IntoIterator<Item = &'a str>) -> Self {
+        for addr in addrs.into_iter().filter_map(|addr| addr.parse().ok()) {
+            if !self.addrs.contains(&addr) {
+                self.addrs.push(addr);
+            }
+        }
+        self
+    }
+
+    /// Makes `ip` the primary address, keeping the others.
+    pub fn with_ip(self, ip: &str) -> Self {
+        let addrs = self.addrs.iter().map(IpAddr::to_string).collect::<Vec<_>>();
+
+        Self::new(&self.name, ip, &self.pod_cidr).with_addrs(addrs.iter().map(String::as_str))
+    }
+
+    pub fn addr(&self) -> Result<IpAddr> {
+        self.addr
+            .ok_or_else(|| anyhow!("node {} has no valid address: {:?}", self.name, self.ip))
+    }
+
+    pub fn pod_net(&self) -> Result<IpNet> {
+        self.pod_net.ok_or_else(|| {
+            anyhow!(
+                "node {} has no valid pod CIDR: {:?}",
+                self.name,
+                self.pod_cidr
+            )
+        })
+    }
+
+    /// The node's first address of the IPv6 family if `v6`, of IPv4
+    /// otherwise; the VXLAN underlay reaches the node there.
+    pub fn underlay_addr(&self, v6: bool) -> Result<IpAddr> {
+        self.addrs
+            .iter()
+            .find(|addr| addr.is_ipv6() == v6)
+            .copied()
+            .ok_or_else(|| {
+                anyhow!(
+                    "node {} has no {} address",
+                    self.name,
+                    if v6 { "IPv6" } else { "IPv4" }
+                )
+            })
+    }
+
+    /// Whether `ip` is one of the node's addresses.
+    pub fn is_at(&self, ip: IpAddr) -> bool {
+        self.addrs.contains(&ip)
+    }
+
+    /// Whether `other` has the same addresses and pod CIDR.
+    pub fn same_place(&self, other: &NodeRoute) -> bool {
+        self.addrs == other.addrs && self.pod_net == other.pod_net
+    }
 }
 
 impl From<Node> for NodeRoute {
     fn from(node: Node) -> Self {
-        let ip = node
+        let name = node.metadata.name.clone().unwrap_or_default();
+        let addresses = node
             .status
             .and_then(|status| status.addresses)
-            .and_then(|addresses| addresses.first().cloned())
+            .unwrap_or_default()
+            .into_iter()
             .map(|address| address.address)
+            .collect::<Vec<_>>();
+        let ip = addresses.first().cloned().unwrap_or_default();
+        let pod_cidr = node
+            .spec
+            .and_then(|spec| {
+                spec.pod_cidr
+                    .or_else(|| spec.pod_cidrs.and_then(|cidrs| cidrs.into_iter().next()))
+            })
             .unwrap_or_default();
-        let pod_cidr = node.spec.and_then(|spec| spec.pod_cidr).unwrap_or_default();
 
-        Self { ip, pod_cidr }
+        Self::new(&name, &ip, &pod_cidr).with_addrs(addresses.iter().map(String::as_str))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use k8s_openapi::api::core::v1::{Node, NodeAddress, NodeSpec, NodeStatus};
+    use k8s_openapi::{
+        api::core::v1::{Node, NodeAddress, NodeSpec, NodeStatus},
+        apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    };
 
     use super::*;
 
     #[test]
     fn test_node_route_from() {
         let node = Node {
+            metadata: ObjectMeta {
+                name: Some("kind-worker".to_string()),
+                ..Default::default()
+            },
             spec: Some(NodeSpec {
                 pod_cidr: Some("10.244.0.0/24".to_string()),
                 ..Default::default()
@@ -45,7 +145,77 @@ mod tests {
 
         let node_route = NodeRoute::from(node);
 
+        assert_eq!(node_route.name, "kind-worker");
         assert_eq!(node_route.ip, "172.18.0.3");
         assert_eq!(node_route.pod_cidr, "10.244.0.0/24");
+        assert_eq!(
+            node_route.addr().unwrap(),
+            "172.18.0.3".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            node_route.pod_net().unwrap(),
+            "10.244.0.0/24".parse::<IpNet>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parsed_comparison() {
+        let node_route = NodeRoute::new("worker", "fd00:0:0::2", "fd00:10:244:1::/64");
+
+        assert!(node_route.is_at("fd00::2".parse().unwrap()));
+        assert!(!node_route.is_at("fd00::3".parse().unwrap()));
+        assert!(node_route.same_place(&NodeRoute::new(
+            "worker",
+            "FD00::2",
+            "fd00:10:244:1:0::/64"
+        )));
+
+        let unset = NodeRoute::new("pending", "", "");
+        assert!(unset.addr().is_err());
+        assert!(unset.pod_net().is_err());
+    }
+
+    #[test]
+    fn test_underlay_addr_by_family() {
+        let node = Node {
+            metadata: ObjectMeta {
+                name: Some("worker".to_string()),
+                ..Default::default()
+            },
+            status: Some(NodeStatus {
+                addresses: Some(
+                    ["172.18.0.2", "fc00:f853:ccd:e793::2", "worker"]
+                        .map(|address| NodeAddress {
+                            address: address.to_string(),
+                            ..Default::default()
+                        })
+                        .to_vec(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let node_route = NodeRoute::from(node);
+        assert_eq!(node_route.ip, "172.18.0.2");
+        assert_eq!(
+            node_route.underlay_addr(true).unwrap(),
+            "fc00:f853:ccd:e793::2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            node_route.underlay_addr(false).unwrap(),
+            "172.18.0.2".parse::<IpAddr>().unwrap()
+        );
+        assert!(node_route.is_at("fc00:f853:ccd:e793::2".parse().unwrap()));
+
+        let moved = node_route.clone().with_ip("172.18.0.9");
+        assert_eq!(
+            moved.addr().unwrap(),
+            "172.18.0.9".parse::<IpAddr>().unwrap()
+        );
+        assert!(moved.underlay_addr(true).is_ok());
+
+        let v4_only = NodeRoute::new("worker", "172.18.0.2", "10.244.1.0/24");
+        assert!(v4_only.underlay_addr(true).is_err());
     }
 }
@@ -0,0 +1,362 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::Node;
+use kube::{runtime::watcher::Event, ResourceExt};
+use tracing::{error, info};
+
+use crate::{
+    convergence::{ConvergenceMetrics, Trigger},
+    kube::{Context, VXLAN_MAC_ANNOTATION},
+    netlink::Netlink,
+    node_route::NodeRoute,
+    prober::ProbeTargets,
+};
+
+/// What a Node event means for the overlay.
+#[derive(Debug)]
+enum NodeChange {
+    Added(NodeRoute),
+    /// The node kept its name but got a new pod CIDR, address or VXLAN MAC.
+    Moved {
+        old: NodeRoute,
+        new: NodeRoute,
+    },
+    Removed(NodeRoute),
+}
+
+/// The remote nodes the overlay is programmed for, keyed by node name.
+struct NodePeers {
+    host_ip: IpAddr,
+    known: HashMap<String, NodeRoute>,
+    /// The VXLAN MAC each node was last seen annotated with. A node whose
+    /// MAC changed, e.g. on upgrade to a derived one, has its neighbor and
+    /// FDB entries replaced like a move.
+    vtep_macs: HashMap<String, Option<String>>,
+    /// Nodes listed since the watcher last (re)started; anything known but
+    /// not listed was deleted while the watch was down.
+    seen: HashSet<String>,
+}
+
+impl NodePeers {
+    fn new(host_ip: IpAddr, node_routes: &[NodeRoute]) -> Self {
+        let known = node_routes
+            .iter()
+            .filter(|node_route| !node_route.is_at(host_ip))
+            .map(|node_route| (node_route.name.clone(), node_route.clone()))
+            .collect();
+
+        Self {
+            host_ip,
+            known,
+            vtep_macs: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    fn changes(&mut self, event: Event<Node>) -> Vec<NodeChange> {
+        match event {
+            Event::Apply(node) => {
+                let vtep_mac = vxlan_mac(&node);
+                self.apply(NodeRoute::from(node), vtep_mac)
+                    .into_iter()
+                    .collect()
+            }
+            Event::Delete(node) => self.remove(&node.name_any()).into_iter().collect(),
+            Event::Init => {
+                self.seen.clear();
+                vec![]
+            }
+            Event::InitApply(node) => {
+                let vtep_mac = vxlan_mac(&node);
+                let node_route = NodeRoute::from(node);
+                self.seen.insert(node_route.name.clone());
+                self.apply(node_route, vtep_mac).into_iter().collect()
+            }
+            Event::InitDone => {
+                let gone = self
+                    .known
+                    .keys()
+                    .filter(|name| !self.seen.contains(*name))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                gone.iter().filter_map(|name| self.remove(name)).collect()
+            }
+        }
+    }
+
+    fn apply(&mut self, node_route: NodeRoute, vtep_mac: Option<String>) -> Option<NodeChange> {
+        if node_route.is_at(self.host_ip) || node_route.pod_cidr.is_empty() {
+            return None;
+        }
+
+        // The first MAC seen for a node is not a change.
+        let mac_changed = self
+            .vtep_macs
+            .insert(node_route.name.clone(), vtep_mac.clone())
+            .is_some_and(|old| old != vtep_mac);
+
+        match self
+            .known
+            .insert(node_route.name.clone(), node_route.clone())
+        {
+            None => Some(NodeChange::Added(node_route)),
+            Some(old) if !old.same_place(&node_route) || mac_changed => Some(NodeChange::Moved {
+                old,
+                new: node_route,
+            }),
+            Some(_) => None,
+        }
+    }
+
+    fn remove(&mut self, name: &str) -> Option<NodeChange> {
+        self.vtep_macs.remove(name);
+        self.known.remove(name).map(NodeChange::Removed)
+    }
+}
+
+fn vxlan_mac(node: &Node) -> Option<String> {
+    node.annotations().get(VXLAN_MAC_ANNOTATION).cloned()
+}
+
+/// Keeps the overlay in step with the cluster's Node objects: programs new
+/// nodes, moves the route when a node's pod CIDR changes and cleans up after
+/// deleted nodes so their CIDRs are not black-holed through a dead VTEP.
+pub struct NodeWatcher {
+    context: Arc<Context>,
+    netlink: Netlink,
+    vxlan_index: i32,
+    peers: NodePeers,
+    probe_targets: ProbeTargets,
+    convergence: ConvergenceMetrics,
+}
+
+impl NodeWatcher {
+    pub fn new(
+        context: Arc<Context>,
+        netlink: Netlink,
+        vxlan_index: i32,
+        probe_targets: ProbeTargets,
+        convergence: ConvergenceMetrics,
+    ) -> Result<Self> {
+        let host_ip = netlink
+            .host_ip
+            .as_deref()
+            .ok_or(anyhow!("host_ip is not set"))?
+            .parse::<IpAddr>()?;
+        let peers = NodePeers::new(host_ip, &netlink.node_routes);
+        for node_route in peers.known.values() {
+            probe_targets.insert(node_route);
+        }
+
+        Ok(Self {
+            context,
+            netlink,
+            vxlan_index,
+            peers,
+            probe_targets,
+            convergence,
+        })
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let context = self.context.clone();
+        context
+            .watch_node_resource(|event| self.handle(event))
+            .await
+    }
+
+    fn handle(&mut self, event: Event<Node>) {
+        self.context.nodes().observe(&event);
+        for change in self.peers.changes(event) {
+            if let Err(e) = self.apply(&change) {
+                error!("failed to apply {:?}: {}", change, e);
+            }
+        }
+    }
+
+    fn apply(&mut self, change: &NodeChange) -> Result<()> {
+        match change {
+            NodeChange::Added(node_route) => {
+                info!("node {} joined, adding its overlay", node_route.name);
+                self.setup(node_route);
+                self.probe_targets.insert(node_route);
+            }
+            NodeChange::Moved { old, new } => {
+                info!(
+                    "node {} moved from {} to {}",
+                    new.name, old.pod_cidr, new.pod_cidr
+                );
+                self.netlink.teardown_node_route(old)?;
+                self.setup(new);
+                self.probe_targets.insert(new);
+            }
+            NodeChange::Removed(node_route) => {
+                info!("node {} left, removing its overlay", node_route.name);
+                self.probe_targets.remove(&node_route.name);
+                self.netlink.teardown_node_route(node_route)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn setup(&self, node_route: &NodeRoute) {
+        let started = Instant::now();
+        let task = self.netlink.spawn_overlay_peer(
+            self.vxlan_index,
+            self.context.clone(),
+            node_route.clone(),
+        );
+
+        let convergence = self.convergence.clone();
+        tokio::spawn(async move { convergence.track(Trigger::Node, started, vec![task]).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::{
+        api::core::v1::{NodeAddress, NodeSpec, NodeStatus},
+        apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    };
+
+    use super::*;
+
+    fn node(name: &str, ip: &str, pod_cidr: &str) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            spec: Some(NodeSpec {
+                pod_cidr: Some(pod_cidr.to_string()),
+                ..Default::default()
+            }),
+            status: Some(NodeStatus {
+                addresses: Some(vec![NodeAddress {
+                    address: ip.to_string(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn node_with_mac(name: &str, ip: &str, pod_cidr: &str, mac: &str) -> Node {
+        let mut node = node(name, ip, pod_cidr);
+        node.annotations_mut()
+            .insert(VXLAN_MAC_ANNOTATION.to_string(), mac.to_string());
+        node
+    }
+
+    fn peers() -> NodePeers {
+        let node_routes = [
+            node("control-plane", "172.18.0.3", "10.244.0.0/24"),
+            node("worker", "172.18.0.2", "10.244.1.0/24"),
+        ]
+        .map(NodeRoute::from);
+
+        NodePeers::new("172.18.0.3".parse().unwrap(), &node_routes)
+    }
+
+    #[test]
+    fn test_apply_and_delete() {
+        let mut peers = peers();
+
+        let changes = peers.changes(Event::Apply(node("worker", "172.18.0.2", "10.244.1.0/24")));
+        assert!(changes.is_empty());
+
+        let changes = peers.changes(Event::Apply(node("worker2", "172.18.0.4", "10.244.2.0/24")));
+        assert!(matches!(&changes[..], [NodeChange::Added(route)] if route.name == "worker2"));
+
+        let changes = peers.changes(Event::Delete(node("worker", "172.18.0.2", "10.244.1.0/24")));
+        assert!(matches!(&changes[..], [NodeChange::Removed(route)] if route.ip == "172.18.0.2"));
+
+        let changes = peers.changes(Event::Delete(node("worker", "172.18.0.2", "10.244.1.0/24")));
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_pod_cidr_change() {
+        let mut peers = peers();
+
+        let changes = peers.changes(Event::Apply(node("worker", "172.18.0.2", "10.244.5.0/24")));
+        assert!(matches!(
+            &changes[..],
+            [NodeChange::Moved { old, new }]
+                if old.pod_cidr == "10.244.1.0/24" && new.pod_cidr == "10.244.5.0/24"
+        ));
+    }
+
+    #[test]
+    fn test_ignores_host_node() {
+        let mut peers = peers();
+
+        let changes = peers.changes(Event::Apply(node(
+            "control-plane",
+            "172.18.0.3",
+            "10.244.9.0/24",
+        )));
+        assert!(changes.is_empty());
+
+        let changes = peers.changes(Event::Delete(node(
+            "control-plane",
+            "172.18.0.3",
+            "10.244.0.0/24",
+        )));
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_relist_removes_missing_nodes() {
+        let mut peers = peers();
+
+        assert!(peers.changes(Event::Init).is_empty());
+        let changes = peers.changes(Event::InitApply(node(
+            "worker2",
+            "172.18.0.4",
+            "10.244.2.0/24",
+        )));
+        assert_eq!(changes.len(), 1);
+
+        let changes = peers.changes(Event::InitDone);
+        assert!(matches!(&changes[..], [NodeChange::Removed(route)] if route.name == "worker"));
+        assert_eq!(peers.known.len(), 1);
+    }
+
+    #[test]
+    fn test_vxlan_mac_change_replaces_neighbors() {
+        let mut peers = peers();
+
+        let changes = peers.changes(Event::Apply(node_with_mac(
+            "worker",
+            "172.18.0.2",
+            "10.244.1.0/24",
+            "66:c4:e2:6b:2f:01",
+        )));
+        assert!(changes.is_empty());
+
+        let changes = peers.changes(Event::Apply(node_with_mac(
+            "worker",
+            "172.18.0.2",
+            "10.244.1.0/24",
+            "0a:3d:52:91:7e:44",
+        )));
+        assert!(matches!(&changes[..], [NodeChange::Moved { new, .. }] if new.name == "worker"));
+
+        let changes = peers.changes(Event::Apply(node_with_mac(
+            "worker",
+            "172.18.0.2",
+            "10.244.1.0/24",
+            "0a:3d:52:91:7e:44",
+        )));
+        assert!(changes.is_empty());
+    }
+}
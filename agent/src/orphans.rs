@@ -0,0 +1,365 @@
+use std::{collections::HashSet, fs, io::ErrorKind, net::IpAddr, path::Path};
+
+use anyhow::{anyhow, Result};
+use rsln::types::link::LinkAttrs;
+use serde::Deserialize;
+use sinabro_config::host_veth_name;
+use tracing::{info, warn};
+
+use crate::netlink::Netlink;
+
+/// Where the CNI plugin caches its ADD results.
+pub const RESULTS_DIR: &str = "/var/lib/sinabro/results";
+/// The plugin's host-local IPAM stores, one directory per network.
+pub const HOST_LOCAL_DIR: &str = "/var/lib/cni/sinabro";
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// The part of a cached ADD result the sweep needs.
+#[derive(Deserialize)]
+struct CachedVeth {
+    host_veth: String,
+}
+
+/// Owners (`<container>/<ifname>`) of the addresses in every host-local
+/// store under `root`: one file per address, named after it.
+fn host_local_owners(root: &Path) -> Result<Vec<String>> {
+    let networks = match fs::read_dir(root) {
+        Ok(networks) => networks,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut owners = vec![];
+    for network in networks {
+        let network = network?.path();
+        if !network.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&network)? {
+            let path = entry?.path();
+            let is_address = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.parse::<IpAddr>().is_ok());
+            if is_address {
+                owners.push(fs::read_to_string(&path)?.trim().to_string());
+            }
+        }
+    }
+
+    Ok(owners)
+}
+
+/// Host veths of live attachments: the ones named in the CNI result cache
+/// and the ones derived from the agent's IPAM lease owners and the
+/// host-local stores' owners (`<container>/<ifname>`). An unreadable cache
+/// or store entry fails the whole lookup, since the veth it names would
+/// otherwise look orphaned.
+fn live_veths<'a>(
+    results_dir: &Path,
+    host_local_root: &Path,
+    lease_owners: impl IntoIterator<Item = &'a str>,
+) -> Result<HashSet<String>> {
+    let host_local_owners = host_local_owners(host_local_root)?;
+    let mut live = lease_owners
+        .into_iter()
+        .chain(host_local_owners.iter().map(String::as_str))
+        .filter_map(|owner| owner.split_once('/'))
+        .map(|(container_id, if_name)| host_veth_name(container_id, if_name))
+        .collect::<HashSet<_>>();
+
+    let entries = match fs::read_dir(results_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(live),
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+
+        let cached = serde_json::from_slice::<CachedVeth>(&fs::read(&path)?)
+            .map_err(|e| anyhow!("unreadable cached result {}: {}", path.display(), e))?;
+        live.insert(cached.host_veth);
+    }
+
+    Ok(live)
+}
+
+/// Whether `name` has the form `host_veth_name` produces. Only such veths
+/// are swept: older plugins named veths randomly, and those cannot be tied
+/// back to an attachment.
+fn is_derived_veth_name(name: &str) -> bool {
+    name.strip_prefix("veth").is_some_and(|suffix| {
+        suffix.len() == 8
+            && suffix
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    })
+}
+
+/// Whether the peer of `veth` never left this netns: `peer<suffix>` is
+/// here and the two name each other in `iflink`. A peer inside a container
+/// means a pod holds the veth, whatever the caches say. An index alone is
+/// not enough, since the peer's index is only unique within its own netns.
+fn peer_in_host_netns(sys_class_net: &Path, veth: &str) -> bool {
+    let Some(suffix) = veth.strip_prefix("veth") else {
+        return false;
+    };
+    let read = |name: &str, attr: &str| {
+        fs::read_to_string(sys_class_net.join(name).join(attr))
+            .ok()
+            .and_then(|value| value.trim().parse::<u32>().ok())
+    };
+    let peer = format!("peer{}", suffix);
+
+    match (
+        read(veth, "ifindex"),
+        read(veth, "iflink"),
+        read(&peer, "ifindex"),
+        read(&peer, "iflink"),
+    ) {
+        (Some(veth_index), Some(veth_link), Some(peer_index), Some(peer_link)) => {
+            veth_link == peer_index && peer_link == veth_index
+        }
+        _ => false,
+    }
+}
+
+fn orphaned<'a>(
+    ports: &'a [String],
+    live: &HashSet<String>,
+    sys_class_net: &Path,
+) -> Vec<&'a String> {
+    ports
+        .iter()
+        .filter(|port| is_derived_veth_name(port) && !live.contains(*port))
+        .filter(|port| peer_in_host_netns(sys_class_net, port))
+        .collect()
+}
+
+fn bridge_ports(bridge: &str) -> Result<Vec<String>> {
+    let ports = match fs::read_dir(Path::new(SYS_CLASS_NET).join(bridge).join("brif")) {
+        Ok(ports) => ports,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    ports
+        .map(|port| Ok(port?.file_name().to_string_lossy().into_owned()))
+        .collect()
+}
+
+/// Deletes host veths left on `bridge` by attachments that no longer exist,
+/// e.g. when the container's netns went away before ADD moved the peer in.
+/// Only veths whose peer is still in this netns are candidates. Returns how
+/// many were removed; a veth that cannot be deleted is skipped.
+pub fn cleanup_orphaned_veths<'a>(
+    netlink: &mut Netlink,
+    bridge: &str,
+    results_dir: &Path,
+    host_local_root: &Path,
+    lease_owners: impl IntoIterator<Item = &'a str>,
+) -> Result<usize> {
+    let live = live_veths(results_dir, host_local_root, lease_owners)?;
+    let ports = bridge_ports(bridge)?;
+    let mut removed = 0;
+
+    for name in orphaned(&ports, &live, Path::new(SYS_CLASS_NET)) {
+        match netlink
+            .link_get(&LinkAttrs::new(name))
+            .and_then(|veth| netlink.link_del(&veth))
+        {
+            Ok(_) => {
+                info!("removed orphaned veth {}", name);
+                removed += 1;
+            }
+            Err(e) => warn!("failed to remove orphaned veth {}: {}", name, e),
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, os::fd::AsRawFd};
+
+    use rsln::types::link::Kind;
+
+    use super::*;
+
+    /// Fakes `/sys/class/net/<name>` with the two attributes the sweep reads.
+    fn fake_link(sys_class_net: &Path, name: &str, ifindex: u32, iflink: u32) {
+        let dir = sys_class_net.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ifindex"), format!("{}\n", ifindex)).unwrap();
+        fs::write(dir.join("iflink"), format!("{}\n", iflink)).unwrap();
+    }
+
+    /// A veth pair whose peer is still in the host netns.
+    fn fake_stranded_pair(sys_class_net: &Path, veth: &str, ifindex: u32) {
+        fake_link(sys_class_net, veth, ifindex, ifindex + 1);
+        fake_link(
+            sys_class_net,
+            &veth.replacen("veth", "peer", 1),
+            ifindex + 1,
+            ifindex,
+        );
+    }
+
+    #[test]
+    fn test_orphaned() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let results_dir = tmp_dir.path().join("results");
+        let host_local_root = tmp_dir.path().join("host-local");
+        let sys_class_net = tmp_dir.path().join("net");
+
+        fs::create_dir_all(&results_dir).unwrap();
+        fs::write(
+            results_dir.join("cached-eth0.json"),
+            format!(r#"{{"host_veth":"{}"}}"#, host_veth_name("cached", "eth0")),
+        )
+        .unwrap();
+        fs::create_dir_all(host_local_root.join("sinabro")).unwrap();
+        fs::write(
+            host_local_root.join("sinabro").join("10.244.0.7"),
+            "host-local/eth0",
+        )
+        .unwrap();
+        fs::write(
+            host_local_root.join("sinabro").join("last_reserved_ip.0"),
+            "10.244.0.7",
+        )
+        .unwrap();
+
+        let ports = [
+            "cached",
+            "leased",
+            "host-local",
+            "gone",
+            // Neither cached nor leased, but its peer is in a container.
+            "uncached",
+        ]
+        .map(|container_id| host_veth_name(container_id, "eth0"));
+        for (index, port) in ports[..4].iter().enumerate() {
+            fake_stranded_pair(&sys_class_net, port, 10 + 2 * index as u32);
+        }
+        // The container's eth0 has index 3 in its own netns; a host link
+        // with the same index is not the peer.
+        fake_link(&sys_class_net, &ports[4], 18, 3);
+        fake_link(&sys_class_net, "eth0", 3, 3);
+
+        let live = live_veths(&results_dir, &host_local_root, ["leased/eth0"]).unwrap();
+        let mut ports = ports.to_vec();
+        ports.extend(["vethBEEF".to_string(), "eth1".to_string()]);
+
+        assert_eq!(
+            orphaned(&ports, &live, &sys_class_net),
+            vec![&host_veth_name("gone", "eth0")]
+        );
+    }
+
+    #[test]
+    fn test_unreadable_cache_stops_sweep() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        fs::write(tmp_dir.path().join("broken-eth0.json"), "{").unwrap();
+
+        assert!(live_veths(tmp_dir.path(), &tmp_dir.path().join("host-local"), []).is_err());
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN and CAP_SYS_ADMIN"]
+    fn test_cleanup_orphaned_veths() {
+        std::thread::spawn(|| {
+            // A private mount of sysfs, so /sys/class/net shows the new netns.
+            assert_eq!(
+                unsafe { libc::unshare(libc::CLONE_NEWNET | libc::CLONE_NEWNS) },
+                0
+            );
+            let root = CString::new("/").unwrap();
+            let sys = CString::new("/sys").unwrap();
+            let sysfs = CString::new("sysfs").unwrap();
+            unsafe {
+                assert_eq!(
+                    libc::mount(
+                        std::ptr::null(),
+                        root.as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_REC | libc::MS_PRIVATE,
+                        std::ptr::null()
+                    ),
+                    0
+                );
+                assert_eq!(
+                    libc::mount(
+                        sysfs.as_ptr(),
+                        sys.as_ptr(),
+                        sysfs.as_ptr(),
+                        0,
+                        std::ptr::null()
+                    ),
+                    0
+                );
+            }
+
+            let mut netlink = Netlink::new();
+            let bridge = netlink.ensure_link(&Kind::new_bridge("br-test")).unwrap();
+
+            // Stands in for a running pod's netns; kept alive by the open fd.
+            let container_netns = std::thread::spawn(|| {
+                assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+                std::fs::File::open("/proc/thread-self/ns/net").unwrap()
+            })
+            .join()
+            .unwrap();
+
+            // The orphan's netns is gone, so its peer never left the host.
+            for container_id in ["live", "gone", "running"] {
+                let name = host_veth_name(container_id, "eth0");
+                let peer_name = name.replacen("veth", "peer", 1);
+                netlink
+                    .link_add(&Kind::Veth {
+                        attrs: LinkAttrs::new(&name),
+                        peer_name: peer_name.clone(),
+                        peer_hw_addr: None,
+                        peer_ns: None,
+                    })
+                    .unwrap();
+                let veth = netlink.link_get(&LinkAttrs::new(&name)).unwrap();
+                netlink
+                    .link_set_master(&veth, bridge.attrs().index)
+                    .unwrap();
+
+                if container_id == "running" {
+                    let peer = netlink.link_get(&LinkAttrs::new(&peer_name)).unwrap();
+                    netlink
+                        .link_set_ns(&peer, container_netns.as_raw_fd())
+                        .unwrap();
+                }
+            }
+
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let removed = cleanup_orphaned_veths(
+                &mut netlink,
+                "br-test",
+                &tmp_dir.path().join("results"),
+                &tmp_dir.path().join("host-local"),
+                ["live/eth0"],
+            )
+            .unwrap();
+
+            assert_eq!(removed, 1);
+            for (container_id, kept) in [("live", true), ("gone", false), ("running", true)] {
+                let veth = netlink.link_get(&LinkAttrs::new(&host_veth_name(container_id, "eth0")));
+                assert_eq!(veth.is_ok(), kept, "{}", container_id);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use rsln::types::{
+    addr::Address,
+    link::{Kind, Link, LinkAttrs},
+    neigh::Neighbor,
+    routing::Routing,
+};
+use serde::Serialize;
+use tracing::info;
+
+use crate::netlink::NetlinkOps;
+
+/// A change to the host that `--dry-run` held back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedChange {
+    pub op: String,
+    pub target: String,
+    pub detail: String,
+}
+
+/// Every change a dry run would have made, in the order it was planned.
+/// Overlay tasks record into it concurrently.
+#[derive(Clone, Default)]
+pub struct Plan(Arc<Mutex<Vec<PlannedChange>>>);
+
+impl Plan {
+    pub fn record(&self, op: &str, target: &str, detail: impl Into<String>) {
+        let change = PlannedChange {
+            op: op.to_string(),
+            target: target.to_string(),
+            detail: detail.into(),
+        };
+        info!("dry run: {} {} {}", change.op, change.target, change.detail);
+
+        self.0.lock().unwrap().push(change);
+    }
+
+    pub fn changes(&self) -> Vec<PlannedChange> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Stands in for the kernel during a dry run: reads go to `reader`, writes
+/// are recorded in the plan and reported as successful.
+pub struct Recorder {
+    reader: Box<dyn NetlinkOps>,
+    plan: Plan,
+}
+
+impl Recorder {
+    pub fn new(reader: Box<dyn NetlinkOps>, plan: Plan) -> Self {
+        Self { reader, plan }
+    }
+
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+}
+
+impl NetlinkOps for Recorder {
+    fn link_get(&mut self, attrs: &LinkAttrs) -> Result<Box<dyn Link>> {
+        self.reader.link_get(attrs)
+    }
+
+    /// Hands back the existing link when there is one, so later steps see
+    /// its real index; otherwise the link as it would have been created.
+    fn ensure_link(&mut self, kind: &Kind) -> Result<Box<dyn Link>> {
+        let name = &kind.attrs().name;
+
+        match self.reader.link_get(&LinkAttrs::new(name)) {
+            Ok(link) => {
+                self.plan.record("ensure_link", name, "exists");
+                Ok(link)
+            }
+            Err(_) => {
+                let detail = match kind.attrs().mtu {
+                    0 => "create".to_string(),
+                    mtu => format!("create mtu {}", mtu),
+                };
+                self.plan.record("ensure_link", name, detail);
+                Ok(Box::new(kind.clone()))
+            }
+        }
+    }
+
+    fn link_up(&mut self, link: &dyn Link) -> Result<()> {
+        self.plan.record("link_up", &link.attrs().name, "");
+        Ok(())
+    }
+
+    fn addr_add(&mut self, link: &dyn Link, address: &Address) -> Result<()> {
+        self.plan
+            .record("addr_add", &link.attrs().name, address.ip.to_string());
+        Ok(())
+    }
+
+    fn route_add(&mut self, route: &Routing) -> Result<()> {
+        let dst = route.dst.map(|dst| dst.to_string()).unwrap_or_default();
+        let gw = route.gw.map(|gw| format!("via {}", gw)).unwrap_or_default();
+        self.plan.record(
+            "route_add",
+            &dst,
+            format!("{} oif {}", gw, route.oif_index).trim_start(),
+        );
+        Ok(())
+    }
+
+    fn neigh_set(&mut self, neigh: &Neighbor) -> Result<()> {
+        let ip = neigh.ip_addr.map(|ip| ip.to_string()).unwrap_or_default();
+        let mac = neigh
+            .mac_addr
+            .iter()
+            .flatten()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(":");
+        self.plan.record(
+            "neigh_set",
+            &ip,
+            format!("lladdr {} ifindex {}", mac, neigh.link_index),
+        );
+        Ok(())
+    }
+}
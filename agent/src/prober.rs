@@ -0,0 +1,493 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{kube::Context, metrics, node_route::NodeRoute};
+
+pub const PROBE_PORT: u16 = 3002;
+const PROBE_MAGIC: &[u8] = b"sinabro-probe";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Gap between two probes, so no node sends more than ten probes a second
+/// however large the cluster is.
+const PROBE_SPACING: Duration = Duration::from_millis(100);
+/// Probe results kept per peer for the loss and RTT figures.
+const PROBE_WINDOW: usize = 10;
+/// Consecutive failures after which a peer is reported unreachable.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// Sends one probe to a peer's VXLAN address and waits for the echo.
+#[async_trait]
+pub trait ProbeTransport: Send + Sync {
+    async fn probe(&self, peer: IpAddr) -> Result<Duration>;
+}
+
+/// Probes over UDP from the local VXLAN address, so the probe crosses the
+/// same tunnel pod traffic does and catches a blocked VXLAN port or an MTU
+/// mismatch.
+pub struct UdpTransport {
+    local: IpAddr,
+    port: u16,
+    seq: AtomicU64,
+}
+
+impl UdpTransport {
+    pub fn new(local: IpAddr, port: u16) -> Self {
+        Self {
+            local,
+            port,
+            seq: AtomicU64::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl ProbeTransport for UdpTransport {
+    async fn probe(&self, peer: IpAddr) -> Result<Duration> {
+        let socket = UdpSocket::bind(SocketAddr::new(self.local, 0)).await?;
+        let mut payload = PROBE_MAGIC.to_vec();
+        payload.extend_from_slice(&self.seq.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+
+        let started = Instant::now();
+        socket
+            .send_to(&payload, SocketAddr::new(peer, self.port))
+            .await?;
+
+        let mut buf = [0; 64];
+        loop {
+            let remaining = PROBE_TIMEOUT.saturating_sub(started.elapsed());
+            let (len, _) = tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await??;
+            if buf[..len] == payload[..] {
+                return Ok(started.elapsed());
+            }
+        }
+    }
+}
+
+/// Echoes probes back to their sender. Every agent runs one when probing is
+/// enabled, so its peers can probe it.
+pub async fn serve_probes(addr: SocketAddr, token: CancellationToken) -> Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    let mut buf = [0; 64];
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = received?;
+                if buf[..len].starts_with(PROBE_MAGIC) {
+                    let _ = socket.send_to(&buf[..len], from).await;
+                }
+            }
+            _ = token.cancelled() => return Ok(()),
+        }
+    }
+}
+
+/// Remote nodes to probe, by node name. Kept up to date by the node watcher.
+#[derive(Clone, Default)]
+pub struct ProbeTargets(Arc<Mutex<BTreeMap<String, IpAddr>>>);
+
+impl ProbeTargets {
+    /// Targets the node's VXLAN address, the first address of its pod CIDR.
+    pub fn insert(&self, node_route: &NodeRoute) {
+        match node_route.pod_net() {
+            Ok(pod_cidr) => {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .insert(node_route.name.clone(), pod_cidr.addr());
+            }
+            Err(e) => warn!("not probing node {}: {}", node_route.name, e),
+        }
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.0.lock().unwrap().remove(name);
+    }
+
+    fn snapshot(&self) -> Vec<(String, IpAddr)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, address)| (name.clone(), *address))
+            .collect()
+    }
+}
+
+/// Probe results of one peer as served on `/network/peers`. `loss` and
+/// `rtt_ms` cover the last few probes only.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerStatus {
+    pub name: String,
+    pub address: IpAddr,
+    pub sent: u64,
+    pub received: u64,
+    pub loss: f64,
+    pub rtt_ms: Option<f64>,
+    pub consecutive_failures: u32,
+}
+
+struct PeerRecord {
+    address: IpAddr,
+    sent: u64,
+    received: u64,
+    window: VecDeque<Option<Duration>>,
+    consecutive_failures: u32,
+}
+
+impl PeerRecord {
+    fn new(address: IpAddr) -> Self {
+        Self {
+            address,
+            sent: 0,
+            received: 0,
+            window: VecDeque::with_capacity(PROBE_WINDOW),
+            consecutive_failures: 0,
+        }
+    }
+
+    fn status(&self, name: &str) -> PeerStatus {
+        let rtts = self.window.iter().flatten().collect::<Vec<_>>();
+        let loss = match self.window.len() {
+            0 => 0.0,
+            len => 1.0 - rtts.len() as f64 / len as f64,
+        };
+        let rtt_ms = (!rtts.is_empty()).then(|| {
+            rtts.iter().map(|rtt| rtt.as_secs_f64()).sum::<f64>() * 1000.0 / rtts.len() as f64
+        });
+
+        PeerStatus {
+            name: name.to_owned(),
+            address: self.address,
+            sent: self.sent,
+            received: self.received,
+            loss,
+            rtt_ms,
+            consecutive_failures: self.consecutive_failures,
+        }
+    }
+}
+
+/// Per-peer probe results, shared between the prober and the API server.
+#[derive(Clone, Default)]
+pub struct PeerTable(Arc<Mutex<BTreeMap<String, PeerRecord>>>);
+
+impl PeerTable {
+    pub fn read(&self) -> Vec<PeerStatus> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, record)| record.status(name))
+            .collect()
+    }
+
+    pub fn write_metrics(&self, out: &mut String) {
+        let peers = self.read();
+
+        metrics::write_gauge(
+            out,
+            "sinabro_peer_rtt_seconds",
+            "Average round-trip time of recent overlay probes to the peer.",
+            peers.iter().filter_map(|peer| {
+                peer.rtt_ms.map(|rtt_ms| {
+                    (
+                        metrics::labels(&[("peer", peer.name.as_str())]),
+                        rtt_ms / 1000.0,
+                    )
+                })
+            }),
+        );
+        metrics::write_gauge(
+            out,
+            "sinabro_peer_loss_ratio",
+            "Share of recent overlay probes to the peer that got no answer.",
+            peers
+                .iter()
+                .map(|peer| (metrics::labels(&[("peer", peer.name.as_str())]), peer.loss)),
+        );
+    }
+
+    /// Records one probe result. Returns the peer's status when this failure
+    /// is the one that crosses the threshold, so it is reported once per
+    /// outage.
+    fn record(&self, name: &str, address: IpAddr, rtt: Option<Duration>) -> Option<PeerStatus> {
+        let mut records = self.0.lock().unwrap();
+        let record = records
+            .entry(name.to_owned())
+            .or_insert_with(|| PeerRecord::new(address));
+
+        record.address = address;
+        record.sent += 1;
+        if record.window.len() == PROBE_WINDOW {
+            record.window.pop_front();
+        }
+        record.window.push_back(rtt);
+
+        match rtt {
+            Some(_) => {
+                record.received += 1;
+                record.consecutive_failures = 0;
+                None
+            }
+            None => {
+                record.consecutive_failures += 1;
+                (record.consecutive_failures == FAILURE_THRESHOLD).then(|| record.status(name))
+            }
+        }
+    }
+
+    fn retain(&self, targets: &[(String, IpAddr)]) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|name, _| targets.iter().any(|(target, _)| target == name));
+    }
+}
+
+/// Periodically probes every remote node over the overlay. Probes are sent
+/// one at a time with a fixed gap between them.
+pub struct Prober<T> {
+    transport: T,
+    targets: ProbeTargets,
+    table: PeerTable,
+    interval: Duration,
+    spacing: Duration,
+}
+
+impl<T: ProbeTransport> Prober<T> {
+    pub fn new(transport: T, targets: ProbeTargets, table: PeerTable, interval: Duration) -> Self {
+        Self {
+            transport,
+            targets,
+            table,
+            interval,
+            spacing: PROBE_SPACING,
+        }
+    }
+
+    /// Probes until `token` is cancelled, raising a Warning Event on the
+    /// local Node for each peer that stops answering.
+    pub async fn run(self, context: Arc<Context>, node_name: String, token: CancellationToken) {
+        loop {
+            for peer in self.probe_round(&token).await {
+                let message = format!(
+                    "{} overlay probes in a row to node {} ({}) went unanswered",
+                    peer.consecutive_failures, peer.name, peer.address
+                );
+                warn!("{}", message);
+
+                if let Err(e) = context
+                    .record_node_event(&node_name, "OverlayPeerUnreachable", &message)
+                    .await
+                {
+                    warn!("failed to record event for node {}: {}", peer.name, e);
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = token.cancelled() => return,
+            }
+        }
+    }
+
+    /// Probes each current target once and returns the peers that just
+    /// became unreachable. Results of nodes no longer targeted are dropped.
+    async fn probe_round(&self, token: &CancellationToken) -> Vec<PeerStatus> {
+        let targets = self.targets.snapshot();
+        self.table.retain(&targets);
+
+        let mut unreachable = vec![];
+        for (i, (name, address)) in targets.iter().enumerate() {
+            if i > 0 {
+                tokio::select! {
+                    biased;
+                    _ = token.cancelled() => break,
+                    _ = tokio::time::sleep(self.spacing) => {}
+                }
+            }
+
+            let rtt = tokio::select! {
+                biased;
+                _ = token.cancelled() => break,
+                result = self.transport.probe(*address) => result.ok(),
+            };
+            unreachable.extend(self.table.record(name, *address, rtt));
+        }
+
+        unreachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// Answers every address in `up` after `rtt` and records the order of
+    /// the probes.
+    struct MockTransport {
+        up: Vec<IpAddr>,
+        rtt: Duration,
+        probed: Mutex<Vec<IpAddr>>,
+    }
+
+    #[async_trait]
+    impl ProbeTransport for MockTransport {
+        async fn probe(&self, peer: IpAddr) -> Result<Duration> {
+            self.probed.lock().unwrap().push(peer);
+            if self.up.contains(&peer) {
+                Ok(self.rtt)
+            } else {
+                Err(anyhow!("timed out"))
+            }
+        }
+    }
+
+    fn route(name: &str, pod_cidr: &str) -> NodeRoute {
+        NodeRoute::new(name, "", pod_cidr)
+    }
+
+    fn prober(up: &[&str]) -> Prober<MockTransport> {
+        let transport = MockTransport {
+            up: up.iter().map(|ip| ip.parse().unwrap()).collect(),
+            rtt: Duration::from_millis(2),
+            probed: Mutex::default(),
+        };
+        let targets = ProbeTargets::default();
+        targets.insert(&route("worker", "10.244.1.0/24"));
+        targets.insert(&route("worker2", "10.244.2.0/24"));
+
+        let mut prober = Prober::new(
+            transport,
+            targets,
+            PeerTable::default(),
+            Duration::from_secs(10),
+        );
+        prober.spacing = Duration::from_millis(1);
+        prober
+    }
+
+    #[tokio::test]
+    async fn test_probe_round_visits_each_target() {
+        let prober = prober(&["10.244.1.0", "10.244.2.0"]);
+
+        assert!(prober
+            .probe_round(&CancellationToken::new())
+            .await
+            .is_empty());
+
+        let probed = prober.transport.probed.lock().unwrap().clone();
+        assert_eq!(
+            probed,
+            vec![
+                "10.244.1.0".parse::<IpAddr>().unwrap(),
+                "10.244.2.0".parse().unwrap()
+            ]
+        );
+
+        let peers = prober.table.read();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].name, "worker");
+        assert_eq!(peers[0].received, 1);
+        assert_eq!(peers[0].loss, 0.0);
+        assert_eq!(peers[0].rtt_ms, Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peer_reported_once() {
+        let prober = prober(&["10.244.1.0"]);
+        let token = CancellationToken::new();
+
+        let mut reported = vec![];
+        for _ in 0..FAILURE_THRESHOLD + 2 {
+            reported.extend(prober.probe_round(&token).await);
+        }
+
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].name, "worker2");
+        assert_eq!(reported[0].consecutive_failures, FAILURE_THRESHOLD);
+
+        let peers = prober.table.read();
+        assert_eq!(peers[1].sent, FAILURE_THRESHOLD as u64 + 2);
+        assert_eq!(peers[1].loss, 1.0);
+        assert_eq!(peers[1].rtt_ms, None);
+    }
+
+    #[test]
+    fn test_loss_covers_recent_probes() {
+        let table = PeerTable::default();
+        let address = "10.244.1.0".parse().unwrap();
+
+        for _ in 0..PROBE_WINDOW {
+            table.record("worker", address, None);
+        }
+        for _ in 0..PROBE_WINDOW / 2 {
+            table.record("worker", address, Some(Duration::from_millis(4)));
+        }
+
+        let peer = &table.read()[0];
+        assert_eq!(peer.sent, PROBE_WINDOW as u64 * 3 / 2);
+        assert_eq!(peer.loss, 0.5);
+        assert_eq!(peer.consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_removed_target_is_forgotten() {
+        let prober = prober(&["10.244.1.0", "10.244.2.0"]);
+        let token = CancellationToken::new();
+
+        prober.probe_round(&token).await;
+        prober.targets.remove("worker2");
+        prober.probe_round(&token).await;
+
+        let peers = prober.table.read();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].name, "worker");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_round_stops_probing() {
+        let prober = prober(&[]);
+        let token = CancellationToken::new();
+        token.cancel();
+
+        assert!(prober.probe_round(&token).await.is_empty());
+        assert!(prober.transport.probed.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_udp_probe_round_trip() {
+        let token = CancellationToken::new();
+        let listener = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let server = tokio::spawn(serve_probes(
+            SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+            token.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let transport = UdpTransport::new("127.0.0.1".parse().unwrap(), port);
+        assert!(transport.probe("127.0.0.1".parse().unwrap()).await.is_ok());
+
+        token.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket shared by callers of a rate-limited API: up to `burst` calls
+/// go through at once, after that one every `1 / rate` seconds.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    /// A bucket refilling `rate` tokens a second; a rate of 0 never waits.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: burst.max(1) as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a token is available and takes it.
+    pub async fn acquire(&self) {
+        if self.rate == 0.0 {
+            return;
+        }
+
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.rate;
+                bucket.tokens = (bucket.tokens + refill).min(self.burst);
+                bucket.refilled_at = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate)
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire_waits_after_burst() {
+        let bucket = TokenBucket::new(50, 2);
+        let started = Instant::now();
+
+        for _ in 0..4 {
+            bucket.acquire().await;
+        }
+
+        // Two tokens come from the burst, the other two take 20ms each.
+        assert!(started.elapsed() >= Duration::from_millis(35));
+    }
+
+    #[tokio::test]
+    async fn test_zero_rate_is_unlimited() {
+        let bucket = TokenBucket::new(0, 1);
+        let started = Instant::now();
+
+        for _ in 0..100 {
+            bucket.acquire().await;
+        }
+
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
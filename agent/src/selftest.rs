@@ -0,0 +1,155 @@
+use std::{net::IpAddr, thread};
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use rsln::types::{
+    addr::AddressBuilder,
+    link::{Kind, LinkAttrs, VxlanAttrs},
+    neigh::NeighborBuilder,
+    routing::RoutingBuilder,
+};
+use sinabro_config::generate_mac;
+
+use crate::netlink::Netlink;
+
+const SELFTEST_BRIDGE: &str = "st_br0";
+const SELFTEST_VXLAN: &str = "st_vxlan0";
+const SELFTEST_ADDR: &str = "10.254.0.1/24";
+const SELFTEST_ROUTE_DST: &str = "10.254.1.0/24";
+const SELFTEST_NEIGH: &str = "10.254.0.2";
+
+pub struct CheckResult {
+    pub name: &'static str,
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Runs the netlink capability checks inside a throwaway network namespace.
+///
+/// The namespace is unshared on a dedicated thread, so the host network is
+/// never touched and the namespace goes away together with the thread.
+pub fn run() -> Result<Vec<CheckResult>> {
+    thread::spawn(|| -> Result<Vec<CheckResult>> {
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            return Err(anyhow!(
+                "failed to create network namespace: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(run_checks())
+    })
+    .join()
+    .map_err(|_| anyhow!("selftest thread panicked"))?
+}
+
+fn run_checks() -> Vec<CheckResult> {
+    let mut netlink = Netlink::new();
+    let mut results = vec![];
+
+    let bridge = record(
+        &mut results,
+        "create bridge",
+        netlink.ensure_link(&Kind::new_bridge(SELFTEST_BRIDGE)),
+    );
+
+    record(
+        &mut results,
+        "create vxlan",
+        netlink.ensure_link(&Kind::Vxlan {
+            attrs: LinkAttrs::new(SELFTEST_VXLAN),
+            vxlan_attrs: VxlanAttrs {
+                id: 1,
+                port: Some(8472),
+                ..Default::default()
+            },
+        }),
+    );
+
+    let Some(bridge) = bridge else {
+        return results;
+    };
+    let index = bridge.attrs().index;
+
+    record(&mut results, "set link up", netlink.link_up(&bridge));
+
+    let address = AddressBuilder::default()
+        .ip(SELFTEST_ADDR.parse::<IpNet>().unwrap())
+        .build()
+        .map_err(|e| anyhow!(e));
+    record(
+        &mut results,
+        "add address",
+        address.and_then(|address| netlink.addr_add(&bridge, &address)),
+    );
+
+    let route = RoutingBuilder::default()
+        .oif_index(index)
+        .dst(Some(SELFTEST_ROUTE_DST.parse::<IpNet>().unwrap()))
+        .build()
+        .map_err(|e| anyhow!(e));
+    record(
+        &mut results,
+        "add route",
+        route.and_then(|route| netlink.route_add(&route)),
+    );
+
+    let neigh = generate_mac().and_then(|mac| {
+        NeighborBuilder::default()
+            .link_index(index as u32)
+            .state(libc::NUD_PERMANENT)
+            .neigh_type(libc::RTN_UNICAST)
+            .ip_addr(Some(SELFTEST_NEIGH.parse::<IpAddr>()?))
+            .mac_addr(Some(mac))
+            .build()
+            .map_err(|e| anyhow!(e))
+    });
+    record(
+        &mut results,
+        "set neighbor",
+        neigh.and_then(|neigh| netlink.neigh_set(&neigh)),
+    );
+
+    results
+}
+
+fn record<T>(results: &mut Vec<CheckResult>, name: &'static str, result: Result<T>) -> Option<T> {
+    match result {
+        Ok(value) => {
+            results.push(CheckResult { name, error: None });
+            Some(value)
+        }
+        Err(e) => {
+            results.push(CheckResult {
+                name,
+                error: Some(e.to_string()),
+            });
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut results = vec![];
+
+        assert_eq!(record(&mut results, "ok", Ok(1)), Some(1));
+        assert_eq!(
+            record::<()>(&mut results, "failed", Err(anyhow!("boom"))),
+            None
+        );
+
+        assert!(results[0].passed());
+        assert!(!results[1].passed());
+        assert_eq!(results[1].error.as_deref(), Some("boom"));
+    }
+}
@@ -1,39 +1,60 @@
+use std::{net::IpAddr, sync::Arc};
+
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
-    routing::{get, put},
-    Router,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
 };
-use tokio::signal::{self};
+use serde::Deserialize;
+use serde_json::json;
 use tokio_util::sync::CancellationToken;
-use tracing::warn;
-
-use super::{ipam::Ipam, state::AppState};
+use tracing::{info, warn};
 
-pub async fn start(pod_cidr: &str, store_path: &str, shutdown: CancellationToken) -> Result<()> {
-    let ipam = Ipam::new(pod_cidr, store_path);
-    let ipam_clone = ipam.clone();
+use super::{
+    ipam::{IpamBackend, Release, Swap},
+    state::AppState,
+};
 
+pub async fn start(state: AppState, shutdown: CancellationToken) -> Result<()> {
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    axum::serve(listener, app(ipam))
-        .with_graceful_shutdown(shutdown_signal(shutdown))
-        .await
-        .unwrap();
+
+    serve(listener, state, shutdown).await
+}
+
+async fn serve(
+    listener: tokio::net::TcpListener,
+    state: AppState,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let ipam_clone = state.ipam.clone();
+    let served = axum::serve(listener, app(state))
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await;
 
     ipam_clone
         .flush()
+        .await
         .unwrap_or_else(|_| warn!("flush ip store failed"));
 
-    Ok(())
+    Ok(served?)
 }
 
-fn app(ipam: Ipam) -> Router {
-    let state = AppState { ipam };
+fn app(state: AppState) -> Router {
     Router::new()
         .route("/", get(root))
-        .route("/ipam/ip", get(pop_first))
-        .route("/ipam/ip/:ip", put(insert))
+        .route("/stats", get(stats))
+        .route("/conntrack", get(conntrack))
+        .route("/bpf/info", get(bpf_info))
+        .route("/network/interfaces", get(interfaces))
+        .route("/network/peers", get(peers))
+        .route("/metrics", get(metrics))
+        .route("/ipam/ip", get(pop_first).delete(release_owner))
+        .route("/ipam/ip/:ip", put(insert).post(compare_and_swap))
+        .route("/ipam/ips", post(allocate_many).put(insert_many))
+        .route("/ipam/stats", get(ipam_stats))
         .with_state(state)
 }
 
@@ -41,44 +62,248 @@ async fn root() -> &'static str {
     "Hello, world!"
 }
 
-async fn pop_first(State(ipam): State<Ipam>) -> impl IntoResponse {
-    ipam.pop_first().unwrap_or_default()
+#[derive(Deserialize)]
+struct AllocateParams {
+    owner: Option<String>,
+    /// `namespace/name` of the pod behind `owner`, only logged.
+    pod: Option<String>,
 }
 
-async fn insert(State(ipam): State<Ipam>, Path(ip): Path<String>) {
-    ipam.insert(&ip);
+/// Allocates one address; the body is empty when the pool is exhausted.
+async fn pop_first(
+    State(ipam): State<Arc<dyn IpamBackend>>,
+    Query(params): Query<AllocateParams>,
+) -> Response {
+    match ipam.allocate(1, params.owner.as_deref()).await {
+        Ok(ips) => {
+            if let (Some(ip), Some(pod)) = (ips.first(), &params.pod) {
+                info!("allocated {} to pod {}", ip, pod);
+            }
+            ips.first()
+                .map(IpAddr::to_string)
+                .unwrap_or_default()
+                .into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
 }
 
-async fn shutdown_signal(shutdown: CancellationToken) {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
+#[derive(Deserialize)]
+struct OwnerParams {
+    owner: String,
+}
+
+/// Releases whatever `owner` holds, so a DEL can clean up after an ADD that
+/// failed before it learned or cached its address.
+async fn release_owner(
+    State(ipam): State<Arc<dyn IpamBackend>>,
+    Query(params): Query<OwnerParams>,
+) -> Response {
+    match ipam.release_owner(&params.owner).await {
+        Ok(ips) => {
+            for ip in &ips {
+                info!("released {} held by {}", ip, params.owner);
+            }
+            Json(ips).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Most addresses one `POST /ipam/ips` may ask for.
+const MAX_BATCH: usize = 256;
+
+#[derive(Deserialize)]
+struct BatchParams {
+    count: usize,
+    owner: Option<String>,
+}
+
+/// Allocates `count` addresses at once, or as many as are left, so a node
+/// starting many pods can pre-allocate them in a single round-trip.
+async fn allocate_many(
+    State(ipam): State<Arc<dyn IpamBackend>>,
+    Query(params): Query<BatchParams>,
+) -> Response {
+    if !(1..=MAX_BATCH).contains(&params.count) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("count must be between 1 and {}", MAX_BATCH),
+        )
+            .into_response();
+    }
+
+    match ipam.allocate(params.count, params.owner.as_deref()).await {
+        Ok(ips) => Json(ips).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn insert_many(
+    State(ipam): State<Arc<dyn IpamBackend>>,
+    Json(ips): Json<Vec<IpAddr>>,
+) -> Response {
+    match ipam.release(&ips).await {
+        Ok(Release::Released) => StatusCode::OK.into_response(),
+        Ok(Release::OutOfPool(ips)) => {
+            (StatusCode::BAD_REQUEST, Json(json!({ "not_in_pool": ips }))).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn ipam_stats(State(ipam): State<Arc<dyn IpamBackend>>) -> Response {
+    match ipam.stats().await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn stats(State(state): State<AppState>) -> Response {
+    let Some(reader) = state.stats else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "stats are not available").into_response();
     };
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
+    match reader.read() {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn conntrack(State(state): State<AppState>) -> Response {
+    let Some(reader) = state.conntrack else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "conntrack is not available",
+        )
+            .into_response();
+    };
+
+    match reader.read() {
+        Ok(flows) => Json(flows).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn bpf_info(State(state): State<AppState>) -> Response {
+    let Some(reader) = state.bpf_info else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "bpf info is not available").into_response();
+    };
+
+    match tokio::task::spawn_blocking(move || reader.read()).await {
+        Ok(Ok(info)) => Json(info).into_response(),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn interfaces(State(state): State<AppState>) -> Response {
+    let Some(reader) = state.interfaces else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "interfaces are not available",
+        )
+            .into_response();
+    };
+
+    match tokio::task::spawn_blocking(move || reader.read()).await {
+        Ok(Ok(interfaces)) => Json(interfaces).into_response(),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn peers(State(state): State<AppState>) -> Response {
+    match state.peers {
+        Some(peers) => Json(peers.read()).into_response(),
+        None => (StatusCode::SERVICE_UNAVAILABLE, "probing is disabled").into_response(),
+    }
+}
+
+async fn metrics(State(state): State<AppState>) -> Response {
+    let mut out = String::new();
+    if let Some(convergence) = state.convergence {
+        convergence.write_metrics(&mut out);
+    }
+    if let Some(peers) = state.peers {
+        peers.write_metrics(&mut out);
+    }
+    if let Some(reader) = state.stats {
+        match reader.read() {
+            Ok(stats) => stats.write_metrics(&mut out),
+            Err(e) => warn!("failed to read the datapath stats: {}", e),
+        }
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
+
+async fn insert(State(ipam): State<Arc<dyn IpamBackend>>, Path(ip): Path<String>) -> Response {
+    let Ok(ip) = ip.parse() else {
+        return (StatusCode::BAD_REQUEST, format!("invalid address {:?}", ip)).into_response();
+    };
+
+    match ipam.release(&[ip]).await {
+        Ok(Release::Released) => StatusCode::OK.into_response(),
+        Ok(Release::OutOfPool(_)) => {
+            (StatusCode::NOT_FOUND, "address is not in the pool").into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Body of a conditional update: the owner the address must currently have
+/// (`null` for free) and the one it should get (`null` to release it).
+#[derive(Deserialize)]
+struct SwapRequest {
+    expected_owner: Option<String>,
+    owner: Option<String>,
+}
+
+async fn compare_and_swap(
+    State(ipam): State<Arc<dyn IpamBackend>>,
+    Path(ip): Path<String>,
+    Json(request): Json<SwapRequest>,
+) -> Response {
+    let Ok(ip) = ip.parse() else {
+        return (StatusCode::BAD_REQUEST, format!("invalid address {:?}", ip)).into_response();
     };
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+    let swap = ipam
+        .reserve(
+            ip,
+            request.expected_owner.as_deref(),
+            request.owner.as_deref(),
+        )
+        .await;
 
-    tokio::select! {
-        _ = ctrl_c => {}
-        _ = terminate => {}
-        _ = shutdown.cancelled() => {}
+    match swap {
+        Ok(Swap::Swapped) => StatusCode::OK.into_response(),
+        Ok(Swap::Conflict(current)) => (
+            StatusCode::CONFLICT,
+            Json(json!({ "current_owner": current })),
+        )
+            .into_response(),
+        Ok(Swap::OutOfPool) => {
+            (StatusCode::NOT_FOUND, "address is not in the pool").into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::Mutex,
+    };
 
     use super::*;
+    use crate::convergence::ConvergenceMetrics;
+    use crate::interfaces::{tests::StubSource, InterfaceReader};
+    use crate::prober::PeerTable;
+    use crate::server::ipam::{Ipam, IpamStats};
+    use crate::server::store::IpStore;
     use axum::{
         body::Body,
         http::{Method, Request},
@@ -96,9 +321,8 @@ mod tests {
         let shutdown_clone = shutdown.clone();
 
         let server = tokio::spawn(async move {
-            start(pod_cidr, store_path.to_str().unwrap(), shutdown_clone)
-                .await
-                .unwrap();
+            let ipam = Ipam::new(pod_cidr, store_path.to_str().unwrap()).unwrap();
+            start(AppState::new(ipam), shutdown_clone).await.unwrap();
         });
 
         let notify = tokio::spawn(async move {
@@ -109,13 +333,26 @@ mod tests {
         tokio::try_join!(server, notify).unwrap();
 
         assert!(std::path::Path::new(store_path_clone.to_str().unwrap()).exists());
-        assert_eq!(
-            std::fs::read_to_string(store_path_clone.to_str().unwrap())
-                .unwrap()
-                .lines()
-                .count(),
-            253
-        );
+        let data = std::fs::read_to_string(store_path_clone.to_str().unwrap()).unwrap();
+        let store = IpStore::parse(&data, pod_cidr).unwrap();
+        assert_eq!(store.free.len(), 253);
+    }
+
+    #[tokio::test]
+    async fn test_serve_flushes_before_returning() {
+        let pod_cidr = "10.244.0.0/24";
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new(pod_cidr, store_path.to_str().unwrap()).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        serve(listener, AppState::new(ipam), shutdown)
+            .await
+            .unwrap();
+
+        assert!(store_path.exists());
     }
 
     #[tokio::test]
@@ -123,8 +360,8 @@ mod tests {
         let pod_cidr = "10.244.0.0/24";
         let tmp_dir = tempfile::tempdir().unwrap();
         let store_path = tmp_dir.path().join("ip_store");
-        let ipam = Ipam::new(pod_cidr, store_path.to_str().unwrap());
-        let app = app(ipam);
+        let ipam = Ipam::new(pod_cidr, store_path.to_str().unwrap()).unwrap();
+        let app = app(AppState::new(ipam));
 
         let response = app
             .oneshot(
@@ -143,19 +380,44 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_put_ipam_ip() {
+    async fn test_get_ipam_ip_with_owner() {
         let pod_cidr = "10.244.0.0/24";
         let tmp_dir = tempfile::tempdir().unwrap();
         let store_path = tmp_dir.path().join("ip_store");
-        let ipam = Ipam::new(pod_cidr, store_path.to_str().unwrap());
+        let ipam = Ipam::new(pod_cidr, store_path.to_str().unwrap()).unwrap();
         let ipam_clone = ipam.clone();
-        let app = app(ipam);
+        let app = app(AppState::new(ipam));
 
         let response = app
             .oneshot(
                 Request::builder()
-                    .method(Method::PUT)
-                    .uri("/ipam/ip/10.244.0.1")
+                    .uri("/ipam/ip?owner=abc123/net1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            ipam_clone.owner_of("10.244.0.2").as_deref(),
+            Some("abc123/net1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_ipam_ips() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+        let ipam_clone = ipam.clone();
+        let app = app(AppState::new(ipam));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/ipam/ips?count=16")
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -164,7 +426,388 @@ mod tests {
 
         assert_eq!(response.status(), 200);
 
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let ips = serde_json::from_slice::<Vec<IpAddr>>(&body).unwrap();
+        let unique = ips.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(ips.len(), 16);
+        assert_eq!(unique.len(), 16);
+        assert_eq!(ipam_clone.count(), 253 - 16);
+    }
+
+    #[tokio::test]
+    async fn test_post_ipam_ips_rejects_bad_count() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+        let ipam_clone = ipam.clone();
+        let app = app(AppState::new(ipam));
+
+        for count in [0, MAX_BATCH + 1, usize::MAX] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::POST)
+                        .uri(format!("/ipam/ips?count={}", count))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 400, "count={}", count);
+        }
+        assert_eq!(ipam_clone.count(), 253);
+    }
+
+    #[tokio::test]
+    async fn test_get_stats_without_dataplane() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+        let app = app(AppState::new(ipam));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/stats")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 503);
+    }
+
+    #[tokio::test]
+    async fn test_get_network_interfaces() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+        let reader = InterfaceReader::new(Arc::new(StubSource::default()), &["cni0", "eth0"]);
+        let app = app(AppState::new(ipam).with_interfaces(reader));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/network/interfaces")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(json[0]["name"], "cni0");
+        assert_eq!(json[1]["mtu"], 1450);
+        assert_eq!(json[1]["stats"]["rx_packets"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_get_network_peers() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+
+        let request = || {
+            Request::builder()
+                .uri("/network/peers")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app(AppState::new(ipam.clone()))
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+
+        let response = app(AppState::new(ipam).with_peers(PeerTable::default()))
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"[]");
+    }
+
+    #[tokio::test]
+    async fn test_get_metrics() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+        let app = app(AppState::new(ipam).with_convergence(ConvergenceMetrics::default()));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("# TYPE sinabro_overlay_convergence_duration_seconds histogram"));
+    }
+
+    #[tokio::test]
+    async fn test_put_ipam_ip() {
+        let pod_cidr = "10.244.0.0/24";
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new(pod_cidr, store_path.to_str().unwrap()).unwrap();
+        let ipam_clone = ipam.clone();
+        let app = app(AppState::new(ipam));
+
+        let leased = ipam_clone.pop_first().unwrap();
+        assert_eq!(leased, "10.244.0.2");
+
+        let put = |uri: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = put("/ipam/ip/10.244.0.2").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(ipam_clone.count(), 253);
+
+        // The gateway and addresses outside the pool are never handed out.
+        for ip in ["10.244.0.1", "10.244.0.0", "10.245.0.2"] {
+            let response = put(&format!("/ipam/ip/{}", ip)).await.unwrap();
+            assert_eq!(response.status(), 404, "{}", ip);
+        }
+        assert_eq!(ipam_clone.count(), 253);
+
         let result = ipam_clone.pop_first().unwrap();
-        assert_eq!(result, "10.244.0.1");
+        assert_eq!(result, "10.244.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_post_ipam_ip_conflict() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+        let ipam_clone = ipam.clone();
+
+        let request = |body: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/ipam/ip/10.244.0.9")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
+
+        let response = app(AppState::new(ipam.clone()))
+            .oneshot(request(r#"{"expected_owner":null,"owner":"abc123/eth0"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        // Releasing on behalf of another container must not free the address.
+        let response = app(AppState::new(ipam))
+            .oneshot(request(r#"{"expected_owner":"def456/eth0","owner":null}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 409);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(json["current_owner"], "abc123/eth0");
+        assert_eq!(
+            ipam_clone.owner_of("10.244.0.9").as_deref(),
+            Some("abc123/eth0")
+        );
+    }
+
+    /// A pool that lives only in memory, standing in for an external store.
+    struct MemoryBackend {
+        pool: BTreeSet<IpAddr>,
+        free: Mutex<BTreeSet<IpAddr>>,
+        leases: Mutex<BTreeMap<IpAddr, String>>,
+    }
+
+    impl MemoryBackend {
+        fn new(pool: &[&str]) -> Self {
+            let pool = pool
+                .iter()
+                .map(|ip| ip.parse::<IpAddr>().unwrap())
+                .collect::<BTreeSet<_>>();
+
+            Self {
+                free: Mutex::new(pool.clone()),
+                pool,
+                leases: Mutex::default(),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl IpamBackend for MemoryBackend {
+        async fn allocate(&self, count: usize, owner: Option<&str>) -> Result<Vec<IpAddr>> {
+            let mut free = self.free.lock().unwrap();
+            let ips = free.iter().take(count).copied().collect::<Vec<_>>();
+
+            for ip in &ips {
+                free.remove(ip);
+                if let Some(owner) = owner {
+                    self.leases.lock().unwrap().insert(*ip, owner.to_owned());
+                }
+            }
+
+            Ok(ips)
+        }
+
+        async fn release(&self, ips: &[IpAddr]) -> Result<Release> {
+            let mut free = self.free.lock().unwrap();
+            let mut leases = self.leases.lock().unwrap();
+            let out_of_pool = ips
+                .iter()
+                .filter(|ip| !self.pool.contains(ip))
+                .copied()
+                .collect::<Vec<_>>();
+            if !out_of_pool.is_empty() {
+                return Ok(Release::OutOfPool(out_of_pool));
+            }
+
+            for ip in ips {
+                leases.remove(ip);
+                free.insert(*ip);
+            }
+
+            Ok(Release::Released)
+        }
+
+        async fn release_owner(&self, owner: &str) -> Result<Vec<IpAddr>> {
+            let mut leases = self.leases.lock().unwrap();
+            let ips = leases
+                .iter()
+                .filter(|(_, holder)| *holder == owner)
+                .map(|(ip, _)| *ip)
+                .collect::<Vec<_>>();
+
+            for ip in &ips {
+                leases.remove(ip);
+                self.free.lock().unwrap().insert(*ip);
+            }
+
+            Ok(ips)
+        }
+
+        async fn reserve(
+            &self,
+            ip: IpAddr,
+            expected: Option<&str>,
+            owner: Option<&str>,
+        ) -> Result<Swap> {
+            let mut free = self.free.lock().unwrap();
+            let mut leases = self.leases.lock().unwrap();
+            if !self.pool.contains(&ip) {
+                return Ok(Swap::OutOfPool);
+            }
+            let current = match free.contains(&ip) {
+                true => None,
+                false => Some(leases.get(&ip).cloned().unwrap_or_default()),
+            };
+            if current.as_deref() != expected {
+                return Ok(Swap::Conflict(current));
+            }
+
+            match owner {
+                Some(owner) => {
+                    free.remove(&ip);
+                    leases.insert(ip, owner.to_owned());
+                }
+                None => {
+                    leases.remove(&ip);
+                    free.insert(ip);
+                }
+            }
+
+            Ok(Swap::Swapped)
+        }
+
+        async fn stats(&self) -> Result<IpamStats> {
+            Ok(IpamStats {
+                pool: "memory".to_owned(),
+                free: self.free.lock().unwrap().len(),
+                leased: self.leases.lock().unwrap().len(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_router_over_memory_backend() {
+        let backend = MemoryBackend::new(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        let app = app(AppState::new(backend));
+
+        let call = |method: Method, uri: &str, body: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let (status, body) = call(Method::GET, "/ipam/ip?owner=abc123/eth0", "").await;
+        assert_eq!((status.as_u16(), body.as_str()), (200, "10.0.0.1"));
+
+        let (_, body) = call(Method::POST, "/ipam/ips?count=5", "").await;
+        assert_eq!(body, r#"["10.0.0.2","10.0.0.3"]"#);
+
+        let (_, body) = call(Method::GET, "/ipam/ip", "").await;
+        assert_eq!(body, "");
+
+        let (status, _) = call(
+            Method::POST,
+            "/ipam/ip/10.0.0.1",
+            r#"{"expected_owner":"def456/eth0","owner":null}"#,
+        )
+        .await;
+        assert_eq!(status, 409);
+
+        let (status, _) = call(Method::PUT, "/ipam/ips", r#"["10.0.0.2","10.0.0.3"]"#).await;
+        assert_eq!(status, 200);
+        let (status, body) = call(Method::DELETE, "/ipam/ip?owner=abc123/eth0", "").await;
+        assert_eq!((status.as_u16(), body.as_str()), (200, r#"["10.0.0.1"]"#));
+        let (_, body) = call(Method::DELETE, "/ipam/ip?owner=abc123/eth0", "").await;
+        assert_eq!(body, "[]");
+        let (status, _) = call(Method::PUT, "/ipam/ip/10.0.0.1", "").await;
+        assert_eq!(status, 200);
+        let (status, _) = call(Method::PUT, "/ipam/ip/10.0.0.9", "").await;
+        assert_eq!(status, 404);
+        let (status, body) = call(Method::PUT, "/ipam/ips", r#"["10.0.0.1","10.0.0.9"]"#).await;
+        assert_eq!(
+            (status.as_u16(), body.as_str()),
+            (400, r#"{"not_in_pool":["10.0.0.9"]}"#)
+        );
+
+        let (_, body) = call(Method::GET, "/ipam/stats", "").await;
+        let stats = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(stats["free"], 3);
+        assert_eq!(stats["leased"], 0);
     }
 }
@@ -1,88 +1,295 @@
 use std::{
-    collections::BTreeSet,
     net::IpAddr,
     sync::{Arc, Mutex},
 };
 
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
 use axum::extract::FromRef;
-use ipnet::IpNet;
+use serde::Serialize;
+use sinabro_config::write_atomic;
 
-use super::state::AppState;
+use super::{state::AppState, store::IpStore};
 
-// TODO: abstract this to a trait
+/// Outcome of `Ipam::compare_and_swap`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Swap {
+    Swapped,
+    /// The address is not held as expected; carries its current owner, with
+    /// an empty owner for an address taken without one.
+    Conflict(Option<String>),
+    /// The address is not part of the pool at all.
+    OutOfPool,
+}
+
+/// Outcome of `IpamBackend::release`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Release {
+    Released,
+    /// Nothing was released: these addresses are not part of the pool.
+    OutOfPool(Vec<IpAddr>),
+}
+
+/// Occupancy of an address pool. `leased` counts addresses held by an
+/// owner; ones taken without an owner are only missing from `free`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IpamStats {
+    pub pool: String,
+    pub free: usize,
+    pub leased: usize,
+}
+
+/// Where the API server allocates pod addresses from. The file-backed
+/// `Ipam` is the default; a backend can keep its pool in an external store
+/// instead, which is why every call may fail.
+#[async_trait]
+pub trait IpamBackend: Send + Sync {
+    /// Takes up to `count` free addresses, fewer if the pool runs short,
+    /// leasing each to `owner` when one is given.
+    async fn allocate(&self, count: usize, owner: Option<&str>) -> Result<Vec<IpAddr>>;
+
+    /// Returns `ips` to the pool, whoever holds them. Either all of them are
+    /// released or, when any is outside the pool, none.
+    async fn release(&self, ips: &[IpAddr]) -> Result<Release>;
+
+    /// Returns every address leased to `owner` to the pool, and lists them.
+    async fn release_owner(&self, owner: &str) -> Result<Vec<IpAddr>>;
+
+    /// Moves `ip` to `owner` (or frees it when `owner` is `None`), but only
+    /// if it is currently held by `expected` (or free when `expected` is
+    /// `None`), atomically.
+    async fn reserve(
+        &self,
+        ip: IpAddr,
+        expected: Option<&str>,
+        owner: Option<&str>,
+    ) -> Result<Swap>;
+
+    async fn stats(&self) -> Result<IpamStats>;
+
+    /// Persists the pool on shutdown, for backends that keep it locally.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The pool in memory, written to `store_path` on flush.
 #[derive(Clone)]
 pub struct Ipam {
-    pub ip_store: Arc<Mutex<BTreeSet<IpAddr>>>,
+    pub ip_store: Arc<Mutex<IpStore>>,
     pub store_path: String,
 }
 
 impl Ipam {
-    pub fn new(pod_cidr: &str, store_path: &str) -> Self {
-        let ip_store = Arc::new(Mutex::new(Self::load(store_path).unwrap_or_else(|| {
-            pod_cidr
-                .parse::<IpNet>()
-                .map(|subnet| subnet.hosts().skip(1).collect::<BTreeSet<IpAddr>>())
-                .unwrap_or_else(|_| BTreeSet::new())
-        })));
-
-        Self {
-            ip_store,
+    pub fn new(pod_cidr: &str, store_path: &str) -> Result<Self> {
+        let ip_store =
+            IpStore::load(store_path, pod_cidr)?.unwrap_or_else(|| IpStore::new(pod_cidr));
+
+        Ok(Self {
+            ip_store: Arc::new(Mutex::new(ip_store)),
             store_path: store_path.to_owned(),
+        })
+    }
+
+    pub fn pop_first(&self) -> Option<String> {
+        self.allocate(None)
+    }
+
+    /// Takes the lowest free address, recording it as leased to `owner`
+    /// (a container attachment) when one is given.
+    pub fn allocate(&self, owner: Option<&str>) -> Option<String> {
+        let mut ip_store = self.ip_store.lock().unwrap();
+        let ip = ip_store.free.pop_first()?;
+
+        if let Some(owner) = owner {
+            ip_store.leases.insert(ip, owner.to_owned());
         }
+
+        Some(ip.to_string())
     }
 
-    fn load(store_path: &str) -> Option<BTreeSet<IpAddr>> {
-        if std::path::Path::new(store_path).exists() {
-            let data = std::fs::read_to_string(store_path).ok()?;
-            let ip_store = data
-                .lines()
-                .map(|ip| ip.parse::<IpAddr>().unwrap())
-                .collect::<BTreeSet<IpAddr>>();
-            Some(ip_store)
-        } else {
-            None
+    /// Takes up to `count` of the lowest free addresses in one go, fewer if
+    /// the pool runs short. Each is leased to `owner` when one is given.
+    pub fn allocate_many(&self, count: usize, owner: Option<&str>) -> Vec<IpAddr> {
+        let mut ip_store = self.ip_store.lock().unwrap();
+        let mut ips = Vec::with_capacity(count.min(ip_store.free.len()));
+
+        while ips.len() < count {
+            let Some(ip) = ip_store.free.pop_first() else {
+                break;
+            };
+            if let Some(owner) = owner {
+                ip_store.leases.insert(ip, owner.to_owned());
+            }
+            ips.push(ip);
         }
+
+        ips
     }
 
-    pub fn pop_first(&self) -> Option<String> {
+    /// Returns `ip` to the pool, whoever holds it.
+    pub fn insert(&self, ip: &str) -> Result<()> {
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|e| anyhow!("invalid address {:?}: {}", ip, e))?;
+
+        match self.insert_many(&[ip]) {
+            Release::Released => Ok(()),
+            Release::OutOfPool(_) => bail!("{} is not in the pool", ip),
+        }
+    }
+
+    /// Moves `ip` to `owner` (or frees it when `owner` is `None`), but only if
+    /// it is currently held by `expected` (or free when `expected` is `None`).
+    /// The check and the update happen under one lock, so concurrent callers
+    /// cannot both win.
+    pub fn compare_and_swap(
+        &self,
+        ip: IpAddr,
+        expected: Option<&str>,
+        owner: Option<&str>,
+    ) -> Swap {
+        let mut ip_store = self.ip_store.lock().unwrap();
+        if !ip_store.in_pool(&ip) {
+            return Swap::OutOfPool;
+        }
+
+        let current = match ip_store.free.contains(&ip) {
+            true => None,
+            false => Some(ip_store.leases.get(&ip).cloned().unwrap_or_default()),
+        };
+        if current.as_deref() != expected {
+            return Swap::Conflict(current);
+        }
+
+        match owner {
+            Some(owner) => {
+                ip_store.free.remove(&ip);
+                ip_store.leases.insert(ip, owner.to_owned());
+            }
+            None => {
+                ip_store.leases.remove(&ip);
+                ip_store.free.insert(ip);
+            }
+        }
+
+        Swap::Swapped
+    }
+
+    /// Returns every address in `ips` to the pool under a single lock, or
+    /// none of them if any is not one the pool hands out.
+    pub fn insert_many(&self, ips: &[IpAddr]) -> Release {
+        let mut ip_store = self.ip_store.lock().unwrap();
+
+        let out_of_pool = ips
+            .iter()
+            .filter(|ip| !ip_store.in_pool(ip))
+            .copied()
+            .collect::<Vec<_>>();
+        if !out_of_pool.is_empty() {
+            return Release::OutOfPool(out_of_pool);
+        }
+
+        for ip in ips {
+            ip_store.leases.remove(ip);
+            ip_store.free.insert(*ip);
+        }
+
+        Release::Released
+    }
+
+    /// Frees every address leased to `owner` under a single lock.
+    pub fn release_owner(&self, owner: &str) -> Vec<IpAddr> {
+        let mut ip_store = self.ip_store.lock().unwrap();
+        let ips = ip_store
+            .leases
+            .iter()
+            .filter(|(_, holder)| *holder == owner)
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+
+        for ip in &ips {
+            ip_store.leases.remove(ip);
+            ip_store.free.insert(*ip);
+        }
+
+        ips
+    }
+
+    /// Owners of every leased address, e.g. to tell live attachments apart
+    /// from leftovers.
+    pub fn lease_owners(&self) -> Vec<String> {
         self.ip_store
             .lock()
             .unwrap()
-            .pop_first()
-            .map(|ip| ip.to_string())
+            .leases
+            .values()
+            .cloned()
+            .collect()
     }
 
-    pub fn insert(&self, ip: &str) {
+    #[cfg(test)]
+    pub fn owner_of(&self, ip: &str) -> Option<String> {
         self.ip_store
             .lock()
             .unwrap()
-            .insert(ip.parse::<IpAddr>().unwrap());
+            .leases
+            .get(&ip.parse::<IpAddr>().unwrap())
+            .cloned()
     }
 
     pub fn flush(&self) -> anyhow::Result<()> {
-        let ip_store = self.ip_store.lock().unwrap();
-        let data = ip_store
-            .iter()
-            .map(|ip| ip.to_string())
-            .collect::<Vec<String>>()
-            .join("\n");
-
-        let path = std::path::Path::new(&self.store_path);
-        if let Some(dir) = path.parent() {
-            std::fs::create_dir_all(dir)?;
-        }
+        let data = self.ip_store.lock().unwrap().to_json()?;
 
-        std::fs::write(path, data)?;
-        Ok(())
+        // A crash mid-write must not leave a truncated store behind.
+        write_atomic(&self.store_path, &data)
     }
 
     #[cfg(test)]
     pub fn count(&self) -> usize {
-        self.ip_store.lock().unwrap().len()
+        self.ip_store.lock().unwrap().free.len()
     }
 }
 
-impl FromRef<AppState> for Ipam {
+#[async_trait]
+impl IpamBackend for Ipam {
+    async fn allocate(&self, count: usize, owner: Option<&str>) -> Result<Vec<IpAddr>> {
+        Ok(self.allocate_many(count, owner))
+    }
+
+    async fn release(&self, ips: &[IpAddr]) -> Result<Release> {
+        Ok(self.insert_many(ips))
+    }
+
+    async fn release_owner(&self, owner: &str) -> Result<Vec<IpAddr>> {
+        Ok(Ipam::release_owner(self, owner))
+    }
+
+    async fn reserve(
+        &self,
+        ip: IpAddr,
+        expected: Option<&str>,
+        owner: Option<&str>,
+    ) -> Result<Swap> {
+        Ok(self.compare_and_swap(ip, expected, owner))
+    }
+
+    async fn stats(&self) -> Result<IpamStats> {
+        let ip_store = self.ip_store.lock().unwrap();
+
+        Ok(IpamStats {
+            pool: ip_store.pool.clone(),
+            free: ip_store.free.len(),
+            leased: ip_store.leases.len(),
+        })
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ipam::flush(self)
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn IpamBackend> {
     fn from_ref(state: &AppState) -> Self {
         state.ipam.clone()
     }
@@ -96,7 +303,7 @@ mod tests {
     fn test_ipam() {
         let tmp_dir = tempfile::tempdir().unwrap();
         let store_path = tmp_dir.path().join("ip_store");
-        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap());
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
 
         assert!(!std::path::Path::new(store_path.to_str().unwrap()).exists());
         assert_eq!(ipam.count(), 253);
@@ -109,7 +316,9 @@ mod tests {
         assert_eq!(addr, "10.244.0.4");
         assert_eq!(ipam.count(), 250);
 
-        ipam.insert("10.244.0.3");
+        ipam.insert("10.244.0.3").unwrap();
+        assert!(ipam.insert("not-an-ip").is_err());
+        assert!(ipam.insert("10.244.0.1").is_err());
         assert_eq!(ipam.count(), 251);
 
         let addr = ipam.pop_first().unwrap();
@@ -120,12 +329,116 @@ mod tests {
 
         assert!(std::path::Path::new(store_path.to_str().unwrap()).exists());
         let data = std::fs::read_to_string(store_path.to_str().unwrap()).unwrap();
-        assert_eq!(data.lines().count(), ipam.count());
+        let store = IpStore::parse(&data, "10.244.0.0/24").unwrap();
+        assert_eq!(store.free.len(), ipam.count());
 
-        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap());
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
         assert_eq!(ipam.count(), 250);
 
         let addr = ipam.pop_first().unwrap();
         assert_eq!(addr, "10.244.0.5");
     }
+
+    #[test]
+    fn test_ipam_leases_per_attachment() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+
+        let eth0 = ipam.allocate(Some("abc123/eth0")).unwrap();
+        let net1 = ipam.allocate(Some("abc123/net1")).unwrap();
+        assert_ne!(eth0, net1);
+        assert_eq!(ipam.owner_of(&eth0).as_deref(), Some("abc123/eth0"));
+        assert_eq!(ipam.owner_of(&net1).as_deref(), Some("abc123/net1"));
+
+        ipam.insert(&net1).unwrap();
+        assert_eq!(ipam.owner_of(&net1), None);
+        assert_eq!(ipam.owner_of(&eth0).as_deref(), Some("abc123/eth0"));
+    }
+
+    #[test]
+    fn test_ipam_release_owner() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+
+        let eth0 = ipam.allocate(Some("abc123/eth0")).unwrap();
+        let other = ipam.allocate(Some("def456/eth0")).unwrap();
+
+        assert_eq!(
+            ipam.release_owner("abc123/eth0"),
+            vec![eth0.parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(ipam.owner_of(&eth0), None);
+        assert_eq!(ipam.owner_of(&other).as_deref(), Some("def456/eth0"));
+        assert!(ipam.release_owner("abc123/eth0").is_empty());
+        assert_eq!(ipam.count(), 252);
+    }
+
+    #[test]
+    fn test_ipam_allocate_many() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+
+        let ips = ipam.allocate_many(3, Some("abc123/eth0"));
+        assert_eq!(
+            ips,
+            ["10.244.0.2", "10.244.0.3", "10.244.0.4"].map(|ip| ip.parse::<IpAddr>().unwrap())
+        );
+        assert_eq!(ipam.owner_of("10.244.0.3").as_deref(), Some("abc123/eth0"));
+
+        assert_eq!(ipam.allocate_many(300, None).len(), 250);
+        assert!(ipam.allocate_many(1, None).is_empty());
+
+        assert_eq!(ipam.insert_many(&ips), Release::Released);
+        assert_eq!(ipam.count(), 3);
+        assert_eq!(ipam.owner_of("10.244.0.3"), None);
+
+        let gateway = "10.244.0.1".parse::<IpAddr>().unwrap();
+        let outside = "10.245.0.2".parse::<IpAddr>().unwrap();
+        let leased = "10.244.0.9".parse::<IpAddr>().unwrap();
+        assert_eq!(
+            ipam.insert_many(&[leased, gateway, outside]),
+            Release::OutOfPool(vec![gateway, outside])
+        );
+        assert_eq!(ipam.count(), 3);
+    }
+
+    #[test]
+    fn test_ipam_compare_and_swap() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store_path = tmp_dir.path().join("ip_store");
+        let ipam = Ipam::new("10.244.0.0/24", store_path.to_str().unwrap()).unwrap();
+        let ip = "10.244.0.7".parse::<IpAddr>().unwrap();
+
+        assert_eq!(
+            ipam.compare_and_swap(ip, None, Some("abc123/eth0")),
+            Swap::Swapped
+        );
+        assert_eq!(ipam.owner_of("10.244.0.7").as_deref(), Some("abc123/eth0"));
+        assert_eq!(ipam.count(), 252);
+
+        assert_eq!(
+            ipam.compare_and_swap(ip, None, Some("def456/eth0")),
+            Swap::Conflict(Some("abc123/eth0".to_owned()))
+        );
+        assert_eq!(
+            ipam.compare_and_swap(ip, Some("def456/eth0"), None),
+            Swap::Conflict(Some("abc123/eth0".to_owned()))
+        );
+
+        assert_eq!(
+            ipam.compare_and_swap(ip, Some("abc123/eth0"), None),
+            Swap::Swapped
+        );
+        assert_eq!(ipam.owner_of("10.244.0.7"), None);
+        assert_eq!(ipam.count(), 253);
+
+        let gateway = "10.244.0.1".parse::<IpAddr>().unwrap();
+        assert_eq!(
+            ipam.compare_and_swap(gateway, None, Some("abc123/eth0")),
+            Swap::OutOfPool
+        );
+    }
 }
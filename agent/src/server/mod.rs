@@ -1,3 +1,5 @@
 pub mod api_server;
-mod ipam;
-mod state;
+pub mod ipam;
+pub mod state;
+pub mod status_server;
+mod store;
@@ -1,6 +1,62 @@
-use super::ipam::Ipam;
+use std::sync::Arc;
+
+use super::ipam::IpamBackend;
+use crate::{
+    bpf_info::BpfInfoReader, conntrack::ConntrackReader, convergence::ConvergenceMetrics,
+    interfaces::InterfaceReader, prober::PeerTable, stats::StatsReader,
+};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub ipam: Ipam,
+    pub ipam: Arc<dyn IpamBackend>,
+    pub stats: Option<StatsReader>,
+    pub conntrack: Option<ConntrackReader>,
+    pub interfaces: Option<InterfaceReader>,
+    pub peers: Option<PeerTable>,
+    pub convergence: Option<ConvergenceMetrics>,
+    pub bpf_info: Option<BpfInfoReader>,
+}
+
+impl AppState {
+    pub fn new(ipam: impl IpamBackend + 'static) -> Self {
+        Self {
+            ipam: Arc::new(ipam),
+            stats: None,
+            conntrack: None,
+            interfaces: None,
+            peers: None,
+            convergence: None,
+            bpf_info: None,
+        }
+    }
+
+    pub fn with_stats(mut self, stats: StatsReader) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn with_conntrack(mut self, conntrack: ConntrackReader) -> Self {
+        self.conntrack = Some(conntrack);
+        self
+    }
+
+    pub fn with_interfaces(mut self, interfaces: InterfaceReader) -> Self {
+        self.interfaces = Some(interfaces);
+        self
+    }
+
+    pub fn with_peers(mut self, peers: PeerTable) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    pub fn with_convergence(mut self, convergence: ConvergenceMetrics) -> Self {
+        self.convergence = Some(convergence);
+        self
+    }
+
+    pub fn with_bpf_info(mut self, bpf_info: BpfInfoReader) -> Self {
+        self.bpf_info = Some(bpf_info);
+        self
+    }
 }
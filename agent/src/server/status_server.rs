@@ -0,0 +1,117 @@
+use anyhow::Result;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use tokio_util::sync::CancellationToken;
+
+use crate::status::AgentStatus;
+
+/// Serves startup progress on its own listener so it is reachable before
+/// the API server, which needs the pod CIDR and the dataplane, comes up.
+pub async fn start(addr: &str, status: AgentStatus, shutdown: CancellationToken) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+
+    axum::serve(listener, app(status))
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+
+    Ok(())
+}
+
+fn app(status: AgentStatus) -> Router {
+    Router::new()
+        .route("/status", get(status_report))
+        .route("/status/nodes", get(node_cache))
+        .route("/readyz", get(readyz))
+        .with_state(status)
+}
+
+async fn status_report(State(status): State<AgentStatus>) -> impl IntoResponse {
+    Json(status.report())
+}
+
+/// The node cache, read-only, to see what the overlay believes about
+/// each peer.
+async fn node_cache(State(status): State<AgentStatus>) -> impl IntoResponse {
+    Json(status.nodes().snapshot())
+}
+
+async fn readyz(State(status): State<AgentStatus>) -> impl IntoResponse {
+    if status.is_ready() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "starting")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::status::StatusReport;
+
+    #[tokio::test]
+    async fn test_readyz_while_starting() {
+        let response = app(AgentStatus::new())
+            .oneshot(
+                Request::builder()
+                    .uri("/readyz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 503);
+    }
+
+    #[tokio::test]
+    async fn test_get_status() {
+        let response = app(AgentStatus::new())
+            .oneshot(
+                Request::builder()
+                    .uri("/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let report = serde_json::from_slice::<StatusReport>(&body).unwrap();
+        assert!(!report.ready);
+    }
+
+    #[tokio::test]
+    async fn test_get_node_cache() {
+        let status = AgentStatus::new();
+        let node = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "worker" },
+            "spec": { "podCIDR": "10.244.1.0/24" },
+            "status": { "addresses": [{ "address": "172.18.0.2", "type": "InternalIP" }] }
+        }))
+        .unwrap();
+        status.nodes().fill([&node]);
+
+        let response = app(status)
+            .oneshot(
+                Request::builder()
+                    .uri("/status/nodes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let nodes = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(nodes["worker"]["internal_ip"], "172.18.0.2");
+        assert_eq!(nodes["worker"]["pod_cidrs"][0], "10.244.1.0/24");
+        assert!(nodes["worker"]["vxlan_mac"].is_null());
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sinabro_config::gateway_for;
+use tracing::warn;
+
+pub const STORE_VERSION: u32 = 1;
+
+/// Upper bound on the addresses tracked per pool; an IPv6 /64 pod CIDR has
+/// far more addresses than a node will ever hand out.
+const MAX_POOL_SIZE: usize = 65536;
+
+/// On-disk representation of the IPAM state.
+///
+/// Version 0 was a bare newline-separated list of free addresses; it has no
+/// version marker and is migrated transparently on load.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpStore {
+    pub version: u32,
+    pub pool: String,
+    pub free: BTreeSet<IpAddr>,
+    #[serde(default)]
+    pub leases: BTreeMap<IpAddr, String>,
+}
+
+impl IpStore {
+    pub fn new(pool: &str) -> Self {
+        let free = pool
+            .parse::<IpNet>()
+            .map(|subnet| pool_hosts(&subnet).collect())
+            .unwrap_or_default();
+
+        Self {
+            version: STORE_VERSION,
+            pool: pool.to_owned(),
+            free,
+            leases: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(store_path: &str, pool: &str) -> Result<Option<Self>> {
+        if !Path::new(store_path).exists() {
+            return Ok(None);
+        }
+
+        let data = std::fs::read_to_string(store_path)?;
+        Self::parse(&data, pool)
+            .map(Some)
+            .with_context(|| format!("failed to load ip store {}", store_path))
+    }
+
+    pub fn parse(data: &str, pool: &str) -> Result<Self> {
+        // Read as a legacy list this would be a pool with nothing free, and
+        // every ADD would fail without saying why.
+        if data.trim().is_empty() {
+            bail!("ip store is empty; remove it to start over from {}", pool);
+        }
+
+        if !data.trim_start().starts_with('{') {
+            return Self::migrate_legacy(data, pool);
+        }
+
+        let store = serde_json::from_str::<Self>(data)?;
+
+        if store.version > STORE_VERSION {
+            bail!(
+                "ip store version {} is newer than the supported version {}",
+                store.version,
+                STORE_VERSION
+            );
+        }
+
+        if !same_pool(&store.pool, pool) {
+            return Ok(store.reseed(pool));
+        }
+
+        Ok(store)
+    }
+
+    /// Starts over on `pool` after the node's pod CIDR changed. Leases on
+    /// addresses the new pool still hands out are kept; the rest are dropped.
+    fn reseed(self, pool: &str) -> Self {
+        warn!(
+            "ip store was written for pool {}, re-seeding it for {}",
+            self.pool, pool
+        );
+
+        let mut store = Self::new(pool);
+        for (ip, owner) in self.leases {
+            if store.free.remove(&ip) {
+                store.leases.insert(ip, owner);
+            } else {
+                warn!(
+                    "dropping lease of {} held by {}: not in {}",
+                    ip, owner, pool
+                );
+            }
+        }
+
+        store
+    }
+
+    /// Whether `ip` is one of the addresses this pool hands out, free or not.
+    pub fn in_pool(&self, ip: &IpAddr) -> bool {
+        if self.free.contains(ip) || self.leases.contains_key(ip) {
+            return true;
+        }
+
+        self.pool
+            .parse::<IpNet>()
+            .is_ok_and(|subnet| subnet.contains(ip) && pool_hosts(&subnet).any(|host| host == *ip))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    fn migrate_legacy(data: &str, pool: &str) -> Result<Self> {
+        let free = data
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|ip| {
+                ip.parse::<IpAddr>()
+                    .map_err(|e| anyhow!("invalid address {:?} in legacy ip store: {}", ip, e))
+            })
+            .collect::<Result<BTreeSet<IpAddr>>>()?;
+
+        Ok(Self {
+            version: STORE_VERSION,
+            pool: pool.to_owned(),
+            free,
+            leases: BTreeMap::new(),
+        })
+    }
+}
+
+/// Compares pools as networks so `10.244.0.0/24` and `10.244.0.1/24` match;
+/// pools that do not parse fall back to comparing the strings.
+fn same_pool(a: &str, b: &str) -> bool {
+    match (a.parse::<IpNet>(), b.parse::<IpNet>()) {
+        (Ok(a), Ok(b)) => a.trunc() == b.trunc(),
+        _ => a == b,
+    }
+}
+
+/// Addresses a pool can hand out: every host address except the network
+/// address and the gateway, capped at `MAX_POOL_SIZE`.
+fn pool_hosts(subnet: &IpNet) -> impl Iterator<Item = IpAddr> {
+    let network = subnet.network();
+    let gateway = gateway_for(subnet);
+
+    subnet
+        .hosts()
+        .filter(move |ip| *ip != network && *ip != gateway)
+        .take(MAX_POOL_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const LEGACY_STORE: &str = include_str!("../../testdata/ip_store.v0");
+
+    #[test]
+    fn test_migrate_legacy_store() {
+        let store = IpStore::parse(LEGACY_STORE, "10.244.0.0/24").unwrap();
+
+        assert_eq!(store.version, STORE_VERSION);
+        assert_eq!(store.pool, "10.244.0.0/24");
+        assert_eq!(store.free.len(), 250);
+        assert_eq!(
+            store.free.first(),
+            Some(&"10.244.0.5".parse::<IpAddr>().unwrap())
+        );
+        assert!(store.leases.is_empty());
+    }
+
+    #[test]
+    fn test_reject_empty_store() {
+        for data in ["", " \n\n"] {
+            let err = IpStore::parse(data, "10.244.0.0/24").unwrap_err();
+            assert!(err.to_string().contains("ip store is empty"));
+        }
+    }
+
+    #[test]
+    fn test_reject_invalid_legacy_store() {
+        let result = IpStore::parse("10.244.0.2\nnot-an-ip", "10.244.0.0/24");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_reject_future_version() {
+        let json = format!(
+            r#"{{"version":{},"pool":"10.244.0.0/24","free":[],"leases":{{}}}}"#,
+            STORE_VERSION + 1
+        );
+
+        let err = IpStore::parse(&json, "10.244.0.0/24").unwrap_err();
+        assert!(err.to_string().contains("newer than the supported version"));
+    }
+
+    #[test]
+    fn test_keep_store_for_the_same_pool() {
+        let mut store = IpStore::new("10.244.0.0/24");
+        let ip = store.free.pop_first().unwrap();
+        store.leases.insert(ip, "pod-a".to_owned());
+
+        let parsed = IpStore::parse(&store.to_json().unwrap(), "10.244.0.0/24").unwrap();
+        assert_eq!(parsed, store);
+    }
+
+    #[test]
+    fn test_reseed_store_for_a_new_pool() {
+        let mut store = IpStore::new("10.244.0.0/23");
+        let kept = "10.244.1.10".parse::<IpAddr>().unwrap();
+        let dropped = "10.244.0.10".parse::<IpAddr>().unwrap();
+        for (ip, owner) in [(kept, "pod-a"), (dropped, "pod-b")] {
+            store.free.remove(&ip);
+            store.leases.insert(ip, owner.to_owned());
+        }
+
+        let parsed = IpStore::parse(&store.to_json().unwrap(), "10.244.1.0/24").unwrap();
+
+        assert_eq!(parsed.pool, "10.244.1.0/24");
+        assert_eq!(parsed.leases.len(), 1);
+        assert_eq!(parsed.leases.get(&kept).map(String::as_str), Some("pod-a"));
+        assert!(!parsed.free.contains(&kept));
+        assert!(!parsed.in_pool(&dropped));
+        assert_eq!(parsed.free.len(), 252);
+    }
+
+    #[test]
+    fn test_new_ipv6_pool() {
+        let store = IpStore::new("fd00:10:244:1::/64");
+
+        assert_eq!(store.free.len(), MAX_POOL_SIZE);
+        assert_eq!(
+            store.free.first(),
+            Some(&"fd00:10:244:1::2".parse::<IpAddr>().unwrap())
+        );
+
+        let store = IpStore::new("10.244.0.0/24");
+        assert_eq!(store.free.len(), 253);
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(
+            free in prop::collection::btree_set(any::<u8>(), 0..64),
+            leases in prop::collection::btree_map(any::<u8>(), "[a-z0-9]{1,12}", 0..16),
+        ) {
+            let store = IpStore {
+                version: STORE_VERSION,
+                pool: "10.244.0.0/24".to_owned(),
+                free: free
+                    .into_iter()
+                    .map(|octet| IpAddr::from([10, 244, 0, octet]))
+                    .collect(),
+                leases: leases
+                    .into_iter()
+                    .map(|(octet, owner)| (IpAddr::from([10, 244, 1, octet]), owner))
+                    .collect(),
+            };
+
+            let parsed = IpStore::parse(&store.to_json().unwrap(), "10.244.0.0/24").unwrap();
+            prop_assert_eq!(parsed, store);
+        }
+    }
+}
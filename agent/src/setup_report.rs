@@ -0,0 +1,194 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Error, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// A part of host setup whose outcome is reported on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetupStep {
+    Sysctl,
+    Bridge,
+    Vxlan,
+    /// Programming the overlay towards one remote node.
+    OverlayNode(String),
+    RouteMark,
+    /// Attaching one tc program to one interface.
+    TcAttach {
+        program: String,
+        iface: String,
+    },
+}
+
+impl SetupStep {
+    /// Whether the agent cannot run without this step. An overlay node that
+    /// failed is left to the node watcher; the rest of the node still works.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, SetupStep::OverlayNode(_))
+    }
+}
+
+impl fmt::Display for SetupStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetupStep::Sysctl => write!(f, "sysctl"),
+            SetupStep::Bridge => write!(f, "bridge"),
+            SetupStep::Vxlan => write!(f, "vxlan"),
+            SetupStep::OverlayNode(node) => write!(f, "overlay {}", node),
+            SetupStep::RouteMark => write!(f, "route mark"),
+            SetupStep::TcAttach { program, iface } => write!(f, "{} on {}", program, iface),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepOutcome {
+    pub step: String,
+    pub fatal: bool,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Outcome of every setup step, in the order they finished. A failed step
+/// does not stop the independent steps after it, so one report shows all
+/// that went wrong.
+#[derive(Clone, Default)]
+pub struct SetupReport(Arc<Mutex<Vec<StepOutcome>>>);
+
+impl SetupReport {
+    /// Runs `f` as `step` and records how it went. The error stays in the
+    /// report; `check` turns fatal ones back into an error.
+    pub fn run<T>(&self, step: SetupStep, f: impl FnOnce() -> Result<T>) -> Option<T> {
+        let started = Instant::now();
+        let result = f();
+        self.record(&step, started.elapsed(), result.as_ref().err());
+
+        result.ok()
+    }
+
+    pub fn record(&self, step: &SetupStep, elapsed: Duration, error: Option<&Error>) {
+        if let Some(e) = error {
+            warn!("setup step {} failed: {:#}", step, e);
+        }
+
+        self.0.lock().unwrap().push(StepOutcome {
+            step: step.to_string(),
+            fatal: step.is_fatal(),
+            duration_ms: elapsed.as_millis() as u64,
+            error: error.map(|e| format!("{:#}", e)),
+        });
+    }
+
+    pub fn outcomes(&self) -> Vec<StepOutcome> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Fails naming every fatal step that failed so far.
+    pub fn check(&self) -> Result<()> {
+        let failed = self
+            .outcomes()
+            .into_iter()
+            .filter(|outcome| outcome.fatal)
+            .filter_map(|outcome| Some(format!("{}: {}", outcome.step, outcome.error?)))
+            .collect::<Vec<_>>();
+
+        if !failed.is_empty() {
+            bail!("setup failed: {}", failed.join("; "));
+        }
+
+        Ok(())
+    }
+
+    /// Logs every outcome as a single JSON line.
+    pub fn log_summary(&self) {
+        let outcomes = self.outcomes();
+        let failed = outcomes
+            .iter()
+            .filter(|outcome| outcome.error.is_some())
+            .count();
+
+        info!(
+            "setup report: {} steps, {} failed: {}",
+            outcomes.len(),
+            failed,
+            serde_json::to_string(&outcomes).unwrap_or_default()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_only_overlay_nodes_are_tolerated() {
+        let table = [
+            (SetupStep::Sysctl, true),
+            (SetupStep::Bridge, true),
+            (SetupStep::Vxlan, true),
+            (SetupStep::OverlayNode("worker".to_string()), false),
+            (SetupStep::RouteMark, true),
+            (
+                SetupStep::TcAttach {
+                    program: "tc_egress".to_string(),
+                    iface: "eth0".to_string(),
+                },
+                true,
+            ),
+        ];
+
+        for (step, fatal) in table {
+            assert_eq!(step.is_fatal(), fatal, "{}", step);
+        }
+    }
+
+    #[test]
+    fn test_report_keeps_every_failure() {
+        let report = SetupReport::default();
+
+        assert_eq!(report.run(SetupStep::Bridge, || Ok(1)), Some(1));
+        assert_eq!(
+            report.run(SetupStep::Vxlan, || -> Result<()> {
+                Err(anyhow!("Operation not permitted"))
+            }),
+            None
+        );
+        for node in ["worker", "worker2"] {
+            report.record(
+                &SetupStep::OverlayNode(node.to_string()),
+                Duration::from_millis(3),
+                Some(&anyhow!("no route to host")),
+            );
+        }
+
+        let outcomes = report.outcomes();
+        assert_eq!(outcomes.len(), 4);
+        assert_eq!(outcomes[0].error, None);
+        assert_eq!(outcomes[3].step, "overlay worker2");
+        assert!(!outcomes[3].fatal);
+
+        assert_eq!(
+            report.check().unwrap_err().to_string(),
+            "setup failed: vxlan: Operation not permitted"
+        );
+    }
+
+    #[test]
+    fn test_overlay_failures_pass_check() {
+        let report = SetupReport::default();
+        report.run(SetupStep::Bridge, || Ok(()));
+        report.record(
+            &SetupStep::OverlayNode("worker".to_string()),
+            Duration::ZERO,
+            Some(&anyhow!("no route to host")),
+        );
+
+        assert!(report.check().is_ok());
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use aya::maps::{MapData, PerCpuArray};
+use common::{
+    STAT_ARP_REPLY_EGRESS, STAT_ARP_REPLY_INGRESS, STAT_ARP_REQUEST_EGRESS,
+    STAT_ARP_REQUEST_INGRESS, STAT_SK_MSG_EXAMINED, STAT_SK_MSG_REDIRECTED,
+    STAT_SK_MSG_REDIRECTED_BYTES, STAT_SK_MSG_REDIRECT_FAILED, STAT_SOCK_OPS_ADDED,
+    STAT_SOCK_OPS_REMOVED, STAT_SOCK_OPS_SKIPPED,
+};
+use serde::Serialize;
+
+use crate::metrics;
+
+/// Reads the per-CPU counters the tc programs keep in `STATS_MAP`.
+#[derive(Clone)]
+pub struct StatsReader {
+    map: Arc<PerCpuArray<MapData, u64>>,
+    sock_ops_max_entries: u32,
+}
+
+impl StatsReader {
+    pub fn new(map: PerCpuArray<MapData, u64>, sock_ops_max_entries: u32) -> Self {
+        Self {
+            map: Arc::new(map),
+            sock_ops_max_entries,
+        }
+    }
+
+    pub fn read(&self) -> Result<Stats> {
+        Stats::collect(
+            |key| Ok(self.map.get(&key, 0)?.iter().sum()),
+            self.sock_ops_max_entries,
+        )
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    pub arp: ArpStats,
+    pub sock_ops: SockOpsStats,
+    pub sk_msg: SkMsgStats,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ArpStats {
+    pub ingress: ArpCounters,
+    pub egress: ArpCounters,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ArpCounters {
+    pub requests: u64,
+    pub replies: u64,
+}
+
+/// Occupancy of each socket acceleration map, counted by the sock_ops
+/// program as sockets are added and closed.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct SockOpsStats {
+    pub active: u64,
+    pub skipped: u64,
+    pub max_entries: u32,
+}
+
+/// What the sk_msg program did with the messages sent on accelerated
+/// sockets. A failed redirect leaves the message on the regular stack.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct SkMsgStats {
+    pub examined: u64,
+    pub redirected: u64,
+    pub redirect_failed: u64,
+    pub redirected_bytes: u64,
+}
+
+impl Stats {
+    fn collect(read: impl Fn(u32) -> Result<u64>, sock_ops_max_entries: u32) -> Result<Self> {
+        let added = read(STAT_SOCK_OPS_ADDED)?;
+        let removed = read(STAT_SOCK_OPS_REMOVED)?;
+
+        Ok(Self {
+            arp: ArpStats {
+                ingress: ArpCounters {
+                    requests: read(STAT_ARP_REQUEST_INGRESS)?,
+                    replies: read(STAT_ARP_REPLY_INGRESS)?,
+                },
+                egress: ArpCounters {
+                    requests: read(STAT_ARP_REQUEST_EGRESS)?,
+                    replies: read(STAT_ARP_REPLY_EGRESS)?,
+                },
+            },
+            sock_ops: SockOpsStats {
+                active: added.saturating_sub(removed),
+                skipped: read(STAT_SOCK_OPS_SKIPPED)?,
+                max_entries: sock_ops_max_entries,
+            },
+            sk_msg: SkMsgStats {
+                examined: read(STAT_SK_MSG_EXAMINED)?,
+                redirected: read(STAT_SK_MSG_REDIRECTED)?,
+                redirect_failed: read(STAT_SK_MSG_REDIRECT_FAILED)?,
+                redirected_bytes: read(STAT_SK_MSG_REDIRECTED_BYTES)?,
+            },
+        })
+    }
+
+    /// Appends the socket acceleration counters in the Prometheus text
+    /// format.
+    pub fn write_metrics(&self, out: &mut String) {
+        let counters = [
+            (
+                "sinabro_sk_msg_examined_total",
+                "Messages the sk_msg program looked at.",
+                self.sk_msg.examined,
+            ),
+            (
+                "sinabro_sk_msg_redirected_total",
+                "Messages redirected straight to the peer socket.",
+                self.sk_msg.redirected,
+            ),
+            (
+                "sinabro_sk_msg_redirect_failed_total",
+                "Messages whose peer socket was not in the sockmap.",
+                self.sk_msg.redirect_failed,
+            ),
+            (
+                "sinabro_sk_msg_redirected_bytes_total",
+                "Bytes that skipped the TCP/IP stack through a redirect.",
+                self.sk_msg.redirected_bytes,
+            ),
+        ];
+
+        for (name, help, value) in counters {
+            metrics::write_header(out, name, help, "counter");
+            out.push_str(&format!("{} {}\n", name, value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_stats() {
+        let stats = Stats::collect(|key| Ok(key as u64 + 10), 65535).unwrap();
+
+        assert_eq!(stats.arp.ingress.requests, 10);
+        assert_eq!(stats.arp.ingress.replies, 11);
+        assert_eq!(stats.arp.egress.requests, 12);
+        assert_eq!(stats.arp.egress.replies, 13);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["arp"]["egress"]["replies"], 13);
+    }
+
+    #[test]
+    fn test_collect_sock_ops_occupancy() {
+        let stats = Stats::collect(
+            |key| match key {
+                STAT_SOCK_OPS_ADDED => Ok(120),
+                STAT_SOCK_OPS_REMOVED => Ok(20),
+                STAT_SOCK_OPS_SKIPPED => Ok(3),
+                _ => Ok(0),
+            },
+            100,
+        )
+        .unwrap();
+
+        assert_eq!(
+            stats.sock_ops,
+            SockOpsStats {
+                active: 100,
+                skipped: 3,
+                max_entries: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn test_sk_msg_metrics() {
+        let stats = Stats::collect(
+            |key| match key {
+                STAT_SK_MSG_EXAMINED => Ok(10),
+                STAT_SK_MSG_REDIRECTED => Ok(7),
+                STAT_SK_MSG_REDIRECT_FAILED => Ok(3),
+                STAT_SK_MSG_REDIRECTED_BYTES => Ok(4096),
+                _ => Ok(0),
+            },
+            100,
+        )
+        .unwrap();
+
+        let mut out = String::new();
+        stats.write_metrics(&mut out);
+
+        assert!(out.contains("# TYPE sinabro_sk_msg_redirected_total counter\n"));
+        assert!(out.contains("sinabro_sk_msg_examined_total 10\n"));
+        assert!(out.contains("sinabro_sk_msg_redirect_failed_total 3\n"));
+        assert!(out.contains("sinabro_sk_msg_redirected_bytes_total 4096\n"));
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::{
+    cni_config::{CniConfigState, CniConfigStatus},
+    kube::NodeInfoCache,
+    setup_report::{SetupReport, StepOutcome},
+};
+
+/// Startup phases in the order the agent runs them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Discovery,
+    CniConfig,
+    Network,
+    Dataplane,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [
+        Phase::Discovery,
+        Phase::CniConfig,
+        Phase::Network,
+        Phase::Dataplane,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Discovery => "discovery",
+            Phase::CniConfig => "cni-config",
+            Phase::Network => "network",
+            Phase::Dataplane => "dataplane",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PhaseState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl fmt::Display for PhaseState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            PhaseState::Pending => "pending",
+            PhaseState::Running => "running",
+            PhaseState::Done => "done",
+            PhaseState::Failed => "failed",
+        })
+    }
+}
+
+/// Progress of a single phase. Timestamps are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseStatus {
+    pub phase: Phase,
+    pub state: PhaseState,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Remote nodes the startup overlay round has finished with, successfully
+/// or not, out of all it covers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlayCounts {
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+impl OverlayCounts {
+    pub fn finished(&self) -> bool {
+        self.done + self.failed >= self.total
+    }
+}
+
+/// Shared progress of the startup overlay round. Every update wakes the
+/// tasks waiting for the round to finish.
+#[derive(Clone)]
+pub struct OverlayProgress(Arc<watch::Sender<OverlayCounts>>);
+
+impl Default for OverlayProgress {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(OverlayCounts::default()).0))
+    }
+}
+
+impl OverlayProgress {
+    pub fn start(&self, total: usize) {
+        self.0.send_replace(OverlayCounts {
+            total,
+            ..Default::default()
+        });
+    }
+
+    pub fn succeeded(&self) {
+        self.0.send_modify(|counts| counts.done += 1);
+    }
+
+    pub fn failed(&self) {
+        self.0.send_modify(|counts| counts.failed += 1);
+    }
+
+    pub fn counts(&self) -> OverlayCounts {
+        *self.0.borrow()
+    }
+
+    /// Resolves once every remote node is done or failed.
+    pub async fn finished(&self) {
+        let mut counts = self.0.subscribe();
+        while !counts.borrow_and_update().finished() {
+            // The sender lives in `self`, so the channel cannot close.
+            if counts.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub ready: bool,
+    pub phases: Vec<PhaseStatus>,
+    #[serde(default)]
+    pub overlay: OverlayCounts,
+    #[serde(default)]
+    pub setup: Vec<StepOutcome>,
+    #[serde(default)]
+    pub cni_config: Option<CniConfigState>,
+}
+
+/// Side effects of each startup phase, kept behind a trait so the phase
+/// bookkeeping can be exercised without touching the network.
+#[async_trait]
+pub trait Startup {
+    async fn discover(&mut self) -> Result<()>;
+    async fn write_cni_config(&mut self) -> Result<()>;
+    async fn setup_network(&mut self) -> Result<()>;
+    async fn attach_dataplane(&mut self) -> Result<()>;
+}
+
+/// Shared record of how far the agent got during startup. The agent is
+/// ready once every phase is done and the overlay round has tried every
+/// remote node; nodes that failed are left to the node watcher.
+#[derive(Clone)]
+pub struct AgentStatus {
+    phases: Arc<Mutex<Vec<PhaseStatus>>>,
+    overlay: OverlayProgress,
+    nodes: NodeInfoCache,
+    setup: SetupReport,
+    cni_config: CniConfigStatus,
+}
+
+impl Default for AgentStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AgentStatus {
+    pub fn new() -> Self {
+        let phases = Phase::ALL
+            .iter()
+            .map(|phase| PhaseStatus {
+                phase: *phase,
+                state: PhaseState::Pending,
+                started_at: None,
+                finished_at: None,
+                error: None,
+            })
+            .collect();
+
+        Self {
+            phases: Arc::new(Mutex::new(phases)),
+            overlay: OverlayProgress::default(),
+            nodes: NodeInfoCache::default(),
+            setup: SetupReport::default(),
+            cni_config: CniConfigStatus::default(),
+        }
+    }
+
+    pub fn overlay(&self) -> OverlayProgress {
+        self.overlay.clone()
+    }
+
+    /// The node cache the agent's kube context fills, shown on the status
+    /// server for debugging.
+    pub fn nodes(&self) -> NodeInfoCache {
+        self.nodes.clone()
+    }
+
+    pub fn setup(&self) -> SetupReport {
+        self.setup.clone()
+    }
+
+    pub fn cni_config(&self) -> CniConfigStatus {
+        self.cni_config.clone()
+    }
+
+    pub fn report(&self) -> StatusReport {
+        let phases = self.phases.lock().unwrap().clone();
+        let overlay = self.overlay.counts();
+
+        StatusReport {
+            ready: phases.iter().all(|phase| phase.state == PhaseState::Done) && overlay.finished(),
+            phases,
+            overlay,
+            setup: self.setup.outcomes(),
+            cni_config: self.cni_config.state(),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.report().ready
+    }
+
+    /// Runs every phase in order, stopping at the first failure.
+    pub async fn run(&self, startup: &mut impl Startup) -> Result<()> {
+        self.track(Phase::Discovery, startup.discover()).await?;
+        self.track(Phase::CniConfig, startup.write_cni_config())
+            .await?;
+        self.track(Phase::Network, startup.setup_network()).await?;
+        self.track(Phase::Dataplane, startup.attach_dataplane())
+            .await
+    }
+
+    async fn track<T>(&self, phase: Phase, step: impl Future<Output = Result<T>>) -> Result<T> {
+        self.update(phase, |status| {
+            status.state = PhaseState::Running;
+            status.started_at = Some(now());
+        });
+        info!("startup phase {} started", phase);
+
+        let result = step.await;
+
+        self.update(phase, |status| {
+            status.finished_at = Some(now());
+            match &result {
+                Ok(_) => status.state = PhaseState::Done,
+                Err(e) => {
+                    status.state = PhaseState::Failed;
+                    status.error = Some(e.to_string());
+                }
+            }
+        });
+
+        match &result {
+            Ok(_) => info!("startup phase {} done", phase),
+            Err(e) => error!("startup phase {} failed: {}", phase, e),
+        }
+
+        result
+    }
+
+    fn update(&self, phase: Phase, f: impl FnOnce(&mut PhaseStatus)) {
+        let mut phases = self.phases.lock().unwrap();
+        if let Some(status) = phases.iter_mut().find(|status| status.phase == phase) {
+            f(status);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::setup_report::SetupStep;
+
+    #[derive(Default)]
+    struct FakeStartup {
+        fail_at: Option<Phase>,
+        calls: Vec<Phase>,
+    }
+
+    impl FakeStartup {
+        fn step(&mut self, phase: Phase) -> Result<()> {
+            self.calls.push(phase);
+            match self.fail_at {
+                Some(fail_at) if fail_at == phase => Err(anyhow!("{} broke", phase)),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Startup for FakeStartup {
+        async fn discover(&mut self) -> Result<()> {
+            self.step(Phase::Discovery)
+        }
+
+        async fn write_cni_config(&mut self) -> Result<()> {
+            self.step(Phase::CniConfig)
+        }
+
+        async fn setup_network(&mut self) -> Result<()> {
+            self.step(Phase::Network)
+        }
+
+        async fn attach_dataplane(&mut self) -> Result<()> {
+            self.step(Phase::Dataplane)
+        }
+    }
+
+    #[test]
+    fn test_initial_status() {
+        let report = AgentStatus::new().report();
+
+        assert!(!report.ready);
+        assert_eq!(report.phases.len(), Phase::ALL.len());
+        assert!(report
+            .phases
+            .iter()
+            .all(|phase| phase.state == PhaseState::Pending && phase.started_at.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_run_all_phases() {
+        let status = AgentStatus::new();
+        let mut startup = FakeStartup::default();
+
+        status.run(&mut startup).await.unwrap();
+
+        assert_eq!(startup.calls, Phase::ALL);
+        let report = status.report();
+        assert!(report.ready);
+        assert!(report
+            .phases
+            .iter()
+            .all(|phase| phase.finished_at.is_some() && phase.error.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_until_overlay_finished() {
+        let status = AgentStatus::new();
+        status.run(&mut FakeStartup::default()).await.unwrap();
+
+        let overlay = status.overlay();
+        overlay.start(2);
+        overlay.succeeded();
+        assert!(!status.is_ready());
+
+        let finished = tokio::spawn({
+            let overlay = overlay.clone();
+            async move { overlay.finished().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!finished.is_finished());
+
+        overlay.failed();
+        finished.await.unwrap();
+        assert!(status.is_ready());
+        assert_eq!(
+            status.report().overlay,
+            OverlayCounts {
+                total: 2,
+                done: 1,
+                failed: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_report_includes_setup_steps() {
+        let status = AgentStatus::new();
+        status.setup().record(
+            &SetupStep::OverlayNode("worker".to_string()),
+            Duration::from_millis(7),
+            Some(&anyhow!("no route to host")),
+        );
+
+        let setup = status.report().setup;
+        assert_eq!(setup.len(), 1);
+        assert_eq!(setup[0].step, "overlay worker");
+        assert_eq!(setup[0].duration_ms, 7);
+        assert_eq!(setup[0].error.as_deref(), Some("no route to host"));
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_at_failed_phase() {
+        let status = AgentStatus::new();
+        let mut startup = FakeStartup {
+            fail_at: Some(Phase::Network),
+            ..Default::default()
+        };
+
+        let err = status.run(&mut startup).await.unwrap_err();
+        assert_eq!(err.to_string(), "network broke");
+        assert_eq!(
+            startup.calls,
+            vec![Phase::Discovery, Phase::CniConfig, Phase::Network]
+        );
+
+        let report = status.report();
+        assert!(!report.ready);
+
+        let states = report
+            .phases
+            .iter()
+            .map(|phase| phase.state)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                PhaseState::Done,
+                PhaseState::Done,
+                PhaseState::Failed,
+                PhaseState::Pending
+            ]
+        );
+        assert_eq!(report.phases[2].error.as_deref(), Some("network broke"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["phases"][2]["phase"], "network");
+        assert_eq!(json["phases"][2]["state"], "failed");
+    }
+}
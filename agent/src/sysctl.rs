@@ -0,0 +1,192 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use tracing::{info, warn};
+
+const PROC_SYS: &str = "/proc/sys";
+
+/// Host sysctls pod networking depends on, applied unless overridden.
+pub const DEFAULT_SYSCTLS: &[(&str, &str)] = &[
+    ("net.ipv4.ip_forward", "1"),
+    ("net.bridge.bridge-nf-call-iptables", "1"),
+    ("net.ipv4.conf.all.rp_filter", "0"),
+];
+
+/// Added to the defaults when pods get IPv6 addresses.
+pub const DEFAULT_IPV6_SYSCTLS: &[(&str, &str)] = &[
+    ("net.ipv6.conf.all.forwarding", "1"),
+    ("net.bridge.bridge-nf-call-ip6tables", "1"),
+];
+
+/// Applies host-wide sysctls and remembers the values they replaced so
+/// they can be put back on cleanup.
+pub struct HostSysctl {
+    root: PathBuf,
+    previous: Vec<(String, String)>,
+}
+
+impl Default for HostSysctl {
+    fn default() -> Self {
+        Self::with_root(PROC_SYS)
+    }
+}
+
+impl HostSysctl {
+    pub fn with_root(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            previous: vec![],
+        }
+    }
+
+    /// Writes each sysctl that differs from the wanted value. Keys the
+    /// kernel does not expose are skipped with a warning.
+    pub fn apply(&mut self, sysctls: &[(String, String)]) -> Result<()> {
+        for (key, value) in sysctls {
+            let path = self.path_of(key);
+
+            if !path.exists() {
+                warn!(
+                    "sysctl {} is not available, skipping it; {}",
+                    key,
+                    guidance(key)
+                );
+                continue;
+            }
+
+            let previous = read(&path)?;
+            if previous == *value {
+                continue;
+            }
+
+            fs::write(&path, value)
+                .map_err(|e| anyhow!("failed to set sysctl {} to {}: {}", key, value, e))?;
+            info!("sysctl {} changed from {} to {}", key, previous, value);
+
+            self.previous.push((key.clone(), previous));
+        }
+
+        Ok(())
+    }
+
+    /// Puts back every value changed by `apply`, newest first.
+    pub fn restore(&mut self) -> Result<()> {
+        while let Some((key, value)) = self.previous.pop() {
+            fs::write(self.path_of(&key), &value)
+                .map_err(|e| anyhow!("failed to restore sysctl {} to {}: {}", key, value, e))?;
+            info!("sysctl {} restored to {}", key, value);
+        }
+
+        Ok(())
+    }
+
+    fn path_of(&self, key: &str) -> PathBuf {
+        self.root.join(key.replace('.', "/"))
+    }
+}
+
+/// Merges `overrides` into the defaults; an override replaces the default
+/// with the same key.
+pub fn with_defaults(overrides: &[(String, String)], ipv6: bool) -> Vec<(String, String)> {
+    let ipv6_defaults = if ipv6 { DEFAULT_IPV6_SYSCTLS } else { &[] };
+
+    let mut sysctls = DEFAULT_SYSCTLS
+        .iter()
+        .chain(ipv6_defaults)
+        .filter(|(key, _)| !overrides.iter().any(|(other, _)| other == key))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+    sysctls.extend_from_slice(overrides);
+
+    sysctls
+}
+
+fn read(path: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)?.trim().to_owned())
+}
+
+fn guidance(key: &str) -> &'static str {
+    if key.starts_with("net.bridge.") {
+        "load the br_netfilter kernel module (modprobe br_netfilter) to enable it"
+    } else {
+        "check that the kernel supports it"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, HostSysctl) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let ipv4 = tmp_dir.path().join("net/ipv4");
+        fs::create_dir_all(ipv4.join("conf/all")).unwrap();
+        fs::write(ipv4.join("ip_forward"), "0\n").unwrap();
+        fs::write(ipv4.join("conf/all/rp_filter"), "0\n").unwrap();
+
+        let sysctl = HostSysctl::with_root(tmp_dir.path());
+        (tmp_dir, sysctl)
+    }
+
+    #[test]
+    fn test_apply_and_restore() {
+        let (tmp_dir, mut sysctl) = setup();
+        let ip_forward = tmp_dir.path().join("net/ipv4/ip_forward");
+
+        sysctl.apply(&with_defaults(&[], false)).unwrap();
+
+        assert_eq!(read(&ip_forward).unwrap(), "1");
+        assert_eq!(
+            sysctl.previous,
+            vec![("net.ipv4.ip_forward".to_string(), "0".to_string())]
+        );
+
+        sysctl.restore().unwrap();
+
+        assert_eq!(read(&ip_forward).unwrap(), "0");
+        assert!(sysctl.previous.is_empty());
+    }
+
+    #[test]
+    fn test_apply_skips_missing_sysctl() {
+        let (tmp_dir, mut sysctl) = setup();
+
+        sysctl
+            .apply(&[(
+                "net.bridge.bridge-nf-call-iptables".to_string(),
+                "1".to_string(),
+            )])
+            .unwrap();
+
+        assert!(!tmp_dir.path().join("net/bridge").exists());
+        assert!(sysctl.previous.is_empty());
+    }
+
+    #[test]
+    fn test_with_defaults() {
+        let sysctls = with_defaults(
+            &[("net.ipv4.conf.all.rp_filter".to_string(), "2".to_string())],
+            false,
+        );
+
+        assert_eq!(sysctls.len(), DEFAULT_SYSCTLS.len());
+        assert_eq!(
+            sysctls.last(),
+            Some(&("net.ipv4.conf.all.rp_filter".to_string(), "2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_with_ipv6_defaults() {
+        let sysctls = with_defaults(&[], true);
+
+        assert_eq!(
+            sysctls.len(),
+            DEFAULT_SYSCTLS.len() + DEFAULT_IPV6_SYSCTLS.len()
+        );
+        assert!(sysctls.contains(&("net.ipv6.conf.all.forwarding".to_string(), "1".to_string())));
+    }
+}
@@ -0,0 +1,304 @@
+use std::{error::Error, io, net::IpAddr, time::Duration};
+
+use anyhow::Result;
+use rand::Rng;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use tracing::warn;
+
+use crate::error::{CniError, ERR_AGENT_REJECTED, ERR_NO_FREE_ADDRESSES};
+
+const AGENT_URL: &str = "http://localhost:3000";
+
+/// Timeouts and retries for calls to the agent.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(2),
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Linear backoff with up to one extra `backoff` of jitter, so plugins
+    /// started together do not hit a restarting agent in lockstep.
+    fn delay(&self, attempt: u32) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0..=self.backoff.as_millis() as u64);
+        self.backoff * attempt + Duration::from_millis(jitter)
+    }
+}
+
+/// Client for the agent's IPAM API.
+///
+/// Refused connections are retried since they usually mean the agent is
+/// restarting; any other failure, including a timeout or a 5xx reply, is
+/// reported right away as "try again later" so the runtime retries the whole
+/// command. A 4xx reply means the request itself was wrong and is not.
+pub struct AgentClient {
+    client: Client,
+    base_url: String,
+    policy: RetryPolicy,
+}
+
+impl AgentClient {
+    pub fn new() -> Result<Self> {
+        Self::with_policy(AGENT_URL, RetryPolicy::default())
+    }
+
+    pub fn with_policy(base_url: &str, policy: RetryPolicy) -> Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(policy.connect_timeout)
+            .timeout(policy.timeout)
+            .build()?;
+
+        Ok(Self {
+            client,
+            base_url: base_url.to_owned(),
+            policy,
+        })
+    }
+
+    pub async fn allocate_ip(&self, owner: &str, pod: Option<&str>) -> Result<String> {
+        let url = format!("{}/ipam/ip", self.base_url);
+        let res = self
+            .send(|client| {
+                let request = client.get(&url).query(&[("owner", owner)]);
+                match pod {
+                    Some(pod) => request.query(&[("pod", pod)]),
+                    None => request,
+                }
+            })
+            .await?;
+
+        // The agent answers an exhausted pool with an empty body.
+        let ip = res.text().await?;
+        if ip.trim().is_empty() {
+            return Err(
+                CniError::new(ERR_NO_FREE_ADDRESSES, "no free addresses in the pool").into(),
+            );
+        }
+
+        Ok(ip)
+    }
+
+    pub async fn release_ip(&self, ip: &str) -> Result<()> {
+        let url = format!("{}/ipam/ip/{}", self.base_url, ip);
+        self.send(|client| client.put(&url)).await?;
+
+        Ok(())
+    }
+
+    /// Releases every address leased to `owner` and returns them.
+    pub async fn release_owner(&self, owner: &str) -> Result<Vec<IpAddr>> {
+        let url = format!("{}/ipam/ip", self.base_url);
+        let res = self
+            .send(|client| client.delete(&url).query(&[("owner", owner)]))
+            .await?;
+
+        Ok(res.json().await?)
+    }
+
+    async fn send(&self, request: impl Fn(&Client) -> RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+
+        loop {
+            match request(&self.client).send().await {
+                Ok(res) if res.status().is_success() => return Ok(res),
+                Ok(res) => {
+                    let status = res.status();
+                    let body = res.text().await.unwrap_or_default();
+                    return Err(status_error(status, body.trim()).into());
+                }
+                Err(e) if attempt < self.policy.retries && is_connection_refused(&e) => {
+                    attempt += 1;
+                    let delay = self.policy.delay(attempt);
+                    warn!("agent refused the connection, retrying in {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    return Err(CniError::try_again_later(format!(
+                        "failed to reach the agent: {}",
+                        e
+                    ))
+                    .into())
+                }
+            }
+        }
+    }
+}
+
+fn status_error(status: StatusCode, body: &str) -> CniError {
+    let msg = format!("agent replied {}: {}", status, body);
+
+    if status.is_server_error() {
+        CniError::try_again_later(msg)
+    } else {
+        CniError::new(ERR_AGENT_REJECTED, msg)
+    }
+}
+
+fn is_connection_refused(e: &reqwest::Error) -> bool {
+    let mut source = e.source();
+
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            return io_err.kind() == io::ErrorKind::ConnectionRefused;
+        }
+        source = err.source();
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::error::ERR_TRY_AGAIN_LATER;
+
+    const OK: &str = "200 OK";
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            connect_timeout: Duration::from_millis(100),
+            timeout: Duration::from_millis(200),
+            retries: 2,
+            backoff: Duration::from_millis(50),
+        }
+    }
+
+    fn client(addr: SocketAddr) -> AgentClient {
+        AgentClient::with_policy(&format!("http://{}", addr), policy()).unwrap()
+    }
+
+    async fn unused_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Answers a single request with `status` and `body` after `delay`.
+    async fn serve_once(
+        listener: TcpListener,
+        delay: Duration,
+        status: &'static str,
+        body: &'static str,
+    ) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).await.unwrap();
+
+        tokio::time::sleep(delay).await;
+        let response = format!(
+            "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+
+    #[tokio::test]
+    async fn test_retries_refused_connection() {
+        let addr = unused_addr().await;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let listener = TcpListener::bind(addr).await.unwrap();
+            serve_once(listener, Duration::ZERO, OK, "10.244.0.2").await;
+        });
+
+        let ip = client(addr).allocate_ip("c1/eth0", None).await.unwrap();
+        assert_eq!(ip, "10.244.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_retries() {
+        let addr = unused_addr().await;
+
+        let err = client(addr).allocate_ip("c1/eth0", None).await.unwrap_err();
+        let err = err.downcast_ref::<CniError>().unwrap();
+        assert_eq!(err.code, ERR_TRY_AGAIN_LATER);
+    }
+
+    #[tokio::test]
+    async fn test_times_out_on_hanging_agent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_once(
+            listener,
+            Duration::from_secs(5),
+            OK,
+            "10.244.0.2",
+        ));
+
+        let started = std::time::Instant::now();
+        let err = client(addr).allocate_ip("c1/eth0", None).await.unwrap_err();
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let err = err.downcast_ref::<CniError>().unwrap();
+        assert_eq!(err.code, ERR_TRY_AGAIN_LATER);
+    }
+
+    #[tokio::test]
+    async fn test_server_error_is_try_again_later() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_once(
+            listener,
+            Duration::ZERO,
+            "500 Internal Server Error",
+            "failed to write the store",
+        ));
+
+        let err = client(addr).allocate_ip("c1/eth0", None).await.unwrap_err();
+        let err = err.downcast_ref::<CniError>().unwrap();
+        assert_eq!(err.code, ERR_TRY_AGAIN_LATER);
+        assert!(err.msg.contains("failed to write the store"));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_once(
+            listener,
+            Duration::ZERO,
+            "404 Not Found",
+            "address is not in the pool",
+        ));
+
+        let err = client(addr).release_ip("10.245.0.2").await.unwrap_err();
+        let err = err.downcast_ref::<CniError>().unwrap();
+        assert_eq!(err.code, ERR_AGENT_REJECTED);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_once(listener, Duration::ZERO, OK, ""));
+
+        let err = client(addr).allocate_ip("c1/eth0", None).await.unwrap_err();
+        let err = err.downcast_ref::<CniError>().unwrap();
+        assert_eq!(err.code, ERR_NO_FREE_ADDRESSES);
+    }
+}
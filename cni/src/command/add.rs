@@ -1,24 +1,106 @@
-use std::{env, fs::File, net::IpAddr, os::fd::AsRawFd};
+use std::{
+    env, fmt,
+    fs::{self, File},
+    io, iter, mem,
+    net::{IpAddr, UdpSocket},
+    os::fd::AsRawFd,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use ipnet::IpNet;
-use nix::sched::{setns, CloneFlags};
 use rand::Rng;
 use rsln::{
     netlink::Netlink,
     types::{
         addr::AddressBuilder,
         link::{Kind, LinkAttrs},
-        routing::RoutingBuilder,
+        routing::{Routing, RoutingBuilder},
     },
 };
-use serde::Serialize;
-use sinabro_config::{generate_mac, Config};
-use tokio::task::spawn_blocking;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sinabro_config::{
+    gateway_for, generate_mac,
+    link_index::LinkIndexCache,
+    retry::{retry_netlink, NetlinkOp, RetryPolicy},
+    validate_ifname, veth_suffix, Config, Datapath, IpamMode,
+};
 use tracing::info;
 
-use super::CniCommand;
+use super::{
+    cni_args::CniArgs,
+    current_attachment_id, netns,
+    result_cache::{CachedResult, ResultCache},
+    CniCommand,
+};
+use crate::{agent_client::AgentClient, host_local::HostLocalStore};
+
+const VETH_NAME_ATTEMPTS: usize = 8;
+/// Veth MTU for configs written before the agent recorded the overlay MTU.
+const DEFAULT_MTU: u32 = 1500;
+const RTNH_F_ONLINK: u32 = 0x4;
+/// Link-local gateway ptp pods route through; never assigned to any link.
+const PTP_GATEWAY: &str = "169.254.1.1";
+
+/// The steps of ADD that touch links, in the order they run. Each failure
+/// names its step and the link involved, so the kubelet's error says how far
+/// the attachment got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddStep {
+    CreateVeth,
+    AttachHostVeth,
+    MovePeer,
+    RenamePeer,
+    AddressPeer,
+    RoutePeer,
+}
+
+impl AddStep {
+    const ALL: [AddStep; 6] = [
+        AddStep::CreateVeth,
+        AddStep::AttachHostVeth,
+        AddStep::MovePeer,
+        AddStep::RenamePeer,
+        AddStep::AddressPeer,
+        AddStep::RoutePeer,
+    ];
+
+    fn position(&self) -> usize {
+        Self::ALL
+            .iter()
+            .position(|step| step == self)
+            .unwrap_or_default()
+            + 1
+    }
+
+    /// Runs this step, wrapping its error with the step's position and
+    /// description and the link it was working on.
+    fn run<T>(self, link: &str, step: impl FnOnce() -> Result<T>) -> Result<T> {
+        step().with_context(|| {
+            format!(
+                "step {}/{} ({}) failed for {}",
+                self.position(),
+                Self::ALL.len(),
+                self,
+                link
+            )
+        })
+    }
+}
+
+impl fmt::Display for AddStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddStep::CreateVeth => "create the veth pair",
+            AddStep::AttachHostVeth => "attach the host veth",
+            AddStep::MovePeer => "move the peer into the container netns",
+            AddStep::RenamePeer => "rename the peer",
+            AddStep::AddressPeer => "address the container interface",
+            AddStep::RoutePeer => "route the container interface",
+        })
+    }
+}
 
 pub struct AddCommand;
 
@@ -27,84 +109,159 @@ impl CniCommand for AddCommand {
     async fn run(&self, cni_config: &Config) -> Result<()> {
         let netns = env::var("CNI_NETNS")?;
         let cni_if_name = env::var("CNI_IFNAME")?;
-        let container_ip = Self::request_container_ip().await?;
-        let subnet_mask_size = cni_config.subnet.split('/').last().unwrap();
-        let container_addr = format!("{}/{}", container_ip, subnet_mask_size);
+        validate_ifname(&cni_if_name)?;
+        let cni_args = CniArgs::parse(&env::var("CNI_ARGS").unwrap_or_default())?;
+        let pod = cni_args.pod();
+        if let Some(pod) = &pod {
+            info!("ADD {} for pod {}", cni_if_name, pod);
+        }
+        let bridge_ip = match &cni_config.gateway {
+            Some(gateway) => gateway.clone(),
+            None => gateway_for(&cni_config.subnet.parse::<IpNet>()?).to_string(),
+        };
+        let datapath = cni_config.datapath();
+        let mtu = cni_config.mtu.unwrap_or(DEFAULT_MTU);
+        // A plugin earlier in the chain may already have picked the address.
+        let prev_ip = Self::prev_result_ip(cni_config.prev_result.as_ref());
+        let container_ip = match prev_ip {
+            Some(ip) => {
+                if datapath == Datapath::Bridge
+                    && !cni_config.subnet.parse::<IpNet>()?.contains(&ip)
+                {
+                    bail!("prevResult address {} is outside {}", ip, cni_config.subnet);
+                }
+                info!("using {} from prevResult", ip);
+                ip
+            }
+            None => Self::allocate_container_ip(cni_config, &bridge_ip, pod.as_deref()).await?,
+        };
+        let (container_addr, gateway) =
+            Self::container_addr(datapath, container_ip, &cni_config.subnet, &bridge_ip)?;
 
         let netns_file = File::open(&netns)?;
         let netns_fd = netns_file.as_raw_fd();
 
-        let veth_suffix = Self::generate_veth_suffix();
-        let veth_name = format!("veth{}", veth_suffix);
-        let peer_name = format!("peer{}", veth_suffix);
-
         let mut netlink = Netlink::new();
-
-        let cni0 = netlink.link_get(&LinkAttrs::new("cni0"))?;
-
-        let mut veth_attr = LinkAttrs::new(&veth_name);
-        veth_attr.mtu = 1500;
-        veth_attr.tx_queue_len = 1000;
-        veth_attr.hw_addr = generate_mac()?;
-
-        let veth = Kind::Veth {
-            attrs: veth_attr.clone(),
-            peer_name: peer_name.clone(),
-            peer_hw_addr: Some(generate_mac()?),
-            peer_ns: None,
+        // Creates are not retried: a veth pair that was added after all would
+        // look like a name collision and be left behind.
+        let policy = RetryPolicy::default();
+
+        let mut links = LinkIndexCache::new();
+        let bridge_index = match datapath {
+            Datapath::Bridge => Some(links.index_of("cni0", |name| {
+                Ok(netlink.link_get(&LinkAttrs::new(name))?.attrs().index)
+            })?),
+            Datapath::Ptp => None,
         };
 
-        netlink.link_add(&veth)?;
-
-        let veth = netlink.link_get(&veth_attr)?;
-        let peer = netlink.link_get(&LinkAttrs::new(&peer_name))?;
-
-        netlink.link_up(&veth)?;
-        netlink.link_set_master(&veth, cni0.attrs().index)?;
-        netlink.link_set_ns(&peer, netns_fd)?;
-
-        let subnet = cni_config.subnet.parse::<IpNet>()?;
-        let bridge_ip = subnet
-            .hosts()
-            .next()
-            .map(|ip| ip.to_string())
-            .ok_or_else(|| anyhow!("failed to get bridge ip"))?;
+        // The name derived from the attachment lets DEL find the veth without
+        // the netns; random suffixes only cover a collision with another link.
+        let container_id = env::var("CNI_CONTAINERID")?;
+        let derived_suffix = veth_suffix(&container_id, &cni_if_name);
+        let mut suffixes =
+            iter::once(derived_suffix.clone()).chain(iter::repeat_with(Self::generate_veth_suffix));
+        let suffix = AddStep::CreateVeth.run(&format!("veth{}", derived_suffix), || {
+            Self::create_with_unique_suffix(
+                || suffixes.next().unwrap_or_default(),
+                |suffix| {
+                    let veth_pair = Self::veth_pair(suffix, mtu)?;
+                    retry_netlink(NetlinkOp::LinkAdd, &policy, || netlink.link_add(&veth_pair))
+                },
+            )
+        })?;
+        let veth_name = format!("veth{}", suffix);
+        let peer_name = format!("peer{}", suffix);
+
+        let (veth, peer) = AddStep::CreateVeth.run(&veth_name, || {
+            let veth = netlink
+                .link_get(&LinkAttrs::new(&veth_name))
+                .context("the host veth is missing after creating the pair")?;
+            let peer = netlink
+                .link_get(&LinkAttrs::new(&peer_name))
+                .with_context(|| format!("{} is missing after creating the pair", peer_name))?;
+            Ok((veth, peer))
+        })?;
+
+        AddStep::AttachHostVeth.run(&veth_name, || {
+            netlink.link_up(&veth)?;
+            match bridge_index {
+                Some(bridge_index) => retry_netlink(NetlinkOp::LinkSet, &policy, || {
+                    netlink.link_set_master(&veth, bridge_index)
+                }),
+                None => {
+                    Self::enable_proxy_arp(&veth_name)?;
+                    let route = Self::ptp_host_route(veth.attrs().index, container_ip)?;
+                    let policy = RetryPolicy::default().with_non_idempotent();
+                    retry_netlink(NetlinkOp::RouteAdd, &policy, || {
+                        netlink.route_replace(&route)
+                    })
+                }
+            }
+        })?;
+
+        AddStep::MovePeer.run(&peer_name, || {
+            retry_netlink(NetlinkOp::LinkSet, &policy, || {
+                netlink.link_set_ns(&peer, netns_fd)
+            })?;
+            if netlink.link_get(&LinkAttrs::new(&peer_name)).is_ok() {
+                bail!("the peer is still in the host netns after the move");
+            }
+            Ok(())
+        })?;
 
         let container_addr_clone = container_addr.clone();
-        let bridge_ip_clone = bridge_ip.clone();
-
-        let mac_addr = spawn_blocking(move || -> Result<String> {
-            setns(netns_file, CloneFlags::CLONE_NEWNET)?;
+        let gateway_clone = gateway.clone();
+        let cni_if_name_clone = cni_if_name.clone();
 
+        let mac_addr = netns::run_in(netns_file, move || -> Result<String> {
             let mut netlink = Netlink::new();
-            let link = netlink.link_get(&LinkAttrs::new(&peer_name))?;
-            netlink.link_set_name(&link, &cni_if_name)?;
-            netlink.link_up(&link)?;
-
-            let container_addr = AddressBuilder::default()
-                .ip(container_addr_clone.parse::<IpNet>()?)
-                .build()?;
-
-            if let Err(e) = netlink.addr_add(&link, &container_addr) {
-                if e.to_string().contains("File exists") {
-                    info!("eth0 interface already has an ip address");
-                } else {
-                    return Err(e);
-                }
-            }
-
-            let route = RoutingBuilder::default()
-                .oif_index(link.attrs().index)
-                .gw(Some(bridge_ip_clone.parse::<IpAddr>()?))
-                .build()?;
-
-            if let Err(e) = netlink.route_add(&route) {
-                if e.to_string().contains("File exists") {
-                    info!("route already exists");
-                } else {
-                    return Err(e);
+            let link = AddStep::MovePeer.run(&peer_name, || {
+                netlink
+                    .link_get(&LinkAttrs::new(&peer_name))
+                    .context("the peer is not in the container netns after the move")
+            })?;
+
+            let link = AddStep::RenamePeer.run(&peer_name, || {
+                netlink.link_set_name(&link, &cni_if_name_clone)?;
+                let link = netlink
+                    .link_get(&LinkAttrs::new(&cni_if_name_clone))
+                    .with_context(|| {
+                        format!("{} is missing after the rename", cni_if_name_clone)
+                    })?;
+                Self::set_mtu(&cni_if_name_clone, mtu)?;
+                netlink.link_up(&link)?;
+                Ok(link)
+            })?;
+
+            // An existing address counts as done and the route is replaced, so
+            // both can be sent again.
+            let policy = RetryPolicy::default().with_non_idempotent();
+            AddStep::AddressPeer.run(&cni_if_name_clone, || {
+                let container_addr = AddressBuilder::default()
+                    .ip(container_addr_clone.parse::<IpNet>()?)
+                    .build()?;
+
+                match retry_netlink(NetlinkOp::AddrAdd, &policy, || {
+                    netlink.addr_add(&link, &container_addr)
+                }) {
+                    Err(e) if e.to_string().contains("File exists") => {
+                        info!("{} interface already has an ip address", cni_if_name_clone);
+                        Ok(())
+                    }
+                    result => result,
                 }
-            }
+            })?;
+
+            AddStep::RoutePeer.run(&cni_if_name_clone, || {
+                let gateway = gateway_clone.parse::<IpAddr>()?;
+                let route = match datapath {
+                    Datapath::Bridge => Self::default_route(link.attrs().index, gateway)?,
+                    Datapath::Ptp => Self::ptp_default_route(link.attrs().index, gateway)?,
+                };
+                retry_netlink(NetlinkOp::RouteAdd, &policy, || {
+                    netlink.route_replace(&route)
+                })
+            })?;
 
             Ok(link
                 .attrs()
@@ -114,17 +271,227 @@ impl CniCommand for AddCommand {
                 .collect::<Vec<String>>()
                 .join(":"))
         })
-        .await??;
-
-        Self::print_result(&mac_addr, &netns, &container_addr, &bridge_ip);
+        .await?;
+
+        let cached = CachedResult {
+            result: AddResult::new(
+                cni_if_name.clone(),
+                mac_addr,
+                netns,
+                container_addr,
+                gateway,
+            ),
+            host_veth: veth_name,
+            lease_id: current_attachment_id()?,
+            ip_from_prev_result: prev_ip.is_some(),
+        };
+        // The agent's orphan sweep counts a veth as live by its cached
+        // result, so an attachment that cannot be cached fails; the runtime's
+        // DEL then removes the veth by its derived name.
+        ResultCache::new()
+            .save(&container_id, &cni_if_name, &cached)
+            .context("failed to cache the result")?;
+
+        let result = Self::chain_result(cni_config.prev_result.as_ref(), &cached.result)?;
+        println!("{}", serde_json::to_string(&result)?);
         Ok(())
     }
 }
 
 impl AddCommand {
-    async fn request_container_ip() -> Result<String> {
-        let res = reqwest::get("http://localhost:3000/ipam/ip").await?;
-        Ok(res.text().await?)
+    fn prev_result_ip(prev_result: Option<&Value>) -> Option<IpAddr> {
+        prev_result?["ips"].as_array()?.iter().find_map(ip_of)
+    }
+
+    /// What ADD prints: our result alone, or when chained, `prev_result`
+    /// with our interface appended, our address pointing at it and anything
+    /// else, e.g. routes and DNS, passed through.
+    fn chain_result(prev_result: Option<&Value>, result: &AddResult) -> Result<Value> {
+        let ours = serde_json::to_value(result)?;
+        let Some(Value::Object(prev_result)) = prev_result else {
+            return Ok(ours);
+        };
+
+        let array =
+            |value: Option<&Value>| value.and_then(Value::as_array).cloned().unwrap_or_default();
+
+        let mut interfaces = array(prev_result.get("interfaces"));
+        let index = interfaces.len();
+        interfaces.extend(array(ours.get("interfaces")));
+
+        let mut our_ips = array(ours.get("ips"));
+        for ip in &mut our_ips {
+            ip["interface"] = index.into();
+        }
+        let taken = our_ips.iter().filter_map(ip_of).collect::<Vec<_>>();
+        let mut ips = array(prev_result.get("ips"))
+            .into_iter()
+            .filter(|ip| ip_of(ip).is_none_or(|addr| !taken.contains(&addr)))
+            .collect::<Vec<_>>();
+        ips.extend(our_ips);
+
+        let mut chained = prev_result.clone();
+        chained
+            .entry("cniVersion")
+            .or_insert_with(|| ours["cniVersion"].clone());
+        chained.insert("interfaces".to_string(), interfaces.into());
+        chained.insert("ips".to_string(), ips.into());
+
+        Ok(Value::Object(chained))
+    }
+
+    /// Allocates the container's address, leased to its attachment. The
+    /// agent also gets `pod` (`namespace/name`) to log who holds it.
+    async fn allocate_container_ip(
+        cni_config: &Config<'_>,
+        bridge_ip: &str,
+        pod: Option<&str>,
+    ) -> Result<IpAddr> {
+        match cni_config.ipam_mode() {
+            IpamMode::Agent => {
+                let ip = AgentClient::new()?
+                    .allocate_ip(&current_attachment_id()?, pod)
+                    .await?;
+
+                ip.trim()
+                    .parse()
+                    .with_context(|| format!("the agent handed out an invalid address {:?}", ip))
+            }
+            IpamMode::HostLocal => HostLocalStore::new(cni_config.name).allocate(
+                &cni_config.subnet.parse::<IpNet>()?,
+                bridge_ip.parse::<IpAddr>()?,
+                &current_attachment_id()?,
+            ),
+        }
+    }
+
+    /// Builds the container's default route through the bridge gateway. It is
+    /// installed with replace semantics so a stale route left in a reused
+    /// netns gets corrected.
+    fn default_route(oif_index: i32, gateway: IpAddr) -> Result<Routing> {
+        Ok(RoutingBuilder::default()
+            .oif_index(oif_index)
+            .gw(Some(gateway))
+            .build()?)
+    }
+
+    /// Builds the container's default route in ptp mode. The link-local
+    /// gateway is not on any subnet the container has, so the route is
+    /// marked onlink; the host veth answers ARP for it via proxy ARP.
+    fn ptp_default_route(oif_index: i32, gateway: IpAddr) -> Result<Routing> {
+        Ok(RoutingBuilder::default()
+            .oif_index(oif_index)
+            .gw(Some(gateway))
+            .flags(RTNH_F_ONLINK)
+            .build()?)
+    }
+
+    /// The address to put on the container's interface and its gateway.
+    /// Checked before any link is created, so a bad combination leaves
+    /// nothing to clean up.
+    fn container_addr(
+        datapath: Datapath,
+        container_ip: IpAddr,
+        subnet: &str,
+        bridge_ip: &str,
+    ) -> Result<(String, String)> {
+        match (datapath, container_ip) {
+            (Datapath::Bridge, _) => {
+                let subnet_mask_size = subnet.split('/').last().unwrap();
+                Ok((
+                    format!("{}/{}", container_ip, subnet_mask_size),
+                    bridge_ip.to_owned(),
+                ))
+            }
+            (Datapath::Ptp, IpAddr::V6(_)) => {
+                bail!(
+                    "the ptp datapath supports IPv4 pods only, got {}",
+                    container_ip
+                )
+            }
+            (Datapath::Ptp, IpAddr::V4(_)) => {
+                Ok((format!("{}/32", container_ip), PTP_GATEWAY.to_string()))
+            }
+        }
+    }
+
+    /// Builds the host route that sends traffic for a ptp pod down its veth.
+    fn ptp_host_route(oif_index: i32, container_ip: IpAddr) -> Result<Routing> {
+        Ok(RoutingBuilder::default()
+            .oif_index(oif_index)
+            .dst(Some(IpNet::new(container_ip, 32)?))
+            .scope(libc::RT_SCOPE_LINK)
+            .build()?)
+    }
+
+    /// Sets the MTU of `if_name` in the calling thread's netns with
+    /// `SIOCSIFMTU`, on a socket opened in that netns.
+    fn set_mtu(if_name: &str, mtu: u32) -> Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let mut request = unsafe { mem::zeroed::<libc::ifreq>() };
+        if if_name.len() >= request.ifr_name.len() {
+            bail!("interface name {} is too long", if_name);
+        }
+        for (dst, src) in request.ifr_name.iter_mut().zip(if_name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFMTU as _, &request) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to set the MTU of {} to {}", if_name, mtu));
+        }
+
+        Ok(())
+    }
+
+    fn enable_proxy_arp(if_name: &str) -> Result<()> {
+        fs::write(
+            format!("/proc/sys/net/ipv4/conf/{}/proxy_arp", if_name),
+            "1",
+        )?;
+        Ok(())
+    }
+
+    /// Builds the veth pair. Only the host side gets `mtu` here: the kernel
+    /// creates the peer with the default MTU, so ADD sets it in the netns.
+    fn veth_pair(suffix: &str, mtu: u32) -> Result<Kind> {
+        let mut veth_attr = LinkAttrs::new(&format!("veth{}", suffix));
+        veth_attr.mtu = mtu;
+        veth_attr.tx_queue_len = 1000;
+        veth_attr.hw_addr = generate_mac()?;
+
+        Ok(Kind::Veth {
+            attrs: veth_attr,
+            peer_name: format!("peer{}", suffix),
+            peer_hw_addr: Some(generate_mac()?),
+            peer_ns: None,
+        })
+    }
+
+    /// Runs `create` with fresh suffixes until one of them is not taken by
+    /// an existing link. Concurrent ADDs on a busy node can draw the same
+    /// random suffix, which `link_add` reports as `EEXIST`.
+    fn create_with_unique_suffix(
+        mut next_suffix: impl FnMut() -> String,
+        mut create: impl FnMut(&str) -> Result<()>,
+    ) -> Result<String> {
+        for _ in 0..VETH_NAME_ATTEMPTS {
+            let suffix = next_suffix();
+
+            match create(&suffix) {
+                Ok(()) => return Ok(suffix),
+                Err(e) if e.to_string().contains("File exists") => {
+                    info!("veth suffix {} is already taken, retrying", suffix);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        bail!(
+            "failed to find a free veth name after {} attempts",
+            VETH_NAME_ATTEMPTS
+        )
     }
 
     fn generate_veth_suffix() -> String {
@@ -138,38 +505,39 @@ impl AddCommand {
             })
             .collect()
     }
-
-    fn print_result(mac: &str, cni_netns: &str, container_addr: &str, bridge_ip: &str) {
-        let add_result = AddResult::new(
-            mac.to_string(),
-            cni_netns.to_string(),
-            container_addr.to_string(),
-            bridge_ip.to_string(),
-        );
-        let add_result_json = serde_json::to_string(&add_result).unwrap();
-
-        println!("{}", add_result_json);
-    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct AddResult {
+    #[serde(rename = "cniVersion", alias = "cni_version")]
     cni_version: String,
     interfaces: Vec<Interface>,
     ips: Vec<Ip>,
 }
 
 impl AddResult {
-    pub fn new(mac: String, cni_netns: String, container_addr: String, bridge_ip: String) -> Self {
+    pub fn new(
+        if_name: String,
+        mac: String,
+        cni_netns: String,
+        container_addr: String,
+        bridge_ip: String,
+    ) -> Self {
         Self {
             cni_version: "0.3.0".to_owned(),
-            interfaces: vec![Interface::new(mac, cni_netns)],
+            interfaces: vec![Interface::new(if_name, mac, cni_netns)],
             ips: vec![Ip::new(container_addr, bridge_ip)],
         }
     }
+
+    /// The container's address without its prefix length.
+    pub fn container_ip(&self) -> Option<IpAddr> {
+        let ip = self.ips.first()?;
+        ip.address.parse::<IpNet>().ok().map(|net| net.addr())
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Interface {
     name: String,
     mac: String,
@@ -177,16 +545,21 @@ pub struct Interface {
 }
 
 impl Interface {
-    pub fn new(mac: String, sandbox: String) -> Self {
-        Self {
-            name: "eth0".to_owned(),
-            mac,
-            sandbox,
-        }
+    pub fn new(name: String, mac: String, sandbox: String) -> Self {
+        Self { name, mac, sandbox }
     }
 }
 
-#[derive(Serialize)]
+/// The address of an entry of a result's `ips`, without its prefix length.
+fn ip_of(ip: &Value) -> Option<IpAddr> {
+    ip["address"]
+        .as_str()?
+        .parse::<IpNet>()
+        .ok()
+        .map(|net| net.addr())
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Ip {
     version: String,
     address: String,
@@ -196,11 +569,246 @@ pub struct Ip {
 
 impl Ip {
     pub fn new(address: String, gateway: String) -> Self {
+        let version = match address.parse::<IpNet>() {
+            Ok(IpNet::V6(_)) => "6",
+            _ => "4",
+        };
+
         Self {
-            version: "4".to_owned(),
+            version: version.to_owned(),
             address,
             gateway,
             interface: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_route() {
+        let gateway = "10.244.0.1".parse::<IpAddr>().unwrap();
+        let route = AddCommand::default_route(3, gateway).unwrap();
+
+        assert_eq!(route.oif_index, 3);
+        assert_eq!(route.gw, Some(gateway));
+        assert_eq!(route.dst, None);
+    }
+
+    #[test]
+    fn test_chain_result() {
+        let prev_result = serde_json::json!({
+            "cniVersion": "0.3.1",
+            "interfaces": [{ "name": "net0", "sandbox": "/var/run/netns/test" }],
+            "ips": [
+                { "version": "4", "address": "10.244.0.9/24", "interface": 0 },
+                { "version": "4", "address": "192.168.1.4/24", "interface": 0 }
+            ],
+            "dns": { "nameservers": ["10.96.0.10"] }
+        });
+        assert_eq!(
+            AddCommand::prev_result_ip(Some(&prev_result)),
+            Some("10.244.0.9".parse::<IpAddr>().unwrap())
+        );
+
+        let result = AddResult::new(
+            "eth0".to_string(),
+            "aa:bb:cc:dd:ee:ff".to_string(),
+            "/var/run/netns/test".to_string(),
+            "10.244.0.9/24".to_string(),
+            "10.244.0.1".to_string(),
+        );
+        let chained = AddCommand::chain_result(Some(&prev_result), &result).unwrap();
+
+        assert_eq!(chained["cniVersion"], "0.3.1");
+        assert_eq!(chained["dns"], prev_result["dns"]);
+        assert_eq!(chained["interfaces"][0]["name"], "net0");
+        assert_eq!(chained["interfaces"][1]["name"], "eth0");
+
+        // The address sinabro took over now belongs to its interface.
+        let ips = chained["ips"].as_array().unwrap();
+        assert_eq!(ips.len(), 2);
+        assert_eq!(ips[0]["address"], "192.168.1.4/24");
+        assert_eq!(ips[1]["address"], "10.244.0.9/24");
+        assert_eq!(ips[1]["interface"], 1);
+        assert_eq!(ips[1]["gateway"], "10.244.0.1");
+
+        let alone = AddCommand::chain_result(None, &result).unwrap();
+        assert_eq!(alone["cniVersion"], "0.3.0");
+        assert_eq!(alone["ips"][0]["interface"], 0);
+        assert_eq!(AddCommand::prev_result_ip(None), None);
+    }
+
+    #[test]
+    fn test_ptp_routes() {
+        let gateway = PTP_GATEWAY.parse::<IpAddr>().unwrap();
+        let route = AddCommand::ptp_default_route(3, gateway).unwrap();
+
+        assert_eq!(route.gw, Some(gateway));
+        assert_eq!(route.dst, None);
+        assert_eq!(route.flags & RTNH_F_ONLINK, RTNH_F_ONLINK);
+
+        let container_ip = "10.244.0.5".parse::<IpAddr>().unwrap();
+        let route = AddCommand::ptp_host_route(7, container_ip).unwrap();
+
+        assert_eq!(route.oif_index, 7);
+        assert_eq!(route.dst, Some("10.244.0.5/32".parse::<IpNet>().unwrap()));
+        assert_eq!(route.gw, None);
+    }
+
+    #[test]
+    fn test_container_addr() {
+        let v4 = "10.244.0.5".parse::<IpAddr>().unwrap();
+        let v6 = "fd00:10:244::5".parse::<IpAddr>().unwrap();
+
+        assert_eq!(
+            AddCommand::container_addr(Datapath::Bridge, v4, "10.244.0.0/24", "10.244.0.1")
+                .unwrap(),
+            ("10.244.0.5/24".to_string(), "10.244.0.1".to_string())
+        );
+        assert_eq!(
+            AddCommand::container_addr(Datapath::Ptp, v4, "10.244.0.0/24", "10.244.0.1").unwrap(),
+            ("10.244.0.5/32".to_string(), PTP_GATEWAY.to_string())
+        );
+        assert_eq!(
+            AddCommand::container_addr(Datapath::Bridge, v6, "fd00:10:244::/64", "fd00:10:244::1")
+                .unwrap()
+                .0,
+            "fd00:10:244::5/64"
+        );
+
+        let err =
+            AddCommand::container_addr(Datapath::Ptp, v6, "fd00:10:244::/64", "fd00:10:244::1")
+                .unwrap_err();
+        assert!(err.to_string().contains("IPv4 pods only"));
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
+    fn test_gateway_only_default_route() {
+        std::thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+
+            let mut netlink = Netlink::new();
+            netlink
+                .link_add(&Kind::Veth {
+                    attrs: LinkAttrs::new("eth0"),
+                    peer_name: "eth0-peer".to_string(),
+                    peer_hw_addr: None,
+                    peer_ns: None,
+                })
+                .unwrap();
+            let link = netlink.link_get(&LinkAttrs::new("eth0")).unwrap();
+            netlink.link_up(&link).unwrap();
+            let address = AddressBuilder::default()
+                .ip("10.244.0.5/24".parse::<IpNet>().unwrap())
+                .build()
+                .unwrap();
+            netlink.addr_add(&link, &address).unwrap();
+
+            // Without an oif the route carries no RTA_OIF and the kernel
+            // picks eth0 from the gateway's subnet.
+            let route = RoutingBuilder::default()
+                .gw(Some("10.244.0.1".parse::<IpAddr>().unwrap()))
+                .build()
+                .unwrap();
+            assert_eq!(route.oif_index, 0);
+            netlink.route_add(&route).unwrap();
+
+            // /proc/self/net belongs to the main thread's netns, not this one.
+            let routes = fs::read_to_string("/proc/thread-self/net/route").unwrap();
+            assert!(routes.lines().any(|line| {
+                let fields = line.split_whitespace().collect::<Vec<_>>();
+                fields[..3] == ["eth0", "00000000", "0100F40A"]
+            }));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_ip_version() {
+        let ip = Ip::new("10.244.0.2/24".to_string(), "10.244.0.1".to_string());
+        assert_eq!(ip.version, "4");
+
+        let ip = Ip::new(
+            "fd00:10:244::2/64".to_string(),
+            "fd00:10:244::1".to_string(),
+        );
+        assert_eq!(ip.version, "6");
+    }
+
+    #[test]
+    fn test_add_step_errors() {
+        let positions = AddStep::ALL
+            .iter()
+            .map(AddStep::position)
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![1, 2, 3, 4, 5, 6]);
+
+        let err = AddStep::MovePeer
+            .run("peerBEEF", || -> Result<()> {
+                Err(anyhow::anyhow!("Invalid argument (os error 22)"))
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "step 3/6 (move the peer into the container netns) failed for peerBEEF"
+        );
+        assert_eq!(
+            err.root_cause().to_string(),
+            "Invalid argument (os error 22)"
+        );
+
+        let err = AddStep::RenamePeer
+            .run("peerBEEF", || -> Result<()> {
+                Err(anyhow::anyhow!("eth0 is missing after the rename"))
+            })
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "step 4/6 (rename the peer) failed for peerBEEF: eth0 is missing after the rename"
+        );
+
+        assert_eq!(AddStep::RoutePeer.run("eth0", || Ok(7)).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_create_with_unique_suffix_retries_on_collision() {
+        let mut suffixes = vec!["BEEF", "BEEF", "CAFE"].into_iter();
+        let mut taken = vec!["BEEF".to_string()];
+
+        let suffix = AddCommand::create_with_unique_suffix(
+            || suffixes.next().unwrap().to_string(),
+            |suffix| {
+                if taken.iter().any(|name| name == suffix) {
+                    return Err(anyhow::anyhow!("File exists (os error 17)"));
+                }
+                taken.push(suffix.to_string());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(suffix, "CAFE");
+        assert_eq!(taken, vec!["BEEF", "CAFE"]);
+    }
+
+    #[test]
+    fn test_create_with_unique_suffix_gives_up() {
+        let mut attempts = 0;
+
+        let result = AddCommand::create_with_unique_suffix(
+            || "BEEF".to_string(),
+            |_| {
+                attempts += 1;
+                Err(anyhow::anyhow!("File exists (os error 17)"))
+            },
+        );
+
+        assert!(result.is_err());
+        assert_eq!(attempts, VETH_NAME_ATTEMPTS);
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{bail, Result};
+
+/// The pod identity the runtime passes in `CNI_ARGS`, e.g.
+/// `IgnoreUnknown=1;K8S_POD_NAMESPACE=default;K8S_POD_NAME=nginx;...`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CniArgs {
+    pub pod_namespace: Option<String>,
+    pub pod_name: Option<String>,
+    pub pod_infra_container_id: Option<String>,
+    pub pod_uid: Option<String>,
+}
+
+impl CniArgs {
+    /// Parses `;`-separated `KEY=VALUE` pairs. As the spec asks, a key this
+    /// plugin does not know is an error unless `IgnoreUnknown` is set.
+    pub fn parse(args: &str) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut unknown = vec![];
+        let mut ignore_unknown = false;
+
+        for pair in args.split(';').filter(|pair| !pair.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                bail!("invalid CNI_ARGS pair {:?}, expected KEY=VALUE", pair);
+            };
+            if key.is_empty() {
+                bail!("invalid CNI_ARGS pair {:?}, the key is empty", pair);
+            }
+
+            let value = (!value.is_empty()).then(|| value.to_owned());
+            match key {
+                "IgnoreUnknown" => {
+                    ignore_unknown = matches!(value.as_deref(), Some("1" | "true"));
+                }
+                "K8S_POD_NAMESPACE" => parsed.pod_namespace = value,
+                "K8S_POD_NAME" => parsed.pod_name = value,
+                "K8S_POD_INFRA_CONTAINER_ID" => parsed.pod_infra_container_id = value,
+                "K8S_POD_UID" => parsed.pod_uid = value,
+                _ => unknown.push(key.to_owned()),
+            }
+        }
+
+        if !unknown.is_empty() && !ignore_unknown {
+            bail!("unknown CNI_ARGS {}", unknown.join(", "));
+        }
+
+        Ok(parsed)
+    }
+
+    /// `namespace/name` of the pod, when the runtime passed both.
+    pub fn pod(&self) -> Option<String> {
+        Some(format!(
+            "{}/{}",
+            self.pod_namespace.as_deref()?,
+            self.pod_name.as_deref()?
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kubelet_args() {
+        let args = CniArgs::parse(
+            "IgnoreUnknown=1;K8S_POD_NAMESPACE=default;K8S_POD_NAME=nginx-7c5ddbdf54-x2k9v;\
+             K8S_POD_INFRA_CONTAINER_ID=4f1c2a;K8S_POD_UID=0b9e;K8S_POD_NETWORK=extra",
+        )
+        .unwrap();
+
+        assert_eq!(args.pod_namespace.as_deref(), Some("default"));
+        assert_eq!(args.pod_infra_container_id.as_deref(), Some("4f1c2a"));
+        assert_eq!(args.pod_uid.as_deref(), Some("0b9e"));
+        assert_eq!(
+            args.pod().as_deref(),
+            Some("default/nginx-7c5ddbdf54-x2k9v")
+        );
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(CniArgs::parse("").unwrap(), CniArgs::default());
+        assert_eq!(CniArgs::parse(";;").unwrap(), CniArgs::default());
+
+        let args = CniArgs::parse("K8S_POD_NAMESPACE=default;K8S_POD_NAME=").unwrap();
+        assert_eq!(args.pod_name, None);
+        assert_eq!(args.pod(), None);
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        for args in [
+            "K8S_POD_NAME",
+            "K8S_POD_NAMESPACE=default;garbage",
+            "=value",
+            "K8S_POD_NAME=nginx;FOO=bar",
+            "IgnoreUnknown=0;FOO=bar",
+        ] {
+            assert!(CniArgs::parse(args).is_err(), "{:?}", args);
+        }
+    }
+}
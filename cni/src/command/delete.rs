@@ -2,32 +2,105 @@ use std::{env, fs::File};
 
 use anyhow::Result;
 use async_trait::async_trait;
-use nix::sched::{setns, CloneFlags};
-use reqwest::Client;
 use rsln::{
     netlink::Netlink,
     types::{addr::AddrFamily, link::LinkAttrs},
 };
-use sinabro_config::Config;
-use tokio::task::spawn_blocking;
+use sinabro_config::{host_veth_name, Config, IpamMode};
 use tracing::{debug, info};
 
-use super::CniCommand;
+use super::{
+    current_attachment_id, netns,
+    result_cache::{CachedResult, ResultCache},
+    CniCommand,
+};
+use crate::{
+    agent_client::AgentClient,
+    error::{CniError, ERR_AGENT_REJECTED},
+    host_local::HostLocalStore,
+};
 
 pub struct DeleteCommand;
 
 #[async_trait]
 impl CniCommand for DeleteCommand {
-    async fn run(&self, _cni_config: &Config) -> Result<()> {
-        let netns = env::var("CNI_NETNS")?;
-        let netns_file = File::open(&netns)?;
+    async fn run(&self, cni_config: &Config) -> Result<()> {
+        let container_id = env::var("CNI_CONTAINERID")?;
         let cni_if_name = env::var("CNI_IFNAME")?;
 
-        let client = Client::new();
+        let cache = ResultCache::new();
+        let cached = cache.load(&container_id, &cni_if_name);
+
+        if cached.as_ref().is_some_and(|c| c.ip_from_prev_result) {
+            debug!("(DELETE) container ip came from prevResult, not releasing it");
+        } else if cni_config.ipam_mode() == IpamMode::HostLocal {
+            let ip = HostLocalStore::new(cni_config.name).release(&current_attachment_id()?)?;
+            debug!("(DELETE) released container ip: {:?}", ip);
+        } else {
+            // Releasing by owner also covers an ADD that failed after the
+            // allocation but before it could cache or assign the address.
+            let agent = AgentClient::new()?;
+            let ips = agent.release_owner(&current_attachment_id()?).await?;
+            debug!("(DELETE) released container ips: {:?}", ips);
+
+            if ips.is_empty() {
+                Self::release_unowned(&agent, cached.as_ref(), cni_if_name.clone()).await?;
+            }
+        }
+
+        Self::delete_host_veth(&Self::host_veth_of(
+            cached.as_ref(),
+            &container_id,
+            &cni_if_name,
+        ));
+        cache.remove(&container_id, &cni_if_name)?;
+
+        Ok(())
+    }
+}
+
+impl DeleteCommand {
+    /// Releases the container address by value, for a lease taken before the
+    /// agent recorded owners.
+    async fn release_unowned(
+        agent: &AgentClient,
+        cached: Option<&CachedResult>,
+        cni_if_name: String,
+    ) -> Result<()> {
+        let container_ip = match cached.and_then(|c| c.result.container_ip()) {
+            Some(ip) => Some(ip.to_string()),
+            None => Self::discover_container_ip(cni_if_name).await?,
+        };
+
+        if let Some(ip) = container_ip {
+            debug!("(DELETE) container ip: {}", ip);
+            // An address the pool no longer hands out has nothing to release,
+            // and DEL must still succeed.
+            if let Err(e) = agent.release_ip(&ip).await {
+                match e.downcast_ref::<CniError>() {
+                    Some(err) if err.code == ERR_AGENT_REJECTED => {
+                        info!("(DELETE) {} was not released: {}", ip, err.msg)
+                    }
+                    _ => return Err(e),
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-        let container_ip = spawn_blocking(move || -> Result<Option<String>> {
-            setns(netns_file, CloneFlags::CLONE_NEWNET)?;
+    /// Looks the container address up in its netns, for attachments without
+    /// a cached result. Returns `None` once the netns is gone.
+    async fn discover_container_ip(cni_if_name: String) -> Result<Option<String>> {
+        let netns_file = match env::var("CNI_NETNS").map(File::open) {
+            Ok(Ok(netns_file)) => netns_file,
+            _ => {
+                info!("(DELETE) netns not found");
+                return Ok(None);
+            }
+        };
 
+        netns::run_in(netns_file, move || -> Result<Option<String>> {
             let mut netlink = Netlink::new();
 
             let link = match netlink.link_get(&LinkAttrs::new(&cni_if_name)) {
@@ -53,17 +126,78 @@ impl CniCommand for DeleteCommand {
 
             Ok(Some(container_ip.to_owned()))
         })
-        .await??;
+        .await
+    }
 
-        if let Some(ip) = container_ip {
-            debug!("(DELETE) container ip: {}", ip);
+    /// The host veth of the attachment: the cached name, or the one derived
+    /// from the container ID when ADD never cached a result.
+    fn host_veth_of(cached: Option<&CachedResult>, container_id: &str, if_name: &str) -> String {
+        match cached {
+            Some(cached) => cached.host_veth.clone(),
+            None => host_veth_name(container_id, if_name),
+        }
+    }
 
-            client
-                .put(format!("http://localhost:3000/ipam/ip/{}", ip))
-                .send()
-                .await?;
+    /// The host veth normally disappears with the netns; this only catches
+    /// one left behind, so failures are not errors.
+    fn delete_host_veth(name: &str) {
+        let mut netlink = Netlink::new();
+
+        if let Ok(veth) = netlink.link_get(&LinkAttrs::new(name)) {
+            match netlink.link_del(&veth) {
+                Ok(_) => info!("(DELETE) deleted host veth {}", name),
+                Err(e) => info!("(DELETE) failed to delete host veth {}: {}", name, e),
+            }
         }
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use rsln::types::link::Kind;
+
+    use super::*;
+
+    #[test]
+    fn test_host_veth_without_cached_result() {
+        assert_eq!(
+            DeleteCommand::host_veth_of(None, "abc123", "eth0"),
+            host_veth_name("abc123", "eth0")
+        );
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
+    fn test_delete_host_veth_without_netns() {
+        std::thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+
+            // ADD failed before moving the peer, and the netns is gone: only
+            // the container ID is left to find the veth by.
+            let host_veth = host_veth_name("abc123", "eth0");
+            let mut netlink = Netlink::new();
+            netlink
+                .link_add(&Kind::Veth {
+                    attrs: LinkAttrs::new(&host_veth),
+                    peer_name: "peer-abc123".to_string(),
+                    peer_hw_addr: None,
+                    peer_ns: None,
+                })
+                .unwrap();
+
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            std::env::set_var("CNI_NETNS", "/var/run/netns/does-not-exist");
+            let container_ip = runtime
+                .block_on(DeleteCommand::discover_container_ip("eth0".to_string()))
+                .unwrap();
+            assert_eq!(container_ip, None);
+
+            DeleteCommand::delete_host_veth(&DeleteCommand::host_veth_of(None, "abc123", "eth0"));
+
+            assert!(netlink.link_get(&LinkAttrs::new(&host_veth)).is_err());
+            assert!(netlink.link_get(&LinkAttrs::new("peer-abc123")).is_err());
+        })
+        .join()
+        .unwrap();
     }
 }
@@ -4,13 +4,29 @@ use sinabro_config::Config;
 use self::{add::AddCommand, delete::DeleteCommand};
 
 mod add;
+mod cni_args;
 mod delete;
+mod netns;
+mod result_cache;
 
 #[async_trait]
 pub trait CniCommand {
     async fn run(&self, cni_config: &Config) -> anyhow::Result<()>;
 }
 
+/// Identifies one attachment of a container: a pod can be attached several
+/// times (e.g. as a Multus secondary network), once per interface name.
+pub fn attachment_id(container_id: &str, if_name: &str) -> String {
+    format!("{}/{}", container_id, if_name)
+}
+
+pub fn current_attachment_id() -> anyhow::Result<String> {
+    Ok(attachment_id(
+        &std::env::var("CNI_CONTAINERID")?,
+        &std::env::var("CNI_IFNAME")?,
+    ))
+}
+
 pub fn cni_command_from(command: &str) -> anyhow::Result<Box<dyn CniCommand>> {
     match command {
         "ADD" => Ok(Box::new(AddCommand)),
@@ -18,3 +34,17 @@ pub fn cni_command_from(command: &str) -> anyhow::Result<Box<dyn CniCommand>> {
         _ => anyhow::bail!("unknown command: {}", command),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attachment_id() {
+        assert_eq!(attachment_id("abc123", "eth0"), "abc123/eth0");
+        assert_ne!(
+            attachment_id("abc123", "eth0"),
+            attachment_id("abc123", "net1")
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::add::AddResult;
+
+const RESULTS_DIR: &str = "/var/lib/sinabro/results";
+
+/// What ADD did for one attachment, kept so DEL can clean up after the
+/// container's netns is gone.
+#[derive(Serialize, Deserialize)]
+pub struct CachedResult {
+    pub result: AddResult,
+    pub host_veth: String,
+    pub lease_id: String,
+    /// The address came from `prevResult`, so it is not ours to release.
+    #[serde(default)]
+    pub ip_from_prev_result: bool,
+}
+
+/// On-disk cache of ADD results, one JSON file per attachment, in the
+/// spirit of the reference plugins' cached results.
+pub struct ResultCache {
+    dir: PathBuf,
+}
+
+impl ResultCache {
+    pub fn new() -> Self {
+        Self::with_root(RESULTS_DIR)
+    }
+
+    pub fn with_root(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Writes the result to a temporary file and renames it into place, so a
+    /// crash mid-write never leaves a truncated entry behind.
+    pub fn save(&self, container_id: &str, if_name: &str, cached: &CachedResult) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let path = self.path_of(container_id, if_name);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(cached)?)?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(())
+    }
+
+    /// Returns `None` when the entry is missing or unreadable; callers fall
+    /// back to best-effort cleanup in both cases.
+    pub fn load(&self, container_id: &str, if_name: &str) -> Option<CachedResult> {
+        let path = self.path_of(container_id, if_name);
+
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("failed to read cached result {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        serde_json::from_slice(&content)
+            .map_err(|e| warn!("ignoring corrupted cached result {}: {}", path.display(), e))
+            .ok()
+    }
+
+    pub fn remove(&self, container_id: &str, if_name: &str) -> Result<()> {
+        match fs::remove_file(self.path_of(container_id, if_name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn path_of(&self, container_id: &str, if_name: &str) -> PathBuf {
+        self.dir.join(format!("{}-{}.json", container_id, if_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    fn cached() -> CachedResult {
+        CachedResult {
+            result: AddResult::new(
+                "eth0".to_string(),
+                "0a:58:0a:f4:00:02".to_string(),
+                "/var/run/netns/test".to_string(),
+                "10.244.0.2/24".to_string(),
+                "10.244.0.1".to_string(),
+            ),
+            host_veth: "vethBEEF".to_string(),
+            lease_id: "abc123/eth0".to_string(),
+            ip_from_prev_result: false,
+        }
+    }
+
+    #[test]
+    fn test_save_load_remove() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cache = ResultCache::with_root(tmp_dir.path().join("results"));
+
+        cache.save("abc123", "eth0", &cached()).unwrap();
+
+        let loaded = cache.load("abc123", "eth0").unwrap();
+        assert_eq!(
+            loaded.result.container_ip(),
+            Some("10.244.0.2".parse::<IpAddr>().unwrap())
+        );
+        assert_eq!(loaded.host_veth, "vethBEEF");
+        assert_eq!(loaded.lease_id, "abc123/eth0");
+        assert!(cache.load("abc123", "net1").is_none());
+
+        cache.remove("abc123", "eth0").unwrap();
+        assert!(cache.load("abc123", "eth0").is_none());
+        cache.remove("abc123", "eth0").unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupted_result() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cache = ResultCache::with_root(tmp_dir.path());
+
+        fs::write(tmp_dir.path().join("abc123-eth0.json"), b"{\"result\":").unwrap();
+
+        assert!(cache.load("abc123", "eth0").is_none());
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// CNI error code asking the runtime to retry the whole operation later.
+pub const ERR_TRY_AGAIN_LATER: u32 = 11;
+
+/// Plugin-specific code for a pool with no address left to hand out.
+pub const ERR_NO_FREE_ADDRESSES: u32 = 100;
+
+/// Plugin-specific code for a request the agent refused as invalid.
+pub const ERR_AGENT_REJECTED: u32 = 101;
+
+/// Error reported to the runtime in the CNI error format, so it can tell a
+/// transient failure from a broken configuration.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CniError {
+    cni_version: &'static str,
+    pub code: u32,
+    pub msg: String,
+}
+
+impl CniError {
+    pub fn new(code: u32, msg: impl Into<String>) -> Self {
+        Self {
+            cni_version: "0.3.0",
+            code,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn try_again_later(msg: impl Into<String>) -> Self {
+        Self::new(ERR_TRY_AGAIN_LATER, msg)
+    }
+}
+
+impl fmt::Display for CniError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.msg, self.code)
+    }
+}
+
+impl std::error::Error for CniError {}
@@ -0,0 +1,159 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use ipnet::IpNet;
+use nix::fcntl::{Flock, FlockArg};
+
+const STORE_ROOT: &str = "/var/lib/cni/sinabro";
+const LAST_RESERVED_FILE: &str = "last_reserved_ip.0";
+const LOCK_FILE: &str = "lock";
+
+/// File-backed allocator used when the plugin cannot rely on the agent.
+///
+/// The layout follows the reference host-local plugin: one file per
+/// allocated address holding its owner, plus a pointer to the last reserved
+/// address so allocation continues round-robin instead of reusing freed
+/// addresses immediately.
+pub struct HostLocalStore {
+    dir: PathBuf,
+}
+
+impl HostLocalStore {
+    pub fn new(network: &str) -> Self {
+        Self::with_root(STORE_ROOT, network)
+    }
+
+    pub fn with_root(root: impl AsRef<Path>, network: &str) -> Self {
+        Self {
+            dir: root.as_ref().join(network),
+        }
+    }
+
+    pub fn allocate(&self, subnet: &IpNet, gateway: IpAddr, owner: &str) -> Result<IpAddr> {
+        let _lock = self.lock()?;
+        let last_reserved = self.last_reserved();
+
+        let after = subnet
+            .hosts()
+            .filter(|ip| last_reserved.is_none_or(|last| *ip > last));
+        let before = subnet
+            .hosts()
+            .filter(|ip| last_reserved.is_some_and(|last| *ip <= last));
+
+        for ip in after.chain(before).filter(|ip| *ip != gateway) {
+            let mut file = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.dir.join(ip.to_string()))
+            {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            file.write_all(owner.as_bytes())?;
+            fs::write(self.dir.join(LAST_RESERVED_FILE), ip.to_string())?;
+
+            return Ok(ip);
+        }
+
+        bail!("no free addresses left in {}", subnet)
+    }
+
+    pub fn release(&self, owner: &str) -> Result<Option<IpAddr>> {
+        let _lock = self.lock()?;
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Some(ip) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<IpAddr>().ok())
+            else {
+                continue;
+            };
+
+            if fs::read_to_string(entry.path())?.trim() == owner {
+                fs::remove_file(entry.path())?;
+                return Ok(Some(ip));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn last_reserved(&self) -> Option<IpAddr> {
+        fs::read_to_string(self.dir.join(LAST_RESERVED_FILE))
+            .ok()
+            .and_then(|ip| ip.trim().parse().ok())
+    }
+
+    fn lock(&self) -> Result<Flock<File>> {
+        fs::create_dir_all(&self.dir)?;
+        let file = File::create(self.dir.join(LOCK_FILE))?;
+
+        Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, e)| anyhow!(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, HostLocalStore, IpNet, IpAddr) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let store = HostLocalStore::with_root(tmp_dir.path(), "sinabro");
+        let subnet = "10.244.0.0/29".parse::<IpNet>().unwrap();
+        let gateway = "10.244.0.1".parse::<IpAddr>().unwrap();
+
+        (tmp_dir, store, subnet, gateway)
+    }
+
+    #[test]
+    fn test_allocate_skips_gateway() {
+        let (_tmp_dir, store, subnet, gateway) = setup();
+
+        let ip = store.allocate(&subnet, gateway, "c1").unwrap();
+        assert_eq!(ip, "10.244.0.2".parse::<IpAddr>().unwrap());
+
+        let ip = store.allocate(&subnet, gateway, "c2").unwrap();
+        assert_eq!(ip, "10.244.0.3".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_release_and_round_robin() {
+        let (_tmp_dir, store, subnet, gateway) = setup();
+
+        store.allocate(&subnet, gateway, "c1").unwrap();
+        store.allocate(&subnet, gateway, "c2").unwrap();
+
+        let released = store.release("c1").unwrap();
+        assert_eq!(released, Some("10.244.0.2".parse::<IpAddr>().unwrap()));
+        assert_eq!(store.release("c1").unwrap(), None);
+
+        let ip = store.allocate(&subnet, gateway, "c3").unwrap();
+        assert_eq!(ip, "10.244.0.4".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_allocate_exhausted() {
+        let (_tmp_dir, store, subnet, gateway) = setup();
+
+        for i in 0..5 {
+            store
+                .allocate(&subnet, gateway, &format!("c{}", i))
+                .unwrap();
+        }
+
+        assert!(store.allocate(&subnet, gateway, "c5").is_err());
+
+        store.release("c0").unwrap();
+        let ip = store.allocate(&subnet, gateway, "c5").unwrap();
+        assert_eq!(ip, "10.244.0.2".parse::<IpAddr>().unwrap());
+    }
+}
@@ -1,7 +1,11 @@
+mod agent_client;
 mod command;
+mod error;
+mod host_local;
 
 use std::{env, io};
 
+use error::CniError;
 use sinabro_config::Config;
 use tracing::{debug, error, Level};
 
@@ -18,10 +22,13 @@ async fn main() -> anyhow::Result<()> {
 
     let cni_config = Config::from(stdin.as_str());
     let cni_command = command::cni_command_from(&command)?;
-    cni_command.run(&cni_config).await.map_err(|e| {
+    if let Err(e) = cni_command.run(&cni_config).await {
         error!("error: {:?}", e);
-        e
-    })?;
+        if let Some(cni_error) = e.downcast_ref::<CniError>() {
+            println!("{}", serde_json::to_string(cni_error)?);
+        }
+        return Err(e);
+    }
 
     Ok(())
 }
@@ -1,7 +1,25 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 pub const CLUSTER_CIDR_KEY: u8 = 0;
 pub const HOST_IP_KEY: u8 = 1;
+pub const LOCAL_POD_CIDR_KEY: u8 = 2;
+pub const SERVICE_CIDR_KEY: u8 = 3;
+
+// Indices into the per-CPU STATS_MAP counters.
+pub const STATS_MAX_ENTRIES: u32 = 32;
+pub const STAT_ARP_REQUEST_INGRESS: u32 = 0;
+pub const STAT_ARP_REPLY_INGRESS: u32 = 1;
+pub const STAT_ARP_REQUEST_EGRESS: u32 = 2;
+pub const STAT_ARP_REPLY_EGRESS: u32 = 3;
+pub const STAT_SOCK_OPS_ADDED: u32 = 4;
+pub const STAT_SOCK_OPS_REMOVED: u32 = 5;
+pub const STAT_SOCK_OPS_SKIPPED: u32 = 6;
+// What the sk_msg program did with the messages it saw; redirected bytes
+// are the ones that skipped the host's TCP/IP stack.
+pub const STAT_SK_MSG_EXAMINED: u32 = 7;
+pub const STAT_SK_MSG_REDIRECTED: u32 = 8;
+pub const STAT_SK_MSG_REDIRECT_FAILED: u32 = 9;
+pub const STAT_SK_MSG_REDIRECTED_BYTES: u32 = 10;
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -36,8 +54,100 @@ pub struct NetworkInfo {
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for NetworkInfo {}
 
+impl NetworkInfo {
+    /// Builds the network info for `ip/prefix_len`, with `ip` in host byte order.
+    pub fn new(ip: u32, prefix_len: u32) -> Self {
+        Self {
+            ip,
+            subnet_mask: prefix_to_mask(prefix_len),
+        }
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, ip: u32) -> bool {
+        self.ip & self.subnet_mask == ip & self.subnet_mask
+    }
+}
+
+#[inline(always)]
+pub fn prefix_to_mask(prefix_len: u32) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len.min(32)).unwrap_or(0)
+}
+
+/// Whether `ip` is treated as cluster-internal: either inside `cidr` or one of
+/// the node addresses, which are never translated.
+#[inline(always)]
+pub fn is_ip_in_cidr(ip: u32, cidr: &NetworkInfo, is_node_ip: impl Fn(u32) -> bool) -> bool {
+    is_node_ip(ip) || cidr.contains(ip)
+}
+
+/// What `tc_egress` does with a TCP packet leaving the node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EgressAction {
+    Pass,
+    Masquerade,
+}
+
+/// Decides whether `src_ip -> dst_ip` is masqueraded on egress. A pod on this
+/// node, i.e. inside `local_pods`, is never a masquerade target even when the
+/// cluster CIDR misses it: its reply would not come back through the
+/// interface that holds the translation. Neither is a ClusterIP in
+/// `services`, which the service proxy's DNAT path handles.
+#[inline(always)]
+pub fn egress_action(
+    src_ip: u32,
+    dst_ip: u32,
+    cluster: &NetworkInfo,
+    local_pods: Option<&NetworkInfo>,
+    services: Option<&NetworkInfo>,
+    is_node_ip: impl Fn(u32) -> bool,
+) -> EgressAction {
+    if [local_pods, services]
+        .into_iter()
+        .flatten()
+        .any(|cidr| cidr.contains(dst_ip))
+    {
+        return EgressAction::Pass;
+    }
+
+    if is_ip_in_cidr(dst_ip, cluster, &is_node_ip) || is_node_ip(src_ip) {
+        EgressAction::Pass
+    } else {
+        EgressAction::Masquerade
+    }
+}
+
+/// skb->mark bits set and honored by the tc programs. A zero field disables
+/// the corresponding behavior.
+#[derive(Clone, Copy, Default)]
 #[repr(C)]
-#[derive(Clone, Copy)]
+pub struct MarkConfig {
+    /// Set on packets sinabro masqueraded.
+    pub masquerade: u32,
+    /// Set on packets whose source is a pod in the cluster CIDR.
+    pub pod: u32,
+    /// Packets carrying all of these bits skip sinabro's NAT entirely.
+    pub exclude: u32,
+    /// Set on pod packets entering the host through the bridge, early enough
+    /// for fwmark routing rules to see it.
+    pub route: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MarkConfig {}
+
+impl MarkConfig {
+    #[inline(always)]
+    pub fn excludes(&self, mark: u32) -> bool {
+        self.exclude != 0 && mark & self.exclude == self.exclude
+    }
+}
+
+pub const AF_INET: u32 = 2;
+pub const AF_INET6: u32 = 10;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct SockKey {
     pub src_ip: u32,
     pub dst_ip: u32,
@@ -48,3 +158,283 @@ pub struct SockKey {
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for SockKey {}
+
+impl SockKey {
+    /// Key sock_ops stores an established socket under. Addresses and the
+    /// remote port come in network byte order, the local port in host order.
+    #[inline(always)]
+    pub fn from_sock_ops(local_ip: u32, remote_ip: u32, local_port: u32, remote_port: u32) -> Self {
+        Self {
+            src_ip: u32::from_be(local_ip),
+            dst_ip: u32::from_be(remote_ip),
+            src_port: local_port,
+            dst_port: u32::from_be(remote_port),
+            family: AF_INET,
+        }
+    }
+
+    /// Key of the peer socket a message sent by sk_msg is redirected to: the
+    /// sender's view with local and remote swapped.
+    #[inline(always)]
+    pub fn peer_of_sk_msg(
+        local_ip: u32,
+        remote_ip: u32,
+        local_port: u32,
+        remote_port: u32,
+    ) -> Self {
+        Self {
+            src_ip: u32::from_be(remote_ip),
+            dst_ip: u32::from_be(local_ip),
+            src_port: u32::from_be(remote_port),
+            dst_port: local_port,
+            family: AF_INET,
+        }
+    }
+}
+
+/// Log every connection sock_ops adds to the socket maps.
+pub const SOCK_OPS_DEBUG: u32 = 1 << 0;
+/// Leave IPv4 connections on the regular network stack.
+pub const SOCK_OPS_SKIP_IPV4: u32 = 1 << 1;
+/// Leave IPv6 connections on the regular network stack.
+pub const SOCK_OPS_SKIP_IPV6: u32 = 1 << 2;
+
+/// Runtime switches for the socket acceleration programs. The zero value
+/// accelerates both families with logging off.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct SockOpsConfig {
+    pub flags: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SockOpsConfig {}
+
+impl SockOpsConfig {
+    #[inline(always)]
+    pub fn debug(&self) -> bool {
+        self.flags & SOCK_OPS_DEBUG != 0
+    }
+
+    #[inline(always)]
+    pub fn accelerates(&self, family: u32) -> bool {
+        match family {
+            AF_INET => self.flags & SOCK_OPS_SKIP_IPV4 == 0,
+            AF_INET6 => self.flags & SOCK_OPS_SKIP_IPV6 == 0,
+            _ => false,
+        }
+    }
+}
+
+/// IPv6 counterpart of `SockKey`. Addresses are kept as the kernel hands
+/// them over, in network byte order words.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SockKeyV6 {
+    pub src_ip: [u32; 4],
+    pub dst_ip: [u32; 4],
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub family: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SockKeyV6 {}
+
+impl SockKeyV6 {
+    #[inline(always)]
+    pub fn from_sock_ops(
+        local_ip: [u32; 4],
+        remote_ip: [u32; 4],
+        local_port: u32,
+        remote_port: u32,
+    ) -> Self {
+        Self {
+            src_ip: local_ip,
+            dst_ip: remote_ip,
+            src_port: local_port,
+            dst_port: u32::from_be(remote_port),
+            family: AF_INET6,
+        }
+    }
+
+    #[inline(always)]
+    pub fn peer_of_sk_msg(
+        local_ip: [u32; 4],
+        remote_ip: [u32; 4],
+        local_port: u32,
+        remote_port: u32,
+    ) -> Self {
+        Self {
+            src_ip: remote_ip,
+            dst_ip: local_ip,
+            src_port: u32::from_be(remote_port),
+            dst_port: local_port,
+            family: AF_INET6,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn ip(a: u8, b: u8, c: u8, d: u8) -> u32 {
+        u32::from_be_bytes([a, b, c, d])
+    }
+
+    #[test]
+    fn test_prefix_to_mask() {
+        assert_eq!(prefix_to_mask(0), 0);
+        assert_eq!(prefix_to_mask(16), 0xffff_0000);
+        assert_eq!(prefix_to_mask(32), u32::MAX);
+        assert_eq!(prefix_to_mask(33), u32::MAX);
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let cluster = NetworkInfo::new(ip(10, 244, 0, 0), 16);
+        assert!(cluster.contains(ip(10, 244, 0, 1)));
+        assert!(cluster.contains(ip(10, 244, 255, 255)));
+        assert!(!cluster.contains(ip(10, 245, 0, 1)));
+
+        let host = NetworkInfo::new(ip(172, 18, 0, 3), 32);
+        assert!(host.contains(ip(172, 18, 0, 3)));
+        assert!(!host.contains(ip(172, 18, 0, 4)));
+
+        let any = NetworkInfo::new(ip(10, 0, 0, 0), 0);
+        assert!(any.contains(ip(8, 8, 8, 8)));
+    }
+
+    #[test]
+    fn test_is_ip_in_cidr_node_exception() {
+        let cluster = NetworkInfo::new(ip(10, 244, 0, 0), 16);
+        let node = ip(172, 18, 0, 2);
+        let is_node_ip = |addr| addr == node;
+
+        assert!(is_ip_in_cidr(ip(10, 244, 1, 5), &cluster, is_node_ip));
+        assert!(is_ip_in_cidr(node, &cluster, is_node_ip));
+        assert!(!is_ip_in_cidr(ip(1, 1, 1, 1), &cluster, is_node_ip));
+    }
+
+    #[test]
+    fn test_egress_action() {
+        use EgressAction::*;
+
+        let cluster = NetworkInfo::new(ip(10, 244, 0, 0), 16);
+        // A pod CIDR the cluster CIDR does not cover, as with a second range.
+        let local_pods = NetworkInfo::new(ip(10, 250, 3, 0), 24);
+        let services = NetworkInfo::new(ip(10, 96, 0, 0), 12);
+        let node = ip(172, 18, 0, 2);
+        let is_node_ip = |addr| addr == node;
+
+        let local_pod = ip(10, 250, 3, 7);
+        let remote_pod = ip(10, 244, 1, 5);
+        let external = ip(1, 1, 1, 1);
+        let cluster_ip = ip(10, 96, 0, 10);
+
+        let table = [
+            (local_pod, local_pod, Pass),
+            (local_pod, remote_pod, Pass),
+            (local_pod, node, Pass),
+            (local_pod, external, Masquerade),
+            (local_pod, cluster_ip, Pass),
+            (remote_pod, cluster_ip, Pass),
+            (remote_pod, local_pod, Pass),
+            (remote_pod, external, Masquerade),
+            (node, local_pod, Pass),
+            (node, external, Pass),
+        ];
+
+        for (src, dst, action) in table {
+            assert_eq!(
+                egress_action(
+                    src,
+                    dst,
+                    &cluster,
+                    Some(&local_pods),
+                    Some(&services),
+                    is_node_ip
+                ),
+                action,
+                "{:x} -> {:x}",
+                src,
+                dst
+            );
+        }
+
+        // Without the local entry, e.g. from an older agent, only the
+        // cluster CIDR keeps the local pod from being masqueraded.
+        assert_eq!(
+            egress_action(remote_pod, local_pod, &cluster, None, None, is_node_ip),
+            Masquerade
+        );
+        // Likewise a ClusterIP, when the service CIDR is unknown.
+        assert_eq!(
+            egress_action(local_pod, cluster_ip, &cluster, None, None, is_node_ip),
+            Masquerade
+        );
+    }
+
+    #[test]
+    fn test_sk_msg_key_matches_peer_sock_ops_key() {
+        // 10.244.0.5:41000 -> 10.244.0.6:80, as seen from both sockets.
+        let client_ip = u32::from_be_bytes([10, 244, 0, 5]).to_be();
+        let server_ip = u32::from_be_bytes([10, 244, 0, 6]).to_be();
+        let client_port = 41000_u32;
+        let server_port = 80_u32;
+
+        let server = SockKey::from_sock_ops(server_ip, client_ip, server_port, client_port.to_be());
+        let msg = SockKey::peer_of_sk_msg(client_ip, server_ip, client_port, server_port.to_be());
+
+        assert_eq!(msg, server);
+        assert_eq!(server.src_ip, u32::from_be_bytes([10, 244, 0, 6]));
+        assert_eq!(server.dst_port, 41000);
+        assert_eq!(server.family, AF_INET);
+    }
+
+    #[test]
+    fn test_sk_msg_key_v6_matches_peer_sock_ops_key() {
+        let client_ip = [0xfd00_u32.to_be(), 0, 0, 5_u32.to_be()];
+        let server_ip = [0xfd00_u32.to_be(), 0, 0, 6_u32.to_be()];
+
+        let server = SockKeyV6::from_sock_ops(server_ip, client_ip, 80, 41000_u32.to_be());
+        let msg = SockKeyV6::peer_of_sk_msg(client_ip, server_ip, 41000, 80_u32.to_be());
+
+        assert_eq!(msg, server);
+        assert_eq!(server.src_ip, server_ip);
+        assert_eq!(server.family, AF_INET6);
+    }
+
+    #[test]
+    fn test_sock_ops_config() {
+        let config = SockOpsConfig::default();
+        assert!(!config.debug());
+        assert!(config.accelerates(AF_INET));
+        assert!(config.accelerates(AF_INET6));
+        assert!(!config.accelerates(1));
+
+        let config = SockOpsConfig {
+            flags: SOCK_OPS_DEBUG | SOCK_OPS_SKIP_IPV6,
+        };
+        assert!(config.debug());
+        assert!(config.accelerates(AF_INET));
+        assert!(!config.accelerates(AF_INET6));
+    }
+
+    #[test]
+    fn test_mark_config_excludes() {
+        let disabled = MarkConfig::default();
+        assert!(!disabled.excludes(0));
+        assert!(!disabled.excludes(u32::MAX));
+
+        let config = MarkConfig {
+            exclude: 0x0300,
+            ..Default::default()
+        };
+        assert!(config.excludes(0x0300));
+        assert!(config.excludes(0x1f300));
+        assert!(!config.excludes(0x0100));
+        assert!(!config.excludes(0));
+    }
+}
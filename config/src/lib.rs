@@ -1,8 +1,18 @@
-use std::path::Path;
+pub mod link_index;
+pub mod retry;
+
+use std::{
+    iter,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
 
 use anyhow::{anyhow, Result};
+use ipnet::IpNet;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tracing::level_filters::LevelFilter;
 use tracing_appender::{non_blocking, rolling};
 use tracing_subscriber::fmt;
@@ -20,6 +30,66 @@ pub struct Config<'a> {
     pub network: &'a str,
 
     pub subnet: &'a str,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipam: Option<IpamConfig>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datapath: Option<Datapath>,
+
+    /// MTU of the pod veths; the agent sets it to the overlay MTU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    /// Result of the plugins before sinabro in a chain, set by the runtime.
+    #[serde(
+        default,
+        rename = "prevResult",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub prev_result: Option<Value>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Datapath {
+    /// Pods are attached to the cni0 bridge and share its broadcast domain.
+    #[default]
+    Bridge,
+    /// Pods get a point-to-point veth: a /32 address, a host route per pod
+    /// and proxy ARP for a link-local gateway. IPv4 only.
+    Ptp,
+}
+
+impl FromStr for Datapath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bridge" => Ok(Datapath::Bridge),
+            "ptp" => Ok(Datapath::Ptp),
+            _ => Err(format!("unknown datapath {:?}, expected bridge or ptp", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpamMode {
+    /// Addresses are allocated by the agent over its local API.
+    #[default]
+    Agent,
+    /// Addresses are allocated by the plugin from a file-backed store.
+    HostLocal,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct IpamConfig {
+    #[serde(default)]
+    pub mode: IpamMode,
 }
 
 impl Config<'_> {
@@ -30,18 +100,112 @@ impl Config<'_> {
             cni_type: "sinabro-cni",
             network,
             subnet,
+            gateway: subnet
+                .parse::<IpNet>()
+                .ok()
+                .map(|pod_cidr| gateway_for(&pod_cidr).to_string()),
+            ipam: None,
+            datapath: None,
+            mtu: None,
+            prev_result: None,
         }
     }
 
+    pub fn with_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    pub fn with_datapath(mut self, datapath: Datapath) -> Self {
+        self.datapath = match datapath {
+            Datapath::Bridge => None,
+            datapath => Some(datapath),
+        };
+        self
+    }
+
+    pub fn datapath(&self) -> Datapath {
+        self.datapath.unwrap_or_default()
+    }
+
+    pub fn ipam_mode(&self) -> IpamMode {
+        self.ipam.as_ref().map(|ipam| ipam.mode).unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
     pub fn write(&self, path: &str) -> Result<()> {
-        let json = serde_json::to_string(self)?;
+        write_atomic(path, &self.to_json()?)
+    }
+}
 
-        if let Some(parent) = std::path::Path::new(path).parent() {
-            std::fs::create_dir_all(parent)?;
+/// A network configuration list: sinabro followed by the plugins chained
+/// after it, e.g. portmap or bandwidth.
+#[derive(Serialize, Deserialize)]
+pub struct ConfList<'a> {
+    #[serde(rename = "cniVersion")]
+    pub cni_version: &'a str,
+
+    pub name: &'a str,
+
+    pub plugins: Vec<Value>,
+}
+
+impl<'a> ConfList<'a> {
+    /// Chains `chained` after `config`. The runtime hands every plugin the
+    /// list's version and name, so sinabro's entry leaves them out.
+    pub fn new(config: &Config<'a>, chained: impl IntoIterator<Item = Value>) -> Result<Self> {
+        let mut sinabro = serde_json::to_value(config)?;
+        if let Some(sinabro) = sinabro.as_object_mut() {
+            sinabro.remove("cniVersion");
+            sinabro.remove("name");
         }
 
-        std::fs::write(path, json).map_err(|e| anyhow!(e))
+        Ok(Self {
+            cni_version: config.cni_version,
+            name: config.name,
+            plugins: iter::once(sinabro).chain(chained).collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        write_atomic(path, &self.to_json()?)
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over
+/// `path`, so the runtime never loads a partially written config.
+pub fn write_atomic(path: &str, contents: &str) -> Result<()> {
+    let path = Path::new(path);
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent directory", path.display()))?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+        .to_string_lossy();
+    std::fs::create_dir_all(parent)?;
+
+    // The runtime skips files not ending in .conf, .conflist or .json, so it
+    // never picks up the temporary one.
+    let tmp = parent.join(format!(".{}.tmp-{}", name, std::process::id()));
+    let result = std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            std::io::Write::write_all(&mut file, contents.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
     }
+
+    result.map_err(|e| anyhow!("failed to write {}: {}", path.display(), e))
 }
 
 impl<'a> From<&'a str> for Config<'a> {
@@ -50,6 +214,76 @@ impl<'a> From<&'a str> for Config<'a> {
     }
 }
 
+/// Returns the pod gateway address for a pod CIDR: the first address after
+/// the network address. Both the bridge and the container default route use
+/// this, so it must stay the single place where the gateway is derived.
+pub fn gateway_for(pod_cidr: &IpNet) -> IpAddr {
+    match pod_cidr {
+        IpNet::V4(v4) => {
+            let net = u32::from(v4.network()).wrapping_add(1);
+            IpAddr::V4(Ipv4Addr::from(net))
+        }
+        IpNet::V6(v6) => {
+            let net = u128::from(v6.network()).wrapping_add(1);
+            IpAddr::V6(Ipv6Addr::from(net))
+        }
+    }
+}
+
+/// Size of the kernel's interface name buffer, including the trailing NUL.
+pub const IFNAMSIZ: usize = 16;
+
+/// Checks `name` the way the kernel's `dev_valid_name` does, so a bad name
+/// fails here with a reason instead of as a bare `EINVAL` from netlink. The
+/// limit is in bytes: a multibyte UTF-8 name runs out sooner than its
+/// character count suggests.
+pub fn validate_ifname(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(anyhow!("invalid interface name {:?}", name));
+    }
+    if name.len() >= IFNAMSIZ {
+        return Err(anyhow!(
+            "interface name {:?} is {} bytes, the limit is {}",
+            name,
+            name.len(),
+            IFNAMSIZ - 1
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| matches!(c, '/' | ':' | '\0') || c.is_whitespace())
+    {
+        return Err(anyhow!(
+            "interface name {:?} must not contain {:?}",
+            name,
+            c
+        ));
+    }
+
+    Ok(())
+}
+
+/// Suffix of the host-side veth of an attachment, derived from the
+/// container ID and interface name so DEL and the agent can find the veth
+/// without the container's netns. FNV-1a keeps it stable across builds, and
+/// the hash is always 8 hex digits, so `veth`/`peer` names stay at 12 bytes
+/// however long the container ID and interface name are.
+pub fn veth_suffix(container_id: &str, if_name: &str) -> String {
+    let hash = fnv1a(container_id.bytes().chain([b'/']).chain(if_name.bytes()));
+
+    format!("{:08x}", (hash ^ (hash >> 32)) as u32)
+}
+
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn host_veth_name(container_id: &str, if_name: &str) -> String {
+    format!("veth{}", veth_suffix(container_id, if_name))
+}
+
 pub fn setup_tracing_to_stdout(filter: impl Into<LevelFilter>) {
     fmt().with_max_level(filter).init();
 }
@@ -79,6 +313,22 @@ pub fn generate_mac() -> Result<Vec<u8>> {
     Ok(buf.to_vec())
 }
 
+/// A locally administered unicast MAC for `device` on the node at
+/// `host_ip`. The same inputs always give the same MAC, so a restarted
+/// agent keeps its devices' MACs and other nodes' neighbor entries stay
+/// valid.
+pub fn derive_mac(host_ip: IpAddr, device: &str) -> Vec<u8> {
+    let ip = match host_ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let hash = fnv1a(device.bytes().chain([b'/']).chain(ip));
+
+    let mut mac = hash.to_be_bytes()[2..].to_vec();
+    mac[0] = (mac[0] | 0x02) & 0xfe;
+    mac
+}
+
 pub fn parse_mac(mac: &str) -> Result<Vec<u8>> {
     let mac = mac
         .split(':')
@@ -107,13 +357,32 @@ mod tests {
             .write("/tmp/10-sinabro.conf")
             .unwrap();
 
-        let expected = r#"{"cniVersion":"0.3.1","name":"sinabro","type":"sinabro-cni","network":"10.244.0.0/16","subnet":"10.244.0.0/24"}"#;
+        let expected = r#"{"cniVersion":"0.3.1","name":"sinabro","type":"sinabro-cni","network":"10.244.0.0/16","subnet":"10.244.0.0/24","gateway":"10.244.0.1"}"#;
         let json = std::fs::read_to_string("/tmp/10-sinabro.conf").unwrap();
         std::fs::remove_file("/tmp/10-sinabro.conf").unwrap();
 
         assert_eq!(expected, json);
     }
 
+    #[test]
+    fn write_atomic_replaces_the_file() {
+        let dir = format!("/tmp/sinabro-write-atomic-{}", std::process::id());
+        let path = format!("{}/10-sinabro.conf", dir);
+
+        write_atomic(&path, "old").unwrap();
+        write_atomic(&path, "new").unwrap();
+
+        let entries = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(contents, "new");
+        assert_eq!(entries, vec!["10-sinabro.conf"]);
+    }
+
     #[test]
     fn config_from_json() {
         let json = r#"{"cniVersion":"0.3.1","name":"sinabro","type":"sinabro-cni","network":"10.244.0.0/16","subnet":"10.244.0.0/24"}"#;
@@ -124,6 +393,105 @@ mod tests {
         assert_eq!("sinabro-cni", cni_config.cni_type);
         assert_eq!("10.244.0.0/16", cni_config.network);
         assert_eq!("10.244.0.0/24", cni_config.subnet);
+        assert_eq!(None, cni_config.gateway);
+        assert_eq!(IpamMode::Agent, cni_config.ipam_mode());
+    }
+
+    #[test]
+    fn config_with_host_local_ipam() {
+        let json = r#"{"cniVersion":"0.3.1","name":"sinabro","type":"sinabro-cni","network":"10.244.0.0/16","subnet":"10.244.0.0/24","ipam":{"mode":"host-local"}}"#;
+        let cni_config = Config::from(json);
+
+        assert_eq!(IpamMode::HostLocal, cni_config.ipam_mode());
+    }
+
+    #[test]
+    fn config_with_ptp_datapath() {
+        let config = Config::new("10.244.0.0/16", "10.244.0.0/24").with_datapath(Datapath::Ptp);
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.ends_with(r#""datapath":"ptp"}"#));
+
+        let cni_config = Config::from(json.as_str());
+        assert_eq!(Datapath::Ptp, cni_config.datapath());
+
+        let config = Config::new("10.244.0.0/16", "10.244.0.0/24").with_datapath(Datapath::Bridge);
+        assert_eq!(None, config.datapath);
+        assert_eq!(Datapath::Bridge, config.datapath());
+
+        assert_eq!("ptp".parse::<Datapath>(), Ok(Datapath::Ptp));
+        assert!("l2".parse::<Datapath>().is_err());
+    }
+
+    #[test]
+    fn config_with_mtu() {
+        let config = Config::new("10.244.0.0/16", "10.244.0.0/24").with_mtu(1350);
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.ends_with(r#""mtu":1350}"#));
+
+        assert_eq!(Some(1350), Config::from(json.as_str()).mtu);
+    }
+
+    #[test]
+    fn config_with_prev_result() {
+        let json = r#"{"cniVersion":"0.3.1","name":"sinabro","type":"sinabro-cni","network":"10.244.0.0/16","subnet":"10.244.0.0/24","prevResult":{"cniVersion":"0.3.1","ips":[{"version":"4","address":"10.244.0.9/24"}],"dns":{"nameservers":["10.96.0.10"]}}}"#;
+        let cni_config = Config::from(json);
+
+        let prev_result = cni_config.prev_result.as_ref().unwrap();
+        assert_eq!(prev_result["ips"][0]["address"], "10.244.0.9/24");
+        assert_eq!(prev_result["dns"]["nameservers"][0], "10.96.0.10");
+
+        let json = serde_json::to_value(&cni_config).unwrap();
+        assert_eq!(&json["prevResult"], prev_result);
+    }
+
+    #[test]
+    fn conflist_with_chained_plugins() {
+        let config = Config::new("10.244.0.0/16", "10.244.0.0/24");
+        let portmap = serde_json::json!({
+            "type": "portmap",
+            "capabilities": { "portMappings": true }
+        });
+
+        let conflist = ConfList::new(&config, [portmap]).unwrap();
+        conflist.write("/tmp/10-sinabro.conflist").unwrap();
+
+        let json = std::fs::read_to_string("/tmp/10-sinabro.conflist").unwrap();
+        std::fs::remove_file("/tmp/10-sinabro.conflist").unwrap();
+
+        let expected = serde_json::json!({
+            "cniVersion": "0.3.1",
+            "name": "sinabro",
+            "plugins": [
+                {
+                    "type": "sinabro-cni",
+                    "network": "10.244.0.0/16",
+                    "subnet": "10.244.0.0/24",
+                    "gateway": "10.244.0.1"
+                },
+                {
+                    "type": "portmap",
+                    "capabilities": { "portMappings": true }
+                }
+            ]
+        });
+        assert_eq!(expected, serde_json::from_str::<Value>(&json).unwrap());
+    }
+
+    #[test]
+    fn test_gateway_for() {
+        let cases = [
+            ("10.244.0.0/24", "10.244.0.1"),
+            ("10.244.0.128/25", "10.244.0.129"),
+            ("10.244.0.2/31", "10.244.0.3"),
+            ("10.244.0.7/24", "10.244.0.1"),
+            ("fd00:10:244::/64", "fd00:10:244::1"),
+            ("fd00:10:244:1::/120", "fd00:10:244:1::1"),
+        ];
+
+        for (pod_cidr, gateway) in cases {
+            let pod_cidr = pod_cidr.parse::<IpNet>().unwrap();
+            assert_eq!(gateway_for(&pod_cidr), gateway.parse::<IpAddr>().unwrap());
+        }
     }
 
     #[tokio::test]
@@ -142,6 +510,44 @@ mod tests {
         std::fs::remove_file(&file_name).unwrap();
     }
 
+    #[test]
+    fn test_host_veth_name() {
+        let name = host_veth_name("abc123", "eth0");
+
+        assert_eq!(name, host_veth_name("abc123", "eth0"));
+        assert_ne!(name, host_veth_name("abc123", "net1"));
+        assert_ne!(name, host_veth_name("abc124", "eth0"));
+        assert_eq!(name.len(), 12);
+        assert!(name.len() < 16);
+
+        let long_id = "f".repeat(64);
+        assert!(validate_ifname(&host_veth_name(&long_id, "net1234567890ab")).is_ok());
+    }
+
+    #[test]
+    fn test_validate_ifname() {
+        assert!(validate_ifname("eth0").is_ok());
+        assert!(validate_ifname("sinabro_vxlan").is_ok());
+        assert!(validate_ifname(&"a".repeat(15)).is_ok());
+
+        let e = validate_ifname(&"a".repeat(16)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "interface name {:?} is 16 bytes, the limit is 15",
+                "a".repeat(16)
+            )
+        );
+
+        // Eight characters, but sixteen bytes.
+        assert!(validate_ifname("éééééééé").is_err());
+        assert!(validate_ifname("ééééééé").is_ok());
+
+        for name in ["", ".", "..", "eth/0", "eth:0", "eth 0", "eth\t0"] {
+            assert!(validate_ifname(name).is_err(), "{:?}", name);
+        }
+    }
+
     #[test]
     fn test_generate_mac_addr() {
         let mac_addr = generate_mac().unwrap();
@@ -150,6 +556,39 @@ mod tests {
         assert_eq!(mac_addr[0] & 0x02, 2);
     }
 
+    #[test]
+    fn test_derive_mac() {
+        let host_ip = "172.18.0.2".parse().unwrap();
+        let mac = derive_mac(host_ip, "sinabro_vxlan");
+
+        assert_eq!(mac, derive_mac(host_ip, "sinabro_vxlan"));
+        assert_eq!(mac.len(), 6);
+        assert_eq!(mac[0] & 0x01, 0);
+        assert_eq!(mac[0] & 0x02, 2);
+        assert_ne!(mac, derive_mac(host_ip, "cni0"));
+        assert_ne!(
+            mac,
+            derive_mac("172.18.0.3".parse().unwrap(), "sinabro_vxlan")
+        );
+
+        let v6 = derive_mac("fc00::2".parse().unwrap(), "sinabro_vxlan");
+        assert_eq!(v6[0] & 0x03, 0x02);
+    }
+
+    #[test]
+    fn test_derive_mac_spreads_across_a_cluster() {
+        let macs = (0..=u16::MAX)
+            .map(|n| {
+                let [a, b] = n.to_be_bytes();
+                derive_mac(IpAddr::V4(Ipv4Addr::new(10, 0, a, b)), "sinabro_vxlan")
+            })
+            .collect::<std::collections::HashSet<_>>();
+
+        // 46 bits of hash make a collision among 65536 nodes unlikely
+        // (about 3 in 100,000), and none happens for this range.
+        assert_eq!(macs.len(), 1 << 16);
+    }
+
     #[test]
     fn test_parse_mac_valid() {
         let mac_str = "aa:bb:cc:dd:00:01";
@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use anyhow::Result;
+use tracing::warn;
+
+/// `struct nlmsghdr`: length, type, flags, sequence number and port id.
+const NLMSG_HDRLEN: usize = 16;
+/// `struct ifinfomsg`: family, padding, type, index, flags and change mask.
+const IFINFOMSG_LEN: usize = 16;
+/// `struct rtattr`: length and type.
+const RTA_HDRLEN: usize = 4;
+const RECV_BUF_LEN: usize = 32 * 1024;
+
+/// A change to a link, as announced on the `RTNLGRP_LINK` multicast group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LinkEvent {
+    /// The link was created or changed; a rename shows up as a new name for
+    /// the same index.
+    New {
+        index: i32,
+        name: String,
+    },
+    Deleted {
+        index: i32,
+    },
+}
+
+/// Interface indexes by name, kept correct by listening for link events.
+///
+/// Before answering from the cache, the queued events are applied: a deleted
+/// link drops its entry, and a link showing up under another name drops the
+/// entries that no longer match. If the events overflowed the socket the
+/// whole cache is dropped. Without the subscription nothing is cached, as an
+/// entry could not be invalidated.
+pub struct LinkIndexCache {
+    indexes: HashMap<String, i32>,
+    events: Option<OwnedFd>,
+}
+
+impl Default for LinkIndexCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkIndexCache {
+    /// Subscribes to link events in the calling thread's network namespace.
+    pub fn new() -> Self {
+        let events = subscribe()
+            .map_err(|e| warn!("not caching link indexes, failed to watch links: {}", e))
+            .ok();
+
+        Self {
+            indexes: HashMap::new(),
+            events,
+        }
+    }
+
+    /// The index of `name`, from the cache or else from `lookup`.
+    pub fn index_of(
+        &mut self,
+        name: &str,
+        lookup: impl FnOnce(&str) -> Result<i32>,
+    ) -> Result<i32> {
+        if let Some(index) = self.cached(name) {
+            return Ok(index);
+        }
+
+        let index = lookup(name)?;
+        self.insert(name, index);
+
+        Ok(index)
+    }
+
+    pub fn cached(&mut self, name: &str) -> Option<i32> {
+        self.sync();
+        self.indexes.get(name).copied()
+    }
+
+    /// Records an index learned some other way, e.g. from creating the link.
+    pub fn insert(&mut self, name: &str, index: i32) {
+        if self.events.is_some() {
+            self.indexes.insert(name.to_owned(), index);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.indexes.remove(name);
+    }
+
+    fn sync(&mut self) {
+        let Some(events) = &self.events else {
+            return;
+        };
+
+        match drain(events) {
+            Ok(events) => {
+                for event in events {
+                    self.apply(event);
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                warn!("missed link events, dropping the link index cache");
+                self.indexes.clear();
+            }
+            Err(e) => {
+                warn!(
+                    "failed to read link events, no longer caching link indexes: {}",
+                    e
+                );
+                self.indexes.clear();
+                self.events = None;
+            }
+        }
+    }
+
+    fn apply(&mut self, event: LinkEvent) {
+        match event {
+            LinkEvent::New { index, name } => self
+                .indexes
+                .retain(|cached, cached_index| (*cached_index == index) == (*cached == name)),
+            LinkEvent::Deleted { index } => self
+                .indexes
+                .retain(|_, cached_index| *cached_index != index),
+        }
+    }
+}
+
+fn subscribe() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr = unsafe { mem::zeroed::<libc::sockaddr_nl>() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = libc::RTMGRP_LINK as u32;
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(fd)
+}
+
+/// Reads every event queued on the subscription without blocking.
+fn drain(fd: &OwnedFd) -> io::Result<Vec<LinkEvent>> {
+    let mut buf = vec![0u8; RECV_BUF_LEN];
+    let mut events = Vec::new();
+
+    loop {
+        let len = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Ok(events);
+            }
+            return Err(e);
+        }
+
+        events.extend(parse_messages(&buf[..len as usize]));
+    }
+}
+
+fn parse_messages(mut buf: &[u8]) -> Vec<LinkEvent> {
+    let mut events = Vec::new();
+
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+
+        if let Some(event) = parse_link(kind, &buf[NLMSG_HDRLEN..len]) {
+            events.push(event);
+        }
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    events
+}
+
+fn parse_link(kind: u16, payload: &[u8]) -> Option<LinkEvent> {
+    if payload.len() < IFINFOMSG_LEN {
+        return None;
+    }
+    let index = i32::from_ne_bytes(payload[4..8].try_into().unwrap());
+
+    match kind {
+        libc::RTM_DELLINK => Some(LinkEvent::Deleted { index }),
+        libc::RTM_NEWLINK => {
+            let name = link_name(&payload[IFINFOMSG_LEN..])?;
+            Some(LinkEvent::New { index, name })
+        }
+        _ => None,
+    }
+}
+
+/// Finds `IFLA_IFNAME` among the link's attributes.
+fn link_name(mut attrs: &[u8]) -> Option<String> {
+    while attrs.len() >= RTA_HDRLEN {
+        let len = u16::from_ne_bytes(attrs[0..2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(attrs[2..4].try_into().unwrap());
+        if len < RTA_HDRLEN || len > attrs.len() {
+            return None;
+        }
+
+        if kind == libc::IFLA_IFNAME {
+            let value = &attrs[RTA_HDRLEN..len];
+            let value = value.split(|byte| *byte == 0).next().unwrap_or_default();
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+
+    None
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link_message(kind: u16, index: i32, name: &str) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend_from_slice(&((RTA_HDRLEN + name.len() + 1) as u16).to_ne_bytes());
+        attr.extend_from_slice(&libc::IFLA_IFNAME.to_ne_bytes());
+        attr.extend_from_slice(name.as_bytes());
+        attr.push(0);
+        attr.resize(align(attr.len()), 0);
+
+        let mut ifinfo = vec![0u8; IFINFOMSG_LEN];
+        ifinfo[4..8].copy_from_slice(&index.to_ne_bytes());
+
+        let len = NLMSG_HDRLEN + ifinfo.len() + attr.len();
+        let mut message = Vec::new();
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend(ifinfo);
+        message.extend(attr);
+        message
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        let mut buf = link_message(libc::RTM_NEWLINK, 7, "cni0");
+        buf.extend(link_message(libc::RTM_DELLINK, 8, "vxlan0"));
+        buf.extend(link_message(libc::RTM_NEWADDR, 7, "cni0"));
+
+        assert_eq!(
+            parse_messages(&buf),
+            vec![
+                LinkEvent::New {
+                    index: 7,
+                    name: "cni0".to_owned()
+                },
+                LinkEvent::Deleted { index: 8 },
+            ]
+        );
+        assert!(parse_messages(&buf[..NLMSG_HDRLEN + 3]).is_empty());
+    }
+
+    #[test]
+    fn test_events_invalidate_entries() {
+        let mut cache = LinkIndexCache {
+            indexes: HashMap::new(),
+            events: None,
+        };
+        cache.indexes.insert("cni0".to_owned(), 7);
+        cache.indexes.insert("vxlan0".to_owned(), 8);
+        cache.indexes.insert("eth0".to_owned(), 2);
+
+        // An unrelated change to a cached link keeps it.
+        cache.apply(LinkEvent::New {
+            index: 2,
+            name: "eth0".to_owned(),
+        });
+        assert_eq!(cache.indexes.get("eth0"), Some(&2));
+
+        // cni0 was renamed, then a new link took its old name.
+        cache.apply(LinkEvent::New {
+            index: 7,
+            name: "cni1".to_owned(),
+        });
+        assert_eq!(cache.indexes.get("cni0"), None);
+        cache.indexes.insert("cni0".to_owned(), 7);
+        cache.apply(LinkEvent::New {
+            index: 9,
+            name: "cni0".to_owned(),
+        });
+        assert_eq!(cache.indexes.get("cni0"), None);
+
+        cache.apply(LinkEvent::Deleted { index: 8 });
+        assert_eq!(cache.indexes.get("vxlan0"), None);
+        assert_eq!(cache.indexes.get("eth0"), Some(&2));
+    }
+
+    #[test]
+    fn test_nothing_cached_without_events() {
+        let mut cache = LinkIndexCache {
+            indexes: HashMap::new(),
+            events: None,
+        };
+
+        cache.insert("cni0", 7);
+        assert_eq!(cache.cached("cni0"), None);
+        assert_eq!(cache.index_of("cni0", |_| Ok(9)).unwrap(), 9);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    io, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use rand::Rng;
+use tracing::warn;
+
+/// Netlink requests, grouped by which transient failures are worth retrying
+/// for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetlinkOp {
+    /// Creating a link, e.g. the bridge, the VXLAN device or a veth pair.
+    LinkAdd,
+    /// Changing an existing link: state, master, namespace or name.
+    LinkSet,
+    AddrAdd,
+    RouteAdd,
+    NeighSet,
+}
+
+impl NetlinkOp {
+    /// Whether sending the request twice is harmless. A create is not: if
+    /// the first attempt was applied after all, the retry fails with
+    /// `EEXIST`.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, NetlinkOp::LinkSet | NetlinkOp::NeighSet)
+    }
+}
+
+/// Whether `errno` from `op` is transient. `EEXIST` never is: the call sites
+/// already treat it as "done" where that is safe.
+pub fn is_retryable(op: NetlinkOp, errno: i32) -> bool {
+    match errno {
+        // The request was interrupted or the socket ran out of room. The
+        // kernel may still have applied it, which is why `RetryPolicy` only
+        // resends creates when the caller opted in.
+        libc::EINTR | libc::EAGAIN | libc::ENOBUFS => true,
+        // A device with the same name is still being torn down, or the link
+        // is in the middle of another change.
+        libc::EBUSY => matches!(op, NetlinkOp::LinkAdd | NetlinkOp::LinkSet),
+        _ => false,
+    }
+}
+
+/// Finds the OS error behind a netlink failure: an `io::Error` in the chain,
+/// or the `(os error N)` suffix such errors leave in their message.
+pub fn errno_of(error: &Error) -> Option<i32> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>()?.raw_os_error())
+        .or_else(|| {
+            let message = error.to_string();
+            let (_, errno) = message.rsplit_once("(os error ")?;
+            errno.strip_suffix(')')?.parse().ok()
+        })
+}
+
+/// Exponential backoff between netlink retries. Each delay is drawn between
+/// half and all of the current step, and no retry starts once `max_elapsed`
+/// would be exceeded. Requests that are not idempotent are only retried
+/// when `non_idempotent` is set.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_elapsed: Duration,
+    pub non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+            max_elapsed: Duration::from_secs(5),
+            non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retries creates as well, for call sites that already take `EEXIST`
+    /// as success or use a request that replaces.
+    pub fn with_non_idempotent(mut self) -> Self {
+        self.non_idempotent = true;
+        self
+    }
+
+    fn retries(&self, op: NetlinkOp, errno: i32) -> bool {
+        (self.non_idempotent || op.is_idempotent()) && is_retryable(op, errno)
+    }
+
+    fn jittered(&self, step: Duration) -> Duration {
+        let half = step / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+/// Where a retry loop stands: when it started and the current backoff step.
+struct Backoff<'a> {
+    op: NetlinkOp,
+    policy: &'a RetryPolicy,
+    started: Instant,
+    step: Duration,
+}
+
+impl<'a> Backoff<'a> {
+    fn new(op: NetlinkOp, policy: &'a RetryPolicy) -> Self {
+        Self {
+            op,
+            policy,
+            started: Instant::now(),
+            step: policy.initial_delay,
+        }
+    }
+
+    /// How long to wait before retrying after `error`, or the error back
+    /// when it should not be retried.
+    fn next(&mut self, error: Error) -> Result<Duration> {
+        let delay = self.policy.jittered(self.step);
+        let retryable = errno_of(&error).is_some_and(|errno| self.policy.retries(self.op, errno));
+        if !retryable || self.started.elapsed() + delay > self.policy.max_elapsed {
+            return Err(error);
+        }
+
+        warn!("{:?} failed: {}, retrying in {:?}", self.op, error, delay);
+        self.step = (self.step * 2).min(self.policy.max_delay);
+        Ok(delay)
+    }
+}
+
+/// Runs the netlink request `f`, retrying it with backoff while it fails with
+/// an error `is_retryable` accepts for `op` and `policy` allows retrying `op`
+/// at all. Blocks the calling thread between attempts, so it is meant for
+/// blocking contexts such as the CNI plugin or `spawn_blocking`; async code
+/// uses `retry_netlink_async`.
+pub fn retry_netlink<T>(
+    op: NetlinkOp,
+    policy: &RetryPolicy,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut backoff = Backoff::new(op, policy);
+
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(error) => thread::sleep(backoff.next(error)?),
+        }
+    }
+}
+
+/// `retry_netlink` for async callers: waits between attempts without
+/// holding up the runtime's worker thread.
+pub async fn retry_netlink_async<T>(
+    op: NetlinkOp,
+    policy: &RetryPolicy,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut backoff = Backoff::new(op, policy);
+
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(error) => tokio::time::sleep(backoff.next(error)?).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(2),
+            max_delay: Duration::from_millis(4),
+            max_elapsed: Duration::from_millis(50),
+            non_idempotent: true,
+        }
+    }
+
+    #[test]
+    fn test_classification() {
+        use NetlinkOp::*;
+
+        let table = [
+            (LinkAdd, libc::EBUSY, true),
+            (LinkSet, libc::EBUSY, true),
+            (AddrAdd, libc::EBUSY, false),
+            (RouteAdd, libc::EBUSY, false),
+            (NeighSet, libc::EBUSY, false),
+            (RouteAdd, libc::EAGAIN, true),
+            (NeighSet, libc::EINTR, true),
+            (AddrAdd, libc::ENOBUFS, true),
+            (LinkAdd, libc::EEXIST, false),
+            (RouteAdd, libc::ENETUNREACH, false),
+            (LinkSet, libc::EPERM, false),
+        ];
+
+        for (op, errno, retryable) in table {
+            assert_eq!(is_retryable(op, errno), retryable, "{:?} {}", op, errno);
+        }
+    }
+
+    #[test]
+    fn test_errno_of() {
+        let error = Error::from(io::Error::from_raw_os_error(libc::EBUSY));
+        assert_eq!(errno_of(&error), Some(libc::EBUSY));
+
+        let error = anyhow!("Device or resource busy (os error 16)");
+        assert_eq!(errno_of(&error), Some(16));
+
+        assert_eq!(errno_of(&anyhow!("failed to get link")), None);
+    }
+
+    #[test]
+    fn test_retries_transient_errors() {
+        let mut attempts = 0;
+
+        let value = retry_netlink(NetlinkOp::LinkAdd, &policy(), || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(io::Error::from_raw_os_error(libc::EBUSY).into()),
+                _ => Ok(attempts),
+            }
+        })
+        .unwrap();
+
+        assert_eq!(value, 3);
+    }
+
+    #[test]
+    fn test_retries_eintr_then_succeeds() {
+        let policy = RetryPolicy {
+            non_idempotent: false,
+            ..policy()
+        };
+        let mut attempts = 0;
+
+        retry_netlink(NetlinkOp::LinkSet, &policy, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from_raw_os_error(libc::EINTR).into()),
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_async_retries_without_blocking() {
+        // The test runtime has a single thread: the other task only runs if
+        // the retry loop yields while it waits.
+        let other_ran = Arc::new(AtomicBool::new(false));
+        let other = other_ran.clone();
+        tokio::spawn(async move { other.store(true, Ordering::SeqCst) });
+
+        let mut attempts = 0;
+        let ran_before_success = retry_netlink_async(NetlinkOp::LinkAdd, &policy(), || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from_raw_os_error(libc::EBUSY).into()),
+                _ => Ok(other_ran.load(Ordering::SeqCst)),
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(ran_before_success);
+    }
+
+    #[test]
+    fn test_non_idempotent_needs_opt_in() {
+        let policy = RetryPolicy {
+            non_idempotent: false,
+            ..policy()
+        };
+
+        for op in [NetlinkOp::LinkAdd, NetlinkOp::AddrAdd, NetlinkOp::RouteAdd] {
+            let mut attempts = 0;
+            let result: Result<()> = retry_netlink(op, &policy, || {
+                attempts += 1;
+                Err(io::Error::from_raw_os_error(libc::EINTR).into())
+            });
+
+            assert!(result.is_err());
+            assert_eq!(attempts, 1, "{:?}", op);
+        }
+
+        let mut attempts = 0;
+        retry_netlink(NetlinkOp::AddrAdd, &policy.with_non_idempotent(), || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from_raw_os_error(libc::EINTR).into()),
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_does_not_retry_permanent_errors() {
+        let mut attempts = 0;
+
+        let result: Result<()> = retry_netlink(NetlinkOp::RouteAdd, &policy(), || {
+            attempts += 1;
+            Err(anyhow!("File exists (os error 17)"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_elapsed() {
+        let started = Instant::now();
+
+        let result: Result<()> = retry_netlink(NetlinkOp::NeighSet, &policy(), || {
+            Err(io::Error::from_raw_os_error(libc::EAGAIN).into())
+        });
+
+        assert!(result.is_err());
+        assert!(started.elapsed() <= policy().max_elapsed + Duration::from_millis(20));
+    }
+}
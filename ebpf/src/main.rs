@@ -5,19 +5,27 @@ use core::mem;
 
 use aya_ebpf::bindings::sk_action::SK_PASS;
 use aya_ebpf::bindings::{
-    sk_msg_md, BPF_ANY, BPF_F_INGRESS, BPF_F_PSEUDO_HDR, BPF_SOCK_OPS_ACTIVE_ESTABLISHED_CB,
-    BPF_SOCK_OPS_PASSIVE_ESTABLISHED_CB, BPF_SOCK_OPS_STATE_CB_FLAG, TC_ACT_PIPE, TC_ACT_SHOT,
+    BPF_ANY, BPF_F_INGRESS, BPF_F_PSEUDO_HDR, BPF_SOCK_OPS_ACTIVE_ESTABLISHED_CB,
+    BPF_SOCK_OPS_PASSIVE_ESTABLISHED_CB, BPF_SOCK_OPS_STATE_CB, BPF_SOCK_OPS_STATE_CB_FLAG,
+    BPF_TCP_CLOSE, TC_ACT_PIPE, TC_ACT_SHOT,
 };
 use aya_ebpf::maps::SockHash;
 use aya_ebpf::{
     cty::c_long,
     helpers::{bpf_csum_diff, bpf_get_prandom_u32},
     macros::{classifier, map, sk_msg, sock_ops},
-    maps::HashMap,
+    maps::{Array, HashMap, PerCpuArray},
     programs::{SkMsgContext, SockOpsContext, TcContext},
 };
 use aya_log_ebpf::{error, info};
-use common::{NatKey, NetworkInfo, OriginValue, SockKey, CLUSTER_CIDR_KEY, HOST_IP_KEY};
+use common::{
+    egress_action, EgressAction, MarkConfig, NatKey, NetworkInfo, OriginValue, SockKey, SockKeyV6,
+    SockOpsConfig, AF_INET, AF_INET6, CLUSTER_CIDR_KEY, HOST_IP_KEY, LOCAL_POD_CIDR_KEY,
+    SERVICE_CIDR_KEY, STATS_MAX_ENTRIES, STAT_ARP_REPLY_EGRESS, STAT_ARP_REPLY_INGRESS,
+    STAT_ARP_REQUEST_EGRESS, STAT_ARP_REQUEST_INGRESS, STAT_SK_MSG_EXAMINED,
+    STAT_SK_MSG_REDIRECTED, STAT_SK_MSG_REDIRECTED_BYTES, STAT_SK_MSG_REDIRECT_FAILED,
+    STAT_SOCK_OPS_ADDED, STAT_SOCK_OPS_REMOVED, STAT_SOCK_OPS_SKIPPED,
+};
 use memoffset::offset_of;
 use network_types::{
     eth::{EthHdr, EtherType},
@@ -29,7 +37,13 @@ use network_types::{
 pub static mut SOCK_OPS_MAP: SockHash<SockKey> = SockHash::with_max_entries(65535, 0);
 
 #[map]
-static mut NET_CONFIG_MAP: HashMap<u8, NetworkInfo> = HashMap::with_max_entries(2, 0);
+pub static mut SOCK_OPS_MAP_V6: SockHash<SockKeyV6> = SockHash::with_max_entries(65535, 0);
+
+#[map]
+static mut SOCK_OPS_CONFIG_MAP: Array<SockOpsConfig> = Array::with_max_entries(1, 0);
+
+#[map]
+static mut NET_CONFIG_MAP: HashMap<u8, NetworkInfo> = HashMap::with_max_entries(4, 0);
 
 #[map]
 static mut NODE_MAP: HashMap<u32, u8> = HashMap::with_max_entries(128, 0);
@@ -37,6 +51,16 @@ static mut NODE_MAP: HashMap<u32, u8> = HashMap::with_max_entries(128, 0);
 #[map]
 static mut SNAT_IPV4_MAP: HashMap<NatKey, OriginValue> = HashMap::with_max_entries(128, 0);
 
+#[map]
+static mut MARK_CONFIG_MAP: Array<MarkConfig> = Array::with_max_entries(1, 0);
+
+#[map]
+static mut STATS_MAP: PerCpuArray<u64> = PerCpuArray::with_max_entries(STATS_MAX_ENTRIES, 0);
+
+const ARP_OPCODE_OFFSET: usize = 6;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+
 #[classifier]
 pub fn tc_ingress(ctx: TcContext) -> i32 {
     match try_tc_ingress(ctx) {
@@ -55,6 +79,10 @@ fn try_tc_ingress(ctx: TcContext) -> Result<i32, ()> {
                 _ => Ok(TC_ACT_PIPE),
             }
         }
+        EtherType::Arp => {
+            count_arp(&ctx, STAT_ARP_REQUEST_INGRESS, STAT_ARP_REPLY_INGRESS);
+            Ok(TC_ACT_PIPE)
+        }
         _ => Ok(TC_ACT_PIPE),
     }
 }
@@ -75,6 +103,10 @@ fn handle_tcp_ingress(mut ctx: TcContext) -> Result<i32, ()> {
         return Ok(TC_ACT_PIPE);
     }
 
+    if mark_config().excludes(skb_mark(&ctx)) {
+        return Ok(TC_ACT_PIPE);
+    }
+
     let nat_key = NatKey {
         src_ip: dst_ip,
         dst_ip: src_ip,
@@ -139,6 +171,10 @@ fn try_tc_egress(ctx: TcContext) -> Result<i32, ()> {
                 _ => Ok(TC_ACT_PIPE),
             }
         }
+        EtherType::Arp => {
+            count_arp(&ctx, STAT_ARP_REQUEST_EGRESS, STAT_ARP_REPLY_EGRESS);
+            Ok(TC_ACT_PIPE)
+        }
         _ => Ok(TC_ACT_PIPE),
     }
 }
@@ -152,14 +188,32 @@ fn handle_tcp_egress(mut ctx: TcContext) -> Result<i32, ()> {
 
     let cluster_cidr = unsafe { NET_CONFIG_MAP.get(&CLUSTER_CIDR_KEY).ok_or(()) }?;
 
-    if is_ip_in_cidr(dst_ip, cluster_cidr) {
+    let src_ip = u32::from_be(ip_hdr.src_addr);
+    let src_port = u16::from_be(tcp_hdr.source);
+
+    let marks = mark_config();
+    let mut mark = skb_mark(&ctx);
+
+    if marks.excludes(mark) {
         return Ok(TC_ACT_PIPE);
     }
 
-    let src_ip = u32::from_be(ip_hdr.src_addr);
-    let src_port = u16::from_be(tcp_hdr.source);
+    if cluster_cidr.contains(src_ip) {
+        mark |= marks.pod;
+    }
 
-    if is_node_ip(src_ip) {
+    let local_pods = unsafe { NET_CONFIG_MAP.get(&LOCAL_POD_CIDR_KEY) };
+    let services = unsafe { NET_CONFIG_MAP.get(&SERVICE_CIDR_KEY) };
+    if egress_action(
+        src_ip,
+        dst_ip,
+        cluster_cidr,
+        local_pods,
+        services,
+        is_node_ip,
+    ) == EgressAction::Pass
+    {
+        update_mark(&mut ctx, mark);
         return Ok(TC_ACT_PIPE);
     }
 
@@ -198,6 +252,8 @@ fn handle_tcp_egress(mut ctx: TcContext) -> Result<i32, ()> {
             .map_err(|_| ())
     }?;
 
+    update_mark(&mut ctx, mark | marks.masquerade);
+
     info!(
         &ctx,
         "egress: {:i}:{} -> {:i}:{} / snat: {:i}:{}",
@@ -212,6 +268,78 @@ fn handle_tcp_egress(mut ctx: TcContext) -> Result<i32, ()> {
     Ok(TC_ACT_PIPE)
 }
 
+/// Tags packets pods send into the host with the route mark. It runs on the
+/// bridge's ingress, i.e. before the routing decision, which is what lets
+/// `ip rule ... fwmark` steer pod traffic into a dedicated routing table.
+#[classifier]
+pub fn tc_route_mark(ctx: TcContext) -> i32 {
+    match try_tc_route_mark(ctx) {
+        Ok(ret) => ret,
+        Err(_) => TC_ACT_PIPE,
+    }
+}
+
+fn try_tc_route_mark(mut ctx: TcContext) -> Result<i32, ()> {
+    let eth_hdr: EthHdr = ctx.load(0).map_err(|_| ())?;
+    if !matches!(eth_hdr.ether_type, EtherType::Ipv4) {
+        return Ok(TC_ACT_PIPE);
+    }
+
+    let ip_hdr: Ipv4Hdr = ctx.load(EthHdr::LEN).map_err(|_| ())?;
+    let cluster_cidr = unsafe { NET_CONFIG_MAP.get(&CLUSTER_CIDR_KEY).ok_or(()) }?;
+
+    let marks = mark_config();
+    let mark = skb_mark(&ctx);
+
+    if !marks.excludes(mark) && cluster_cidr.contains(u32::from_be(ip_hdr.src_addr)) {
+        update_mark(&mut ctx, mark | marks.route);
+    }
+
+    Ok(TC_ACT_PIPE)
+}
+
+#[inline(always)]
+fn mark_config() -> MarkConfig {
+    unsafe { MARK_CONFIG_MAP.get(0) }
+        .copied()
+        .unwrap_or_default()
+}
+
+#[inline(always)]
+fn skb_mark(ctx: &TcContext) -> u32 {
+    unsafe { (*ctx.skb.skb).mark }
+}
+
+#[inline(always)]
+fn update_mark(ctx: &mut TcContext, mark: u32) {
+    if mark != skb_mark(ctx) {
+        ctx.set_mark(mark);
+    }
+}
+
+#[inline(always)]
+fn count_arp(ctx: &TcContext, request_key: u32, reply_key: u32) {
+    let key = match ctx.load::<u16>(EthHdr::LEN + ARP_OPCODE_OFFSET) {
+        Ok(op) if u16::from_be(op) == ARP_OP_REQUEST => request_key,
+        Ok(op) if u16::from_be(op) == ARP_OP_REPLY => reply_key,
+        _ => return,
+    };
+
+    increment_stat(key);
+}
+
+#[inline(always)]
+fn increment_stat(key: u32) {
+    add_stat(key, 1);
+}
+
+#[inline(always)]
+fn add_stat(key: u32, value: u64) {
+    if let Some(counter) = unsafe { STATS_MAP.get_ptr_mut(key) } {
+        unsafe { *counter += value };
+    }
+}
+
 #[inline(always)]
 fn snat_v4_rewrite_headers(
     ctx: &mut TcContext,
@@ -270,13 +398,7 @@ fn snat_try_keep_port(start: u16, end: u16, val: u16) -> u16 {
 }
 
 fn is_ip_in_cidr(ip: u32, cidr: &NetworkInfo) -> bool {
-    if is_node_ip(ip) {
-        return true;
-    }
-
-    let network_addr = cidr.ip & cidr.subnet_mask;
-    let masked_ip = ip & cidr.subnet_mask;
-    network_addr == masked_ip
+    common::is_ip_in_cidr(ip, cidr, is_node_ip)
 }
 
 fn is_node_ip(ip: u32) -> bool {
@@ -290,70 +412,85 @@ pub fn tcp_accelerate(ctx: SockOpsContext) -> u32 {
 
 fn try_tcp_accelerate(ctx: SockOpsContext) -> Result<u32, ()> {
     let family = ctx.family();
+    let config = unsafe { SOCK_OPS_CONFIG_MAP.get(0) }
+        .copied()
+        .unwrap_or_default();
 
-    // currently only support IPv4
-    if family != 2 {
+    if !config.accelerates(family) {
         return Ok(0);
     }
 
     match ctx.op() {
         BPF_SOCK_OPS_ACTIVE_ESTABLISHED_CB | BPF_SOCK_OPS_PASSIVE_ESTABLISHED_CB => {
-            // info!(
-            //     &ctx,
-            //     "<<< ipv4 op = {}, src {:i}:{} => dst {:i}:{}",
-            //     ctx.op(),
-            //     u32::from_be(ctx.local_ip4()),
-            //     ctx.local_port(),
-            //     u32::from_be(ctx.remote_ip4()),
-            //     u32::from_be(ctx.remote_port())
-            // );
-
-            let mut sock_key = extract_sock_key_from(&ctx);
-
-            unsafe {
-                SOCK_OPS_MAP
-                    .update(&mut sock_key, &mut *ctx.ops, BPF_ANY.into())
-                    .map_err(|e| {
-                        error!(&ctx, "failed to update SOCK_OPS_MAP: {}", e);
-                    })?;
+            if config.debug() {
+                if family == AF_INET {
+                    info!(
+                        &ctx,
+                        "sock_ops: op = {}, {:i}:{} => {:i}:{}",
+                        ctx.op(),
+                        u32::from_be(ctx.local_ip4()),
+                        ctx.local_port(),
+                        u32::from_be(ctx.remote_ip4()),
+                        u32::from_be(ctx.remote_port())
+                    );
+                } else {
+                    info!(
+                        &ctx,
+                        "sock_ops: op = {}, ipv6 port {} => {}",
+                        ctx.op(),
+                        ctx.local_port(),
+                        u32::from_be(ctx.remote_port())
+                    );
+                }
+            }
+
+            let updated = if family == AF_INET {
+                let mut sock_key = SockKey::from_sock_ops(
+                    ctx.local_ip4(),
+                    ctx.remote_ip4(),
+                    ctx.local_port(),
+                    ctx.remote_port(),
+                );
+                unsafe { SOCK_OPS_MAP.update(&mut sock_key, &mut *ctx.ops, BPF_ANY.into()) }
+            } else {
+                let mut sock_key = SockKeyV6::from_sock_ops(
+                    ctx.local_ip6(),
+                    ctx.remote_ip6(),
+                    ctx.local_port(),
+                    ctx.remote_port(),
+                );
+                unsafe { SOCK_OPS_MAP_V6.update(&mut sock_key, &mut *ctx.ops, BPF_ANY.into()) }
+            };
+
+            // A full map must not abort the callback; the connection just
+            // stays on the regular network stack.
+            if let Err(e) = updated {
+                increment_stat(STAT_SOCK_OPS_SKIPPED);
+                if config.debug() {
+                    error!(&ctx, "failed to update the sock ops map: {}", e);
+                }
+                return Ok(0);
             }
+            increment_stat(STAT_SOCK_OPS_ADDED);
 
             ctx.set_cb_flags(BPF_SOCK_OPS_STATE_CB_FLAG as i32)
                 .map_err(|e| {
                     error!(&ctx, "failed to set BPF_SOCK_OPS_STATE_CB_FLAG: {}", e);
                 })?;
         }
-        // BPF_SOCK_OPS_STATE_CB => match ctx.arg(1) {
-        //     BPF_TCP_CLOSE | BPF_TCP_CLOSE_WAIT | BPF_TCP_LAST_ACK => {
-        //         // info!(
-        //         //     &ctx,
-        //         //     ">>> ipv4 op = {}, src {:i}:{} => dst {:i}:{}, state: {}",
-        //         //     ctx.op(),
-        //         //     u32::from_be(ctx.local_ip4()),
-        //         //     ctx.local_port(),
-        //         //     u32::from_be(ctx.remote_ip4()),
-        //         //     u32::from_be(ctx.remote_port()),
-        //         //     ctx.arg(1)
-        //         // );
-        //     }
-        //     _ => {}
-        // },
+        // The kernel drops closed sockets from the sockhash on its own; this
+        // only keeps the occupancy count in step.
+        BPF_SOCK_OPS_STATE_CB => {
+            if ctx.arg(1) == BPF_TCP_CLOSE {
+                increment_stat(STAT_SOCK_OPS_REMOVED);
+            }
+        }
         _ => {}
     }
 
     Ok(0)
 }
 
-fn extract_sock_key_from(ctx: &SockOpsContext) -> SockKey {
-    SockKey {
-        src_ip: u32::from_be(ctx.local_ip4()),
-        dst_ip: u32::from_be(ctx.remote_ip4()),
-        src_port: ctx.local_port(),
-        dst_port: u32::from_be(ctx.remote_port()),
-        family: ctx.family(),
-    }
-}
-
 #[sk_msg]
 pub fn tcp_bypass(ctx: SkMsgContext) -> u32 {
     try_tcp_bypass(ctx).unwrap_or(SK_PASS)
@@ -363,14 +500,38 @@ fn try_tcp_bypass(ctx: SkMsgContext) -> Result<u32, ()> {
     // info!(&ctx, "received a message on the socket");
 
     let msg = unsafe { &*ctx.msg };
+    increment_stat(STAT_SK_MSG_EXAMINED);
+
+    let ret = match msg.family {
+        AF_INET => {
+            let mut sock_key = SockKey::peer_of_sk_msg(
+                msg.local_ip4,
+                msg.remote_ip4,
+                msg.local_port,
+                msg.remote_port,
+            );
+            unsafe { SOCK_OPS_MAP.redirect_msg(&ctx, &mut sock_key, BPF_F_INGRESS as u64) }
+        }
+        AF_INET6 => {
+            let mut sock_key = SockKeyV6::peer_of_sk_msg(
+                msg.local_ip6,
+                msg.remote_ip6,
+                msg.local_port,
+                msg.remote_port,
+            );
+            unsafe { SOCK_OPS_MAP_V6.redirect_msg(&ctx, &mut sock_key, BPF_F_INGRESS as u64) }
+        }
+        _ => return Ok(SK_PASS),
+    };
 
-    if msg.family != 2 {
-        return Ok(SK_PASS);
+    // The helper returns SK_DROP when the peer socket is not in the map,
+    // and the message then goes through the regular stack.
+    if ret == SK_PASS as i64 {
+        increment_stat(STAT_SK_MSG_REDIRECTED);
+        add_stat(STAT_SK_MSG_REDIRECTED_BYTES, msg.size as u64);
+    } else {
+        increment_stat(STAT_SK_MSG_REDIRECT_FAILED);
     }
-
-    let mut sock_key = sk_msg_extract_key(msg);
-
-    unsafe { SOCK_OPS_MAP.redirect_msg(&ctx, &mut sock_key, BPF_F_INGRESS as u64) };
     // info!(
     //     &ctx,
     //     "tcp_bypass: {:i}:{} <-> {:i}:{} / ret: {}",
@@ -384,16 +545,6 @@ fn try_tcp_bypass(ctx: SkMsgContext) -> Result<u32, ()> {
     Ok(SK_PASS)
 }
 
-fn sk_msg_extract_key(msg: &sk_msg_md) -> SockKey {
-    SockKey {
-        src_ip: u32::from_be(msg.remote_ip4),
-        dst_ip: u32::from_be(msg.local_ip4),
-        src_port: u32::from_be(msg.remote_port),
-        dst_port: msg.local_port,
-        family: msg.family,
-    }
-}
-
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }